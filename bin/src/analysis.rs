@@ -8,8 +8,10 @@ use axum::{
 };
 use futures::TryStreamExt;
 use log::{debug, error, info};
-use rayhunter::analysis::analyzer::Harness;
-use rayhunter::diag::{DataType, MessagesContainer};
+use rayhunter::analysis::analyzer::{Harness, Severity};
+use rayhunter::analysis::observed_plmn::Plmn;
+use rayhunter::analysis::warning_rate::WarningRateAnalyzer;
+use rayhunter::diag::{DataType, DiagParsingError, Message, MessagesContainer};
 use rayhunter::qmdl::QmdlReader;
 use serde::Serialize;
 use tokio::fs::File;
@@ -18,14 +20,35 @@ use tokio::sync::mpsc::Receiver;
 use tokio::sync::{RwLock, RwLockWriteGuard};
 use tokio_util::task::TaskTracker;
 
+use crate::config::AnalyzerConfig;
+use crate::framebuffer::UiUpdate;
 use crate::qmdl_store::RecordingStore;
 use crate::server::ServerState;
 use crate::dummy_analyzer::TestAnalyzer;
 
+// Builds a Harness with all the analyzers enabled by the config
+pub fn build_harness(analyzer_config: &AnalyzerConfig) -> Harness {
+    let mut harness = Harness::new_with_analyzers(|key| analyzer_config.is_analyzer_enabled(key));
+    if analyzer_config.enable_dummy_analyzer {
+        harness.add_analyzer(Box::new(TestAnalyzer { count: 0 }));
+    }
+    if analyzer_config.warning_rate_threshold > 0 {
+        harness.set_warning_rate_analyzer(WarningRateAnalyzer::new(
+            analyzer_config.warning_rate_threshold,
+            chrono::Duration::seconds(analyzer_config.warning_rate_window_secs as i64),
+        ));
+    }
+    harness
+}
+
 pub struct AnalysisWriter {
     writer: BufWriter<File>,
     harness: Harness,
     bytes_written: usize,
+    warning_count: usize,
+    // how many of the harness's observed PLMNs have been handed out by
+    // take_new_observed_plmns
+    reported_plmns: usize,
 }
 
 // We write our analysis results to a file immediately to minimize the amount of
@@ -35,15 +58,13 @@ pub struct AnalysisWriter {
 // lets us simply append new rows to the end without parsing the entire JSON
 // object beforehand.
 impl AnalysisWriter {
-    pub async fn new(file: File, enable_dummy_analyzer: bool) -> Result<Self, std::io::Error> {
-        let mut harness = Harness::new_with_all_analyzers();
-        if enable_dummy_analyzer {
-            harness.add_analyzer(Box::new(TestAnalyzer { count: 0 }));
-        }
-
+    pub async fn new(file: File, analyzer_config: &AnalyzerConfig) -> Result<Self, std::io::Error> {
+        let harness = build_harness(analyzer_config);
         let mut result = Self {
             writer: BufWriter::new(file),
             bytes_written: 0,
+            warning_count: 0,
+            reported_plmns: 0,
             harness,
         };
         let metadata = result.harness.get_metadata();
@@ -52,13 +73,37 @@ impl AnalysisWriter {
     }
 
     // Runs the analysis harness on the given container, serializing the results
-    // to the analysis file and returning the file's new length.
-    pub async fn analyze(&mut self, container: MessagesContainer) -> Result<(usize, bool), std::io::Error> {
-        let row = self.harness.analyze_qmdl_messages(container);
+    // to the analysis file and returning the file's new length, along with the
+    // highest severity of any warnings raised.
+    pub async fn analyze(&mut self, container: MessagesContainer) -> Result<(usize, Option<Severity>), std::io::Error> {
+        self.analyze_messages(container.into_messages()).await
+    }
+
+    // Same as analyze, but for messages that have already been parsed out of
+    // their container
+    pub async fn analyze_messages(&mut self, messages: Vec<Result<Message, DiagParsingError>>) -> Result<(usize, Option<Severity>), std::io::Error> {
+        let row = self.harness.analyze_messages(messages);
+        self.warning_count += row.warning_count();
         if !row.is_empty() {
             self.write(&row).await?;
         }
-        Ok((self.bytes_written, row.contains_warnings()))
+        Ok((self.bytes_written, row.max_warning_severity()))
+    }
+
+    // The total number of warnings raised so far
+    pub fn warning_count(&self) -> usize {
+        self.warning_count
+    }
+
+    // Every PLMN seen so far, if any new ones have been seen since this was
+    // last called, so they only need writing to the manifest when they change
+    pub fn take_new_observed_plmns(&mut self) -> Option<Vec<Plmn>> {
+        let observed_plmns = self.harness.observed_plmns();
+        if observed_plmns.len() == self.reported_plmns {
+            return None;
+        }
+        self.reported_plmns = observed_plmns.len();
+        Some(observed_plmns.to_vec())
     }
 
     async fn write<T: Serialize>(&mut self, value: &T) -> Result<(), std::io::Error> {
@@ -81,10 +126,26 @@ impl AnalysisWriter {
 pub struct AnalysisStatus {
     queued: Vec<String>,
     running: Option<String>,
+    // how many of the current recording's warnings the user has acknowledged,
+    // i.e. they've acknowledged up to this warning
+    pub acknowledged_warnings: usize,
+}
+
+impl AnalysisStatus {
+    pub fn is_queued_or_running(&self, name: &str) -> bool {
+        self.queued.iter().any(|n| n == name) || self.running.iter().any(|n| n == name)
+    }
+
+    // How many recordings are waiting for or undergoing analysis
+    pub fn num_pending(&self) -> usize {
+        self.queued.len() + self.running.iter().count()
+    }
 }
 
 pub enum AnalysisCtrlMessage {
     NewFilesQueued,
+    // the config was reloaded, so use this for any analysis from now on
+    UpdateConfig(AnalyzerConfig),
     Exit,
 }
 
@@ -108,14 +169,15 @@ async fn clear_running(analysis_status_lock: Arc<RwLock<AnalysisStatus>>) {
 async fn perform_analysis(
     name: &str,
     qmdl_store_lock: Arc<RwLock<RecordingStore>>,
-    enable_dummy_analyzer: bool,
+    analyzer_config: &AnalyzerConfig,
 ) -> Result<(), String> {
     info!("Opening QMDL and analysis file for {}...", name);
-    let (analysis_file, qmdl_file, entry_index) = {
+    let (analysis_file, qmdl_file, qmdl_size_bytes) = {
         let mut qmdl_store = qmdl_store_lock.write().await;
-        let (entry_index, _) = qmdl_store
+        let (entry_index, entry) = qmdl_store
             .entry_for_name(&name)
             .ok_or(format!("failed to find QMDL store entry for {}", name))?;
+        let qmdl_size_bytes = entry.qmdl_size_bytes;
         let analysis_file = qmdl_store
             .clear_and_open_entry_analysis(entry_index)
             .await
@@ -125,18 +187,13 @@ async fn perform_analysis(
             .await
             .map_err(|e| format!("{:?}", e))?;
 
-        (analysis_file, qmdl_file, entry_index)
+        (analysis_file, qmdl_file, qmdl_size_bytes)
     };
 
-    let mut analysis_writer = AnalysisWriter::new(analysis_file, enable_dummy_analyzer)
+    let mut analysis_writer = AnalysisWriter::new(analysis_file, analyzer_config)
         .await
         .map_err(|e| format!("{:?}", e))?;
-    let file_size = qmdl_file
-        .metadata()
-        .await
-        .expect("failed to get QMDL file metadata")
-        .len();
-    let mut qmdl_reader = QmdlReader::new(qmdl_file, Some(file_size as usize));
+    let mut qmdl_reader = QmdlReader::new(qmdl_file, Some(qmdl_size_bytes));
     let mut qmdl_stream = pin::pin!(qmdl_reader
         .as_stream()
         .try_filter(|container| future::ready(container.data_type == DataType::UserSpace)));
@@ -153,10 +210,21 @@ async fn perform_analysis(
             .map_err(|e| format!("{:?}", e))?;
         debug!("{} analysis: {} bytes written", name, size_bytes);
         let mut qmdl_store = qmdl_store_lock.write().await;
+        // old recordings may have been deleted (see max_recordings) since we
+        // started, which shifts entry indices around
+        let (entry_index, _) = qmdl_store
+            .entry_for_name(name)
+            .ok_or(format!("QMDL store entry for {} was deleted during analysis", name))?;
         qmdl_store
-            .update_entry_analysis_size(entry_index, size_bytes)
+            .update_entry_analysis(entry_index, size_bytes, analysis_writer.warning_count())
             .await
             .map_err(|e| format!("{:?}", e))?;
+        if let Some(observed_plmns) = analysis_writer.take_new_observed_plmns() {
+            qmdl_store
+                .update_entry_observed_plmns(entry_index, observed_plmns)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+        }
     }
 
     analysis_writer
@@ -168,12 +236,49 @@ async fn perform_analysis(
     Ok(())
 }
 
+// What replaying a QMDL file found
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplaySummary {
+    pub containers: usize,
+    pub warning_count: usize,
+    pub max_severity: Option<Severity>,
+}
+
+// Runs a QMDL file through the same analysis as a live recording, writing the
+// results to a fresh analysis file at analysis_path. This doesn't need the
+// diag device or a recording store, so it can be run anywhere, e.g. to try new
+// heuristics out on old recordings.
+pub async fn replay_qmdl(
+    qmdl_path: &std::path::Path,
+    analysis_path: &std::path::Path,
+    analyzer_config: &AnalyzerConfig,
+) -> Result<ReplaySummary, std::io::Error> {
+    let qmdl_file = File::open(qmdl_path).await?;
+    let qmdl_size_bytes = qmdl_file.metadata().await?.len() as usize;
+    let analysis_file = File::create(analysis_path).await?;
+    let mut analysis_writer = AnalysisWriter::new(analysis_file, analyzer_config).await?;
+    let mut qmdl_reader = QmdlReader::new(qmdl_file, Some(qmdl_size_bytes));
+    let mut qmdl_stream = pin::pin!(qmdl_reader
+        .as_stream()
+        .try_filter(|container| future::ready(container.data_type == DataType::UserSpace)));
+
+    let mut summary = ReplaySummary::default();
+    while let Some(container) = qmdl_stream.try_next().await? {
+        let (_, max_severity) = analysis_writer.analyze(container).await?;
+        summary.containers += 1;
+        summary.max_severity = summary.max_severity.max(max_severity);
+    }
+    summary.warning_count = analysis_writer.warning_count();
+    analysis_writer.close().await?;
+    Ok(summary)
+}
+
 pub fn run_analysis_thread(
     task_tracker: &TaskTracker,
     mut analysis_rx: Receiver<AnalysisCtrlMessage>,
     qmdl_store_lock: Arc<RwLock<RecordingStore>>,
     analysis_status_lock: Arc<RwLock<AnalysisStatus>>,
-    enable_dummy_analyzer: bool,
+    mut analyzer_config: AnalyzerConfig,
 ) {
     task_tracker.spawn(async move {
         loop {
@@ -182,12 +287,15 @@ pub fn run_analysis_thread(
                     let count = queued_len(analysis_status_lock.clone()).await;
                     for _ in 0..count {
                         let name = dequeue_to_running(analysis_status_lock.clone()).await;
-                        if let Err(err) = perform_analysis(&name, qmdl_store_lock.clone(), enable_dummy_analyzer).await {
+                        if let Err(err) = perform_analysis(&name, qmdl_store_lock.clone(), &analyzer_config).await {
                             error!("failed to analyze {}: {}", name, err);
                         }
                         clear_running(analysis_status_lock.clone()).await;
                     }
                 }
+                Some(AnalysisCtrlMessage::UpdateConfig(new_analyzer_config)) => {
+                    analyzer_config = new_analyzer_config;
+                },
                 Some(AnalysisCtrlMessage::Exit) | None => return,
             }
         }
@@ -200,6 +308,22 @@ pub async fn get_analysis_status(
     Ok(Json(state.analysis_status_lock.read().await.clone()))
 }
 
+// Takes the current warning off the screen once the user's seen it, without
+// having to stop the recording. The warnings stay in the analysis file; we
+// only note how many have been acknowledged.
+pub async fn ack_warnings(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<AnalysisStatus>, (StatusCode, String)> {
+    let warning_count = state.qmdl_store_lock.read().await
+        .get_current_entry()
+        .map_or(0, |(_, entry)| entry.warning_count);
+    let mut analysis_status = state.analysis_status_lock.write().await;
+    analysis_status.acknowledged_warnings = warning_count;
+    state.ui_update_sender.send(UiUpdate::AcknowledgeWarnings).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't send ui update message: {}", e)))?;
+    Ok(Json(analysis_status.clone()))
+}
+
 fn queue_qmdl(name: &str, analysis_status: &mut RwLockWriteGuard<AnalysisStatus>) -> bool {
     if analysis_status.queued.iter().any(|n| n == name)
         || analysis_status.running.iter().any(|n| n == name)
@@ -246,3 +370,61 @@ pub async fn start_analysis(
     }
     Ok((StatusCode::ACCEPTED, Json(analysis_status.clone())))
 }
+
+// Deletes a recording's analysis results to free up space, keeping its QMDL
+// file so it can be re-analyzed later
+pub async fn clear_analysis(
+    State(state): State<Arc<ServerState>>,
+    Path(qmdl_name): Path<String>,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let analysis_status = state.analysis_status_lock.read().await;
+    if analysis_status.is_queued_or_running(&qmdl_name) {
+        return Err((StatusCode::CONFLICT, format!("{} is queued for analysis", qmdl_name)));
+    }
+    let mut qmdl_store = state.qmdl_store_lock.write().await;
+    let (entry_index, _) = qmdl_store.entry_for_name(&qmdl_name)
+        .ok_or((StatusCode::NOT_FOUND, format!("couldn't find qmdl file with name {}", qmdl_name)))?;
+    if qmdl_store.current_entry == Some(entry_index) {
+        return Err((StatusCode::CONFLICT, "can't clear the analysis of the current recording".to_string()));
+    }
+    qmdl_store.delete_entry_analysis(entry_index).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't clear analysis: {}", e)))?;
+    Ok((StatusCode::OK, "ok".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayhunter::diag::CRC_CCITT;
+    use rayhunter::hdlc::hdlc_encapsulate;
+
+    use crate::self_test::SYNTHETIC_MESSAGE;
+
+    #[tokio::test]
+    async fn test_replay_qmdl() {
+        let dir = tempfile::Builder::new().prefix("replay_test").tempdir().unwrap();
+        let qmdl_path = dir.path().join("capture.qmdl");
+        let analysis_path = dir.path().join("capture.ndjson");
+        let num_messages = 5;
+        tokio::fs::write(&qmdl_path, hdlc_encapsulate(SYNTHETIC_MESSAGE, &CRC_CCITT).repeat(num_messages)).await.unwrap();
+
+        // the dummy analyzer warns about every one of the messages
+        let analyzer_config = AnalyzerConfig { enable_dummy_analyzer: true, ..AnalyzerConfig::default() };
+        let summary = replay_qmdl(&qmdl_path, &analysis_path, &analyzer_config).await.unwrap();
+        assert_eq!(summary, ReplaySummary {
+            containers: num_messages,
+            warning_count: num_messages,
+            max_severity: Some(Severity::Low),
+        });
+
+        // the metadata, then a row per container
+        let analysis = tokio::fs::read_to_string(&analysis_path).await.unwrap();
+        let lines: Vec<serde_json::Value> = analysis.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), num_messages + 1);
+        assert!(lines[0]["analyzers"].is_array());
+
+        assert!(replay_qmdl(&dir.path().join("missing.qmdl"), &analysis_path, &analyzer_config).await.is_err());
+    }
+}
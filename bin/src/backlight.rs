@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{error, info, warn};
+
+const BACKLIGHT_SYSFS_PATH: &str = "/sys/class/backlight";
+
+// Controls the screen's backlight via the sysfs backlight interface. Like
+// WarningLed, this is a no-op if the device doesn't have one, so callers don't
+// have to care whether the hardware is actually present.
+pub struct Backlight {
+    brightness_path: Option<PathBuf>,
+    max_brightness: u32,
+}
+
+impl Backlight {
+    pub fn new() -> Self {
+        match find_backlight(Path::new(BACKLIGHT_SYSFS_PATH)) {
+            Some((brightness_path, max_brightness)) => {
+                info!("controlling backlight at {:?}", brightness_path);
+                Backlight { brightness_path: Some(brightness_path), max_brightness }
+            },
+            None => {
+                warn!("couldn't find a backlight under {}, brightness can't be changed", BACKLIGHT_SYSFS_PATH);
+                Backlight { brightness_path: None, max_brightness: 0 }
+            },
+        }
+    }
+
+    // Sets the brightness as a percentage of the backlight's maximum
+    pub fn set_percent(&self, percent: u8) {
+        let Some(brightness_path) = &self.brightness_path else {
+            return;
+        };
+        let value = scale_brightness(percent, self.max_brightness);
+        if let Err(err) = fs::write(brightness_path, value.to_string()) {
+            error!("failed to write backlight brightness {:?}: {}", brightness_path, err);
+        }
+    }
+}
+
+// Returns the brightness file and max brightness of the first backlight under
+// sysfs_path which has both
+fn find_backlight(sysfs_path: &Path) -> Option<(PathBuf, u32)> {
+    let mut backlights: Vec<PathBuf> = fs::read_dir(sysfs_path).ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    // read_dir's order isn't stable, so pick the same one every time
+    backlights.sort();
+    backlights.into_iter().find_map(|backlight| {
+        let max_brightness = fs::read_to_string(backlight.join("max_brightness")).ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        let brightness_path = backlight.join("brightness");
+        brightness_path.exists().then_some((brightness_path, max_brightness))
+    })
+}
+
+// Maps a percentage onto the backlight's 0..=max_brightness range, treating
+// anything over 100 as 100. Any non-zero percentage maps to at least 1, so a
+// dim setting doesn't turn the screen off entirely.
+fn scale_brightness(percent: u8, max_brightness: u32) -> u32 {
+    let percent = u32::from(percent.min(100));
+    let value = (percent * max_brightness + 50) / 100;
+    if percent > 0 {
+        value.max(1).min(max_brightness)
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scale_brightness() {
+        assert_eq!(scale_brightness(0, 255), 0);
+        assert_eq!(scale_brightness(50, 255), 128);
+        assert_eq!(scale_brightness(100, 255), 255);
+        assert_eq!(scale_brightness(200, 255), 255);
+        assert_eq!(scale_brightness(1, 10), 1);
+        assert_eq!(scale_brightness(100, 0), 0);
+    }
+
+    #[test]
+    fn test_find_backlight() {
+        let dir = tempfile::Builder::new().prefix("backlight_test").tempdir().unwrap();
+        assert_eq!(find_backlight(dir.path()), None);
+
+        // a backlight without a max_brightness is skipped
+        let incomplete = dir.path().join("a-incomplete");
+        fs::create_dir(&incomplete).unwrap();
+        fs::write(incomplete.join("brightness"), "0\n").unwrap();
+        let panel = dir.path().join("panel");
+        fs::create_dir(&panel).unwrap();
+        fs::write(panel.join("brightness"), "255\n").unwrap();
+        fs::write(panel.join("max_brightness"), "255\n").unwrap();
+        assert_eq!(find_backlight(dir.path()), Some((panel.join("brightness"), 255)));
+    }
+}
@@ -0,0 +1,243 @@
+use std::io::{Read, Seek, Write};
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::{ReaderStream, SyncIoBridge};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use crate::capabilities::{read_device_model, VERSION};
+use crate::qmdl_store::ManifestEntry;
+use crate::server::ServerState;
+
+const COPY_BUF_SIZE: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("Couldn't read recording: {0}")]
+    ReadError(std::io::Error),
+    #[error("Couldn't write bundle: {0}")]
+    WriteError(#[from] std::io::Error),
+    #[error("Couldn't write zip file: {0}")]
+    ZipError(#[from] zip::result::ZipError),
+    #[cfg(feature = "bundle-signing")]
+    #[error("Couldn't load signing key {0:?}: {1}")]
+    InvalidSigningKey(std::path::PathBuf, String),
+    #[cfg(not(feature = "bundle-signing"))]
+    #[error("A signing key is configured, but this build doesn't support signing")]
+    SigningUnsupported,
+}
+
+// Written to the bundle as metadata.json. Since it includes the hashes of the
+// other files, the (optional) signature only needs to cover this file.
+#[derive(Serialize, Debug)]
+pub struct BundleMetadata {
+    // the recording's time range, the PLMNs seen during it, and the version
+    // of the daemon that recorded it
+    #[serde(flatten)]
+    pub entry: ManifestEntry,
+    // the version of the daemon that made the bundle, which may be newer
+    // than the one that made the recording
+    pub bundler_version: String,
+    pub device_model: Option<String>,
+    pub qmdl_sha256: String,
+    pub analysis_sha256: String,
+}
+
+// Returns a zip file containing a recording's QMDL file, its analysis, and a
+// metadata.json describing them. If a signing key is configured (and the
+// bundle-signing feature is enabled), metadata.json.sig holds an ed25519
+// signature of metadata.json.
+pub async fn get_bundle(State(state): State<Arc<ServerState>>, Path(name): Path<String>) -> Result<Response, (StatusCode, String)> {
+    let name = name.trim_end_matches(".zip");
+    // only hold the lock while opening the files, so recording isn't held up
+    // while the bundle's built
+    let (entry, qmdl_file, analysis_file, store_path) = {
+        let qmdl_store = state.qmdl_store_lock.read().await;
+        let (entry_index, entry) = qmdl_store.entry_for_name(name)
+            .ok_or((StatusCode::NOT_FOUND, format!("couldn't find qmdl file with name {}", name)))?;
+        if qmdl_store.current_entry == Some(entry_index) {
+            return Err((StatusCode::CONFLICT, "can't bundle the current recording, stop it first".to_string()));
+        }
+        let qmdl_file = qmdl_store.open_entry_qmdl(entry_index).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't open recording: {}", e)))?;
+        let analysis_file = qmdl_store.open_entry_analysis(entry_index).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't open recording: {}", e)))?;
+        (entry.clone(), qmdl_file, analysis_file, qmdl_store.path.clone())
+    };
+    let qmdl_reader = SyncIoBridge::new(qmdl_file.take(entry.qmdl_size_bytes as u64));
+    let analysis_reader = SyncIoBridge::new(analysis_file.take(entry.analysis_size_bytes as u64));
+    let device_model = read_device_model().await;
+    let signing_key_path = state.config_lock.read().await.bundle_signing_key_path.as_ref().map(PathBuf::from);
+
+    // zipping and hashing a whole recording is slow, blocking work
+    let bundle_file = tokio::task::spawn_blocking(move || {
+        // bundles can be large, so build them in the store's directory rather
+        // than in memory (or /tmp, which is often a tmpfs)
+        let bundle_file = tempfile::tempfile_in(&store_path)?;
+        let mut zip = ZipWriter::new(bundle_file);
+        write_bundle(&mut zip, entry, qmdl_reader, analysis_reader, device_model, signing_key_path.as_deref())?;
+        let mut bundle_file = zip.finish()?;
+        bundle_file.rewind()?;
+        Ok::<_, BundleError>(bundle_file)
+    }).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't create bundle: {}", e)))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't create bundle: {}", e)))?;
+
+    let headers = [
+        (CONTENT_TYPE, "application/zip".to_string()),
+        (CONTENT_DISPOSITION, format!("attachment; filename=\"{}.zip\"", name)),
+    ];
+    let body = Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(bundle_file)));
+    Ok((headers, body).into_response())
+}
+
+// Writes a bundle of the entry's QMDL data and analysis to zip. This does
+// blocking I/O, so shouldn't be called from an async context.
+pub fn write_bundle<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    entry: ManifestEntry,
+    qmdl_reader: impl Read,
+    analysis_reader: impl Read,
+    device_model: Option<String>,
+    signing_key_path: Option<&FsPath>,
+) -> Result<BundleMetadata, BundleError> {
+    let options = FileOptions::default();
+
+    zip.start_file(format!("{}.qmdl", entry.name), options)?;
+    let qmdl_sha256 = copy_and_hash(qmdl_reader, zip)?;
+
+    zip.start_file(format!("{}.ndjson", entry.name), options)?;
+    let analysis_sha256 = copy_and_hash(analysis_reader, zip)?;
+
+    let metadata = BundleMetadata {
+        entry,
+        bundler_version: VERSION.to_string(),
+        device_model,
+        qmdl_sha256,
+        analysis_sha256,
+    };
+    let metadata_json = serde_json::to_vec_pretty(&metadata)
+        .expect("failed to serialize bundle metadata");
+    zip.start_file("metadata.json", options)?;
+    zip.write_all(&metadata_json)?;
+
+    if let Some(signing_key_path) = signing_key_path {
+        let signature = sign(signing_key_path, &metadata_json)?;
+        zip.start_file("metadata.json.sig", options)?;
+        zip.write_all(&signature)?;
+    }
+    Ok(metadata)
+}
+
+// Copies everything from reader to writer, returning the hex-encoded SHA-256
+// of what was copied
+fn copy_and_hash<R: Read, W: Write>(mut reader: R, writer: &mut W) -> Result<String, BundleError> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; COPY_BUF_SIZE];
+    loop {
+        let bytes_read = reader.read(&mut buf)
+            .map_err(BundleError::ReadError)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buf[..bytes_read]);
+        writer.write_all(&buf[..bytes_read])?;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Signs data with the raw 32 byte ed25519 secret key at signing_key_path
+#[cfg(feature = "bundle-signing")]
+fn sign(signing_key_path: &FsPath, data: &[u8]) -> Result<Vec<u8>, BundleError> {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    let key_bytes = std::fs::read(signing_key_path)
+        .map_err(|e| BundleError::InvalidSigningKey(signing_key_path.to_path_buf(), e.to_string()))?;
+    let key_bytes: [u8; 32] = key_bytes.try_into()
+        .map_err(|_| BundleError::InvalidSigningKey(signing_key_path.to_path_buf(), "expected a 32 byte key".to_string()))?;
+    let signing_key = SigningKey::from_bytes(&key_bytes);
+    Ok(signing_key.sign(data).to_bytes().to_vec())
+}
+
+// Rather than quietly handing out unsigned bundles when signing was asked for
+#[cfg(not(feature = "bundle-signing"))]
+fn sign(_signing_key_path: &FsPath, _data: &[u8]) -> Result<Vec<u8>, BundleError> {
+    Err(BundleError::SigningUnsupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmdl_store::RecordingStore;
+    use rayhunter::analysis::observed_plmn::Plmn;
+    use std::io::Cursor;
+    use zip::ZipArchive;
+
+    fn read_zip_file(archive: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> Vec<u8> {
+        let mut contents = Vec::new();
+        archive.by_name(name).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    async fn make_entry(dir: &tempfile::TempDir) -> ManifestEntry {
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let entry_index = store.current_entry.unwrap();
+        store.update_entry_observed_plmns(entry_index, vec![Plmn::new("310", "410")]).await.unwrap();
+        store.manifest.entries[entry_index].clone()
+    }
+
+    #[tokio::test]
+    async fn test_write_bundle() {
+        let dir = tempfile::Builder::new().prefix("bundle_test").tempdir().unwrap();
+        let entry = make_entry(&dir).await;
+        let name = entry.name.clone();
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let metadata = write_bundle(&mut zip, entry, &b"qmdl data"[..], &b"{}\n"[..], Some("Orbic RC400L".to_string()), None).unwrap();
+        let mut archive = ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        assert_eq!(read_zip_file(&mut archive, &format!("{}.qmdl", name)), b"qmdl data");
+        assert_eq!(read_zip_file(&mut archive, &format!("{}.ndjson", name)), b"{}\n");
+        assert_eq!(metadata.qmdl_sha256, format!("{:x}", Sha256::digest(b"qmdl data")));
+        let metadata_json: serde_json::Value = serde_json::from_slice(&read_zip_file(&mut archive, "metadata.json")).unwrap();
+        assert_eq!(metadata_json["name"], name);
+        assert_eq!(metadata_json["bundler_version"], VERSION);
+        assert_eq!(metadata_json["device_model"], "Orbic RC400L");
+        assert_eq!(metadata_json["observed_plmns"], serde_json::json!([{ "mcc": "310", "mnc": "410" }]));
+        assert!(metadata_json["start_time"].is_string());
+        assert_eq!(metadata_json["qmdl_sha256"], metadata.qmdl_sha256);
+        assert_eq!(metadata_json["analysis_sha256"], metadata.analysis_sha256);
+        assert!(archive.by_name("metadata.json.sig").is_err());
+    }
+
+    #[cfg(feature = "bundle-signing")]
+    #[tokio::test]
+    async fn test_signed_bundle() {
+        use ed25519_dalek::{Signature, SigningKey, Verifier};
+
+        let dir = tempfile::Builder::new().prefix("bundle_test").tempdir().unwrap();
+        let entry = make_entry(&dir).await;
+        let key_path = dir.path().join("bundle.key");
+        std::fs::write(&key_path, [7; 32]).unwrap();
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        write_bundle(&mut zip, entry, &b"qmdl data"[..], &b"{}\n"[..], None, Some(key_path.as_path())).unwrap();
+        let mut archive = ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        let metadata_json = read_zip_file(&mut archive, "metadata.json");
+        let signature_bytes: [u8; 64] = read_zip_file(&mut archive, "metadata.json.sig").try_into().unwrap();
+        let verifying_key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        assert!(verifying_key.verify(&metadata_json, &Signature::from_bytes(&signature_bytes)).is_ok());
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::Json;
+use rayhunter::analysis::analyzer::AnalyzerMetadata;
+use rayhunter::diag_device::LOG_CODES_FOR_RAW_PACKET_LOGGING;
+use rayhunter::util::RuntimeMetadata;
+use serde::Serialize;
+
+use crate::analysis::build_harness;
+use crate::config::Config;
+use crate::framebuffer::{self, Dimensions, Framebuffer};
+use crate::server::ServerState;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Where the kernel exposes the board's model name, on devices with a device
+// tree
+const DEVICE_MODEL_PATH: &str = "/proc/device-tree/model";
+
+// Optional functionality built into this version of the daemon, so clients can
+// tell what's supported without guessing from the version number
+const FEATURES: [&str; 12] = [
+    "pcap_export",
+    "gzip_qmdl",
+    "nr_nas_parsing",
+    "gpio_warning_led",
+    "analysis_report_follow",
+    "raw_diag_commands",
+    "screenshot",
+    "bundle_export",
+    "self_test",
+    "live_ws",
+    "metrics",
+    "export_all",
+];
+
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    pub features: Vec<&'static str>,
+    pub debug_mode: bool,
+    pub read_only: bool,
+    pub log_codes: Vec<String>,
+    pub analyzers: Vec<AnalyzerMetadata>,
+    pub display: DisplayCapabilities,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DisplayCapabilities {
+    pub ui_level: u8,
+    pub dimensions: Dimensions,
+    pub bits_per_pixel: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    pub version: &'static str,
+    pub device_model: Option<String>,
+    pub system_os: String,
+    pub arch: String,
+    pub features: Vec<&'static str>,
+    pub config: Config,
+    pub display_dimensions: Dimensions,
+    pub recording: bool,
+    pub bound_port: Option<u16>,
+}
+
+fn features() -> Vec<&'static str> {
+    let mut features = FEATURES.to_vec();
+    if cfg!(feature = "bundle-signing") {
+        features.push("bundle_signing");
+    }
+    features
+}
+
+pub async fn get_capabilities(State(state): State<Arc<ServerState>>) -> Json<Capabilities> {
+    let config = state.config_lock.read().await;
+    let harness = build_harness(&config.analyzer_config);
+    Json(Capabilities {
+        features: features(),
+        debug_mode: config.debug_mode,
+        read_only: config.read_only,
+        log_codes: LOG_CODES_FOR_RAW_PACKET_LOGGING.iter()
+            .map(|code| format!("{:#06x}", code))
+            .collect(),
+        analyzers: harness.get_metadata().analyzers,
+        display: DisplayCapabilities {
+            ui_level: config.ui_level,
+            dimensions: Framebuffer::new_virtual().dimensions(),
+            bits_per_pixel: framebuffer::BITS_PER_PIXEL,
+        },
+    })
+}
+
+// The board's model name, if the kernel exposes one
+pub async fn read_device_model() -> Option<String> {
+    tokio::fs::read_to_string(DEVICE_MODEL_PATH).await.ok()
+        .map(|model| model.trim_end_matches(['\0', '\n']).to_string())
+}
+
+// Everything a dashboard might want to know about the daemon and the device
+// it's running on, in one call
+pub async fn get_device_info(State(state): State<Arc<ServerState>>) -> Json<DeviceInfo> {
+    let metadata = RuntimeMetadata::new();
+    Json(DeviceInfo {
+        version: VERSION,
+        device_model: read_device_model().await,
+        system_os: metadata.system_os,
+        arch: metadata.arch,
+        features: features(),
+        config: state.config_lock.read().await.clone(),
+        display_dimensions: Framebuffer::new_virtual().dimensions(),
+        recording: state.qmdl_store_lock.read().await.current_entry.is_some(),
+        bound_port: state.bound_port,
+    })
+}
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, future, path::PathBuf, pin::pin};
 use log::{info, warn};
-use rayhunter::{analysis::analyzer::{EventType, Harness}, diag::DataType, gsmtap_parser, pcap::GsmtapPcapWriter, qmdl::QmdlReader};
+use rayhunter::{analysis::analyzer::{all_analyzers, EventType, Harness}, diag::DataType, gsmtap_parser, pcap::GsmtapPcapWriter, qmdl::QmdlReader};
 use tokio::fs::{metadata, read_dir, File};
 use clap::Parser;
 use futures::TryStreamExt;
@@ -22,6 +22,11 @@ struct Args {
     #[arg(long)]
     enable_dummy_analyzer: bool,
 
+    /// Don't run the analyzer with this key, e.g. "rat_downgrade". Can be
+    /// given more than once.
+    #[arg(long)]
+    disable_analyzer: Vec<String>,
+
     #[arg(short, long)]
     verbose: bool,
 }
@@ -44,24 +49,44 @@ async fn analyze_file(harness: &mut Harness, qmdl_path: &str, show_skipped: bool
             skipped += 1;
         }
         for analysis in row.analysis {
+            let mut cell = Vec::new();
+            if let Some(earfcn) = analysis.earfcn {
+                cell.push(format!("EARFCN {}", earfcn));
+            }
+            if let Some(band) = analysis.band {
+                cell.push(band.to_string());
+            }
+            if let Some(phy_cell_id) = analysis.phy_cell_id {
+                cell.push(format!("PCI {}", phy_cell_id));
+            }
+            if let Some(tac) = analysis.tac {
+                cell.push(format!("TAC {}", tac));
+            }
+            let location = if cell.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", cell.join(", "))
+            };
             for maybe_event in analysis.events {
                 let Some(event) = maybe_event else { continue };
                 match event.event_type {
                     EventType::Informational => {
                         info!(
-                            "{}: INFO - {} {}",
+                            "{}: INFO - {} {}{}",
                             qmdl_path,
                             analysis.timestamp,
                             event.message,
+                            location,
                         );
                     }
                     EventType::QualitativeWarning { severity } => {
                         warn!(
-                            "{}: WARNING (Severity: {:?}) - {} {}",
+                            "{}: WARNING (Severity: {:?}) - {} {}{}",
                             qmdl_path,
                             severity,
                             analysis.timestamp,
                             event.message,
+                            location,
                         );
                         warnings += 1;
                     }
@@ -113,7 +138,13 @@ async fn main() {
         .with_level(level)
         .init().unwrap();
 
-    let mut harness = Harness::new_with_all_analyzers();
+    let known_analyzers: Vec<&str> = all_analyzers().into_iter().map(|(key, _)| key).collect();
+    for key in &args.disable_analyzer {
+        if !known_analyzers.contains(&key.as_str()) {
+            warn!("ignoring unknown analyzer \"{}\"", key);
+        }
+    }
+    let mut harness = Harness::new_with_analyzers(|key| !args.disable_analyzer.iter().any(|disabled| disabled == key));
     if args.enable_dummy_analyzer {
         harness.add_analyzer(Box::new(dummy_analyzer::TestAnalyzer { count: 0 }));
     }
@@ -1,25 +1,137 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
 use crate::error::RayhunterError;
+use crate::framebuffer::Rotation;
+use crate::logging::LogFormat;
 
-use serde::Deserialize;
+use clap::Parser;
+use log::warn;
+use rayhunter::analysis::analyzer::all_analyzers;
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 struct ConfigFile {
     qmdl_store_path: Option<String>,
     port: Option<u16>,
+    bind_address: Option<IpAddr>,
+    port_fallback: Option<bool>,
+    diag_tcp_port: Option<u16>,
     debug_mode: Option<bool>,
     ui_level: Option<u8>,
     enable_dummy_analyzer: Option<bool>,
     colorblind_mode: Option<bool>,
+    read_only: Option<bool>,
+    api_token: Option<String>,
+    api_token_protects_static: Option<bool>,
+    warning_led_gpio_pin: Option<u32>,
+    auto_pause_idle_secs: Option<u64>,
+    warning_display_secs: Option<u64>,
+    bundle_signing_key_path: Option<String>,
+    max_recordings: Option<usize>,
+    max_store_bytes: Option<u64>,
+    max_recording_bytes: Option<usize>,
+    unix_socket_path: Option<String>,
+    display_rotation: Option<Rotation>,
+    framebuffer_path: Option<String>,
+    brightness: Option<u8>,
+    gif_loops: Option<u32>,
+    gif_speed: Option<f32>,
+    log_format: Option<LogFormat>,
+    log_buffer_lines: Option<usize>,
+    warning_rate_threshold: Option<usize>,
+    warning_rate_window_secs: Option<u64>,
+    analyzers: Option<HashMap<String, bool>>,
 }
 
-#[derive(Debug)]
+// The commented example config, with every setting at its default. This is
+// written out on first run so that there's a config file to edit.
+const DEFAULT_CONFIG_TOML: &str = include_str!("../../dist/config.toml.example");
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Config {
     pub qmdl_store_path: String,
     pub port: u16,
+    // the address to listen on, which is every IPv4 interface by default
+    pub bind_address: IpAddr,
+    // whether to try other ports if port is taken, rather than failing
+    pub port_fallback: bool,
+    // the port to stream the raw diag data on, or None to not
+    pub diag_tcp_port: Option<u16>,
     pub debug_mode: bool,
     pub ui_level: u8,
-    pub enable_dummy_analyzer: bool,
     pub colorblind_mode: bool,
+    pub read_only: bool,
+    // never reported over the API, since it'd give the token away
+    #[serde(skip_serializing)]
+    pub api_token: Option<String>,
+    pub api_token_protects_static: bool,
+    pub warning_led_gpio_pin: Option<u32>,
+    pub auto_pause_idle_secs: Option<u64>,
+    // how long to show a warning on screen before going back to the current
+    // status. 0 shows it until the status next changes.
+    pub warning_display_secs: u64,
+    pub bundle_signing_key_path: Option<String>,
+    pub max_recordings: Option<usize>,
+    pub max_store_bytes: Option<u64>,
+    pub max_recording_bytes: Option<usize>,
+    pub unix_socket_path: Option<String>,
+    pub display_rotation: Rotation,
+    pub framebuffer_path: String,
+    // the backlight's brightness as a percentage, or None to leave it alone
+    pub brightness: Option<u8>,
+    // how many times to play the animated UI's GIF before leaving its last
+    // frame up, or 0 to play it forever
+    pub gif_loops: u32,
+    // how fast to play the GIF, relative to its own frame delays
+    pub gif_speed: f32,
+    pub log_format: LogFormat,
+    // how many of the latest log lines to keep for /api/logs, or None to not
+    // keep any
+    pub log_buffer_lines: Option<usize>,
+    pub analyzer_config: AnalyzerConfig,
+}
+
+// Settings which control which heuristics get run, and how
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AnalyzerConfig {
+    pub enable_dummy_analyzer: bool,
+    // the warning rate heuristic fires when more than this many warnings are
+    // raised within warning_rate_window_secs. 0 disables it.
+    pub warning_rate_threshold: usize,
+    pub warning_rate_window_secs: u64,
+    // whether each heuristic, by key, should run. Any not listed do.
+    pub analyzers: HashMap<String, bool>,
+}
+
+impl AnalyzerConfig {
+    pub fn is_analyzer_enabled(&self, key: &str) -> bool {
+        self.analyzers.get(key).copied().unwrap_or(true)
+    }
+
+    // Logs any analyzers in the config which don't exist, e.g. from a typo.
+    // This isn't done while parsing the config, since logging isn't set up
+    // until after that.
+    pub fn warn_about_unknown_analyzers(&self) {
+        let known_analyzers: Vec<&str> = all_analyzers().into_iter().map(|(key, _)| key).collect();
+        for key in self.analyzers.keys() {
+            if !known_analyzers.contains(&key.as_str()) {
+                warn!("ignoring unknown analyzer \"{}\" in config", key);
+            }
+        }
+    }
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        AnalyzerConfig {
+            enable_dummy_analyzer: false,
+            warning_rate_threshold: 10,
+            warning_rate_window_secs: 60,
+            analyzers: HashMap::new(),
+        }
+    }
 }
 
 impl Default for Config {
@@ -27,10 +139,88 @@ impl Default for Config {
         Config {
             qmdl_store_path: "/data/rayhunter/qmdl".to_string(),
             port: 8080,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port_fallback: true,
+            diag_tcp_port: None,
             debug_mode: false,
             ui_level: 1,
-            enable_dummy_analyzer: false,
             colorblind_mode: false,
+            read_only: false,
+            api_token: None,
+            api_token_protects_static: false,
+            warning_led_gpio_pin: None,
+            auto_pause_idle_secs: None,
+            warning_display_secs: 0,
+            bundle_signing_key_path: None,
+            max_recordings: None,
+            max_store_bytes: None,
+            max_recording_bytes: None,
+            unix_socket_path: None,
+            display_rotation: Rotation::None,
+            framebuffer_path: "/dev/fb0".to_string(),
+            brightness: None,
+            gif_loops: 0,
+            gif_speed: 1.0,
+            log_format: LogFormat::Text,
+            log_buffer_lines: None,
+            analyzer_config: AnalyzerConfig::default(),
+        }
+    }
+}
+
+// How a freshly parsed config differs from the running one. Only some settings
+// can be changed without restarting the daemon, and any others which changed
+// are listed in requires_restart.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub ui_level: Option<u8>,
+    pub colorblind_mode: Option<bool>,
+    pub analyzer_config: Option<AnalyzerConfig>,
+    pub requires_restart: Vec<&'static str>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == ConfigDiff::default()
+    }
+}
+
+impl Config {
+    pub fn diff(&self, new: &Config) -> ConfigDiff {
+        let changed = [
+            ("qmdl_store_path", self.qmdl_store_path != new.qmdl_store_path),
+            ("port", self.port != new.port),
+            ("bind_address", self.bind_address != new.bind_address),
+            ("port_fallback", self.port_fallback != new.port_fallback),
+            ("diag_tcp_port", self.diag_tcp_port != new.diag_tcp_port),
+            ("debug_mode", self.debug_mode != new.debug_mode),
+            ("read_only", self.read_only != new.read_only),
+            ("api_token", self.api_token != new.api_token),
+            ("api_token_protects_static", self.api_token_protects_static != new.api_token_protects_static),
+            ("warning_led_gpio_pin", self.warning_led_gpio_pin != new.warning_led_gpio_pin),
+            ("auto_pause_idle_secs", self.auto_pause_idle_secs != new.auto_pause_idle_secs),
+            ("warning_display_secs", self.warning_display_secs != new.warning_display_secs),
+            ("bundle_signing_key_path", self.bundle_signing_key_path != new.bundle_signing_key_path),
+            ("max_recordings", self.max_recordings != new.max_recordings),
+            ("max_store_bytes", self.max_store_bytes != new.max_store_bytes),
+            ("max_recording_bytes", self.max_recording_bytes != new.max_recording_bytes),
+            ("unix_socket_path", self.unix_socket_path != new.unix_socket_path),
+            ("display_rotation", self.display_rotation != new.display_rotation),
+            ("framebuffer_path", self.framebuffer_path != new.framebuffer_path),
+            ("brightness", self.brightness != new.brightness),
+            ("gif_loops", self.gif_loops != new.gif_loops),
+            ("gif_speed", self.gif_speed != new.gif_speed),
+            ("log_format", self.log_format != new.log_format),
+            ("log_buffer_lines", self.log_buffer_lines != new.log_buffer_lines),
+        ];
+        ConfigDiff {
+            ui_level: (self.ui_level != new.ui_level).then_some(new.ui_level),
+            colorblind_mode: (self.colorblind_mode != new.colorblind_mode).then_some(new.colorblind_mode),
+            analyzer_config: (self.analyzer_config != new.analyzer_config).then(|| new.analyzer_config.clone()),
+            requires_restart: changed.into_iter()
+                .filter(|(_, changed)| *changed)
+                .map(|(name, _)| name)
+                .collect(),
         }
     }
 }
@@ -42,25 +232,198 @@ pub fn parse_config<P>(path: P) -> Result<Config, RayhunterError> where P: AsRef
             .map_err(RayhunterError::ConfigFileParsingError)?;
         parsed_config.qmdl_store_path.map(|v| config.qmdl_store_path = v);
         parsed_config.port.map(|v| config.port = v);
+        parsed_config.bind_address.map(|v| config.bind_address = v);
+        parsed_config.port_fallback.map(|v| config.port_fallback = v);
+        parsed_config.diag_tcp_port.map(|v| config.diag_tcp_port = Some(v));
         parsed_config.debug_mode.map(|v| config.debug_mode = v);
         parsed_config.ui_level.map(|v| config.ui_level = v);
-        parsed_config.enable_dummy_analyzer.map(|v| config.enable_dummy_analyzer = v);
+        parsed_config.enable_dummy_analyzer.map(|v| config.analyzer_config.enable_dummy_analyzer = v);
         parsed_config.colorblind_mode.map(|v| config.colorblind_mode = v);
+        parsed_config.read_only.map(|v| config.read_only = v);
+        parsed_config.api_token.map(|v| config.api_token = Some(v));
+        parsed_config.api_token_protects_static.map(|v| config.api_token_protects_static = v);
+        parsed_config.warning_led_gpio_pin.map(|v| config.warning_led_gpio_pin = Some(v));
+        parsed_config.auto_pause_idle_secs.map(|v| config.auto_pause_idle_secs = Some(v));
+        parsed_config.warning_display_secs.map(|v| config.warning_display_secs = v);
+        parsed_config.bundle_signing_key_path.map(|v| config.bundle_signing_key_path = Some(v));
+        parsed_config.max_recordings.map(|v| config.max_recordings = Some(v));
+        parsed_config.max_store_bytes.map(|v| config.max_store_bytes = Some(v));
+        parsed_config.max_recording_bytes.map(|v| config.max_recording_bytes = Some(v));
+        parsed_config.unix_socket_path.map(|v| config.unix_socket_path = Some(v));
+        parsed_config.display_rotation.map(|v| config.display_rotation = v);
+        parsed_config.framebuffer_path.map(|v| config.framebuffer_path = v);
+        parsed_config.brightness.map(|v| config.brightness = Some(v));
+        parsed_config.gif_loops.map(|v| config.gif_loops = v);
+        parsed_config.gif_speed.map(|v| config.gif_speed = v);
+        parsed_config.log_format.map(|v| config.log_format = v);
+        parsed_config.log_buffer_lines.map(|v| config.log_buffer_lines = Some(v));
+        parsed_config.warning_rate_threshold.map(|v| config.analyzer_config.warning_rate_threshold = v);
+        parsed_config.warning_rate_window_secs.map(|v| config.analyzer_config.warning_rate_window_secs = v);
+        parsed_config.analyzers.map(|v| config.analyzer_config.analyzers = v);
     }
     Ok(config)
 }
 
+// Writes the default config to the given path if there's nothing there yet,
+// returning whether it did
+pub fn write_default_config_if_missing<P>(path: P) -> Result<bool, RayhunterError> where P: AsRef<std::path::Path> {
+    if path.as_ref().exists() {
+        return Ok(false);
+    }
+    std::fs::write(path, DEFAULT_CONFIG_TOML)?;
+    Ok(true)
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
 pub struct Args {
+    /// Path to the config file
+    #[arg(default_value = "/data/rayhunter/config.toml")]
     pub config_path: String,
+
+    /// Port to serve the web UI and API on, overriding the config file
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Run without reading from the diag device, overriding the config file
+    #[arg(long)]
+    pub debug: bool,
+
+    /// UI level to draw on the device's screen, overriding the config file
+    #[arg(long)]
+    pub ui_level: Option<u8>,
+
+    /// Directory to store recordings in, overriding the config file
+    #[arg(long)]
+    pub qmdl_store_path: Option<String>,
+
+    /// Analyze this QMDL file with the configured heuristics, writing the
+    /// results next to it as a .ndjson file, then exit without recording
+    #[arg(long, value_name = "QMDL_PATH")]
+    pub replay: Option<PathBuf>,
+
+    /// Cycle the display through each color, then draw a test card showing
+    /// its border, glyphs and dimensions, and exit without recording
+    #[arg(long)]
+    pub test_display: bool,
+}
+
+impl Args {
+    // Overrides the config with any flags that were given. This needs to be
+    // reapplied whenever the config file is reparsed.
+    pub fn apply_overrides(&self, config: &mut Config) {
+        self.port.map(|v| config.port = v);
+        self.ui_level.map(|v| config.ui_level = v);
+        self.qmdl_store_path.clone().map(|v| config.qmdl_store_path = v);
+        if self.debug {
+            config.debug_mode = true;
+        }
+    }
 }
 
 pub fn parse_args() -> Args {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} /path/to/config/file", args[0]);
-        std::process::exit(1);
+    Args::parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_analyzers() {
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut config_file, b"port = 8081\n\n[analyzers]\nnull_cipher = false\nrat_downgrade = true\nnot_an_analyzer = false\n").unwrap();
+        let config = parse_config(config_file.path()).unwrap();
+        assert_eq!(config.port, 8081);
+        assert!(!config.analyzer_config.is_analyzer_enabled("null_cipher"));
+        assert!(config.analyzer_config.is_analyzer_enabled("rat_downgrade"));
+        // analyzers which aren't mentioned are enabled
+        assert!(config.analyzer_config.is_analyzer_enabled("imsi_requested"));
     }
-    Args {
-        config_path: args[1].clone(),
+
+    #[test]
+    fn test_parse_bind_address() {
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut config_file, b"bind_address = \"::1\"\n").unwrap();
+        let config = parse_config(config_file.path()).unwrap();
+        assert_eq!(config.bind_address, "::1".parse::<IpAddr>().unwrap());
+
+        // a typo is an error, rather than quietly listening somewhere else
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut config_file, b"bind_address = \"localhost\"\n").unwrap();
+        let err = parse_config(config_file.path()).unwrap_err();
+        assert!(matches!(err, RayhunterError::ConfigFileParsingError(_)));
+        assert!(err.to_string().contains("bind_address"), "{}", err);
+    }
+
+    #[test]
+    fn test_config_diff() {
+        let config = Config::default();
+        assert!(config.diff(&Config::default()).is_empty());
+
+        let mut analyzer_config = AnalyzerConfig::default();
+        analyzer_config.analyzers.insert("null_cipher".to_string(), false);
+        let new_config = Config {
+            ui_level: 2,
+            port: 8081,
+            max_recordings: Some(10),
+            analyzer_config,
+            ..Config::default()
+        };
+        let diff = config.diff(&new_config);
+        assert_eq!(diff.ui_level, Some(2));
+        assert_eq!(diff.colorblind_mode, None);
+        assert_eq!(diff.analyzer_config, Some(new_config.analyzer_config.clone()));
+        assert_eq!(diff.requires_restart, vec!["port", "max_recordings"]);
+
+        // parsing the same file twice finds nothing to change
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut config_file, b"log_format = \"json\"\ncolorblind_mode = true\nwarning_rate_threshold = 5\n").unwrap();
+        let parsed = parse_config(config_file.path()).unwrap();
+        assert!(parsed.diff(&parse_config(config_file.path()).unwrap()).is_empty());
+        let diff = config.diff(&parsed);
+        assert_eq!(diff.colorblind_mode, Some(true));
+        assert_eq!(diff.analyzer_config.map(|c| c.warning_rate_threshold), Some(5));
+        assert_eq!(diff.requires_restart, vec!["log_format"]);
+    }
+
+    #[test]
+    fn test_args_override_config() {
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut config_file, b"port = 8081\nui_level = 2\nqmdl_store_path = \"/data/qmdl\"\n").unwrap();
+        let config_path = config_file.path().to_str().unwrap();
+        let mut config = parse_config(config_path).unwrap();
+        let args = Args::try_parse_from(["rayhunter-daemon", config_path, "--port", "9000", "--debug", "--ui-level", "0"]).unwrap();
+        args.apply_overrides(&mut config);
+        assert_eq!(config.port, 9000);
+        assert!(config.debug_mode);
+        assert_eq!(config.ui_level, 0);
+        // values without a flag are left as they were in the file
+        assert_eq!(config.qmdl_store_path, "/data/qmdl");
+
+        let args = Args::try_parse_from(["rayhunter-daemon"]).unwrap();
+        assert_eq!(args.config_path, "/data/rayhunter/config.toml");
+    }
+
+    #[test]
+    fn test_write_default_config() {
+        let dir = tempfile::Builder::new().prefix("config_test").tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        assert!(write_default_config_if_missing(&config_path).unwrap());
+        assert_eq!(parse_config(&config_path).unwrap(), Config::default());
+
+        // an existing config is left alone
+        std::fs::write(&config_path, "port = 8081\n").unwrap();
+        assert!(!write_default_config_if_missing(&config_path).unwrap());
+        assert_eq!(parse_config(&config_path).unwrap().port, 8081);
+    }
+
+    #[test]
+    fn test_write_default_config_unwritable() {
+        let dir = tempfile::Builder::new().prefix("config_test").tempdir().unwrap();
+        let config_path = dir.path().join("nonexistent").join("config.toml");
+        assert!(write_default_config_if_missing(&config_path).is_err());
+        // the daemon carries on with the defaults
+        assert_eq!(parse_config(&config_path).unwrap(), Config::default());
     }
 }
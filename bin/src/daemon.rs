@@ -1,88 +1,242 @@
 mod analysis;
+mod backlight;
+mod bundle;
+mod capabilities;
 mod config;
 mod error;
+mod export;
 mod pcap;
 mod server;
 mod stats;
 mod qmdl_store;
 mod diag;
+mod diag_tcp;
 mod framebuffer;
 mod dummy_analyzer;
+mod gpio;
+mod live;
+mod logging;
+mod metrics;
+mod self_test;
 
-use crate::config::{parse_config, parse_args};
+use crate::bundle::get_bundle;
+use crate::export::get_export_all;
+use crate::backlight::Backlight;
+use crate::capabilities::{get_capabilities, get_device_info};
+use crate::config::{parse_config, parse_args, write_default_config_if_missing, Args};
 use crate::diag::run_diag_read_thread;
+use crate::diag_tcp::{run_diag_tcp_server, DiagTcpSender};
 use crate::qmdl_store::RecordingStore;
-use crate::server::{ServerState, get_qmdl, serve_static};
+use crate::self_test::run_self_test;
+use crate::server::{ServerState, api_token_guard, compress_response, delete_qmdl, get_openapi_spec, get_qmdl, get_qmdl_hexdump, get_qmdl_message, get_screenshot, read_only_guard, rename_qmdl, serve_static};
 use crate::pcap::get_pcap;
 use crate::stats::get_system_stats;
 use crate::error::RayhunterError;
-use crate::framebuffer::Framebuffer;
+use crate::framebuffer::{Color565, DisplayStateTracker, Framebuffer, FramebufferError, GifPlayback};
+use crate::gpio::{run_warning_led_thread, WarningLedMessage};
+use crate::live::{get_live_ws, LiveUpdateSender};
+use crate::logging::{get_logs, init_logging, LogBuffer};
+use crate::metrics::get_metrics;
 
-use analysis::{get_analysis_status, run_analysis_thread, start_analysis, AnalysisCtrlMessage, AnalysisStatus};
+use analysis::{ack_warnings, clear_analysis, get_analysis_status, replay_qmdl, run_analysis_thread, start_analysis, AnalysisCtrlMessage, AnalysisStatus};
+use axum::middleware;
 use axum::response::Redirect;
-use diag::{get_analysis_report, start_recording, stop_recording, DiagDeviceCtrlMessage};
-use log::{info, error};
+use diag::{get_analysis_report, send_raw_diag_command, start_recording, stop_recording, DiagDeviceCtrlMessage};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::{debug, info, error, warn};
 use rayhunter::diag_device::DiagDevice;
 use axum::routing::{get, post};
 use axum::Router;
-use stats::get_qmdl_manifest;
+use stats::{get_diag_loop_stats, get_message_stats, get_qmdl_manifest, DiagLoopStats, MessageStats};
 use tokio::sync::mpsc::{self, Sender, Receiver};
-use tokio::sync::oneshot::error::TryRecvError;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::future::Future;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
 use std::thread::sleep;
-use std::time::Duration;
-use tokio::net::TcpListener;
-use tokio::sync::{RwLock, oneshot};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UnixListener};
+use tower::Service;
+use tokio::sync::{RwLock, oneshot, watch};
 use std::sync::Arc;
-use include_dir::{include_dir, Dir};
 
 // Runs the axum server, taking all the elements needed to build up our
 // ServerState and a oneshot Receiver that'll fire when it's time to shutdown
 // (i.e. user hit ctrl+c)
 async fn run_server(
     task_tracker: &TaskTracker,
-    config: &config::Config,
+    config_lock: Arc<RwLock<config::Config>>,
+    listener: Option<TcpListener>,
     qmdl_store_lock: Arc<RwLock<RecordingStore>>,
     server_shutdown_rx: oneshot::Receiver<()>,
-    ui_update_tx: Sender<framebuffer::DisplayState>,
+    shutdown_token: CancellationToken,
+    ui_update_tx: Sender<framebuffer::UiUpdate>,
+    display_state_lock: Arc<RwLock<framebuffer::DisplayState>>,
+    live_update_sender: LiveUpdateSender,
     diag_device_sender: Sender<DiagDeviceCtrlMessage>,
     analysis_sender: Sender<AnalysisCtrlMessage>,
     analysis_status_lock: Arc<RwLock<AnalysisStatus>>,
-) -> JoinHandle<()> {
+    message_stats_lock: Arc<RwLock<MessageStats>>,
+    diag_loop_stats_lock: Arc<RwLock<DiagLoopStats>>,
+    log_buffer: Option<LogBuffer>,
+) -> Result<JoinHandle<()>, RayhunterError> {
     info!("spinning up server");
+    let config = config_lock.read().await.clone();
+    let bound_port = bound_port(listener.as_ref())?;
     let state = Arc::new(ServerState {
         qmdl_store_lock,
         diag_device_ctrl_sender: diag_device_sender,
         ui_update_sender: ui_update_tx,
-        debug_mode: config.debug_mode,
+        display_state_lock,
+        live_update_sender,
         analysis_status_lock,
         analysis_sender,
-        colorblind_mode: config.colorblind_mode,
+        message_stats_lock,
+        diag_loop_stats_lock,
+        started_at: Instant::now(),
+        config_lock,
+        log_buffer,
+        bound_port,
+        task_tracker: task_tracker.clone(),
+        shutdown_token,
+        recording_timer_lock: std::sync::Mutex::new(None),
     });
 
-    let app = Router::new()
+    let app = build_router(state);
+    let Some(listener) = listener else {
+        let socket_path = PathBuf::from(config.unix_socket_path.as_ref().unwrap());
+        let listener = bind_unix_socket(&socket_path).await?;
+        return Ok(task_tracker.spawn(async move {
+            info!("The orca is hunting for stingrays on {}...", socket_path.display());
+            serve_unix(listener, app, server_shutdown_signal(server_shutdown_rx)).await;
+            if let Err(e) = tokio::fs::remove_file(&socket_path).await {
+                warn!("couldn't remove unix socket {}: {}", socket_path.display(), e);
+            }
+        }));
+    };
+    Ok(task_tracker.spawn(async move {
+        info!("The orca is hunting for stingrays on port {}...", bound_port.unwrap_or_default());
+        axum::serve(listener, app)
+            .with_graceful_shutdown(server_shutdown_signal(server_shutdown_rx))
+            .await.unwrap();
+    }))
+}
+
+// Binds a TCP listener at addr. If the port's taken and port_fallback is set,
+// each of FALLBACK_PORTS is tried in turn, loudly, since the web UI won't be
+// where the user expects it.
+async fn bind_tcp_listener(addr: SocketAddr, port_fallback: bool) -> Result<TcpListener, RayhunterError> {
+    let err = match TcpListener::bind(addr).await {
+        Ok(listener) => return Ok(listener),
+        Err(err) => err,
+    };
+    if !port_fallback || err.kind() != std::io::ErrorKind::AddrInUse {
+        return Err(RayhunterError::BindError(addr, err));
+    }
+    for port in FALLBACK_PORTS {
+        let fallback_addr = SocketAddr::new(addr.ip(), port);
+        match TcpListener::bind(fallback_addr).await {
+            Ok(listener) => {
+                warn!("port {} is already in use, serving the web UI on port {} instead", addr.port(), port);
+                return Ok(listener);
+            },
+            Err(e) => warn!("couldn't fall back to port {}: {}", port, e),
+        }
+    }
+    Err(RayhunterError::BindError(addr, err))
+}
+
+fn build_router(state: Arc<ServerState>) -> Router {
+    Router::new()
         .route("/api/pcap/*name", get(get_pcap))
-        .route("/api/qmdl/*name", get(get_qmdl))
+        .route("/api/qmdl/:name", get(get_qmdl).delete(delete_qmdl))
+        .route("/api/qmdl/:name/hexdump", get(get_qmdl_hexdump))
+        .route("/api/qmdl/:name/message", get(get_qmdl_message))
+        .route("/api/qmdl/:name/clear-analysis", post(clear_analysis))
+        .route("/api/qmdl/:name/rename", post(rename_qmdl))
+        .route("/api/bundle/:name", get(get_bundle))
+        .route("/api/export-all.zip", get(get_export_all))
         .route("/api/system-stats", get(get_system_stats))
+        .route("/api/capabilities", get(get_capabilities))
+        .route("/api/device-info", get(get_device_info))
+        .route("/api/logs", get(get_logs))
+        .route("/api/screenshot.png", get(get_screenshot))
         .route("/api/qmdl-manifest", get(get_qmdl_manifest))
+        .route("/api/message-stats", get(get_message_stats))
+        .route("/api/diag-stats", get(get_diag_loop_stats))
         .route("/api/start-recording", post(start_recording))
         .route("/api/stop-recording", post(stop_recording))
         .route("/api/analysis-report/*name", get(get_analysis_report))
+        .route("/api/live-ws", get(get_live_ws))
         .route("/api/analysis", get(get_analysis_status))
         .route("/api/analysis/*name", post(start_analysis))
+        .route("/api/ack-warnings", post(ack_warnings))
+        .route("/api/diag/raw-command", post(send_raw_diag_command))
+        .route("/api/self-test", post(run_self_test))
+        .route("/api/openapi.json", get(get_openapi_spec))
+        .route("/metrics", get(get_metrics))
         .route("/", get(|| async { Redirect::permanent("/index.html") }))
         .route("/*path", get(serve_static))
-        .with_state(state);
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
-    let listener = TcpListener::bind(&addr).await.unwrap();
-    task_tracker.spawn(async move {
-        info!("The orca is hunting for stingrays...");
-        axum::serve(listener, app)
-            .with_graceful_shutdown(server_shutdown_signal(server_shutdown_rx))
-            .await.unwrap();
-    })
+        .layer(middleware::from_fn(compress_response))
+        .layer(middleware::from_fn_with_state(state.clone(), read_only_guard))
+        .layer(middleware::from_fn_with_state(state.clone(), api_token_guard))
+        .with_state(state)
+}
+
+// Binds a unix domain socket at socket_path, removing any stale socket left
+// behind by a previous run that didn't shut down cleanly. Anything else at
+// socket_path is left alone, in case it was misconfigured to point at a file
+// that matters.
+async fn bind_unix_socket(socket_path: &Path) -> std::io::Result<UnixListener> {
+    match tokio::fs::symlink_metadata(socket_path).await {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            tokio::fs::remove_file(socket_path).await?;
+            info!("removed stale unix socket {}", socket_path.display());
+        },
+        Ok(_) => return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists and isn't a unix socket", socket_path.display()),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(socket_path)
+}
+
+// axum::serve only supports TCP listeners, so this drives hyper directly to
+// serve app over a unix domain socket until shutdown_signal completes
+async fn serve_unix(listener: UnixListener, app: Router, shutdown_signal: impl Future<Output = ()>) {
+    let mut shutdown_signal = pin!(shutdown_signal);
+    loop {
+        let socket = tokio::select! {
+            _ = &mut shutdown_signal => return,
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    error!("couldn't accept unix socket connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        let app = app.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |request: axum::extract::Request<Incoming>| {
+                app.clone().call(request)
+            });
+            if let Err(e) = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(socket), service)
+                .await
+            {
+                debug!("error serving unix socket connection: {}", e);
+            }
+        });
+    }
 }
 
 async fn server_shutdown_signal(server_shutdown_rx: oneshot::Receiver<()>) {
@@ -107,7 +261,7 @@ fn run_ctrl_c_thread(
     task_tracker: &TaskTracker,
     diag_device_sender: Sender<DiagDeviceCtrlMessage>,
     server_shutdown_tx: oneshot::Sender<()>,
-    maybe_ui_shutdown_tx: Option<oneshot::Sender<()>>,
+    shutdown_token: CancellationToken,
     qmdl_store_lock: Arc<RwLock<RecordingStore>>,
     analysis_tx: Sender<AnalysisCtrlMessage>,
 ) -> JoinHandle<Result<(), RayhunterError>> {
@@ -123,11 +277,8 @@ fn run_ctrl_c_thread(
 
                 server_shutdown_tx.send(())
                     .expect("couldn't send server shutdown signal");
-                info!("sending UI shutdown");
-                if let Some(ui_shutdown_tx) = maybe_ui_shutdown_tx {
-                    ui_shutdown_tx.send(())
-                        .expect("couldn't send ui shutdown signal");
-                }
+                info!("sending UI and diag TCP shutdown");
+                shutdown_token.cancel();
                 diag_device_sender.send(DiagDeviceCtrlMessage::Exit).await
                     .expect("couldn't send Exit message to diag thread");
                 analysis_tx.send(AnalysisCtrlMessage::Exit).await
@@ -141,75 +292,288 @@ fn run_ctrl_c_thread(
     })
 }
 
-fn update_ui(task_tracker: &TaskTracker,  config: &config::Config, mut ui_shutdown_rx: oneshot::Receiver<()>, mut ui_update_rx: Receiver<framebuffer::DisplayState>) -> JoinHandle<()> {
-    static IMAGE_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/static/images/");
-    let mut display_color: framebuffer::Color565;
-    let display_level = config.ui_level;
+// The UI settings which can be changed by reloading the config
+#[derive(Clone, Copy)]
+struct UiConfig {
+    ui_level: u8,
+    colorblind_mode: bool,
+}
+
+// Re-reads the config whenever we get a SIGHUP, and applies it
+fn run_config_reload_thread(
+    args: Args,
+    config_lock: Arc<RwLock<config::Config>>,
+    ui_config_tx: Option<watch::Sender<UiConfig>>,
+    diag_device_sender: Sender<DiagDeviceCtrlMessage>,
+    analysis_tx: Sender<AnalysisCtrlMessage>,
+) {
+    // this isn't on the TaskTracker, since it'd otherwise keep the daemon from
+    // ever shutting down
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                error!("Unable to listen for SIGHUP, config reloading is disabled: {}", err);
+                return;
+            },
+        };
+        while hangups.recv().await.is_some() {
+            info!("received SIGHUP, reloading config from {}", args.config_path);
+            let mut new_config = match parse_config(&args.config_path) {
+                Ok(new_config) => new_config,
+                Err(err) => {
+                    error!("couldn't reload config, keeping the current one: {}", err);
+                    continue;
+                },
+            };
+            args.apply_overrides(&mut new_config);
+            new_config.analyzer_config.warn_about_unknown_analyzers();
+            apply_config(&config_lock, new_config, ui_config_tx.as_ref(), &diag_device_sender, &analysis_tx).await;
+        }
+    });
+}
+
+// Applies any changes in new_config which don't need a restart, both to
+// config_lock (where the server reads its settings from) and to the threads
+// they affect
+async fn apply_config(
+    config_lock: &RwLock<config::Config>,
+    new_config: config::Config,
+    ui_config_tx: Option<&watch::Sender<UiConfig>>,
+    diag_device_sender: &Sender<DiagDeviceCtrlMessage>,
+    analysis_tx: &Sender<AnalysisCtrlMessage>,
+) {
+    let mut config = config_lock.write().await;
+    let diff = config.diff(&new_config);
+    if diff.is_empty() {
+        info!("config is unchanged");
+        return;
+    }
+    for name in &diff.requires_restart {
+        warn!("ignoring change to {}, which only takes effect after a restart", name);
+    }
+    if diff.ui_level.is_some() || diff.colorblind_mode.is_some() {
+        config.ui_level = new_config.ui_level;
+        config.colorblind_mode = new_config.colorblind_mode;
+        info!("applying ui_level = {}, colorblind_mode = {}", config.ui_level, config.colorblind_mode);
+        // there's no UI thread to tell in debug mode
+        if let Some(ui_config_tx) = ui_config_tx {
+            ui_config_tx.send_replace(UiConfig { ui_level: config.ui_level, colorblind_mode: config.colorblind_mode });
+        }
+    }
+    if let Some(analyzer_config) = diff.analyzer_config {
+        info!("applying new analyzer settings, starting from the next recording");
+        config.analyzer_config = analyzer_config.clone();
+        let debug_mode = config.debug_mode;
+        drop(config);
+        // the diag thread isn't running in debug mode, so nothing would ever
+        // receive this
+        if !debug_mode {
+            if let Err(err) = diag_device_sender.send(DiagDeviceCtrlMessage::UpdateAnalyzerConfig(analyzer_config.clone())).await {
+                error!("couldn't send analyzer config to diag thread: {}", err);
+            }
+        }
+        if let Err(err) = analysis_tx.send(AnalysisCtrlMessage::UpdateConfig(analyzer_config)).await {
+            error!("couldn't send analyzer config to analysis thread: {}", err);
+        }
+    }
+}
+
+// Ports to try, in order, if the configured one is taken
+const FALLBACK_PORTS: [u16; 2] = [8888, 9999];
+
+// How long the web UI's QR code is shown for at startup
+const QR_CODE_DISPLAY_SECS: u64 = 10;
+
+// How long --test-display shows each color, and then the test card
+const DISPLAY_TEST_COLOR_SECS: u64 = 1;
+const DISPLAY_TEST_CARD_SECS: u64 = 5;
+
+// Picks the address a phone on the device's network is most likely to reach
+// us at: a private address (i.e. the hotspot's) if there is one, otherwise any
+// which isn't loopback.
+fn pick_web_ip(addrs: impl IntoIterator<Item = Ipv4Addr>) -> Option<Ipv4Addr> {
+    let addrs: Vec<Ipv4Addr> = addrs.into_iter()
+        .filter(|addr| !addr.is_loopback() && !addr.is_unspecified())
+        .collect();
+    addrs.iter().find(|addr| addr.is_private()).or(addrs.first()).copied()
+}
+
+// The port the server's listener ended up on, which differs from the config's
+// if we fell back to another one. None when serving on a unix socket.
+fn bound_port(listener: Option<&TcpListener>) -> Result<Option<u16>, RayhunterError> {
+    Ok(listener.map(|listener| listener.local_addr()).transpose()?.map(|addr| addr.port()))
+}
+
+// When the server listens on every interface, its URL is built from the
+// interfaces' addresses rather than the listener's. There's nothing to link to
+// when it's only listening on a unix socket (i.e. there's no bound_port) or on
+// loopback.
+fn web_url(config: &config::Config, bound_port: Option<u16>) -> Option<String> {
+    let port = bound_port?;
+    if config.bind_address.is_loopback() {
+        return None;
+    }
+    if !config.bind_address.is_unspecified() {
+        return Some(format!("http://{}", SocketAddr::new(config.bind_address, port)));
+    }
+    let ifaddrs = match nix::ifaddrs::getifaddrs() {
+        Ok(ifaddrs) => ifaddrs,
+        Err(e) => {
+            warn!("couldn't list network interfaces: {}", e);
+            return None;
+        },
+    };
+    let addrs = ifaddrs.filter_map(|ifaddr| ifaddr.address?.as_sockaddr_in().map(|addr| addr.ip()));
+    pick_web_ip(addrs).map(|ip| format!("http://{}:{}", ip, port))
+}
+
+fn update_ui(task_tracker: &TaskTracker,  config: &config::Config, bound_port: Option<u16>, ui_shutdown_token: CancellationToken, mut ui_update_rx: Receiver<framebuffer::UiUpdate>, mut ui_config_rx: watch::Receiver<UiConfig>, display_state_lock: Arc<RwLock<framebuffer::DisplayState>>) -> JoinHandle<()> {
+    let mut display_level = config.ui_level;
     if display_level == 0 {
         info!("Invisible mode, not spawning UI.");
     }
 
-    if config.colorblind_mode {
-        display_color = framebuffer::Color565::Blue;
-    } else {
-        display_color = framebuffer::Color565::Green;
-    }
+    let warning_display = match config.warning_display_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let mut colorblind_mode = config.colorblind_mode;
+    let display_rotation = config.display_rotation;
+    let framebuffer_path = config.framebuffer_path.clone();
+    let brightness = config.brightness;
+    let gif_playback = GifPlayback {
+        loops: (config.gif_loops != 0).then_some(config.gif_loops),
+        speed: config.gif_speed,
+    };
+    let web_url = web_url(config, bound_port);
 
     task_tracker.spawn_blocking(move || {
-        let mut fb: Framebuffer = Framebuffer::new();
-        // this feels wrong, is there a more rusty way to do this?
-        let mut img: Option<&[u8]> = None;
-        if display_level == 2 {
-            img = Some(IMAGE_DIR.get_file("orca.gif").expect("failed to read orca.gif").contents());
-        } else if display_level == 3 {
-            img = Some(IMAGE_DIR.get_file("eff.png").expect("failed to read eff.png").contents());
-        }
+        // the shutdown token also cuts a GIF short, so we don't have to wait
+        // for it to finish playing before exiting
+        let mut fb: Framebuffer = Framebuffer::new(&framebuffer_path)
+            .with_rotation(display_rotation)
+            .with_gif_playback(gif_playback)
+            .with_interrupt(ui_shutdown_token.clone());
+        // in invisible mode there's nothing of ours to light up, so the
+        // backlight is turned off
+        let backlight = brightness.map(|brightness| (Backlight::new(), brightness));
+        let set_backlight = |display_level: u8| {
+            if let Some((backlight, brightness)) = &backlight {
+                backlight.set_percent(if display_level == 0 { 0 } else { *brightness });
+            }
+        };
+        set_backlight(display_level);
+        let mut display_state_tracker = DisplayStateTracker::new(framebuffer::DisplayState::Recording, warning_display);
+        // show where the web UI is for a little while, unless there's a
+        // warning to show instead
+        let qr_code_until = Instant::now() + Duration::from_secs(QR_CODE_DISPLAY_SECS);
         loop {
-            match ui_shutdown_rx.try_recv() {
-                Ok(_) => {
-                    info!("received UI shutdown");
-                    break;
-                },
-                Err(TryRecvError::Empty) => {},
-                Err(e) => panic!("error receiving shutdown message: {e}")
+            if ui_shutdown_token.is_cancelled() {
+                info!("received UI shutdown");
+                break;
             }
             match ui_update_rx.try_recv() {
-                    Ok(state) => {
-                        display_color = state.into();
+                    Ok(framebuffer::UiUpdate::State(state)) => {
+                        display_state_tracker.update(state, Instant::now());
+                    },
+                    Ok(framebuffer::UiUpdate::AcknowledgeWarnings) => {
+                        display_state_tracker.acknowledge_warning();
                     },
                     Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {},
                     Err(e) => error!("error receiving framebuffer update message: {e}")
             }
+            // only the latest UI config matters, so it's sent on a watch
+            // channel, which a burst of reloads can't fill up
+            if ui_config_rx.has_changed().unwrap_or(false) {
+                let ui_config = *ui_config_rx.borrow_and_update();
+                if (display_level == 0) != (ui_config.ui_level == 0) {
+                    set_backlight(ui_config.ui_level);
+                }
+                display_level = ui_config.ui_level;
+                colorblind_mode = ui_config.colorblind_mode;
+            }
 
-            match display_level  {
-                2 => {
-                    fb.draw_gif(img.unwrap());
-                },
-                3 => {
-                    fb.draw_img(img.unwrap())
-                },
-                128 => {
-                    fb.draw_line(framebuffer::Color565::Cyan, 128);
-                    fb.draw_line(framebuffer::Color565::Pink, 102);
-                    fb.draw_line(framebuffer::Color565::White, 76);
-                    fb.draw_line(framebuffer::Color565::Pink, 50);
-                    fb.draw_line(framebuffer::Color565::Cyan, 25);
-                },
-                1 | _ => {
-                    fb.draw_line(display_color, 2);
-                },
+            let display_state = display_state_tracker.current(Instant::now());
+            *display_state_lock.blocking_write() = display_state;
+            let result = match &web_url {
+                Some(url) if display_level != 0 && Instant::now() < qr_code_until
+                    && !matches!(display_state, framebuffer::DisplayState::WarningDetected(_)) => fb.draw_qr(url),
+                _ => fb.draw_ui(display_level, display_state, colorblind_mode),
             };
+            match result {
+                Ok(()) => {},
+                // the device may just be busy, so try again next time around
+                Err(e @ FramebufferError::WriteError(_)) => error!("{}", e),
+                Err(e) => {
+                    error!("failed to draw ui, falling back to the status line: {}", e);
+                    display_level = 1;
+                },
+            }
             sleep(Duration::from_millis(1000));
         }
     })
 }
 
+// Analyzes a QMDL file instead of recording, printing a summary of what was
+// found
+async fn run_replay(qmdl_path: &Path, analyzer_config: &config::AnalyzerConfig) -> Result<(), RayhunterError> {
+    let analysis_path = qmdl_path.with_extension("ndjson");
+    let summary = replay_qmdl(qmdl_path, &analysis_path, analyzer_config).await?;
+    println!("Replayed {} containers from {}", summary.containers, qmdl_path.display());
+    match summary.max_severity {
+        Some(severity) => println!("{} warnings, the most severe being {:?}", summary.warning_count, severity),
+        None => println!("No warnings"),
+    }
+    println!("Analysis written to {}", analysis_path.display());
+    Ok(())
+}
+
+// Draws test screens on the display so its path, geometry and colors can be
+// checked on new hardware. This doesn't touch the diag device, so it works
+// anywhere there's a framebuffer.
+fn run_display_test(config: &config::Config) -> Result<(), RayhunterError> {
+    if let Some(brightness) = config.brightness {
+        Backlight::new().set_percent(brightness);
+    }
+    let mut fb = Framebuffer::new(&config.framebuffer_path).with_rotation(config.display_rotation);
+    for color in Color565::ALL {
+        println!("Showing {:?}", color);
+        fb.draw_line(color, fb.dimensions().height)?;
+        std::thread::sleep(Duration::from_secs(DISPLAY_TEST_COLOR_SECS));
+    }
+    let dimensions = fb.dimensions();
+    println!("Showing the test card, drawn at {}x{}", dimensions.width, dimensions.height);
+    fb.draw_test_card(Color565::White)?;
+    std::thread::sleep(Duration::from_secs(DISPLAY_TEST_CARD_SECS));
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), RayhunterError> {
-    env_logger::init();
-
     let args = parse_args();
-    let config = parse_config(&args.config_path)?;
+    // replays and display tests can be run anywhere, so they shouldn't leave
+    // a config file behind
+    let wrote_default_config = (args.replay.is_none() && !args.test_display)
+        .then(|| write_default_config_if_missing(&args.config_path));
+    let mut config = parse_config(&args.config_path)?;
+    args.apply_overrides(&mut config);
+    let log_buffer = init_logging(config.log_format, config.log_buffer_lines);
+    // failing to write the default config (e.g. because its directory doesn't
+    // exist) leaves us running with the defaults, which is fine
+    match wrote_default_config {
+        Some(Ok(true)) => info!("no config found, wrote the default config to {}", args.config_path),
+        Some(Err(err)) => warn!("couldn't write the default config to {}, continuing with the defaults: {}", args.config_path, err),
+        _ => {},
+    }
+    config.analyzer_config.warn_about_unknown_analyzers();
+
+    if let Some(qmdl_path) = &args.replay {
+        return run_replay(qmdl_path, &config.analyzer_config).await;
+    }
+    if args.test_display {
+        return run_display_test(&config);
+    }
 
     // TaskTrackers give us an interface to spawn tokio threads, and then
     // eventually await all of them ending
@@ -218,28 +582,54 @@ async fn main() -> Result<(), RayhunterError> {
 
     let qmdl_store_lock = Arc::new(RwLock::new(init_qmdl_store(&config).await?));
     let (tx, rx) = mpsc::channel::<DiagDeviceCtrlMessage>(1);
-    let (ui_update_tx, ui_update_rx) = mpsc::channel::<framebuffer::DisplayState>(1);
+    let (ui_update_tx, ui_update_rx) = mpsc::channel::<framebuffer::UiUpdate>(1);
     let (analysis_tx, analysis_rx) = mpsc::channel::<AnalysisCtrlMessage>(5);
-    let mut maybe_ui_shutdown_tx = None;
+    let message_stats_lock = Arc::new(RwLock::new(MessageStats::default()));
+    let diag_loop_stats_lock = Arc::new(RwLock::new(DiagLoopStats::default()));
+    let display_state_lock = Arc::new(RwLock::new(framebuffer::DisplayState::Recording));
+    let live_update_sender = LiveUpdateSender::new();
+    // stops the threads which don't have a channel of their own to be told
+    // to exit on
+    let shutdown_token = CancellationToken::new();
+    // bound up front, so the UI can show the port we actually got
+    let listener = match config.unix_socket_path {
+        Some(_) => None,
+        None => Some(bind_tcp_listener(SocketAddr::new(config.bind_address, config.port), config.port_fallback).await?),
+    };
+    let bound_port = bound_port(listener.as_ref())?;
+    let mut maybe_ui_config_tx = None;
     if !config.debug_mode {
-        let (ui_shutdown_tx, ui_shutdown_rx) = oneshot::channel();
-        maybe_ui_shutdown_tx = Some(ui_shutdown_tx);
         let mut dev = DiagDevice::new().await
             .map_err(RayhunterError::DiagInitError)?;
         dev.config_logs().await
             .map_err(RayhunterError::DiagInitError)?;
 
+        let (led_tx, led_rx) = mpsc::channel::<WarningLedMessage>(5);
+        run_warning_led_thread(&task_tracker, config.warning_led_gpio_pin, led_rx);
+
+        let diag_tcp_sender = DiagTcpSender::new();
+        if let Some(diag_tcp_port) = config.diag_tcp_port {
+            let addr = SocketAddr::new(config.bind_address, diag_tcp_port);
+            let listener = bind_tcp_listener(addr, false).await?;
+            info!("Streaming diag data on {}", addr);
+            run_diag_tcp_server(&task_tracker, listener, diag_tcp_sender.clone(), shutdown_token.clone());
+        }
+
         info!("Starting Diag Thread");
-        run_diag_read_thread(&task_tracker, dev, rx, ui_update_tx.clone(), qmdl_store_lock.clone(), config.enable_dummy_analyzer);
+        run_diag_read_thread(&task_tracker, dev, rx, ui_update_tx.clone(), led_tx, live_update_sender.clone(), diag_tcp_sender, qmdl_store_lock.clone(), message_stats_lock.clone(), diag_loop_stats_lock.clone(), shutdown_token.clone(), &config);
         info!("Starting UI");
-        update_ui(&task_tracker, &config, ui_shutdown_rx, ui_update_rx);
+        let (ui_config_tx, ui_config_rx) = watch::channel(UiConfig { ui_level: config.ui_level, colorblind_mode: config.colorblind_mode });
+        maybe_ui_config_tx = Some(ui_config_tx);
+        update_ui(&task_tracker, &config, bound_port, shutdown_token.clone(), ui_update_rx, ui_config_rx, display_state_lock.clone());
     }
     let (server_shutdown_tx, server_shutdown_rx) = oneshot::channel::<()>();
     info!("create shutdown thread");
     let analysis_status_lock = Arc::new(RwLock::new(AnalysisStatus::default()));
-    run_analysis_thread(&task_tracker, analysis_rx, qmdl_store_lock.clone(), analysis_status_lock.clone(), config.enable_dummy_analyzer);
-    run_ctrl_c_thread(&task_tracker, tx.clone(), server_shutdown_tx, maybe_ui_shutdown_tx, qmdl_store_lock.clone(), analysis_tx.clone());
-    run_server(&task_tracker, &config, qmdl_store_lock.clone(), server_shutdown_rx, ui_update_tx, tx, analysis_tx, analysis_status_lock).await;
+    run_analysis_thread(&task_tracker, analysis_rx, qmdl_store_lock.clone(), analysis_status_lock.clone(), config.analyzer_config.clone());
+    run_ctrl_c_thread(&task_tracker, tx.clone(), server_shutdown_tx, shutdown_token.clone(), qmdl_store_lock.clone(), analysis_tx.clone());
+    let config_lock = Arc::new(RwLock::new(config));
+    run_config_reload_thread(args, config_lock.clone(), maybe_ui_config_tx, tx.clone(), analysis_tx.clone());
+    run_server(&task_tracker, config_lock, listener, qmdl_store_lock.clone(), server_shutdown_rx, shutdown_token, ui_update_tx, display_state_lock, live_update_sender, tx, analysis_tx, analysis_status_lock, message_stats_lock, diag_loop_stats_lock, log_buffer).await?;
 
     task_tracker.close();
     task_tracker.wait().await;
@@ -247,3 +637,734 @@ async fn main() -> Result<(), RayhunterError> {
     info!("see you space cowboy...");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    async fn make_test_state(read_only: bool) -> (Arc<ServerState>, tempfile::TempDir) {
+        let dir = tempfile::Builder::new().prefix("daemon_test").tempdir().unwrap();
+        let store = RecordingStore::create(dir.path()).await.unwrap();
+        let (diag_device_ctrl_sender, _) = mpsc::channel(1);
+        let (ui_update_sender, _) = mpsc::channel(1);
+        let (analysis_sender, _) = mpsc::channel(1);
+        let state = Arc::new(ServerState {
+            qmdl_store_lock: Arc::new(RwLock::new(store)),
+            diag_device_ctrl_sender,
+            ui_update_sender,
+            display_state_lock: Arc::new(RwLock::new(framebuffer::DisplayState::Recording)),
+            live_update_sender: LiveUpdateSender::new(),
+            analysis_status_lock: Arc::new(RwLock::new(AnalysisStatus::default())),
+            analysis_sender,
+            message_stats_lock: Arc::new(RwLock::new(MessageStats::default())),
+            diag_loop_stats_lock: Arc::new(RwLock::new(DiagLoopStats::default())),
+            started_at: Instant::now(),
+            config_lock: Arc::new(RwLock::new(Config { read_only, ..Config::default() })),
+            log_buffer: None,
+            bound_port: None,
+            task_tracker: TaskTracker::new(),
+            shutdown_token: CancellationToken::new(),
+            recording_timer_lock: std::sync::Mutex::new(None),
+        });
+        (state, dir)
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (state, dir) = make_test_state(false).await;
+        let socket_path = dir.path().join("rayhunter.sock");
+        // a stale socket from a previous run shouldn't stop us from binding
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+        let listener = bind_unix_socket(&socket_path).await.unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_unix(listener, build_router(state), server_shutdown_signal(shutdown_rx)));
+
+        let mut stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        stream.write_all(b"GET /api/qmdl-manifest HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap();
+
+        // but anything else is left alone
+        let file_path = dir.path().join("not-a-socket");
+        std::fs::write(&file_path, b"important").unwrap();
+        let err = bind_unix_socket(&file_path).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"important");
+    }
+
+    #[tokio::test]
+    async fn test_live_ws() {
+        use crate::live::LiveUpdate;
+        use futures::StreamExt;
+        use rayhunter::analysis::analyzer::Severity;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (state, _dir) = make_test_state(false).await;
+        let entry_name = {
+            let mut store = state.qmdl_store_lock.write().await;
+            let _ = store.new_entry().await.unwrap();
+            store.get_current_entry().unwrap().1.name.clone()
+        };
+        // sent before anyone's listening, so it's only seen as the current state
+        state.live_update_sender.send(LiveUpdate::DisplayState { state: framebuffer::DisplayState::Paused });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = build_router(state.clone());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/api/live-ws", addr)).await.unwrap();
+        async fn next_update<S>(socket: &mut S) -> serde_json::Value
+        where S: futures::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin {
+            let Some(Ok(Message::Text(text))) = socket.next().await else {
+                panic!("expected a text message");
+            };
+            serde_json::from_str(&text).unwrap()
+        }
+
+        // on connecting, we're caught up on the current state
+        let update = next_update(&mut socket).await;
+        assert_eq!(update["type"], "display_state");
+        assert_eq!(update["state"], "paused");
+        let update = next_update(&mut socket).await;
+        assert_eq!(update["type"], "entry");
+        assert_eq!(update["entry"]["name"], entry_name.as_str());
+
+        // then get updates as they happen
+        state.live_update_sender.send(LiveUpdate::Warning { severity: Severity::High, warning_count: 1 });
+        let update = next_update(&mut socket).await;
+        assert_eq!(update["type"], "warning");
+        assert_eq!(update["severity"], "High");
+        assert_eq!(update["warning_count"], 1);
+        state.live_update_sender.send(LiveUpdate::DisplayState { state: framebuffer::DisplayState::WarningDetected(Severity::High) });
+        let update = next_update(&mut socket).await;
+        assert_eq!(update["state"], "warning_detected");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_api_token() {
+        async fn make_app(api_token_protects_static: bool) -> (Router, tempfile::TempDir) {
+            let (state, dir) = make_test_state(false).await;
+            let state = Arc::new(ServerState {
+                config_lock: Arc::new(RwLock::new(Config {
+                    api_token: Some("hunter2".to_string()),
+                    api_token_protects_static,
+                    ..Config::default()
+                })),
+                ..Arc::into_inner(state).unwrap()
+            });
+            (build_router(state), dir)
+        }
+        async fn get_status(app: &Router, uri: &str, authorization: Option<&str>) -> StatusCode {
+            let mut request = Request::get(uri);
+            if let Some(authorization) = authorization {
+                request = request.header("Authorization", authorization);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap().status()
+        }
+
+        let (app, _dir) = make_app(false).await;
+        for authorization in [None, Some("Bearer hunter3"), Some("Bearer hunter"), Some("hunter2")] {
+            assert_eq!(get_status(&app, "/api/qmdl-manifest", authorization).await, StatusCode::UNAUTHORIZED, "{:?}", authorization);
+        }
+        assert_eq!(get_status(&app, "/metrics", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_status(&app, "/api/qmdl-manifest", Some("Bearer hunter2")).await, StatusCode::OK);
+        // the web UI itself is exempt, unless configured otherwise
+        assert_eq!(get_status(&app, "/index.html", None).await, StatusCode::OK);
+
+        let (app, _dir) = make_app(true).await;
+        assert_eq!(get_status(&app, "/index.html", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_status(&app, "/index.html", Some("Bearer hunter2")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_mode() {
+        let (state, _dir) = make_test_state(true).await;
+        let app = build_router(state);
+
+        let response = app.clone()
+            .oneshot(Request::post("/api/start-recording").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = app
+            .oneshot(Request::get("/api/qmdl-manifest").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_timed_recording() {
+        let (state, _dir) = make_test_state(false).await;
+        let (diag_device_ctrl_sender, mut diag_device_ctrl_rx) = mpsc::channel(5);
+        let (ui_update_sender, _ui_update_rx) = mpsc::channel(5);
+        let state = Arc::new(ServerState {
+            diag_device_ctrl_sender,
+            ui_update_sender,
+            ..Arc::into_inner(state).unwrap()
+        });
+        let response = build_router(state.clone())
+            .oneshot(Request::post("/api/start-recording?duration_secs=1").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(matches!(diag_device_ctrl_rx.recv().await, Some(DiagDeviceCtrlMessage::StartRecording(_))));
+        assert!(state.qmdl_store_lock.read().await.current_entry.is_some());
+
+        let stop = tokio::time::timeout(Duration::from_secs(5), diag_device_ctrl_rx.recv()).await
+            .expect("recording wasn't stopped");
+        assert!(matches!(stop, Some(DiagDeviceCtrlMessage::StopRecording)));
+        let store = state.qmdl_store_lock.read().await;
+        assert!(store.current_entry.is_none());
+        assert_eq!(store.manifest.entries.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_timed_recording_restarted() {
+        let (state, _dir) = make_test_state(false).await;
+        let (diag_device_ctrl_sender, mut diag_device_ctrl_rx) = mpsc::channel(5);
+        let (ui_update_sender, _ui_update_rx) = mpsc::channel(5);
+        let state = Arc::new(ServerState {
+            diag_device_ctrl_sender,
+            ui_update_sender,
+            ..Arc::into_inner(state).unwrap()
+        });
+        let app = build_router(state.clone());
+        let response = app.clone()
+            .oneshot(Request::post("/api/start-recording?duration_secs=1").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let response = app.clone()
+            .oneshot(Request::post("/api/stop-recording").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let response = app
+            .oneshot(Request::post("/api/start-recording").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(matches!(diag_device_ctrl_rx.recv().await, Some(DiagDeviceCtrlMessage::StartRecording(_))));
+        assert!(matches!(diag_device_ctrl_rx.recv().await, Some(DiagDeviceCtrlMessage::StopRecording)));
+        assert!(matches!(diag_device_ctrl_rx.recv().await, Some(DiagDeviceCtrlMessage::StartRecording(_))));
+
+        // the first recording's timer was cancelled rather than left to fire
+        // on the second one
+        state.task_tracker.close();
+        tokio::time::timeout(Duration::from_millis(500), state.task_tracker.wait()).await
+            .expect("the first recording's timer is still running");
+        assert!(diag_device_ctrl_rx.try_recv().is_err());
+        assert!(state.qmdl_store_lock.read().await.current_entry.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ack_warnings() {
+        let (state, _dir) = make_test_state(false).await;
+        let (ui_update_sender, mut ui_update_rx) = mpsc::channel(5);
+        let state = Arc::new(ServerState {
+            ui_update_sender,
+            ..Arc::into_inner(state).unwrap()
+        });
+        {
+            let mut store = state.qmdl_store_lock.write().await;
+            store.new_entry().await.unwrap();
+            let entry_index = store.current_entry.unwrap();
+            store.update_entry_analysis(entry_index, 100, 3).await.unwrap();
+        }
+
+        let response = build_router(state.clone())
+            .oneshot(Request::post("/api/ack-warnings").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["acknowledged_warnings"], 3);
+        assert_eq!(ui_update_rx.recv().await, Some(framebuffer::UiUpdate::AcknowledgeWarnings));
+
+        // the warnings themselves are still on record
+        let store = state.qmdl_store_lock.read().await;
+        let (_, entry) = store.get_current_entry().unwrap();
+        assert_eq!(entry.warning_count, 3);
+        assert_eq!(entry.analysis_size_bytes, 100);
+    }
+
+    #[tokio::test]
+    async fn test_qmdl_message() {
+        use rayhunter::diag::CRC_CCITT;
+        use rayhunter::hdlc::hdlc_encapsulate;
+        use tokio::io::AsyncWriteExt;
+
+        let (state, _dir) = make_test_state(false).await;
+        let app = build_router(state.clone());
+        // a log message too short to hold its own header
+        let malformed: &[u8] = &[16, 0, 4, 0, 4, 0, 192, 176, 0, 0];
+        let mut qmdl = hdlc_encapsulate(crate::self_test::SYNTHETIC_MESSAGE, &CRC_CCITT);
+        qmdl.extend(hdlc_encapsulate(malformed, &CRC_CCITT));
+        let name = {
+            let mut store = state.qmdl_store_lock.write().await;
+            let (mut qmdl_file, _) = store.new_entry().await.unwrap();
+            qmdl_file.write_all(&qmdl).await.unwrap();
+            qmdl_file.flush().await.unwrap();
+            let entry_index = store.current_entry.unwrap();
+            store.update_entry_qmdl_size(entry_index, qmdl.len()).await.unwrap();
+            store.manifest.entries[entry_index].name.clone()
+        };
+        let get = |index: usize| app.clone().oneshot(
+            Request::get(format!("/api/qmdl/{}/message?index={}", name, index)).body(Body::empty()).unwrap()
+        );
+
+        let response = get(0).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let decoded: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(decoded["index"], 0);
+        assert_eq!(decoded["container_index"], 0);
+        let log = &decoded["message"]["Log"];
+        assert_eq!(log["log_type"], 0xb0c0);
+        assert!(log["timestamp"]["ts"].is_u64());
+        let rrc = &log["body"]["LteRrcOtaMessage"];
+        assert_eq!(rrc["ext_header_version"], 20);
+        assert_eq!(rrc["packet"]["V8"]["phy_cell_id"], 160);
+        assert_eq!(rrc["packet"]["V8"]["earfcn"], 2050);
+        assert!(rrc["packet"]["V8"]["packet"].is_array());
+
+        assert_eq!(get(1).await.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(get(2).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_clear_analysis() {
+        use tokio::io::AsyncWriteExt;
+
+        let (state, _dir) = make_test_state(false).await;
+        let app = build_router(state.clone());
+        let name = {
+            let mut store = state.qmdl_store_lock.write().await;
+            let (mut qmdl_file, mut analysis_file) = store.new_entry().await.unwrap();
+            qmdl_file.write_all(b"qmdl").await.unwrap();
+            analysis_file.write_all(b"analysis").await.unwrap();
+            let entry_index = store.current_entry.unwrap();
+            store.update_entry_analysis(entry_index, 8, 0).await.unwrap();
+            store.manifest.entries[entry_index].name.clone()
+        };
+        let uri = format!("/api/qmdl/{}/clear-analysis", name);
+
+        // the live recording is off limits
+        let response = app.clone()
+            .oneshot(Request::post(&uri).body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        state.qmdl_store_lock.write().await.close_current_entry().await.unwrap();
+        let response = app
+            .oneshot(Request::post(&uri).body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let store = state.qmdl_store_lock.read().await;
+        let (_, entry) = store.entry_for_name(&name).unwrap();
+        assert_eq!(entry.analysis_size_bytes, 0);
+        assert!(!entry.get_analysis_filepath(&store.path).exists());
+        assert_eq!(std::fs::read(entry.get_qmdl_filepath(&store.path)).unwrap(), b"qmdl");
+    }
+
+    #[tokio::test]
+    async fn test_get_bundle() {
+        use std::io::Read;
+        use tokio::io::AsyncWriteExt;
+
+        let (state, _dir) = make_test_state(false).await;
+        let app = build_router(state.clone());
+        let name = {
+            let mut store = state.qmdl_store_lock.write().await;
+            let (mut qmdl_file, mut analysis_file) = store.new_entry().await.unwrap();
+            qmdl_file.write_all(b"qmdl").await.unwrap();
+            analysis_file.write_all(b"{}\n").await.unwrap();
+            let entry_index = store.current_entry.unwrap();
+            store.update_entry_qmdl_size(entry_index, 4).await.unwrap();
+            store.update_entry_analysis(entry_index, 3, 0).await.unwrap();
+            store.manifest.entries[entry_index].name.clone()
+        };
+        let uri = format!("/api/bundle/{}.zip", name);
+
+        let response = app.clone()
+            .oneshot(Request::get(&uri).body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        state.qmdl_store_lock.write().await.close_current_entry().await.unwrap();
+        let response = app
+            .oneshot(Request::get(&uri).body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(body.to_vec())).unwrap();
+        let mut qmdl = Vec::new();
+        archive.by_name(&format!("{}.qmdl", name)).unwrap().read_to_end(&mut qmdl).unwrap();
+        assert_eq!(qmdl, b"qmdl");
+        assert!(archive.by_name("metadata.json").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_delete_qmdl() {
+        let (state, _dir) = make_test_state(false).await;
+        let app = build_router(state.clone());
+        // entry names are timestamps, so give them distinct names by hand
+        for name in ["1", "2"] {
+            let mut store = state.qmdl_store_lock.write().await;
+            let _ = store.new_entry().await.unwrap();
+            let path = store.path.clone();
+            let entry = store.manifest.entries.last_mut().unwrap();
+            entry.name = name.to_string();
+            std::fs::write(entry.get_qmdl_filepath(&path), name).unwrap();
+            std::fs::write(entry.get_analysis_filepath(&path), name).unwrap();
+        }
+        let deleted = state.qmdl_store_lock.read().await.manifest.entries[0].clone();
+
+        let response = app.clone()
+            .oneshot(Request::delete("/api/qmdl/1").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        {
+            let store = state.qmdl_store_lock.read().await;
+            let names: Vec<&str> = store.manifest.entries.iter().map(|e| e.name.as_str()).collect();
+            assert_eq!(names, vec!["2"]);
+            assert_eq!(RecordingStore::load(&store.path).await.unwrap().manifest, store.manifest);
+            assert!(!deleted.get_qmdl_filepath(&store.path).exists());
+            assert!(!deleted.get_analysis_filepath(&store.path).exists());
+            assert_eq!(store.get_current_entry().unwrap().1.name, "2");
+        }
+
+        let response = app.clone()
+            .oneshot(Request::delete("/api/qmdl/1").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // the live recording is off limits
+        let response = app
+            .oneshot(Request::delete("/api/qmdl/2.qmdl").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(state.qmdl_store_lock.read().await.manifest.entries.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rename_qmdl() {
+        let (state, dir) = make_test_state(false).await;
+        let app = build_router(state.clone());
+        let name = {
+            let mut store = state.qmdl_store_lock.write().await;
+            let _ = store.new_entry().await.unwrap();
+            store.get_current_entry().unwrap().1.name.clone()
+        };
+        let uri = format!("/api/qmdl/{}/rename", name);
+        let rename = |body: &'static str| Request::post(&uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = app.clone()
+            .oneshot(rename(r#"{"display_name": " downtown walk test ", "tags": ["walk", ""]}"#))
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let entry: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entry["name"], name.as_str());
+        assert_eq!(entry["display_name"], "downtown walk test");
+        assert_eq!(entry["tags"], serde_json::json!(["walk"]));
+
+        // leaving out the tags keeps them, and the change is persisted
+        let response = app.clone()
+            .oneshot(rename(r#"{"display_name": ""}"#))
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let manifest = RecordingStore::load(dir.path()).await.unwrap().manifest;
+        assert_eq!(manifest.entries[0].display_name, None);
+        assert_eq!(manifest.entries[0].tags, vec!["walk"]);
+        assert!(manifest.entries[0].get_qmdl_filepath(dir.path()).exists());
+
+        let long_name = format!(r#"{{"display_name": "{}"}}"#, "a".repeat(200));
+        let response = app.clone()
+            .oneshot(Request::post(&uri).header("content-type", "application/json").body(Body::from(long_name)).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .oneshot(Request::post("/api/qmdl/nope/rename").header("content-type", "application/json").body(Body::from("{}")).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_analysis_report_headers() {
+        use tokio::io::AsyncWriteExt;
+
+        let (state, _dir) = make_test_state(false).await;
+        {
+            let mut store = state.qmdl_store_lock.write().await;
+            let (_, mut analysis_file) = store.new_entry().await.unwrap();
+            analysis_file.write_all(b"{}\n").await.unwrap();
+            let entry_index = store.current_entry.unwrap();
+            store.update_entry_analysis(entry_index, 3, 2).await.unwrap();
+        }
+        let response = build_router(state)
+            .oneshot(Request::get("/api/analysis-report/live").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-warning-count"], "2");
+        assert_eq!(response.headers()["x-analysis-size-bytes"], "3");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"{}\n");
+    }
+
+    #[tokio::test]
+    async fn test_screenshot() {
+        let (state, _dir) = make_test_state(false).await;
+        let response = build_router(state)
+            .oneshot(Request::get("/api/screenshot.png").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "image/png");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let img = image::load_from_memory_with_format(&body, image::ImageFormat::Png).unwrap();
+        assert_eq!((img.width(), img.height()), (128, 128));
+    }
+
+    #[tokio::test]
+    async fn test_capabilities() {
+        let (state, _dir) = make_test_state(false).await;
+        let response = build_router(state)
+            .oneshot(Request::get("/api/capabilities").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let capabilities: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(capabilities["log_codes"].as_array().unwrap().contains(&"0xb0c0".into()));
+        assert!(!capabilities["analyzers"].as_array().unwrap().is_empty());
+        assert_eq!(capabilities["display"]["dimensions"]["width"], 128);
+    }
+
+    #[test]
+    fn test_pick_web_ip() {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let hotspot = Ipv4Addr::new(192, 168, 1, 1);
+        let public = Ipv4Addr::new(100, 64, 12, 34);
+        assert_eq!(pick_web_ip([localhost, public, hotspot]), Some(hotspot));
+        assert_eq!(pick_web_ip([localhost, public]), Some(public));
+        assert_eq!(pick_web_ip([localhost, Ipv4Addr::UNSPECIFIED]), None);
+    }
+
+    #[test]
+    fn test_web_url() {
+        let config = Config {
+            bind_address: Ipv4Addr::new(192, 168, 1, 1).into(),
+            port: 8080,
+            ..Config::default()
+        };
+        // the port we fell back to, not the configured one
+        assert_eq!(web_url(&config, Some(8888)), Some("http://192.168.1.1:8888".to_string()));
+        assert_eq!(web_url(&config, None), None);
+        let loopback = Config { bind_address: Ipv4Addr::LOCALHOST.into(), ..config };
+        assert_eq!(web_url(&loopback, Some(8080)), None);
+    }
+
+    #[tokio::test]
+    async fn test_apply_config() {
+        let (state, _dir) = make_test_state(false).await;
+        let (ui_config_tx, ui_config_rx) = watch::channel(UiConfig { ui_level: 1, colorblind_mode: false });
+        let (diag_device_sender, mut diag_device_rx) = mpsc::channel(1);
+        // a full channel shouldn't make us drop the new config
+        diag_device_sender.send(DiagDeviceCtrlMessage::StopRecording).await.unwrap();
+        let (analysis_tx, mut analysis_rx) = mpsc::channel(1);
+        let mut new_config = Config {
+            ui_level: 2,
+            read_only: true,
+            ..Config::default()
+        };
+        new_config.analyzer_config.enable_dummy_analyzer = true;
+        let config_lock = state.config_lock.clone();
+        let applying = tokio::spawn(async move {
+            apply_config(&config_lock, new_config, Some(&ui_config_tx), &diag_device_sender, &analysis_tx).await;
+        });
+        assert!(matches!(diag_device_rx.recv().await, Some(DiagDeviceCtrlMessage::StopRecording)));
+        assert!(matches!(diag_device_rx.recv().await, Some(DiagDeviceCtrlMessage::UpdateAnalyzerConfig(c)) if c.enable_dummy_analyzer));
+        applying.await.unwrap();
+
+        assert_eq!(ui_config_rx.borrow().ui_level, 2);
+        assert!(matches!(analysis_rx.try_recv(), Ok(AnalysisCtrlMessage::UpdateConfig(c)) if c.enable_dummy_analyzer));
+
+        // the server sees the new settings too, but not ones needing a restart
+        let response = build_router(state)
+            .oneshot(Request::get("/api/capabilities").body(Body::empty()).unwrap())
+            .await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let capabilities: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(capabilities["display"]["ui_level"], 2);
+        assert_eq!(capabilities["read_only"], false);
+        let analyzers = capabilities["analyzers"].as_array().unwrap();
+        assert!(analyzers.iter().any(|analyzer| analyzer["name"] == "Example Analyzer"), "{:?}", analyzers);
+    }
+
+    #[tokio::test]
+    async fn test_device_info() {
+        let (state, _dir) = make_test_state(false).await;
+        let state = Arc::new(ServerState {
+            config_lock: Arc::new(RwLock::new(Config { api_token: Some("hunter2".to_string()), ..Config::default() })),
+            ..Arc::into_inner(state).unwrap()
+        });
+        let response = build_router(state)
+            .oneshot(Request::get("/api/device-info")
+                .header("Authorization", "Bearer hunter2")
+                .body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let device_info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(device_info["version"], capabilities::VERSION);
+        assert_eq!(device_info["config"]["ui_level"], 1);
+        assert_eq!(device_info["config"]["display_rotation"], 0);
+        assert!(device_info["config"].get("api_token").is_none());
+        assert_eq!(device_info["recording"], false);
+    }
+
+    #[tokio::test]
+    async fn test_port_in_use_without_fallback() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap();
+        let err = bind_tcp_listener(addr, false).await.unwrap_err();
+        assert!(matches!(&err, RayhunterError::BindError(err_addr, _) if *err_addr == addr));
+        assert!(err.to_string().contains(&addr.to_string()), "{}", err);
+
+        // other errors never fall back, even when it's enabled
+        let unavailable = SocketAddr::from(([192, 0, 2, 1], addr.port()));
+        assert!(matches!(bind_tcp_listener(unavailable, true).await, Err(RayhunterError::BindError(..))));
+    }
+
+    #[tokio::test]
+    async fn test_logs() {
+        let (state, _dir) = make_test_state(false).await;
+        let response = build_router(state)
+            .oneshot(Request::get("/api/logs").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let log_buffer = LogBuffer::new(10);
+        let logger = logging::BufferedLogger::new(
+            env_logger::Builder::new().filter_level(log::LevelFilter::Info).build(),
+            log_buffer.clone(),
+        );
+        log::Log::log(&logger, &log::Record::builder()
+            .args(format_args!("the orca is hunting"))
+            .level(log::Level::Warn)
+            .target("rayhunter_daemon::diag")
+            .build());
+        let (state, _dir) = make_test_state(false).await;
+        let state = Arc::new(ServerState {
+            log_buffer: Some(log_buffer),
+            ..Arc::into_inner(state).unwrap()
+        });
+        let app = build_router(state);
+
+        let response = app.clone()
+            .oneshot(Request::get("/api/logs").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.ends_with(" WARN  rayhunter_daemon::diag: the orca is hunting\n"));
+
+        let response = app
+            .oneshot(Request::get("/api/logs?format=json").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.headers()[axum::http::header::CONTENT_TYPE], "application/x-ndjson");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["message"], "the orca is hunting");
+    }
+
+    #[tokio::test]
+    async fn test_gzip_responses() {
+        let (state, _dir) = make_test_state(false).await;
+        let app = build_router(state);
+        let get = |uri: &str, gzip: bool| {
+            let mut request = Request::get(uri);
+            if gzip {
+                request = request.header("accept-encoding", "gzip, deflate");
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let plain = get("/api/qmdl-manifest", false).await.unwrap();
+        assert!(plain.headers().get("content-encoding").is_none());
+        let plain = axum::body::to_bytes(plain.into_body(), usize::MAX).await.unwrap();
+
+        let response = get("/api/qmdl-manifest", true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+        let compressed = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut decompressed = Vec::new();
+        let mut decoder = async_compression::tokio::bufread::GzipDecoder::new(&compressed[..]);
+        tokio::io::AsyncReadExt::read_to_end(&mut decoder, &mut decompressed).await.unwrap();
+        assert_eq!(decompressed, plain);
+
+        // binary and small API responses are left alone
+        let response = get("/api/system-stats", true).await.unwrap();
+        assert!(response.headers().get("content-encoding").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_openapi_spec_covers_routes() {
+        let (state, _dir) = make_test_state(false).await;
+        let response = build_router(state)
+            .oneshot(Request::get("/api/openapi.json").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // these should match the API routes in build_router
+        let routes = [
+            ("/api/pcap/{name}", "get"),
+            ("/api/qmdl/{name}", "get"),
+            ("/api/qmdl/{name}", "delete"),
+            ("/api/qmdl/{name}/hexdump", "get"),
+            ("/api/qmdl/{name}/message", "get"),
+            ("/api/qmdl/{name}/clear-analysis", "post"),
+            ("/api/qmdl/{name}/rename", "post"),
+            ("/api/bundle/{name}", "get"),
+            ("/api/export-all.zip", "get"),
+            ("/api/system-stats", "get"),
+            ("/api/capabilities", "get"),
+            ("/api/device-info", "get"),
+            ("/api/logs", "get"),
+            ("/api/screenshot.png", "get"),
+            ("/api/qmdl-manifest", "get"),
+            ("/api/message-stats", "get"),
+            ("/api/diag-stats", "get"),
+            ("/api/start-recording", "post"),
+            ("/api/stop-recording", "post"),
+            ("/api/analysis-report/{name}", "get"),
+            ("/api/live-ws", "get"),
+            ("/api/analysis", "get"),
+            ("/api/analysis/{name}", "post"),
+            ("/api/ack-warnings", "post"),
+            ("/api/diag/raw-command", "post"),
+            ("/api/self-test", "post"),
+            ("/api/openapi.json", "get"),
+            ("/metrics", "get"),
+        ];
+        for (path, method) in routes {
+            assert!(spec["paths"][path][method].is_object(), "spec is missing {} {}", method, path);
+        }
+    }
+}
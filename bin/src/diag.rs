@@ -1,164 +1,803 @@
+use std::future::Future;
+use std::ops::ControlFlow;
 use std::pin::pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use axum::body::Body;
-use axum::extract::{Path, State};
-use axum::http::header::CONTENT_TYPE;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::header::{HeaderName, CONTENT_TYPE};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
-use rayhunter::diag::DataType;
-use rayhunter::diag_device::DiagDevice;
+use axum::Json;
+use chrono::{DateTime, Local};
+use rayhunter::analysis::observed_plmn::Plmn;
+use rayhunter::diag::{DataType, MessagesContainer};
+use rayhunter::diag_device::{DiagDevice, DiagDeviceError};
 use tokio::sync::RwLock;
 use tokio::sync::mpsc::{Receiver, Sender};
 use rayhunter::qmdl::QmdlWriter;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use tokio::fs::File;
 use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
-use futures::{StreamExt, TryStreamExt};
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
 
+use crate::config::{AnalyzerConfig, Config};
+use crate::diag_tcp::DiagTcpSender;
+use crate::error::DiagError;
 use crate::framebuffer;
-use crate::qmdl_store::RecordingStore;
+use crate::gpio::WarningLedMessage;
+use crate::live::{LiveUpdate, LiveUpdateSender};
+use crate::qmdl_store::{RecordingStore, RecordingStoreError};
 use crate::server::ServerState;
+use crate::stats::{DiagLoopStats, MessageStats};
 use crate::analysis::AnalysisWriter;
 
+// How long a container can take to process before we consider it slow, and how
+// many slow ones in a row we see before warning about it
+const SLOW_CONTAINER_THRESHOLD: Duration = Duration::from_millis(500);
+const SLOW_CONTAINER_WARNING_COUNT: u32 = 10;
+// how long to wait for the modem to stop logging when exiting, so a modem
+// that doesn't respond can't hold up shutting down
+const DISABLE_LOGS_TIMEOUT: Duration = Duration::from_secs(5);
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// If the diag device goes away (e.g. the modem crashed or its USB interface was
+// reset), how many times we try to reopen it, and how long we wait before each
+// attempt. That's a couple of minutes in all before giving up on recording.
+const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+// How often we write the current entry's file sizes to the store. Doing it for
+// every container means constantly holding the store's write lock, which
+// starves the server and UI.
+const STORE_UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+// How many containers' stats we hold on to between STORE_UPDATE_INTERVALs
+// before writing them out anyway, so the stats endpoints don't fall too far
+// behind on a busy cell
+const MAX_BATCHED_CONTAINERS: u64 = 64;
+
+// Stats for containers which haven't been added to the shared stats yet. Like
+// PendingStoreUpdate, this saves taking the stats' write locks (several times
+// over) for every container.
+#[derive(Default)]
+struct PendingStats {
+    diag_loop_stats: DiagLoopStats,
+    message_stats: MessageStats,
+}
+
+impl PendingStats {
+    fn is_empty(&self) -> bool {
+        self.diag_loop_stats.containers_read == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.diag_loop_stats.containers_read >= MAX_BATCHED_CONTAINERS
+    }
+
+    async fn flush(&mut self, diag_loop_stats_lock: &RwLock<DiagLoopStats>, message_stats_lock: &RwLock<MessageStats>) {
+        if self.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(self);
+        diag_loop_stats_lock.write().await.merge(&pending.diag_loop_stats);
+        if !pending.message_stats.counts.is_empty() {
+            message_stats_lock.write().await.merge(&pending.message_stats);
+        }
+    }
+}
+
+// File sizes for the current entry which haven't been written to the store yet.
+// The entry is kept track of by name, since deleting an older entry (e.g. from
+// the web UI) shifts the indices of every one after it.
+struct PendingStoreUpdate {
+    entry_name: Option<String>,
+    qmdl_size_bytes: Option<usize>,
+    // the analysis file's size in bytes, and the number of warnings in it
+    analysis: Option<(usize, usize)>,
+    observed_plmns: Option<Vec<Plmn>>,
+}
+
+impl PendingStoreUpdate {
+    fn new(entry_name: Option<String>) -> Self {
+        PendingStoreUpdate {
+            entry_name,
+            qmdl_size_bytes: None,
+            analysis: None,
+            observed_plmns: None,
+        }
+    }
+
+    fn for_current_entry(qmdl_store: &RecordingStore) -> Self {
+        Self::new(qmdl_store.get_current_entry().map(|(_, entry)| entry.name.clone()))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.qmdl_size_bytes.is_none() && self.analysis.is_none() && self.observed_plmns.is_none()
+    }
+
+    // Writes any pending sizes to the store. This takes the store's write lock,
+    // so callers should batch updates rather than flushing after each one.
+    async fn flush(&mut self, qmdl_store_lock: &RwLock<RecordingStore>) -> Result<(), RecordingStoreError> {
+        let Some(name) = &self.entry_name else {
+            return Ok(());
+        };
+        if self.is_empty() {
+            return Ok(());
+        }
+        let mut qmdl_store = qmdl_store_lock.write().await;
+        let Some((index, _)) = qmdl_store.entry_for_name(name) else {
+            // the entry's been deleted, so there's nothing left to update
+            self.qmdl_size_bytes = None;
+            self.analysis = None;
+            self.observed_plmns = None;
+            return Ok(());
+        };
+        if let Some(size_bytes) = self.qmdl_size_bytes.take() {
+            qmdl_store.update_entry_qmdl_size(index, size_bytes).await?;
+        }
+        if let Some((size_bytes, warning_count)) = self.analysis.take() {
+            qmdl_store.update_entry_analysis(index, size_bytes, warning_count).await?;
+        }
+        if let Some(observed_plmns) = self.observed_plmns.take() {
+            qmdl_store.update_entry_observed_plmns(index, observed_plmns).await?;
+        }
+        Ok(())
+    }
+}
+
+// Keeps track of how long it's been since we last got diag messages, so we can
+// show the recording as paused while the modem's not logging anything
+struct IdleDetector {
+    timeout: Option<Duration>,
+    last_activity: Instant,
+    idle: bool,
+}
+
+impl IdleDetector {
+    fn new(timeout: Option<Duration>) -> Self {
+        IdleDetector {
+            timeout,
+            last_activity: Instant::now(),
+            idle: false,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.timeout.is_some()
+    }
+
+    // Returns true if this activity ends an idle period
+    fn record_activity(&mut self, now: Instant) -> bool {
+        self.last_activity = now;
+        let was_idle = self.idle;
+        self.idle = false;
+        was_idle
+    }
+
+    // Returns true if we've just become idle
+    fn check_idle(&mut self, now: Instant) -> bool {
+        let Some(timeout) = self.timeout else {
+            return false;
+        };
+        if self.idle || now.duration_since(self.last_activity) < timeout {
+            return false;
+        }
+        self.idle = true;
+        true
+    }
+}
+
 pub enum DiagDeviceCtrlMessage {
     StopRecording,
     StartRecording((QmdlWriter<File>, File)),
+    // the config was reloaded. The current recording's analysis carries on
+    // as it was, so this only applies from the next recording.
+    UpdateAnalyzerConfig(AnalyzerConfig),
     Exit,
 }
 
+// Deletes the oldest recordings beyond max_recordings, then enough of the
+// oldest ones left to bring the store under max_store_bytes, if either is set
+async fn delete_old_recordings(
+    qmdl_store_lock: &RwLock<RecordingStore>,
+    max_recordings: Option<usize>,
+    max_store_bytes: Option<u64>,
+) -> Result<(), DiagError> {
+    if let Some(max_recordings) = max_recordings {
+        let deleted = qmdl_store_lock.write().await.delete_oldest_entries(max_recordings).await?;
+        for name in &deleted {
+            info!("deleted recording {} to stay within max_recordings = {}", name, max_recordings);
+        }
+    }
+    if let Some(max_store_bytes) = max_store_bytes {
+        let deleted = qmdl_store_lock.write().await.delete_oldest_entries_over_size(max_store_bytes).await?;
+        for name in &deleted {
+            info!("deleted recording {} to stay within max_store_bytes = {}", name, max_store_bytes);
+        }
+    }
+    Ok(())
+}
+
+// Opens the diag device and sets up the logs we're interested in
+async fn open_diag_device() -> Result<DiagDevice, DiagDeviceError> {
+    let mut dev = DiagDevice::new().await?;
+    dev.config_logs().await?;
+    Ok(dev)
+}
+
+// When to next try reconnecting to the diag device, waiting twice as long
+// after each failure (up to MAX_RECONNECT_BACKOFF)
+struct Backoff {
+    attempt: u32,
+    max_attempts: u32,
+    delay: Duration,
+    next_attempt: tokio::time::Instant,
+}
+
+impl Backoff {
+    fn new(max_attempts: u32, initial_delay: Duration) -> Self {
+        Backoff {
+            attempt: 1,
+            max_attempts,
+            delay: initial_delay,
+            next_attempt: tokio::time::Instant::now() + initial_delay,
+        }
+    }
+
+    fn is_last_attempt(&self) -> bool {
+        self.attempt >= self.max_attempts
+    }
+
+    fn advance(&mut self) {
+        self.attempt += 1;
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_BACKOFF);
+        self.next_attempt = tokio::time::Instant::now() + self.delay;
+    }
+}
+
+// How reconnect_with_backoff finished. Only ever returned by value, so the
+// size difference doesn't matter.
+#[allow(clippy::large_enum_variant)]
+enum Reconnect<T> {
+    Connected(T),
+    // a control message arrived, which should be handled before carrying on
+    Message(Option<DiagDeviceCtrlMessage>),
+    Shutdown,
+}
+
+// Calls connect until it succeeds, waiting as backoff says between attempts,
+// and giving up after its last attempt. The waits can take minutes in all, so
+// they're cut short by control messages and shutting down. To carry on
+// reconnecting after handling a message, call this again with the same
+// backoff.
+async fn reconnect_with_backoff<T, F, Fut>(
+    connect: &mut F,
+    backoff: &mut Backoff,
+    ctrl_rx: &mut Receiver<DiagDeviceCtrlMessage>,
+    shutdown_token: &CancellationToken,
+) -> Result<Reconnect<T>, DiagDeviceError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, DiagDeviceError>>,
+{
+    loop {
+        tokio::select! {
+            biased;
+            _ = shutdown_token.cancelled() => return Ok(Reconnect::Shutdown),
+            msg = ctrl_rx.recv() => return Ok(Reconnect::Message(msg)),
+            _ = tokio::time::sleep_until(backoff.next_attempt) => {},
+        }
+        match connect().await {
+            Ok(connection) => return Ok(Reconnect::Connected(connection)),
+            Err(err) if backoff.is_last_attempt() => return Err(err),
+            Err(err) => warn!("couldn't reconnect to the diag device (attempt {}/{}): {}", backoff.attempt, backoff.max_attempts, err),
+        }
+        backoff.advance();
+    }
+}
+
+// Closes the current entry and opens a new one to carry on recording into,
+// once everything written to the old one has been flushed to the store
+async fn split_recording(
+    qmdl_store_lock: &RwLock<RecordingStore>,
+    pending_store_update: &mut PendingStoreUpdate,
+) -> Result<(QmdlWriter<File>, File), DiagError> {
+    pending_store_update.flush(qmdl_store_lock).await?;
+    let mut qmdl_store = qmdl_store_lock.write().await;
+    let (qmdl_file, analysis_file) = qmdl_store.new_entry().await?;
+    *pending_store_update = PendingStoreUpdate::for_current_entry(&qmdl_store);
+    Ok((QmdlWriter::new(qmdl_file), analysis_file))
+}
+
+// Lets live clients know about the current entry's latest sizes and warning
+// count, or that a new one has started
+async fn send_current_entry(qmdl_store_lock: &RwLock<RecordingStore>, live_update_sender: &LiveUpdateSender) {
+    if let Some((_, entry)) = qmdl_store_lock.read().await.get_current_entry() {
+        live_update_sender.send(LiveUpdate::Entry { entry: Box::new(entry.clone()) });
+    }
+}
+
+// Everything the diag thread keeps track of while it records, so that each
+// container and control message can be handled (and tested) on its own
+struct DiagThreadState {
+    ui_update_sender: Sender<framebuffer::UiUpdate>,
+    led_sender: Sender<WarningLedMessage>,
+    live_update_sender: LiveUpdateSender,
+    diag_tcp_sender: DiagTcpSender,
+    qmdl_store_lock: Arc<RwLock<RecordingStore>>,
+    message_stats_lock: Arc<RwLock<MessageStats>>,
+    diag_loop_stats_lock: Arc<RwLock<DiagLoopStats>>,
+    analyzer_config: AnalyzerConfig,
+    max_recordings: Option<usize>,
+    max_store_bytes: Option<u64>,
+    max_recording_bytes: Option<usize>,
+    idle_detector: IdleDetector,
+    maybe_qmdl_writer: Option<QmdlWriter<File>>,
+    maybe_analysis_writer: Option<AnalysisWriter>,
+    pending_store_update: PendingStoreUpdate,
+    pending_stats: PendingStats,
+    num_slow_containers: u32,
+}
+
+impl DiagThreadState {
+    // Starts the initial recording. If that fails, the thread keeps running
+    // so one can still be started from the web UI.
+    async fn start(&mut self) -> Result<(), DiagError> {
+        let new_entry = self.qmdl_store_lock.write().await.new_entry().await;
+        if let Some((initial_qmdl_file, initial_analysis_file)) = recover(new_entry.map_err(DiagError::from))? {
+            self.maybe_qmdl_writer = Some(QmdlWriter::new(initial_qmdl_file));
+            let analysis_writer = AnalysisWriter::new(initial_analysis_file, &self.analyzer_config).await
+                .map_err(DiagError::AnalysisWriteError);
+            self.maybe_analysis_writer = recover(analysis_writer)?;
+        } else {
+            recover(self.send_ui_update(framebuffer::DisplayState::NoQmdlData).await)?;
+        }
+        recover(delete_old_recordings(&self.qmdl_store_lock, self.max_recordings, self.max_store_bytes).await)?;
+        self.pending_store_update = PendingStoreUpdate::for_current_entry(&*self.qmdl_store_lock.read().await);
+        Ok(())
+    }
+
+    fn has_pending_updates(&self) -> bool {
+        !self.pending_store_update.is_empty() || !self.pending_stats.is_empty()
+    }
+
+    async fn flush_pending_updates(&mut self) -> Result<(), DiagError> {
+        self.pending_stats.flush(&self.diag_loop_stats_lock, &self.message_stats_lock).await;
+        if !self.pending_store_update.is_empty() {
+            recover(self.pending_store_update.flush(&self.qmdl_store_lock).await.map_err(DiagError::from))?;
+            // the current recording keeps growing, so make room for it
+            // as it goes rather than only when the next one starts
+            if self.max_store_bytes.is_some() {
+                recover(delete_old_recordings(&self.qmdl_store_lock, None, self.max_store_bytes).await)?;
+            }
+            send_current_entry(&self.qmdl_store_lock, &self.live_update_sender).await;
+        }
+        Ok(())
+    }
+
+    async fn check_idle(&mut self) -> Result<(), DiagError> {
+        if self.idle_detector.check_idle(Instant::now()) && self.maybe_qmdl_writer.is_some() {
+            info!("no diag messages received in {:?}, pausing recording", self.idle_detector.timeout);
+            recover(self.send_ui_update(framebuffer::DisplayState::Paused).await)?;
+        }
+        Ok(())
+    }
+
+    // Returns ControlFlow::Break once it's time for the thread to exit. None
+    // means all the Senders have been dropped, which is treated like Exit.
+    async fn handle_ctrl_message(&mut self, msg: Option<DiagDeviceCtrlMessage>) -> Result<ControlFlow<()>, DiagError> {
+        match msg {
+            Some(DiagDeviceCtrlMessage::StartRecording((new_writer, new_analysis_file))) => {
+                // finish off the previous entry before moving on to the new one
+                recover(self.pending_store_update.flush(&self.qmdl_store_lock).await.map_err(DiagError::from))?;
+                // the previous entry's final size counts towards
+                // max_store_bytes, so this has to wait until the
+                // pending update is flushed
+                recover(delete_old_recordings(&self.qmdl_store_lock, self.max_recordings, self.max_store_bytes).await)?;
+                self.pending_store_update = PendingStoreUpdate::for_current_entry(&*self.qmdl_store_lock.read().await);
+                send_current_entry(&self.qmdl_store_lock, &self.live_update_sender).await;
+                self.maybe_qmdl_writer = Some(new_writer);
+                self.idle_detector.record_activity(Instant::now());
+                if let Some(analysis_writer) = self.maybe_analysis_writer.take() {
+                    recover(analysis_writer.close().await.map_err(DiagError::AnalysisWriteError))?;
+                }
+                let analysis_writer = AnalysisWriter::new(new_analysis_file, &self.analyzer_config).await
+                    .map_err(DiagError::AnalysisWriteError);
+                self.maybe_analysis_writer = recover(analysis_writer)?;
+                // the message stats only cover the current recording
+                self.pending_stats.message_stats.reset();
+                self.message_stats_lock.write().await.reset();
+                self.send_led_message(WarningLedMessage::Clear);
+            },
+            Some(DiagDeviceCtrlMessage::UpdateAnalyzerConfig(new_analyzer_config)) => {
+                self.analyzer_config = new_analyzer_config;
+            },
+            Some(DiagDeviceCtrlMessage::StopRecording) => {
+                recover(self.pending_store_update.flush(&self.qmdl_store_lock).await.map_err(DiagError::from))?;
+                self.pending_store_update = PendingStoreUpdate::new(None);
+                self.maybe_qmdl_writer = None;
+                if let Some(analysis_writer) = self.maybe_analysis_writer.take() {
+                    recover(analysis_writer.close().await.map_err(DiagError::AnalysisWriteError))?;
+                }
+                // the warning was about the recording that just stopped
+                self.send_led_message(WarningLedMessage::Clear);
+            },
+            Some(DiagDeviceCtrlMessage::Exit) | None => {
+                info!("Diag reader thread exiting...");
+                self.pending_stats.flush(&self.diag_loop_stats_lock, &self.message_stats_lock).await;
+                recover(self.pending_store_update.flush(&self.qmdl_store_lock).await.map_err(DiagError::from))?;
+                if let Some(analysis_writer) = self.maybe_analysis_writer.take() {
+                    recover(analysis_writer.close().await.map_err(DiagError::AnalysisWriteError))?;
+                }
+                // don't leave the LED lit once nothing's watching
+                if self.maybe_qmdl_writer.take().is_some() {
+                    self.send_led_message(WarningLedMessage::Clear);
+                }
+                return Ok(ControlFlow::Break(()));
+            },
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    async fn handle_container(&mut self, container: MessagesContainer) -> Result<(), DiagError> {
+        if self.pending_stats.is_full() {
+            self.pending_stats.flush(&self.diag_loop_stats_lock, &self.message_stats_lock).await;
+        }
+        self.pending_stats.diag_loop_stats.containers_read += 1;
+        if container.data_type != DataType::UserSpace {
+            debug!("skipping non-userspace diag messages...");
+            self.pending_stats.diag_loop_stats.containers_skipped += 1;
+            return Ok(());
+        }
+        let processing_start = Instant::now();
+        if self.idle_detector.record_activity(processing_start) && self.maybe_qmdl_writer.is_some() {
+            info!("received diag messages, resuming recording");
+            recover(self.send_ui_update(framebuffer::DisplayState::Recording).await)?;
+        }
+        // tethered clients get everything, recording or not
+        self.diag_tcp_sender.send_container(&container);
+        // keep track of how many bytes were written to the QMDL file so we can read
+        // a valid block of data from it in the HTTP server
+        if let Some(qmdl_writer) = self.maybe_qmdl_writer.as_mut() {
+            let write_result = qmdl_writer.write_container(&container).await
+                .map_err(DiagError::QmdlWriteError);
+            if recover(write_result)?.is_some() {
+                debug!("total QMDL bytes written: {}", qmdl_writer.total_written);
+                self.pending_store_update.qmdl_size_bytes = Some(qmdl_writer.total_written);
+            }
+        } else {
+            debug!("no qmdl_writer set, continuing...");
+        }
+
+        if let Some(analysis_writer) = self.maybe_analysis_writer.as_mut() {
+            let messages = container.into_messages();
+            self.pending_stats.message_stats.record(&messages);
+            let analysis_output = analysis_writer.analyze_messages(messages).await
+                .map_err(DiagError::AnalysisWriteError);
+            if let Some((analysis_file_len, max_warning_severity)) = recover(analysis_output)? {
+                let warning_count = analysis_writer.warning_count();
+                self.pending_store_update.analysis = Some((analysis_file_len, warning_count));
+                if let Some(observed_plmns) = analysis_writer.take_new_observed_plmns() {
+                    self.pending_store_update.observed_plmns = Some(observed_plmns);
+                }
+                if let Some(severity) = max_warning_severity {
+                    info!("a heuristic triggered on this run!");
+                    self.live_update_sender.send(LiveUpdate::Warning { severity, warning_count });
+                    recover(self.send_ui_update(framebuffer::DisplayState::WarningDetected(severity)).await)?;
+                    self.send_led_message(WarningLedMessage::Warning(severity));
+                }
+            }
+        }
+
+        // if we're consistently slow to process containers,
+        // the modem's probably getting ahead of us
+        let processing_time = processing_start.elapsed();
+        self.pending_stats.diag_loop_stats.record_processing_time(processing_time);
+        if processing_time > SLOW_CONTAINER_THRESHOLD {
+            self.num_slow_containers += 1;
+            if self.num_slow_containers == SLOW_CONTAINER_WARNING_COUNT {
+                warn!("the last {} diag containers each took over {:?} to process, we may be falling behind the modem",
+                    self.num_slow_containers, SLOW_CONTAINER_THRESHOLD);
+            }
+        } else {
+            self.num_slow_containers = 0;
+        }
+
+        // only split once the whole container has been written
+        // and analyzed, so none of it ends up in the new entry
+        let should_split = self.maybe_qmdl_writer.as_ref().zip(self.max_recording_bytes)
+            .is_some_and(|(qmdl_writer, max_bytes)| qmdl_writer.total_written >= max_bytes);
+        if should_split {
+            info!("recording reached max_recording_bytes = {}, starting a new one", self.max_recording_bytes.unwrap_or_default());
+            if let Some((new_writer, new_analysis_file)) = recover(split_recording(&self.qmdl_store_lock, &mut self.pending_store_update).await)? {
+                self.maybe_qmdl_writer = Some(new_writer);
+                if let Some(analysis_writer) = self.maybe_analysis_writer.take() {
+                    recover(analysis_writer.close().await.map_err(DiagError::AnalysisWriteError))?;
+                }
+                let analysis_writer = AnalysisWriter::new(new_analysis_file, &self.analyzer_config).await
+                    .map_err(DiagError::AnalysisWriteError);
+                self.maybe_analysis_writer = recover(analysis_writer)?;
+                // split_recording flushed the old entry's sizes, so
+                // they count towards max_store_bytes
+                recover(delete_old_recordings(&self.qmdl_store_lock, self.max_recordings, self.max_store_bytes).await)?;
+                send_current_entry(&self.qmdl_store_lock, &self.live_update_sender).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_ui_update(&mut self, state: framebuffer::DisplayState) -> Result<(), DiagError> {
+        send_ui_update(&self.ui_update_sender, &self.live_update_sender, state).await
+    }
+
+    // The LED is purely cosmetic, so don't hold up the diag stream if it's
+    // busy blinking
+    fn send_led_message(&mut self, message: WarningLedMessage) {
+        if let Err(e) = self.led_sender.try_send(message) {
+            debug!("couldn't send warning LED message: {}", e);
+            self.pending_stats.diag_loop_stats.led_updates_dropped += 1;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn run_diag_read_thread(
     task_tracker: &TaskTracker,
     mut dev: DiagDevice,
     mut qmdl_file_rx: Receiver<DiagDeviceCtrlMessage>,
-    ui_update_sender: Sender<framebuffer::DisplayState>,
+    ui_update_sender: Sender<framebuffer::UiUpdate>,
+    led_sender: Sender<WarningLedMessage>,
+    live_update_sender: LiveUpdateSender,
+    diag_tcp_sender: DiagTcpSender,
     qmdl_store_lock: Arc<RwLock<RecordingStore>>,
-    enable_dummy_analyzer: bool,
+    message_stats_lock: Arc<RwLock<MessageStats>>,
+    diag_loop_stats_lock: Arc<RwLock<DiagLoopStats>>,
+    shutdown_token: CancellationToken,
+    config: &Config,
 ) {
+    let mut thread_state = DiagThreadState {
+        ui_update_sender,
+        led_sender,
+        live_update_sender,
+        diag_tcp_sender,
+        qmdl_store_lock,
+        message_stats_lock,
+        diag_loop_stats_lock,
+        analyzer_config: config.analyzer_config.clone(),
+        max_recordings: config.max_recordings,
+        max_store_bytes: config.max_store_bytes,
+        max_recording_bytes: config.max_recording_bytes,
+        idle_detector: IdleDetector::new(config.auto_pause_idle_secs.map(Duration::from_secs)),
+        maybe_qmdl_writer: None,
+        maybe_analysis_writer: None,
+        pending_store_update: PendingStoreUpdate::new(None),
+        pending_stats: PendingStats::default(),
+        num_slow_containers: 0,
+    };
     task_tracker.spawn(async move {
-        let (initial_qmdl_file, initial_analysis_file) = qmdl_store_lock.write().await.new_entry().await.expect("failed creating QMDL file entry");
-        let mut maybe_qmdl_writer: Option<QmdlWriter<File>> = Some(QmdlWriter::new(initial_qmdl_file));
-        let mut diag_stream = pin!(dev.as_stream().into_stream());
-        let mut maybe_analysis_writer = Some(AnalysisWriter::new(initial_analysis_file, enable_dummy_analyzer).await
-            .expect("failed to create analysis writer"));
+        thread_state.start().await?;
+        let mut idle_check_interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        let mut store_update_interval = tokio::time::interval(STORE_UPDATE_INTERVAL);
         loop {
-            tokio::select! {
-                msg = qmdl_file_rx.recv() => {
-                    match msg {
-                        Some(DiagDeviceCtrlMessage::StartRecording((new_writer, new_analysis_file))) => {
-                            maybe_qmdl_writer = Some(new_writer);
-                            if let Some(analysis_writer) = maybe_analysis_writer {
-                                analysis_writer.close().await.expect("failed to close analysis writer");
-                            }
-                            maybe_analysis_writer = Some(AnalysisWriter::new(new_analysis_file, enable_dummy_analyzer).await
-                                .expect("failed to write to analysis file"));
-                        },
-                        Some(DiagDeviceCtrlMessage::StopRecording) => {
-                            maybe_qmdl_writer = None;
-                            if let Some(analysis_writer) = maybe_analysis_writer {
-                                analysis_writer.close().await.expect("failed to close analysis writer");
-                            }
-                            maybe_analysis_writer = None;
-                        },
-                        // None means all the Senders have been dropped, so it's
-                        // time to go
-                        Some(DiagDeviceCtrlMessage::Exit) | None => {
-                            info!("Diag reader thread exiting...");
-                            if let Some(analysis_writer) = maybe_analysis_writer {
-                                analysis_writer.close().await.expect("failed to close analysis writer");
-                            }
-                            return Ok(())
-                        },
-                    }
-                }
-                maybe_container = diag_stream.next() => {
-                    match maybe_container.unwrap() {
-                        Ok(container) => {
-                            if container.data_type != DataType::UserSpace {
-                                debug!("skipping non-userspace diag messages...");
-                                continue;
-                            }
-                            // keep track of how many bytes were written to the QMDL file so we can read
-                            // a valid block of data from it in the HTTP server
-                            if let Some(qmdl_writer) = maybe_qmdl_writer.as_mut() {
-                                qmdl_writer.write_container(&container).await.expect("failed to write to QMDL writer");
-                                debug!("total QMDL bytes written: {}, updating manifest...", qmdl_writer.total_written);
-                                let mut qmdl_store = qmdl_store_lock.write().await;
-                                let index = qmdl_store.current_entry.expect("DiagDevice had qmdl_writer, but QmdlStore didn't have current entry???");
-                                qmdl_store.update_entry_qmdl_size(index, qmdl_writer.total_written).await
-                                    .expect("failed to update qmdl file size");
-                                debug!("done!");
-                            } else {
-                                debug!("no qmdl_writer set, continuing...");
+            // the stream borrows the device, so it has to be dropped before a
+            // broken device can be swapped for a fresh one, or before we can
+            // send it anything on the way out. None means it's time to exit.
+            let maybe_device_error = {
+                let mut diag_stream = pin!(dev.as_stream().into_stream());
+                loop {
+                    tokio::select! {
+                        _ = store_update_interval.tick(), if thread_state.has_pending_updates() => {
+                            thread_state.flush_pending_updates().await?;
+                        }
+                        _ = idle_check_interval.tick(), if thread_state.idle_detector.is_enabled() => {
+                            thread_state.check_idle().await?;
+                        }
+                        msg = qmdl_file_rx.recv() => {
+                            if thread_state.handle_ctrl_message(msg).await?.is_break() {
+                                break None;
                             }
-
-                            if let Some(analysis_writer) = maybe_analysis_writer.as_mut() {
-                                let analysis_output = analysis_writer.analyze(container).await
-                                    .expect("failed to analyze container");
-                                let (analysis_file_len, heuristic_warning) = analysis_output;
-                                if heuristic_warning {
-                                    info!("a heuristic triggered on this run!");
-                                    ui_update_sender.send(framebuffer::DisplayState::WarningDetected).await
-                                        .expect("couldn't send ui update message: {}");
-                                }
-                                let mut qmdl_store = qmdl_store_lock.write().await;
-                                let index = qmdl_store.current_entry.expect("DiagDevice had qmdl_writer, but QmdlStore didn't have current entry???");
-                                qmdl_store.update_entry_analysis_size(index, analysis_file_len as usize).await
-                                    .expect("failed to update analysis file size");
+                        }
+                        maybe_container = diag_stream.next() => {
+                            match maybe_container {
+                                Some(Ok(container)) => thread_state.handle_container(container).await?,
+                                Some(Err(err)) => break Some(DiagError::DeviceError(err)),
+                                None => break Some(DiagError::StreamEnded),
                             }
-                        },
-                        Err(err) => {
-                            error!("error reading diag device: {}", err);
-                            return Err(err);
                         }
                     }
                 }
-            }
+            };
+            let Some(device_error) = maybe_device_error else {
+                // otherwise the modem keeps logging with nobody reading
+                match tokio::time::timeout(DISABLE_LOGS_TIMEOUT, dev.disable_logs()).await {
+                    Ok(Ok(())) => info!("disabled diag logging"),
+                    Ok(Err(err)) => warn!("couldn't disable diag logging: {}", err),
+                    Err(_) => warn!("timed out disabling diag logging"),
+                }
+                return Ok(());
+            };
+            error!("{}, reconnecting", device_error);
+            recover(thread_state.send_ui_update(framebuffer::DisplayState::Reconnecting).await)?;
+            let mut backoff = Backoff::new(MAX_RECONNECT_ATTEMPTS, INITIAL_RECONNECT_BACKOFF);
+            dev = loop {
+                let msg = match reconnect_with_backoff(&mut open_diag_device, &mut backoff, &mut qmdl_file_rx, &shutdown_token).await {
+                    Ok(Reconnect::Connected(dev)) => break dev,
+                    Ok(Reconnect::Message(msg)) => msg,
+                    Ok(Reconnect::Shutdown) => Some(DiagDeviceCtrlMessage::Exit),
+                    Err(err) => return Err(log_fatal(DiagError::ReconnectFailed(err))),
+                };
+                // e.g. stopping the recording doesn't need a device, so
+                // handle it and carry on trying
+                if thread_state.handle_ctrl_message(msg).await?.is_break() {
+                    return Ok(());
+                }
+            };
+            // carry on with whatever entry we were writing to before
+            info!("reconnected to the diag device");
+            let state = if thread_state.maybe_qmdl_writer.is_some() {
+                framebuffer::DisplayState::Recording
+            } else {
+                framebuffer::DisplayState::NoQmdlData
+            };
+            recover(thread_state.send_ui_update(state).await)?;
         }
     });
 }
 
-pub async fn start_recording(State(state): State<Arc<ServerState>>) -> Result<(StatusCode, String), (StatusCode, String)> {
-    if state.debug_mode {
+// Logs a recoverable error and returns None so the diag thread can carry on,
+// or passes a fatal one up to stop it
+fn recover<T>(result: Result<T, DiagError>) -> Result<Option<T>, DiagError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.is_fatal() => Err(log_fatal(err)),
+        Err(err) => {
+            error!("{}, continuing", err);
+            Ok(None)
+        },
+    }
+}
+
+fn log_fatal(err: DiagError) -> DiagError {
+    error!("{}, stopping diag thread", err);
+    err
+}
+
+async fn send_ui_update(
+    ui_update_sender: &Sender<framebuffer::UiUpdate>,
+    live_update_sender: &LiveUpdateSender,
+    state: framebuffer::DisplayState,
+) -> Result<(), DiagError> {
+    live_update_sender.send(LiveUpdate::DisplayState { state });
+    ui_update_sender.send(state.into()).await
+        .map_err(|_| DiagError::UiChannelClosed)
+}
+
+// Identifies the entry created by a call to start_recording, so clients don't
+// have to go digging through the manifest to find it
+#[derive(Serialize)]
+pub struct NewRecording {
+    pub name: String,
+    pub start_time: DateTime<Local>,
+}
+
+#[derive(Deserialize)]
+pub struct StartRecordingParams {
+    // if set and non-zero, stop the recording again after this many seconds
+    duration_secs: Option<u64>,
+}
+
+pub async fn start_recording(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<StartRecordingParams>,
+) -> Result<(StatusCode, Json<NewRecording>), (StatusCode, String)> {
+    if state.config_lock.read().await.debug_mode {
         return Err((StatusCode::FORBIDDEN, "server is in debug mode".to_string()));
     }
     let mut qmdl_store = state.qmdl_store_lock.write().await;
     let (qmdl_file, analysis_file) = qmdl_store.new_entry().await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't create new qmdl entry: {}", e)))?;
+    let (_, entry) = qmdl_store.get_current_entry()
+        .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "couldn't find newly created qmdl entry".to_string()))?;
+    let new_recording = NewRecording {
+        name: entry.name.clone(),
+        start_time: entry.start_time,
+    };
     let qmdl_writer = QmdlWriter::new(qmdl_file);
     state.diag_device_ctrl_sender.send(DiagDeviceCtrlMessage::StartRecording((qmdl_writer, analysis_file))).await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't send stop recording message: {}", e)))?;
 
-    let display_state: framebuffer::DisplayState;
-    if state.colorblind_mode { 
-        display_state = framebuffer::DisplayState::RecordingCBM;
-    } else {
-        display_state = framebuffer::DisplayState::Recording;
-    }
-    state.ui_update_sender.send(display_state).await
+    // the new recording's warnings haven't been seen yet
+    state.analysis_status_lock.write().await.acknowledged_warnings = 0;
+    state.live_update_sender.send(LiveUpdate::DisplayState { state: framebuffer::DisplayState::Recording });
+    state.ui_update_sender.send(framebuffer::DisplayState::Recording.into()).await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't send ui update message: {}", e)))?;
 
-    Ok((StatusCode::ACCEPTED, "ok".to_string()))
+    // starting a recording closes the previous one, so its timer's done too
+    cancel_recording_timer(&state);
+    if let Some(duration_secs) = params.duration_secs.filter(|secs| *secs > 0) {
+        // a child of the shutdown token, so the timer doesn't hold up the
+        // daemon exiting either
+        let timer_token = state.shutdown_token.child_token();
+        *state.recording_timer_lock.lock().unwrap() = Some(timer_token.clone());
+        let state = state.clone();
+        let name = new_recording.name.clone();
+        state.task_tracker.clone().spawn(async move {
+            tokio::select! {
+                _ = timer_token.cancelled() => {},
+                _ = tokio::time::sleep(Duration::from_secs(duration_secs)) => {
+                    match stop_current_recording(&state, Some(&name)).await {
+                        Ok(true) => info!("stopped recording {} after {} seconds", name, duration_secs),
+                        Ok(false) => {},
+                        Err((_, e)) => error!("{}", e),
+                    }
+                },
+            }
+        });
+    }
+
+    Ok((StatusCode::ACCEPTED, Json(new_recording)))
 }
 
-pub async fn stop_recording(State(state): State<Arc<ServerState>>) -> Result<(StatusCode, String), (StatusCode, String)> {
-    if state.debug_mode {
-        return Err((StatusCode::FORBIDDEN, "server is in debug mode".to_string()));
+// Stops the duration_secs timer of the current recording, if it has one
+fn cancel_recording_timer(state: &ServerState) {
+    if let Some(timer_token) = state.recording_timer_lock.lock().unwrap().take() {
+        timer_token.cancel();
     }
+}
+
+// Stops the current recording, if it's named only_name when that's given, so
+// that a timed recording's timer doesn't stop whichever recording came after
+// it. Returns whether a recording was stopped.
+async fn stop_current_recording(state: &ServerState, only_name: Option<&str>) -> Result<bool, (StatusCode, String)> {
     let mut qmdl_store = state.qmdl_store_lock.write().await;
+    if let Some(only_name) = only_name {
+        if qmdl_store.get_current_entry().is_none_or(|(_, entry)| entry.name != only_name) {
+            return Ok(false);
+        }
+    }
     qmdl_store.close_current_entry().await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't close current qmdl entry: {}", e)))?;
+    cancel_recording_timer(state);
     state.diag_device_ctrl_sender.send(DiagDeviceCtrlMessage::StopRecording).await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't send stop recording message: {}", e)))?;
-    state.ui_update_sender.send(framebuffer::DisplayState::Paused).await
+    state.live_update_sender.send(LiveUpdate::DisplayState { state: framebuffer::DisplayState::NoQmdlData });
+    state.ui_update_sender.send(framebuffer::DisplayState::NoQmdlData.into()).await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't send ui update message: {}", e)))?;
+    Ok(true)
+}
+
+pub async fn stop_recording(State(state): State<Arc<ServerState>>) -> Result<(StatusCode, String), (StatusCode, String)> {
+    if state.config_lock.read().await.debug_mode {
+        return Err((StatusCode::FORBIDDEN, "server is in debug mode".to_string()));
+    }
+    stop_current_recording(&state, None).await?;
     Ok((StatusCode::ACCEPTED, "ok".to_string()))
 }
 
-pub async fn get_analysis_report(State(state): State<Arc<ServerState>>, Path(qmdl_name): Path<String>) -> Result<Response, (StatusCode, String)> {
+const ANALYSIS_TAIL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Deserialize)]
+pub struct AnalysisReportParams {
+    // if set, keep streaming newly written rows until the entry stops recording
+    #[serde(default)]
+    follow: bool,
+}
+
+pub async fn get_analysis_report(
+    State(state): State<Arc<ServerState>>,
+    Path(qmdl_name): Path<String>,
+    Query(params): Query<AnalysisReportParams>,
+) -> Result<Response, (StatusCode, String)> {
     let qmdl_store = state.qmdl_store_lock.read().await;
-    let (entry_index, _) = if qmdl_name == "live" {
+    let (entry_index, entry) = if qmdl_name == "live" {
         qmdl_store.get_current_entry().ok_or((
             StatusCode::SERVICE_UNAVAILABLE,
             "No QMDL data's being recorded to analyze, try starting a new recording!".to_string()
@@ -169,11 +808,481 @@ pub async fn get_analysis_report(State(state): State<Arc<ServerState>>, Path(qmd
             format!("Couldn't find QMDL entry with name \"{}\"", qmdl_name)
         ))?
     };
+    let entry_name = entry.name.clone();
     let analysis_file = qmdl_store.open_entry_analysis(entry_index).await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", e)))?;
-    let analysis_stream = ReaderStream::new(analysis_file);
 
-    let headers = [(CONTENT_TYPE, "application/x-ndjson")];
-    let body = Body::from_stream(analysis_stream);
+    // let clients show progress before the body arrives. For the live entry
+    // (or with follow=true) these are only a snapshot of the counts so far.
+    let headers = [
+        (CONTENT_TYPE, "application/x-ndjson".to_string()),
+        (HeaderName::from_static("x-warning-count"), entry.warning_count.to_string()),
+        (HeaderName::from_static("x-analysis-size-bytes"), entry.analysis_size_bytes.to_string()),
+    ];
+    let body = if params.follow {
+        Body::from_stream(tail_analysis_file(analysis_file, state.qmdl_store_lock.clone(), entry_name))
+    } else {
+        Body::from_stream(ReaderStream::new(analysis_file))
+    };
     Ok((headers, body).into_response())
 }
+
+// Streams an analysis file like `tail -f`: once we've caught up to the end of
+// the file, keep polling for newly written rows until the entry is no longer
+// the one being recorded. Nothing is spawned, so if the client disconnects the
+// stream is simply dropped.
+fn tail_analysis_file(
+    analysis_file: File,
+    qmdl_store_lock: Arc<RwLock<RecordingStore>>,
+    entry_name: String,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+    futures::stream::try_unfold((analysis_file, false), move |(mut analysis_file, mut finished)| {
+        let qmdl_store_lock = qmdl_store_lock.clone();
+        let entry_name = entry_name.clone();
+        async move {
+            let mut buf = vec![0; 8192];
+            loop {
+                let bytes_read = analysis_file.read(&mut buf).await?;
+                if bytes_read > 0 {
+                    buf.truncate(bytes_read);
+                    return Ok(Some((Bytes::from(buf), (analysis_file, finished))));
+                }
+                if finished {
+                    return Ok(None);
+                }
+                let still_recording = qmdl_store_lock.read().await
+                    .get_current_entry()
+                    .is_some_and(|(_, entry)| entry.name == entry_name);
+                if still_recording {
+                    tokio::time::sleep(ANALYSIS_TAIL_POLL_INTERVAL).await;
+                } else {
+                    // read once more to pick up anything written before the
+                    // entry was closed
+                    finished = true;
+                }
+            }
+        }
+    })
+}
+
+// Sends an arbitrary hex-encoded diag request to the modem and returns the
+// hex-encoded response messages, one per line. Since this can reconfigure the
+// modem, it's only available in debug mode, where the daemon isn't otherwise
+// using the diag device.
+pub async fn send_raw_diag_command(State(state): State<Arc<ServerState>>, body: String) -> Result<(StatusCode, String), (StatusCode, String)> {
+    if !state.config_lock.read().await.debug_mode {
+        return Err((StatusCode::FORBIDDEN, "raw diag commands are only available in debug mode".to_string()));
+    }
+    let req_bytes = parse_hex(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let mut dev = DiagDevice::new().await
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, format!("no diag device available: {}", e)))?;
+    let response = dev.send_raw_request(&req_bytes).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("diag request failed: {}", e)))?;
+    let response_hex: Vec<String> = response.iter()
+        .map(|msg| msg.iter().map(|b| format!("{:02x}", b)).collect())
+        .collect();
+    Ok((StatusCode::OK, response_hex.join("\n")))
+}
+
+// Parses a string of hex digits (ignoring any whitespace) into bytes
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    let pairs = digits.chunks_exact(2);
+    if digits.is_empty() || !pairs.remainder().is_empty() {
+        return Err("request must be a non-empty, even number of hex digits".to_string());
+    }
+    pairs
+        .map(|pair| {
+            let byte_str: String = pair.iter().collect();
+            u8::from_str_radix(&byte_str, 16)
+                .map_err(|_| format!("invalid hex byte \"{}\"", byte_str))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("73000000 01000000\n"), Ok(vec![0x73, 0, 0, 0, 1, 0, 0, 0]));
+        assert_eq!(parse_hex("7E7d"), Ok(vec![0x7e, 0x7d]));
+        assert!(parse_hex("").is_err());
+        assert!(parse_hex("730").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pending_store_update() {
+        let dir = tempfile::Builder::new().prefix("diag_test").tempdir().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let entry_index = store.current_entry.unwrap();
+        let mut pending = PendingStoreUpdate::for_current_entry(&store);
+        let store_lock = RwLock::new(store);
+
+        assert!(pending.is_empty());
+        // several updates between flushes only keep the latest sizes
+        pending.qmdl_size_bytes = Some(100);
+        pending.analysis = Some((10, 1));
+        pending.qmdl_size_bytes = Some(200);
+        assert!(!pending.is_empty());
+        pending.flush(&store_lock).await.unwrap();
+        assert!(pending.is_empty());
+        {
+            let store = store_lock.read().await;
+            let entry = &store.manifest.entries[entry_index];
+            assert_eq!(entry.qmdl_size_bytes, 200);
+            assert_eq!(entry.analysis_size_bytes, 10);
+            assert_eq!(entry.warning_count, 1);
+        }
+
+        // the pending update remembers its entry even after it's closed
+        pending.qmdl_size_bytes = Some(300);
+        store_lock.write().await.close_current_entry().await.unwrap();
+        pending.flush(&store_lock).await.unwrap();
+        assert_eq!(store_lock.read().await.manifest.entries[entry_index].qmdl_size_bytes, 300);
+
+        // without an entry, flushing is a no-op
+        let mut pending = PendingStoreUpdate::new(None);
+        pending.qmdl_size_bytes = Some(400);
+        pending.flush(&store_lock).await.unwrap();
+        assert_eq!(store_lock.read().await.manifest.entries[entry_index].qmdl_size_bytes, 300);
+    }
+
+    #[tokio::test]
+    async fn test_pending_store_update_after_deleting_older_entry() {
+        let dir = tempfile::Builder::new().prefix("diag_test").tempdir().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let older_name = store.get_current_entry().unwrap().1.name.clone();
+        let _ = store.new_entry().await.unwrap();
+        let current_name = store.get_current_entry().unwrap().1.name.clone();
+        let mut pending = PendingStoreUpdate::for_current_entry(&store);
+        let store_lock = RwLock::new(store);
+
+        // deleting an older entry while recording moves the current one down
+        // an index
+        pending.qmdl_size_bytes = Some(100);
+        pending.analysis = Some((10, 2));
+        store_lock.write().await.delete_entry(&older_name).await.unwrap();
+        pending.flush(&store_lock).await.unwrap();
+
+        let store = store_lock.read().await;
+        assert_eq!(store.manifest.entries.len(), 1);
+        let (_, entry) = store.get_current_entry().unwrap();
+        assert_eq!(entry.name, current_name);
+        assert_eq!(entry.qmdl_size_bytes, 100);
+        assert_eq!(entry.analysis_size_bytes, 10);
+        assert_eq!(entry.warning_count, 2);
+    }
+
+    // A diag thread's state, minus the diag device, with receivers for what
+    // it sends to the UI and warning LED
+    fn make_thread_state(qmdl_store_lock: Arc<RwLock<RecordingStore>>) -> (DiagThreadState, Receiver<framebuffer::UiUpdate>, Receiver<WarningLedMessage>) {
+        let (ui_update_sender, ui_update_rx) = tokio::sync::mpsc::channel(10);
+        let (led_sender, led_rx) = tokio::sync::mpsc::channel(10);
+        let thread_state = DiagThreadState {
+            ui_update_sender,
+            led_sender,
+            live_update_sender: LiveUpdateSender::new(),
+            diag_tcp_sender: DiagTcpSender::new(),
+            qmdl_store_lock,
+            message_stats_lock: Arc::new(RwLock::new(MessageStats::default())),
+            diag_loop_stats_lock: Arc::new(RwLock::new(DiagLoopStats::default())),
+            analyzer_config: crate::config::AnalyzerConfig::default(),
+            max_recordings: None,
+            max_store_bytes: None,
+            max_recording_bytes: None,
+            idle_detector: IdleDetector::new(None),
+            maybe_qmdl_writer: None,
+            maybe_analysis_writer: None,
+            pending_store_update: PendingStoreUpdate::new(None),
+            pending_stats: PendingStats::default(),
+            num_slow_containers: 0,
+        };
+        (thread_state, ui_update_rx, led_rx)
+    }
+
+    #[tokio::test]
+    async fn test_pending_stats_batching() {
+        let dir = tempfile::Builder::new().prefix("diag_test").tempdir().unwrap();
+        let store = RecordingStore::create(dir.path()).await.unwrap();
+        let (mut thread_state, _ui_update_rx, _led_rx) = make_thread_state(Arc::new(RwLock::new(store)));
+        let num_containers: u64 = 1000;
+
+        // count how often the shared stats get updated as containers come in
+        let mut flushes = 0;
+        let mut flushed_containers = 0;
+        for i in 0..num_containers {
+            let data_type = if i % 10 == 0 { DataType::Other(0) } else { DataType::UserSpace };
+            let container = MessagesContainer { data_type, num_messages: 0, messages: vec![] };
+            thread_state.handle_container(container).await.unwrap();
+            let containers_read = thread_state.diag_loop_stats_lock.read().await.containers_read;
+            if containers_read != flushed_containers {
+                flushes += 1;
+                flushed_containers = containers_read;
+            }
+        }
+        assert!(thread_state.has_pending_updates());
+        thread_state.flush_pending_updates().await.unwrap();
+        flushes += 1;
+        assert!(!thread_state.has_pending_updates());
+
+        // unbatched, that'd be an update per container
+        assert_eq!(flushes, num_containers.div_ceil(MAX_BATCHED_CONTAINERS));
+        let stats = thread_state.diag_loop_stats_lock.read().await;
+        assert_eq!(stats.containers_read, num_containers);
+        assert_eq!(stats.containers_skipped, 100);
+        assert_eq!(stats.containers_processed, 900);
+    }
+
+    #[tokio::test]
+    async fn test_split_recording() {
+        use rayhunter::diag::{HdlcEncapsulatedMessage, MessagesContainer};
+
+        let dir = tempfile::Builder::new().prefix("diag_test").tempdir().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let (qmdl_file, _) = store.new_entry().await.unwrap();
+        let first_entry_index = store.current_entry.unwrap();
+        let store_lock = RwLock::new(store);
+
+        let container = MessagesContainer {
+            data_type: DataType::UserSpace,
+            num_messages: 1,
+            messages: vec![HdlcEncapsulatedMessage { len: 4, data: vec![1, 2, 3, 0x7e] }],
+        };
+        let max_recording_bytes = 10;
+        let mut qmdl_writer = QmdlWriter::new(qmdl_file);
+        let mut pending = PendingStoreUpdate::for_current_entry(&*store_lock.read().await);
+        while qmdl_writer.total_written < max_recording_bytes {
+            qmdl_writer.write_container(&container).await.unwrap();
+            pending.qmdl_size_bytes = Some(qmdl_writer.total_written);
+        }
+        let first_entry_size = qmdl_writer.total_written;
+
+        let (mut qmdl_writer, _) = split_recording(&store_lock, &mut pending).await.unwrap();
+        qmdl_writer.write_container(&container).await.unwrap();
+        pending.qmdl_size_bytes = Some(qmdl_writer.total_written);
+        pending.flush(&store_lock).await.unwrap();
+
+        let store = store_lock.read().await;
+        assert_eq!(store.manifest.entries.len(), 2);
+        let (current_index, current_entry) = store.get_current_entry().unwrap();
+        assert_ne!(current_index, first_entry_index);
+        assert_ne!(current_entry.name, store.manifest.entries[first_entry_index].name);
+        // the old entry got everything written before the split, and nothing after
+        assert_eq!(store.manifest.entries[first_entry_index].qmdl_size_bytes, first_entry_size);
+        assert_eq!(current_entry.qmdl_size_bytes, qmdl_writer.total_written);
+        let first_qmdl_path = store.manifest.entries[first_entry_index].get_qmdl_filepath(&store.path);
+        assert_eq!(std::fs::metadata(first_qmdl_path).unwrap().len() as usize, first_entry_size);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_with_backoff() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        fn device_error() -> DiagDeviceError {
+            DiagDeviceError::DeviceReadFailed(std::io::Error::other("device went away"))
+        }
+
+        let (_ctrl_tx, mut ctrl_rx) = tokio::sync::mpsc::channel(1);
+        let shutdown_token = CancellationToken::new();
+
+        // a device that can't be reopened on the first try
+        let attempts = AtomicU32::new(0);
+        let mut connect = || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(device_error()),
+                n => Ok(n),
+            }
+        };
+        let mut backoff = Backoff::new(3, Duration::from_millis(1));
+        let result = reconnect_with_backoff(&mut connect, &mut backoff, &mut ctrl_rx, &shutdown_token).await;
+        assert!(matches!(result, Ok(Reconnect::Connected(1))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // give up once we're out of attempts
+        let attempts = AtomicU32::new(0);
+        let mut connect = || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(device_error())
+        };
+        let mut backoff = Backoff::new(3, Duration::from_millis(1));
+        let result = reconnect_with_backoff(&mut connect, &mut backoff, &mut ctrl_rx, &shutdown_token).await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_with_backoff_interrupted() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let (ctrl_tx, mut ctrl_rx) = tokio::sync::mpsc::channel(1);
+        let shutdown_token = CancellationToken::new();
+        let attempts = AtomicU32::new(0);
+        let mut connect = || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(DiagDeviceError::DeviceReadFailed(std::io::Error::other("device went away")))
+        };
+        // long enough that the test would time out if we waited it out
+        let mut backoff = Backoff::new(3, Duration::from_secs(600));
+
+        // control messages get handled without waiting out the backoff
+        ctrl_tx.send(DiagDeviceCtrlMessage::StopRecording).await.unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5),
+            reconnect_with_backoff(&mut connect, &mut backoff, &mut ctrl_rx, &shutdown_token)).await.unwrap();
+        assert!(matches!(result, Ok(Reconnect::Message(Some(DiagDeviceCtrlMessage::StopRecording)))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 0);
+
+        // and so does shutting down, even when the Senders are all gone
+        drop(ctrl_tx);
+        shutdown_token.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(5),
+            reconnect_with_backoff(&mut connect, &mut backoff, &mut ctrl_rx, &shutdown_token)).await.unwrap();
+        assert!(matches!(result, Ok(Reconnect::Shutdown)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_recover() {
+        assert!(matches!(recover::<u32>(Ok(1)), Ok(Some(1))));
+        assert!(matches!(recover::<u32>(Err(DiagError::UiChannelClosed)), Ok(None)));
+        assert!(matches!(recover::<u32>(Err(DiagError::StreamEnded)), Err(DiagError::StreamEnded)));
+    }
+
+    #[tokio::test]
+    async fn test_warning_led_cleared() {
+        use crate::self_test::SYNTHETIC_MESSAGE;
+        use rayhunter::diag::{HdlcEncapsulatedMessage, CRC_CCITT};
+        use rayhunter::hdlc::hdlc_encapsulate;
+
+        let data = hdlc_encapsulate(SYNTHETIC_MESSAGE, &CRC_CCITT);
+        let warning_container = MessagesContainer {
+            data_type: DataType::UserSpace,
+            num_messages: 1,
+            messages: vec![HdlcEncapsulatedMessage { len: data.len() as u32, data }],
+        };
+        let dir = tempfile::Builder::new().prefix("diag_test").tempdir().unwrap();
+        let store = RecordingStore::create(dir.path()).await.unwrap();
+        let (mut thread_state, _ui_update_rx, mut led_rx) = make_thread_state(Arc::new(RwLock::new(store)));
+        // the dummy analyzer warns about every message
+        thread_state.analyzer_config.enable_dummy_analyzer = true;
+        thread_state.start().await.unwrap();
+
+        thread_state.handle_container(warning_container.clone()).await.unwrap();
+        assert!(matches!(led_rx.try_recv(), Ok(WarningLedMessage::Warning(_))));
+        assert!(thread_state.handle_ctrl_message(Some(DiagDeviceCtrlMessage::StopRecording)).await.unwrap().is_continue());
+        assert!(matches!(led_rx.try_recv(), Ok(WarningLedMessage::Clear)));
+
+        let (qmdl_file, analysis_file) = thread_state.qmdl_store_lock.write().await.new_entry().await.unwrap();
+        let start = DiagDeviceCtrlMessage::StartRecording((QmdlWriter::new(qmdl_file), analysis_file));
+        assert!(thread_state.handle_ctrl_message(Some(start)).await.unwrap().is_continue());
+        assert!(matches!(led_rx.try_recv(), Ok(WarningLedMessage::Clear)));
+        thread_state.handle_container(warning_container).await.unwrap();
+        assert!(matches!(led_rx.try_recv(), Ok(WarningLedMessage::Warning(_))));
+        assert!(thread_state.handle_ctrl_message(Some(DiagDeviceCtrlMessage::Exit)).await.unwrap().is_break());
+        assert!(matches!(led_rx.try_recv(), Ok(WarningLedMessage::Clear)));
+    }
+
+    #[test]
+    fn test_idle_detector() {
+        let start = Instant::now();
+        let mut detector = IdleDetector::new(Some(Duration::from_secs(60)));
+        detector.record_activity(start);
+        assert!(!detector.check_idle(start + Duration::from_secs(59)));
+        assert!(detector.check_idle(start + Duration::from_secs(60)));
+        // only report the transition once
+        assert!(!detector.check_idle(start + Duration::from_secs(120)));
+        assert!(detector.record_activity(start + Duration::from_secs(121)));
+        assert!(!detector.record_activity(start + Duration::from_secs(122)));
+        assert!(!detector.check_idle(start + Duration::from_secs(122)));
+
+        let mut disabled_detector = IdleDetector::new(None);
+        assert!(!disabled_detector.is_enabled());
+        assert!(!disabled_detector.check_idle(start + Duration::from_secs(1_000_000)));
+    }
+
+    #[tokio::test]
+    async fn test_analyze_malformed_container() {
+        use crate::self_test::SYNTHETIC_MESSAGE;
+        use rayhunter::diag::{HdlcEncapsulatedMessage, CRC_CCITT};
+        use rayhunter::hdlc::hdlc_encapsulate;
+
+        fn container(messages: Vec<Vec<u8>>) -> MessagesContainer {
+            MessagesContainer {
+                data_type: DataType::UserSpace,
+                num_messages: messages.len() as u32,
+                messages: messages.into_iter()
+                    .map(|data| HdlcEncapsulatedMessage { len: data.len() as u32, data })
+                    .collect(),
+            }
+        }
+
+        let dir = tempfile::Builder::new().prefix("diag_test").tempdir().unwrap();
+        let store = RecordingStore::create(dir.path()).await.unwrap();
+        let (mut thread_state, _ui_update_rx, mut led_rx) = make_thread_state(Arc::new(RwLock::new(store)));
+        // the dummy analyzer warns about the valid message
+        thread_state.analyzer_config.enable_dummy_analyzer = true;
+        thread_state.start().await.unwrap();
+
+        // a log message whose lengths are too short for its own header, and
+        // one that isn't even validly HDLC encapsulated
+        let truncated = hdlc_encapsulate(&[16, 0, 4, 0, 4, 0, 0xc0, 0xb0, 0, 0, 0, 0, 0, 0, 0, 0], &CRC_CCITT);
+        let garbage = vec![1, 2, 3, 0x7e];
+        thread_state.handle_container(container(vec![truncated, garbage])).await.unwrap();
+        assert!(led_rx.try_recv().is_err());
+
+        // the next container is still analyzed as normal
+        thread_state.handle_container(container(vec![hdlc_encapsulate(SYNTHETIC_MESSAGE, &CRC_CCITT)])).await.unwrap();
+        assert!(matches!(led_rx.try_recv(), Ok(WarningLedMessage::Warning(_))));
+        assert!(matches!(thread_state.pending_store_update.analysis, Some((_, 1))));
+        assert!(thread_state.handle_ctrl_message(Some(DiagDeviceCtrlMessage::Exit)).await.unwrap().is_break());
+
+        // the malformed messages are skipped, with reasons
+        let store = thread_state.qmdl_store_lock.read().await;
+        let analysis_path = store.manifest.entries[0].get_analysis_filepath(&store.path);
+        let contents = std::fs::read_to_string(analysis_path).unwrap();
+        let rows: Vec<serde_json::Value> = contents.lines().skip(1)
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["skipped_message_reasons"].as_array().unwrap().len(), 2);
+        assert_eq!(rows[1]["skipped_message_reasons"].as_array().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tail_analysis_file() {
+        use tokio::io::AsyncWriteExt;
+
+        let dir = tempfile::Builder::new().prefix("diag_test").tempdir().unwrap();
+        let store = RecordingStore::create(dir.path()).await.unwrap();
+        let store_lock = Arc::new(RwLock::new(store));
+        let (_, mut analysis_file) = store_lock.write().await.new_entry().await.unwrap();
+        analysis_file.write_all(b"first\n").await.unwrap();
+        analysis_file.flush().await.unwrap();
+
+        let (entry_index, entry_name) = {
+            let store = store_lock.read().await;
+            let (entry_index, entry) = store.get_current_entry().unwrap();
+            (entry_index, entry.name.clone())
+        };
+        let reader = store_lock.read().await.open_entry_analysis(entry_index).await.unwrap();
+        let stream = tail_analysis_file(reader, store_lock.clone(), entry_name);
+
+        let writer_store_lock = store_lock.clone();
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            analysis_file.write_all(b"second\n").await.unwrap();
+            analysis_file.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            writer_store_lock.write().await.close_current_entry().await.unwrap();
+        });
+
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(chunks.concat(), b"first\nsecond\n");
+    }
+}
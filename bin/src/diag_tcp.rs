@@ -0,0 +1,178 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use rayhunter::diag::MessagesContainer;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+// How many containers a client can fall behind by before it starts missing
+// them. A busy cell can produce a few dozen a second, so this is a few
+// seconds' worth.
+const DIAG_TCP_CAPACITY: usize = 256;
+
+// Fans the raw diag stream out to every client connected to diag_tcp_port.
+// Each container is sent as its HDLC frames back to back, the same bytes that
+// go into a QMDL file, so tools which read QMDL files or a live diag device
+// can parse it as it comes in.
+#[derive(Clone)]
+pub struct DiagTcpSender {
+    sender: broadcast::Sender<Arc<[u8]>>,
+}
+
+impl DiagTcpSender {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(DIAG_TCP_CAPACITY);
+        DiagTcpSender { sender }
+    }
+
+    // Sends the container's frames to every connected client, if there are any
+    pub fn send_container(&self, container: &MessagesContainer) {
+        // most of the time nobody's connected, so don't bother copying
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let data: Vec<u8> = container.messages.iter()
+            .flat_map(|msg| msg.data.iter().copied())
+            .collect();
+        // this only fails if everyone disconnected in the meantime
+        let _ = self.sender.send(data.into());
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<[u8]>> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for DiagTcpSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Accepts clients on listener and streams the diag data sent through
+// diag_tcp_sender to each of them until shutdown_token is cancelled
+pub fn run_diag_tcp_server(
+    task_tracker: &TaskTracker,
+    listener: TcpListener,
+    diag_tcp_sender: DiagTcpSender,
+    shutdown_token: CancellationToken,
+) {
+    let client_tracker = task_tracker.clone();
+    task_tracker.spawn(async move {
+        loop {
+            let (stream, addr) = tokio::select! {
+                _ = shutdown_token.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("couldn't accept diag TCP client: {}", e);
+                        continue;
+                    },
+                },
+            };
+            info!("diag TCP client {} connected", addr);
+            // subscribe before spawning, so the client can't miss anything
+            // sent in between
+            let receiver = diag_tcp_sender.subscribe();
+            client_tracker.spawn(stream_to_client(stream, addr, receiver, shutdown_token.clone()));
+        }
+        info!("diag TCP server exiting...");
+    });
+}
+
+async fn stream_to_client(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    mut receiver: broadcast::Receiver<Arc<[u8]>>,
+    shutdown_token: CancellationToken,
+) {
+    loop {
+        let data = tokio::select! {
+            _ = shutdown_token.cancelled() => break,
+            received = receiver.recv() => match received {
+                Ok(data) => data,
+                // each container is a whole number of frames, so a client
+                // that fell behind can carry on from the next one
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("diag TCP client {} fell behind, skipped {} containers", addr, skipped);
+                    continue;
+                },
+                Err(RecvError::Closed) => break,
+            },
+        };
+        if let Err(e) = stream.write_all(&data).await {
+            debug!("couldn't write to diag TCP client {}: {}", addr, e);
+            break;
+        }
+    }
+    info!("diag TCP client {} disconnected", addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayhunter::diag::{DataType, HdlcEncapsulatedMessage};
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    fn make_container(frames: &[&[u8]]) -> MessagesContainer {
+        MessagesContainer {
+            data_type: DataType::UserSpace,
+            num_messages: frames.len() as u32,
+            messages: frames.iter()
+                .map(|frame| HdlcEncapsulatedMessage { len: frame.len() as u32, data: frame.to_vec() })
+                .collect(),
+        }
+    }
+
+    async fn connect(addr: SocketAddr, sender: &DiagTcpSender, expected_clients: usize) -> TcpStream {
+        let client = TcpStream::connect(addr).await.unwrap();
+        // the server subscribes the client once it's accepted it
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while sender.sender.receiver_count() < expected_clients {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("client was never subscribed");
+        client
+    }
+
+    #[tokio::test]
+    async fn test_diag_tcp_forwarding() {
+        let task_tracker = TaskTracker::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sender = DiagTcpSender::new();
+        let shutdown_token = CancellationToken::new();
+        run_diag_tcp_server(&task_tracker, listener, sender.clone(), shutdown_token.clone());
+
+        // nothing's connected yet, so this goes nowhere
+        sender.send_container(&make_container(&[&[0xff, 0x7e]]));
+
+        let mut client = connect(addr, &sender, 1).await;
+        sender.send_container(&make_container(&[&[0x01, 0x02, 0x7e], &[0x03, 0x7e]]));
+        let mut received = [0; 5];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, [0x01, 0x02, 0x7e, 0x03, 0x7e]);
+
+        // a client going away doesn't affect anyone else
+        let mut other_client = connect(addr, &sender, 2).await;
+        drop(client);
+        for _ in 0..3 {
+            sender.send_container(&make_container(&[&[0x04, 0x7e]]));
+        }
+        let mut received = [0; 6];
+        other_client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, [0x04, 0x7e, 0x04, 0x7e, 0x04, 0x7e]);
+
+        // shutting down closes the remaining connections
+        shutdown_token.cancel();
+        task_tracker.close();
+        tokio::time::timeout(Duration::from_secs(5), task_tracker.wait()).await.unwrap();
+        assert_eq!(other_client.read(&mut received).await.unwrap(), 0);
+    }
+}
@@ -1,6 +1,7 @@
 use thiserror::Error;
 use rayhunter::diag_device::DiagDeviceError;
 
+use crate::framebuffer::FramebufferError;
 use crate::qmdl_store::RecordingStoreError;
 
 #[derive(Error, Debug)]
@@ -15,4 +16,65 @@ pub enum RayhunterError{
     QmdlStoreError(#[from] RecordingStoreError),
     #[error("No QMDL store found at path {0}, but can't create a new one due to debug mode")]
     NoStoreDebugMode(String),
+    #[error("Couldn't listen on {0}: {1}")]
+    BindError(std::net::SocketAddr, std::io::Error),
+    #[error("Display error: {0}")]
+    FramebufferError(#[from] FramebufferError),
+}
+
+// Errors the diag read thread can run into. Most of these only affect one
+// container or one manifest update, so they're logged and the thread keeps
+// recording; see DiagError::is_fatal.
+#[derive(Error, Debug)]
+pub enum DiagError {
+    #[error("Error reading diag device: {0}")]
+    DeviceError(DiagDeviceError),
+    #[error("Diag device stream ended")]
+    StreamEnded,
+    #[error("Couldn't reconnect to diag device: {0}")]
+    ReconnectFailed(DiagDeviceError),
+    #[error("Couldn't update recording store: {0}")]
+    StoreError(#[from] RecordingStoreError),
+    #[error("Couldn't write to QMDL file: {0}")]
+    QmdlWriteError(std::io::Error),
+    #[error("Couldn't write to analysis file: {0}")]
+    AnalysisWriteError(std::io::Error),
+    #[error("Couldn't send UI update, the UI thread has stopped")]
+    UiChannelClosed,
+}
+
+impl DiagError {
+    // Whether the diag read thread has to give up. Once the device's stream
+    // errors out it won't yield anything else, but everything else only loses
+    // us some data (or a UI update), which beats losing the whole recording.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            DiagError::DeviceError(_) | DiagError::StreamEnded | DiagError::ReconnectFailed(_) => true,
+            DiagError::StoreError(_)
+            | DiagError::QmdlWriteError(_)
+            | DiagError::AnalysisWriteError(_)
+            | DiagError::UiChannelClosed => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_error() -> std::io::Error {
+        std::io::Error::other("disk full")
+    }
+
+    #[test]
+    fn test_diag_error_is_fatal() {
+        assert!(DiagError::DeviceError(DiagDeviceError::DeviceReadFailed(io_error())).is_fatal());
+        assert!(DiagError::StreamEnded.is_fatal());
+        assert!(DiagError::ReconnectFailed(DiagDeviceError::DeviceReadFailed(io_error())).is_fatal());
+
+        assert!(!DiagError::StoreError(RecordingStoreError::WriteManifestError(io_error())).is_fatal());
+        assert!(!DiagError::QmdlWriteError(io_error()).is_fatal());
+        assert!(!DiagError::AnalysisWriteError(io_error()).is_fatal());
+        assert!(!DiagError::UiChannelClosed.is_fatal());
+    }
 }
@@ -0,0 +1,156 @@
+use std::io::{Seek, Write};
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Handle;
+use tokio::sync::RwLock;
+use tokio_util::io::{ReaderStream, SyncIoBridge};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use crate::qmdl_store::{Manifest, ManifestEntry, RecordingStore, RecordingStoreError};
+use crate::server::ServerState;
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("Recording store error: {0}")]
+    Store(#[from] RecordingStoreError),
+    #[error("Couldn't copy recording: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Couldn't write zip file: {0}")]
+    Zip(#[from] zip::result::ZipError),
+}
+
+// Returns a zip file containing every recording's QMDL file and analysis, plus
+// a manifest.json listing them. The zip crate has to seek back over each file
+// it writes, so rather than streaming the zip as it's written, it's built in
+// the store's directory and then sent.
+pub async fn get_export_all(State(state): State<Arc<ServerState>>) -> Result<Response, (StatusCode, String)> {
+    let (entries, store_path) = {
+        let qmdl_store = state.qmdl_store_lock.read().await;
+        (qmdl_store.manifest.entries.clone(), qmdl_store.path.clone())
+    };
+    let qmdl_store_lock = state.qmdl_store_lock.clone();
+
+    // zipping every recording is slow, blocking work
+    let export_file = tokio::task::spawn_blocking(move || {
+        // exports can be large, so build them in the store's directory rather
+        // than in memory (or /tmp, which is often a tmpfs)
+        let export_file = tempfile::tempfile_in(&store_path)?;
+        let mut zip = ZipWriter::new(export_file);
+        write_export(&mut zip, &qmdl_store_lock, &entries)?;
+        let mut export_file = zip.finish()?;
+        export_file.rewind()?;
+        Ok::<_, ExportError>(export_file)
+    }).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't export recordings: {}", e)))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't export recordings: {}", e)))?;
+
+    let headers = [
+        (CONTENT_TYPE, "application/zip"),
+        (CONTENT_DISPOSITION, "attachment; filename=\"rayhunter-export.zip\""),
+    ];
+    let body = Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(export_file)));
+    Ok((headers, body).into_response())
+}
+
+// Writes each entry's files, followed by the manifest, to zip. This does
+// blocking I/O, so has to be run from somewhere like spawn_blocking rather
+// than an async context.
+fn write_export<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    qmdl_store_lock: &RwLock<RecordingStore>,
+    entries: &[ManifestEntry],
+) -> Result<(), ExportError> {
+    let options = FileOptions::default();
+    let handle = Handle::current();
+    for entry in entries {
+        // only hold the lock while opening the files, so recording isn't held
+        // up for the whole export
+        let files = handle.block_on(async {
+            let qmdl_store = qmdl_store_lock.read().await;
+            // the entry may have been deleted since the export started
+            let Some((entry_index, _)) = qmdl_store.entry_for_name(&entry.name) else {
+                return Ok(None);
+            };
+            let qmdl_file = qmdl_store.open_entry_qmdl(entry_index).await?;
+            let analysis_file = qmdl_store.open_entry_analysis(entry_index).await?;
+            Ok::<_, RecordingStoreError>(Some((qmdl_file, analysis_file)))
+        })?;
+        let Some((qmdl_file, analysis_file)) = files else {
+            continue;
+        };
+        zip.start_file(format!("{}.qmdl", entry.name), options)?;
+        std::io::copy(&mut SyncIoBridge::new(qmdl_file.take(entry.qmdl_size_bytes as u64)), zip)?;
+        zip.start_file(format!("{}.ndjson", entry.name), options)?;
+        std::io::copy(&mut SyncIoBridge::new(analysis_file.take(entry.analysis_size_bytes as u64)), zip)?;
+    }
+
+    let manifest = Manifest { entries: entries.to_vec() };
+    let manifest_json = serde_json::to_vec_pretty(&manifest)
+        .expect("failed to serialize manifest");
+    zip.start_file("manifest.json", options)?;
+    zip.write_all(&manifest_json)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+    use tokio::io::AsyncWriteExt;
+    use zip::ZipArchive;
+
+    #[tokio::test]
+    async fn test_write_export() {
+        let dir = tempfile::Builder::new().prefix("export_test").tempdir().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        for data in [&b"first"[..], &b"second"[..]] {
+            let (mut qmdl_file, mut analysis_file) = store.new_entry().await.unwrap();
+            qmdl_file.write_all(data).await.unwrap();
+            analysis_file.write_all(b"{}\n").await.unwrap();
+            let entry_index = store.current_entry.unwrap();
+            store.update_entry_qmdl_size(entry_index, data.len()).await.unwrap();
+            store.update_entry_analysis(entry_index, 3, 0).await.unwrap();
+            store.close_current_entry().await.unwrap();
+        }
+        let entries = store.manifest.entries.clone();
+        let qmdl_store_lock = RwLock::new(store);
+
+        let qmdl_store_lock = Arc::new(qmdl_store_lock);
+        let output = tokio::task::spawn_blocking({
+            let entries = entries.clone();
+            move || {
+                let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+                write_export(&mut zip, &qmdl_store_lock, &entries).unwrap();
+                zip.finish().unwrap().into_inner()
+            }
+        }).await.unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(output)).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        let mut expected = vec![
+            format!("{}.qmdl", entries[0].name),
+            format!("{}.ndjson", entries[0].name),
+            format!("{}.qmdl", entries[1].name),
+            format!("{}.ndjson", entries[1].name),
+            "manifest.json".to_string(),
+        ];
+        expected.sort();
+        assert_eq!(names, expected);
+
+        let mut contents = String::new();
+        archive.by_name(&format!("{}.qmdl", entries[1].name)).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "second");
+        let mut manifest_json = String::new();
+        archive.by_name("manifest.json").unwrap().read_to_string(&mut manifest_json).unwrap();
+        let manifest: Manifest = serde_json::from_str(&manifest_json).unwrap();
+        assert_eq!(manifest.entries, entries);
+    }
+}
@@ -1,17 +1,45 @@
-use image::{codecs::gif::GifDecoder, imageops::FilterType, AnimationDecoder, DynamicImage};
-use std::{io::Cursor, time::Duration};
+use rayhunter::analysis::analyzer::Severity;
+use image::{codecs::gif::GifDecoder, imageops::FilterType, AnimationDecoder, Delay, DynamicImage, ImageError, ImageFormat, RgbImage};
+use include_dir::{include_dir, Dir};
+use std::{io::Cursor, time::{Duration, Instant}};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
 
-const FB_PATH:&str = "/dev/fb0";
+const WRITE_ATTEMPTS: u32 = 3;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(5);
+// the blank border a QR code needs around it to be scanned, in modules
+const QR_QUIET_ZONE: u32 = 2;
+// how often a long GIF frame delay checks whether it's been interrupted
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
-#[derive(Copy, Clone)]
+static IMAGE_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/static/images/");
+
+#[derive(Error, Debug)]
+pub enum FramebufferError {
+    #[error("Failed to decode image: {0}")]
+    ImageDecodeError(#[from] ImageError),
+    #[error("GIF contains no frames")]
+    EmptyGif,
+    #[error("Only a virtual framebuffer can be captured")]
+    NotVirtual,
+    #[error("Failed to write to framebuffer: {0}")]
+    WriteError(std::io::Error),
+    #[error("Failed to encode QR code: {0}")]
+    QrEncodeError(#[from] qrcode::types::QrError),
+}
+
+pub const BITS_PER_PIXEL: u32 = 16;
+
+#[derive(Copy, Clone, Debug, Serialize)]
 // TODO actually poll for this, maybe w/ fbset?
-struct Dimensions {
-    height: u32,
-    width: u32,
+pub struct Dimensions {
+    pub height: u32,
+    pub width: u32,
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Color565 {
     Red    = 0b1111100000000000,
     Green  = 0b0000011111100000,
@@ -21,41 +49,439 @@ pub enum Color565 {
     Cyan   = 0b0000011111111111,
     Yellow = 0b1111111111100000,
     Pink =   0b1111010010011111,
+    Gray =   0b1000010000010000,
 }
 
+impl Color565 {
+    pub const ALL: [Color565; 9] = [
+        Color565::Red,
+        Color565::Green,
+        Color565::Blue,
+        Color565::White,
+        Color565::Black,
+        Color565::Cyan,
+        Color565::Yellow,
+        Color565::Pink,
+        Color565::Gray,
+    ];
+
+    // Packs an 8-bit-per-channel RGB color into RGB565, for colors that aren't
+    // one of the named variants
+    pub const fn from_rgb(r: u8, g: u8, b: u8) -> u16 {
+        ((r as u16 & 0b11111000) << 8) | ((g as u16 & 0b11111100) << 3) | (b as u16 >> 3)
+    }
+}
+
+impl From<Color565> for u16 {
+    fn from(color: Color565) -> Self {
+        color as u16
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "state", content = "severity", rename_all = "snake_case")]
 pub enum DisplayState {
     Recording,
     Paused,
-    WarningDetected,
-    RecordingCBM,
+    WarningDetected(Severity),
+    // the diag device went away and we're trying to reopen it
+    Reconnecting,
+    // there's no recording to write to, e.g. it was stopped from the web UI.
+    // Unlike Paused, where there is one but the modem's gone quiet.
+    NoQmdlData,
 }
 
-impl From<DisplayState> for Color565 {
+impl DisplayState {
+    // Colorblind mode swaps green and red for blue and yellow, which are much
+    // easier to tell apart with the most common kinds of colorblindness
+    pub fn to_color(self, colorblind_mode: bool) -> Color565 {
+        match (self, colorblind_mode) {
+            (DisplayState::Paused, _) => Color565::White,
+            (DisplayState::Recording, false) => Color565::Green,
+            (DisplayState::Recording, true) => Color565::Blue,
+            (DisplayState::WarningDetected(_), false) => Color565::Red,
+            (DisplayState::WarningDetected(_), true) => Color565::Yellow,
+            (DisplayState::Reconnecting, _) => Color565::Cyan,
+            (DisplayState::NoQmdlData, _) => Color565::Gray,
+        }
+    }
+
+    // Yellow, white and cyan are still hard to tell apart without color
+    // vision, so colorblind mode also gives those states' status lines their
+    // own patterns
+    pub fn to_line_pattern(self, colorblind_mode: bool) -> LinePattern {
+        match (self, colorblind_mode) {
+            (DisplayState::WarningDetected(_), true) => LinePattern::Dashed,
+            (DisplayState::Reconnecting, true) => LinePattern::Dotted,
+            _ => LinePattern::Solid,
+        }
+    }
+}
+
+// What the UI thread can be told to do
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UiUpdate {
+    State(DisplayState),
+    // the user's seen the warning being shown, so go back to the state
+    // underneath it
+    AcknowledgeWarnings,
+}
+
+impl From<DisplayState> for UiUpdate {
     fn from(state: DisplayState) -> Self {
+        UiUpdate::State(state)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LinePattern {
+    Solid,
+    Dashed,
+    Dotted,
+}
+
+impl LinePattern {
+    // Whether the line is drawn in column x, rather than left black
+    fn is_drawn(self, x: u32) -> bool {
+        match self {
+            LinePattern::Solid => true,
+            LinePattern::Dashed => x % 16 < 10,
+            LinePattern::Dotted => x % 4 < 2,
+        }
+    }
+}
+
+// Decides which DisplayState is on screen. Warnings are held for
+// warning_display, during which other updates are remembered but not shown
+// and only a warning at least as severe restarts the timer. Without a
+// warning_display, a warning is shown until the next update of any kind.
+pub struct DisplayStateTracker {
+    warning_display: Option<Duration>,
+    base_state: DisplayState,
+    warning: Option<(Severity, Instant)>,
+}
+
+impl DisplayStateTracker {
+    pub fn new(initial_state: DisplayState, warning_display: Option<Duration>) -> Self {
+        DisplayStateTracker {
+            warning_display,
+            base_state: initial_state,
+            warning: None,
+        }
+    }
+
+    pub fn update(&mut self, state: DisplayState, now: Instant) {
+        self.expire_warning(now);
         match state {
-            DisplayState::Paused => Color565::White,
-            DisplayState::Recording => Color565::Green, 
-            DisplayState::RecordingCBM => Color565::Blue, 
-            DisplayState::WarningDetected => Color565::Red,
+            DisplayState::WarningDetected(severity) => {
+                let preempts = match self.warning {
+                    Some((current_severity, _)) => severity >= current_severity,
+                    None => true,
+                };
+                if preempts {
+                    self.warning = Some((severity, now));
+                }
+            },
+            _ => {
+                self.base_state = state;
+                if self.warning_display.is_none() {
+                    self.warning = None;
+                }
+            },
+        }
+    }
+
+    // Stops showing the current warning, if any, without waiting for its
+    // warning_display to run out. Later warnings are shown as usual.
+    pub fn acknowledge_warning(&mut self) {
+        self.warning = None;
+    }
+
+    pub fn current(&mut self, now: Instant) -> DisplayState {
+        self.expire_warning(now);
+        match self.warning {
+            Some((severity, _)) => DisplayState::WarningDetected(severity),
+            None => self.base_state,
+        }
+    }
+
+    fn expire_warning(&mut self, now: Instant) {
+        if let (Some((_, started)), Some(warning_display)) = (self.warning, self.warning_display) {
+            if now.duration_since(started) >= warning_display {
+                self.warning = None;
+            }
         }
     }
 }
 
-#[derive(Copy, Clone)]
+// How far the display is rotated clockwise relative to how we draw, for
+// devices whose screen is mounted sideways or upside down
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum Rotation {
+    #[default]
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+impl Rotation {
+    fn swaps_dimensions(self) -> bool {
+        matches!(self, Rotation::Clockwise90 | Rotation::Clockwise270)
+    }
+
+    // The rotation which undoes this one
+    fn inverse(self) -> Rotation {
+        match self {
+            Rotation::None => Rotation::None,
+            Rotation::Clockwise90 => Rotation::Clockwise270,
+            Rotation::Clockwise180 => Rotation::Clockwise180,
+            Rotation::Clockwise270 => Rotation::Clockwise90,
+        }
+    }
+}
+
+impl TryFrom<u16> for Rotation {
+    type Error = String;
+
+    fn try_from(degrees: u16) -> Result<Self, Self::Error> {
+        match degrees {
+            0 => Ok(Rotation::None),
+            90 => Ok(Rotation::Clockwise90),
+            180 => Ok(Rotation::Clockwise180),
+            270 => Ok(Rotation::Clockwise270),
+            _ => Err(format!("display rotation must be 0, 90, 180, or 270, not {}", degrees)),
+        }
+    }
+}
+
+impl From<Rotation> for u16 {
+    fn from(rotation: Rotation) -> Self {
+        match rotation {
+            Rotation::None => 0,
+            Rotation::Clockwise90 => 90,
+            Rotation::Clockwise180 => 180,
+            Rotation::Clockwise270 => 270,
+        }
+    }
+}
+
+// Rotates a frame of RGB565 pixels that's dimensions in size, returning the
+// rotated frame
+fn rotate(buf: &[u8], dimensions: Dimensions, rotation: Rotation) -> Vec<u8> {
+    let (width, height) = (dimensions.width, dimensions.height);
+    let (rotated_width, rotated_height) = if rotation.swaps_dimensions() {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let mut rotated = Vec::with_capacity(buf.len());
+    for y in 0..rotated_height {
+        for x in 0..rotated_width {
+            // find where this pixel came from in the unrotated frame
+            let (src_x, src_y) = match rotation {
+                Rotation::None => (x, y),
+                Rotation::Clockwise90 => (y, height - 1 - x),
+                Rotation::Clockwise180 => (width - 1 - x, height - 1 - y),
+                Rotation::Clockwise270 => (width - 1 - y, x),
+            };
+            let i = ((src_y * width + src_x) * 2) as usize;
+            rotated.extend_from_slice(&buf[i..i + 2]);
+        }
+    }
+    rotated
+}
+
+const NO_DATA_TEXT: &str = "NO DATA";
+
+// Glyphs are 5x7 pixels, each drawn as a GLYPH_SCALE pixel square
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const GLYPH_SCALE: u32 = 3;
+
+// Every character glyph() can draw
+pub const GLYPH_CHARS: &str = "ADNOTX0123456789";
+
+// The rows of a character's glyph, top first, with the leftmost pixel in the
+// highest of the low GLYPH_WIDTH bits
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT as usize] {
+    match c {
+        'A' => [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'D' => [0b11110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11110],
+        'N' => [0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'X' => [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        _ => [0; GLYPH_HEIGHT as usize],
+    }
+}
+
+// How GIFs are animated: how many times each is played before its last frame
+// is left on screen (None plays it forever), and how fast relative to its own
+// frame delays
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GifPlayback {
+    pub loops: Option<u32>,
+    pub speed: f32,
+}
+
+const DEFAULT_GIF_PLAYBACK: GifPlayback = GifPlayback { loops: None, speed: 1.0 };
+
+// Scales a GIF frame's delay by speed, e.g. a speed of 2 halves it. Speeds
+// which aren't positive play at normal speed.
+fn scale_frame_delay(delay: Delay, speed: f32) -> Duration {
+    let delay = Duration::from(delay);
+    if speed.is_finite() && speed > 0.0 {
+        delay.div_f64(speed as f64)
+    } else {
+        delay
+    }
+}
+
+// Where a Framebuffer's pixels end up. A virtual framebuffer only has its back
+// buffer, which can be captured, e.g. for screenshots.
+enum Output<'a> {
+    Device(&'a str),
+    Virtual,
+}
+
 pub struct Framebuffer<'a> {
+    // the dimensions we draw in, i.e. before rotation
     dimensions: Dimensions,
-    path: &'a str,
+    rotation: Rotation,
+    output: Output<'a>,
+    // Everything's drawn here first and then written to the device a whole
+    // frame at a time, so the screen never shows a half-drawn frame. Empty
+    // until the first write.
+    back_buffer: Vec<u8>,
+    gif_playback: GifPlayback,
+    // how many times draw_gif has played a GIF all the way through
+    gif_plays: u32,
+    // cancelled when a GIF should stop animating, e.g. on shutdown
+    interrupt: Option<CancellationToken>,
 }
 
-impl Framebuffer<'_>{
-    pub const fn new() -> Self {
+impl<'a> Framebuffer<'a>{
+    pub const fn new(path: &'a str) -> Self {
         Framebuffer{
             dimensions: Dimensions{height: 128, width: 128},
-            path: FB_PATH,
+            rotation: Rotation::None,
+            output: Output::Device(path),
+            back_buffer: Vec::new(),
+            gif_playback: DEFAULT_GIF_PLAYBACK,
+            gif_plays: 0,
+            interrupt: None,
+        }
+    }
+
+    pub fn with_gif_playback(mut self, gif_playback: GifPlayback) -> Self {
+        self.gif_playback = gif_playback;
+        self
+    }
+
+    // Stops draw_gif mid-animation once interrupt is cancelled, so whoever's
+    // drawing doesn't have to wait out the rest of the GIF
+    pub fn with_interrupt(mut self, interrupt: CancellationToken) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    // Rotates everything drawn from now on before it's written to the device
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        if rotation.swaps_dimensions() != self.rotation.swaps_dimensions() {
+            let Dimensions { height, width } = self.dimensions;
+            self.dimensions = Dimensions { height: width, width: height };
+        }
+        self.rotation = rotation;
+        self
+    }
+
+    // Starts out black, like the device's screen
+    pub fn new_virtual() -> Self {
+        let mut fb = Framebuffer{
+            dimensions: Dimensions{height: 128, width: 128},
+            rotation: Rotation::None,
+            output: Output::Virtual,
+            back_buffer: Vec::new(),
+            gif_playback: DEFAULT_GIF_PLAYBACK,
+            gif_plays: 0,
+            interrupt: None,
+        };
+        fb.back_buffer = vec![0; fb.frame_len()];
+        fb
+    }
+
+    pub fn dimensions(&self) -> Dimensions {
+        self.dimensions
+    }
+
+    fn frame_len(&self) -> usize {
+        (self.dimensions.height * self.dimensions.width * BITS_PER_PIXEL / 8) as usize
+    }
+
+    // Draws what the UI shows at the given ui_level for display_state. The
+    // status line is drawn in the state's color, while the image levels are
+    // replaced by a "NO DATA" screen when there's no recording.
+    pub fn draw_ui(&mut self, ui_level: u8, display_state: DisplayState, colorblind_mode: bool) -> Result<(), FramebufferError> {
+        let display_color = display_state.to_color(colorblind_mode);
+        match ui_level {
+            2 | 3 if display_state == DisplayState::NoQmdlData => {
+                self.draw_text(NO_DATA_TEXT, display_color)?;
+            },
+            2 => {
+                let img = IMAGE_DIR.get_file("orca.gif").expect("failed to read orca.gif").contents();
+                self.draw_gif(img)?;
+            },
+            3 => {
+                let img = IMAGE_DIR.get_file("eff.png").expect("failed to read eff.png").contents();
+                self.draw_img(img)?;
+            },
+            128 => {
+                self.draw_line(Color565::Cyan, 128)?;
+                self.draw_line(Color565::Pink, 102)?;
+                self.draw_line(Color565::White, 76)?;
+                self.draw_line(Color565::Pink, 50)?;
+                self.draw_line(Color565::Cyan, 25)?;
+            },
+            _ => {
+                self.draw_patterned_line(display_color, 2, display_state.to_line_pattern(colorblind_mode))?;
+            },
         }
+        Ok(())
+    }
+
+    // Encodes the contents of a virtual framebuffer as a PNG
+    pub fn to_png(&self) -> Result<Vec<u8>, FramebufferError> {
+        let Output::Virtual = self.output else {
+            return Err(FramebufferError::NotVirtual);
+        };
+        let buf = &self.back_buffer;
+        let img = RgbImage::from_fn(self.dimensions.width, self.dimensions.height, |x, y| {
+            let i = ((y * self.dimensions.width + x) * 2) as usize;
+            let px = u16::from_le_bytes([buf[i], buf[i + 1]]);
+            // scale each channel back up to 8 bits
+            let r = ((px >> 11) & 0b11111) as u32 * 255 / 0b11111;
+            let g = ((px >> 5) & 0b111111) as u32 * 255 / 0b111111;
+            let b = (px & 0b11111) as u32 * 255 / 0b11111;
+            image::Rgb([r as u8, g as u8, b as u8])
+        });
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        Ok(png)
     }
 
-    fn write(&mut self, img: DynamicImage) {
+    fn write(&mut self, img: DynamicImage) -> Result<(), FramebufferError> {
         let mut width = img.width();
         let mut height = img.height();
         let resized_img: DynamicImage;
@@ -67,45 +493,539 @@ impl Framebuffer<'_>{
         } else {
             resized_img = img;
         }
-        let img_rgba8 = resized_img.as_rgba8().unwrap();
+        let img_rgba8 = resized_img.to_rgba8();
         let mut buf = Vec::new();
         for y in 0..height {
             for x in 0..width {
                 let px = img_rgba8.get_pixel(x, y);
-                let mut rgb565: u16 = (px[0] as u16 & 0b11111000) << 8;
-                rgb565 |= (px[1] as u16 & 0b11111100) << 3;
-                rgb565 |= (px[2] as u16) >> 3;
+                let rgb565 = Color565::from_rgb(px[0], px[1], px[2]);
                 buf.extend(rgb565.to_le_bytes());
             }
         }
-        std::fs::write(self.path, &buf).unwrap();
+        self.write_buffer(&buf)
     }
 
-    pub fn draw_gif(&mut self, img_buffer: &[u8]) {
-        // this is dumb and i'm sure there's a better way to loop this
+    // Draws raw pixel data over the top of the back buffer, then flushes it.
+    // A short buffer only overwrites the top of the screen.
+    fn write_buffer(&mut self, buf: &[u8]) -> Result<(), FramebufferError> {
+        if self.back_buffer.is_empty() {
+            self.back_buffer = self.read_device();
+        }
+        let len = buf.len().min(self.back_buffer.len());
+        self.back_buffer[..len].copy_from_slice(&buf[..len]);
+        self.flush(&self.back_buffer)
+    }
+
+    // Reads whatever's on the device's screen, so that drawing over part of
+    // it leaves the rest alone. Starts out black if it can't be read.
+    fn read_device(&self) -> Vec<u8> {
+        let frame_len = self.frame_len();
+        let mut frame = match self.output {
+            Output::Device(path) => std::fs::read(path).unwrap_or_default(),
+            Output::Virtual => Vec::new(),
+        };
+        frame.resize(frame_len, 0);
+        let Dimensions { height, width } = self.dimensions;
+        let device_dimensions = if self.rotation.swaps_dimensions() {
+            Dimensions { height: width, width: height }
+        } else {
+            self.dimensions
+        };
+        rotate(&frame, device_dimensions, self.rotation.inverse())
+    }
+
+    // Writes a whole frame to the device, retrying a few times on errors which
+    // some framebuffer drivers return transiently
+    fn flush(&self, frame: &[u8]) -> Result<(), FramebufferError> {
+        assert_eq!(frame.len(), self.frame_len(), "can only flush whole frames");
+        let path = match self.output {
+            Output::Device(path) => path,
+            Output::Virtual => return Ok(()),
+        };
+        let rotated;
+        let frame = if self.rotation == Rotation::None {
+            frame
+        } else {
+            rotated = rotate(frame, self.dimensions, self.rotation);
+            &rotated
+        };
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match std::fs::write(path, frame) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempts < WRITE_ATTEMPTS && matches!(
+                    e.kind(),
+                    std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock
+                ) => std::thread::sleep(WRITE_RETRY_DELAY),
+                Err(e) => return Err(FramebufferError::WriteError(e)),
+            }
+        }
+    }
+
+    // Plays a GIF through once, or once it's been played gif_playback.loops
+    // times, just draws its last frame
+    pub fn draw_gif(&mut self, img_buffer: &[u8]) -> Result<(), FramebufferError> {
         let cursor = Cursor::new(img_buffer);
-        let decoder = GifDecoder::new(cursor).unwrap();
-        for maybe_frame in decoder.into_frames() {
-            let frame = maybe_frame.unwrap();
-            let (numerator, _) = frame.delay().numer_denom_ms();
-            let img = DynamicImage::from(frame.into_buffer());
-            self.write(img);
-            std::thread::sleep(Duration::from_millis(numerator as u64));
+        let decoder = GifDecoder::new(cursor)?;
+        let frames = decoder.into_frames().collect_frames()?;
+        let Some(last_frame) = frames.last() else {
+            return Err(FramebufferError::EmptyGif);
+        };
+        if self.gif_playback.loops.is_some_and(|loops| self.gif_plays >= loops) {
+            return self.write(DynamicImage::from(last_frame.buffer().clone()));
         }
+        for frame in frames {
+            if self.is_interrupted() {
+                return Ok(());
+            }
+            let delay = scale_frame_delay(frame.delay(), self.gif_playback.speed);
+            self.write(DynamicImage::from(frame.into_buffer()))?;
+            // there's no one watching a virtual framebuffer animate, so skip
+            // straight to the last frame
+            if matches!(self.output, Output::Device(_)) {
+                self.sleep_unless_interrupted(delay);
+            }
+        }
+        self.gif_plays = self.gif_plays.saturating_add(1);
+        Ok(())
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.interrupt.as_ref().is_some_and(|interrupt| interrupt.is_cancelled())
+    }
+
+    fn sleep_unless_interrupted(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while !self.is_interrupted() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            std::thread::sleep(remaining.min(INTERRUPT_POLL_INTERVAL));
+        }
+    }
+
+    pub fn draw_img(&mut self, img_buffer: &[u8]) -> Result<(), FramebufferError> {
+        let img = image::load_from_memory(img_buffer)?;
+        self.write(img)
     }
 
-    pub fn draw_img(&mut self, img_buffer: &[u8]) {
-        let img = image::load_from_memory(img_buffer).unwrap();
-        self.write(img);
+    // Draws text in the middle of an otherwise black screen, in big enough
+    // blocky letters to read at a glance. Only the letters with a glyph are
+    // supported, anything else is drawn as a space.
+    pub fn draw_text(&mut self, text: &str, color: impl Into<u16>) -> Result<(), FramebufferError> {
+        let mut buffer = vec![0; self.frame_len()];
+        let top = self.dimensions.height.saturating_sub(GLYPH_HEIGHT * GLYPH_SCALE) / 2;
+        self.render_text(&mut buffer, text, color.into(), top, GLYPH_SCALE);
+        self.write_buffer(&buffer)
     }
 
-    pub fn draw_line(&mut self, color: Color565, height: u32){
-        let px_num= height * self.dimensions.width;
-        let color: u16 = color as u16;
+    // Draws a test card for checking a screen's geometry on new hardware: a
+    // border around the very edge of the screen, every glyph we can draw, and
+    // the dimensions we think the screen has
+    pub fn draw_test_card(&mut self, color: impl Into<u16>) -> Result<(), FramebufferError> {
+        let color: u16 = color.into();
+        let Dimensions { height, width } = self.dimensions;
+        let mut buffer = vec![0; self.frame_len()];
+        for y in 0..height {
+            for x in 0..width {
+                if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+                    let px = ((y * width + x) * 2) as usize;
+                    buffer[px..px + 2].copy_from_slice(&color.to_le_bytes());
+                }
+            }
+        }
+        self.render_text(&mut buffer, GLYPH_CHARS, color, height / 4, 1);
+        let dimensions_text = format!("{}X{}", width, height);
+        self.render_text(&mut buffer, &dimensions_text, color, height / 2, 2);
+        self.write_buffer(&buffer)
+    }
+
+    // Renders a line of text into a frame, horizontally centered and with its
+    // top at the given row, with each glyph pixel drawn as a scale pixel square
+    fn render_text(&self, buffer: &mut [u8], text: &str, color: u16, top: u32, scale: u32) {
+        let Dimensions { height, width } = self.dimensions;
+        let num_chars = text.chars().count() as u32;
+        let text_width = (num_chars * (GLYPH_WIDTH + 1)).saturating_sub(1) * scale;
+        let left = width.saturating_sub(text_width) / 2;
+        for (i, c) in text.chars().enumerate() {
+            for (row, bits) in glyph(c).into_iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                        continue;
+                    }
+                    let glyph_x = left + (i as u32 * (GLYPH_WIDTH + 1) + col) * scale;
+                    let glyph_y = top + row as u32 * scale;
+                    for y in glyph_y..(glyph_y + scale).min(height) {
+                        for x in glyph_x..(glyph_x + scale).min(width) {
+                            let px = ((y * width + x) * 2) as usize;
+                            buffer[px..px + 2].copy_from_slice(&color.to_le_bytes());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Draws text as a QR code filling as much of the screen as it can, black
+    // on white since not every scanner copes with inverted codes. The modules
+    // are scaled up by a whole number of pixels so they stay crisp.
+    pub fn draw_qr(&mut self, text: &str) -> Result<(), FramebufferError> {
+        let code = qrcode::QrCode::new(text.as_bytes())?;
+        let modules = code.width() as u32;
+        let Dimensions { height, width } = self.dimensions;
+        let scale = (height.min(width) / (modules + 2 * QR_QUIET_ZONE)).max(1);
+        let left = width.saturating_sub(modules * scale) / 2;
+        let top = height.saturating_sub(modules * scale) / 2;
+        let white = (Color565::White as u16).to_le_bytes();
+        let black = (Color565::Black as u16).to_le_bytes();
+        let mut buffer = white.repeat((height * width) as usize);
+        for (i, module) in code.to_colors().into_iter().enumerate() {
+            if module == qrcode::Color::Light {
+                continue;
+            }
+            let module_x = left + (i as u32 % modules) * scale;
+            let module_y = top + (i as u32 / modules) * scale;
+            for y in module_y..(module_y + scale).min(height) {
+                for x in module_x..(module_x + scale).min(width) {
+                    let px = ((y * width + x) * 2) as usize;
+                    buffer[px..px + 2].copy_from_slice(&black);
+                }
+            }
+        }
+        self.write_buffer(&buffer)
+    }
+
+    // Accepts either a named Color565 or an RGB565-packed u16 (e.g. from
+    // Color565::from_rgb)
+    pub fn draw_line(&mut self, color: impl Into<u16>, height: u32) -> Result<(), FramebufferError> {
+        self.draw_patterned_line(color, height, LinePattern::Solid)
+    }
+
+    pub fn draw_patterned_line(&mut self, color: impl Into<u16>, height: u32, pattern: LinePattern) -> Result<(), FramebufferError> {
+        let width = self.dimensions.width;
+        let color: u16 = color.into();
         let mut buffer: Vec<u8> = Vec::new();
-        for _ in 0..px_num {
-            buffer.extend(color.to_le_bytes());
+        for _ in 0..height {
+            for x in 0..width {
+                let px = if pattern.is_drawn(x) { color } else { Color565::Black as u16 };
+                buffer.extend(px.to_le_bytes());
+            }
         }
-        std::fs::write(self.path, &buffer).unwrap();
+        self.write_buffer(&buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rgb_to_565_packing() {
+        assert_eq!(Color565::from_rgb(255, 0, 0), Color565::Red as u16);
+        assert_eq!(Color565::from_rgb(0, 255, 0), Color565::Green as u16);
+        assert_eq!(Color565::from_rgb(0, 0, 255), Color565::Blue as u16);
+        assert_eq!(Color565::from_rgb(255, 255, 255), Color565::White as u16);
+        assert_eq!(Color565::from_rgb(0, 0, 0), Color565::Black as u16);
+        // the low bits of each channel get truncated
+        assert_eq!(Color565::from_rgb(0x80, 0x80, 0x80), 0b1000010000010000);
+        assert_eq!(Color565::from_rgb(0x87, 0x83, 0x87), 0b1000010000010000);
+    }
+
+    #[test]
+    fn test_write_buffer() {
+        let dir = tempfile::Builder::new().prefix("framebuffer_test").tempdir().unwrap();
+        let path = dir.path().join("fb0");
+        let mut fb = Framebuffer::new(path.to_str().unwrap());
+        // whatever was already on screen is left alone
+        std::fs::write(&path, (Color565::Blue as u16).to_le_bytes().repeat(128 * 128)).unwrap();
+        fb.draw_line(Color565::Red, 1).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len(), 128 * 128 * 2);
+        assert_eq!(&written[..256], (Color565::Red as u16).to_le_bytes().repeat(128).as_slice());
+        assert_eq!(&written[256..], (Color565::Blue as u16).to_le_bytes().repeat(128 * 127).as_slice());
+
+        // a later partial write is drawn over the previous frame, not the
+        // device's original contents
+        std::fs::write(&path, b"").unwrap();
+        fb.draw_line(Color565::Green, 1).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(&written[..256], (Color565::Green as u16).to_le_bytes().repeat(128).as_slice());
+        assert_eq!(&written[256..], (Color565::Blue as u16).to_le_bytes().repeat(128 * 127).as_slice());
+
+        // a persistent failure is returned rather than panicking
+        let missing_path = dir.path().join("missing").join("fb0");
+        fb.output = Output::Device(missing_path.to_str().unwrap());
+        assert!(matches!(fb.draw_line(Color565::Red, 1), Err(FramebufferError::WriteError(_))));
+        assert!(matches!(fb.draw_ui(1, DisplayState::Recording, false), Err(FramebufferError::WriteError(_))));
+        assert!(matches!(fb.draw_img(&[]), Err(FramebufferError::ImageDecodeError(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_rotate() {
+        // a 3x2 frame where each pixel's value is its index:
+        //   0 1 2
+        //   3 4 5
+        let buf: Vec<u8> = (0..6u16).flat_map(|px| px.to_le_bytes()).collect();
+        let dimensions = Dimensions { height: 2, width: 3 };
+        let pixels = |rotated: Vec<u8>| -> Vec<u16> {
+            rotated.chunks(2).map(|px| u16::from_le_bytes([px[0], px[1]])).collect()
+        };
+        assert_eq!(pixels(rotate(&buf, dimensions, Rotation::None)), vec![0, 1, 2, 3, 4, 5]);
+        // 3 0
+        // 4 1
+        // 5 2
+        assert_eq!(pixels(rotate(&buf, dimensions, Rotation::Clockwise90)), vec![3, 0, 4, 1, 5, 2]);
+        assert_eq!(pixels(rotate(&buf, dimensions, Rotation::Clockwise180)), vec![5, 4, 3, 2, 1, 0]);
+        // 2 5
+        // 1 4
+        // 0 3
+        assert_eq!(pixels(rotate(&buf, dimensions, Rotation::Clockwise270)), vec![2, 5, 1, 4, 0, 3]);
+
+        assert_eq!(Rotation::try_from(270), Ok(Rotation::Clockwise270));
+        assert!(Rotation::try_from(45).is_err());
+    }
+
+    #[test]
+    fn test_rotated_write() {
+        let dir = tempfile::Builder::new().prefix("framebuffer_test").tempdir().unwrap();
+        let path = dir.path().join("fb0");
+        let mut fb = Framebuffer::new(path.to_str().unwrap()).with_rotation(Rotation::Clockwise180);
+        fb.draw_line(Color565::Red, 1).unwrap();
+        // the top line ends up at the bottom of a whole frame
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len(), 128 * 128 * 2);
+        assert_eq!(&written[..written.len() - 256], vec![0; 128 * 127 * 2].as_slice());
+        assert_eq!(&written[written.len() - 256..], (Color565::Red as u16).to_le_bytes().repeat(128).as_slice());
+    }
+
+    #[test]
+    fn test_display_state_colors() {
+        let warning = DisplayState::WarningDetected(Severity::High);
+        assert_eq!(DisplayState::Recording.to_color(false), Color565::Green);
+        assert_eq!(warning.to_color(false), Color565::Red);
+        assert_eq!(DisplayState::Paused.to_color(false), Color565::White);
+
+        assert_eq!(DisplayState::Recording.to_color(true), Color565::Blue);
+        assert_eq!(warning.to_color(true), Color565::Yellow);
+        assert_eq!(DisplayState::Paused.to_color(true), Color565::White);
+
+        // there's nothing for colorblind mode to tell apart here
+        assert_eq!(DisplayState::NoQmdlData.to_color(false), Color565::Gray);
+        assert_eq!(DisplayState::NoQmdlData.to_color(true), Color565::Gray);
+        assert_eq!(Color565::Gray as u16, Color565::from_rgb(0x80, 0x80, 0x80));
+    }
+
+    #[test]
+    fn test_line_patterns() {
+        let states = [
+            DisplayState::Recording,
+            DisplayState::Paused,
+            DisplayState::WarningDetected(Severity::Low),
+            DisplayState::Reconnecting,
+            DisplayState::NoQmdlData,
+        ];
+        for state in states {
+            assert_eq!(state.to_line_pattern(false), LinePattern::Solid);
+        }
+        let patterns: Vec<LinePattern> = states.iter().map(|state| state.to_line_pattern(true)).collect();
+        assert_eq!(patterns, vec![
+            LinePattern::Solid,
+            LinePattern::Solid,
+            LinePattern::Dashed,
+            LinePattern::Dotted,
+            LinePattern::Solid,
+        ]);
+
+        let mut fb = Framebuffer::new_virtual();
+        fb.draw_ui(1, DisplayState::WarningDetected(Severity::High), true).unwrap();
+        let png = fb.to_png().unwrap();
+        let img = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap().to_rgb8();
+        let yellow = image::Rgb([255, 255, 0]);
+        let black = image::Rgb([0, 0, 0]);
+        assert_eq!(img.get_pixel(0, 0), &yellow);
+        assert_eq!(img.get_pixel(12, 1), &black);
+        assert_eq!(img.get_pixel(16, 1), &yellow);
+    }
+
+    #[test]
+    fn test_display_state_tracker_timeout() {
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+        let mut tracker = DisplayStateTracker::new(DisplayState::Recording, Some(Duration::from_secs(10)));
+        assert_eq!(tracker.current(start), DisplayState::Recording);
+
+        tracker.update(DisplayState::WarningDetected(Severity::Medium), start);
+        assert_eq!(tracker.current(secs(5)), DisplayState::WarningDetected(Severity::Medium));
+
+        // other updates wait for the warning to expire
+        tracker.update(DisplayState::Paused, secs(6));
+        assert_eq!(tracker.current(secs(6)), DisplayState::WarningDetected(Severity::Medium));
+
+        // a new warning restarts the timer, but a less severe one doesn't
+        tracker.update(DisplayState::WarningDetected(Severity::Low), secs(7));
+        tracker.update(DisplayState::WarningDetected(Severity::Medium), secs(8));
+        assert_eq!(tracker.current(secs(15)), DisplayState::WarningDetected(Severity::Medium));
+        tracker.update(DisplayState::WarningDetected(Severity::High), secs(16));
+        assert_eq!(tracker.current(secs(25)), DisplayState::WarningDetected(Severity::High));
+
+        assert_eq!(tracker.current(secs(26)), DisplayState::Paused);
+
+        // once expired, any warning is shown again
+        tracker.update(DisplayState::WarningDetected(Severity::Low), secs(30));
+        assert_eq!(tracker.current(secs(30)), DisplayState::WarningDetected(Severity::Low));
+    }
+
+    #[test]
+    fn test_display_state_tracker_without_timeout() {
+        let start = Instant::now();
+        let mut tracker = DisplayStateTracker::new(DisplayState::Recording, None);
+        tracker.update(DisplayState::WarningDetected(Severity::Low), start);
+        assert_eq!(tracker.current(start + Duration::from_secs(3600)), DisplayState::WarningDetected(Severity::Low));
+        tracker.update(DisplayState::Paused, start + Duration::from_secs(3600));
+        assert_eq!(tracker.current(start + Duration::from_secs(3600)), DisplayState::Paused);
+    }
+
+    #[test]
+    fn test_display_state_tracker_acknowledge() {
+        let start = Instant::now();
+        let mut tracker = DisplayStateTracker::new(DisplayState::Recording, Some(Duration::from_secs(60)));
+        tracker.update(DisplayState::WarningDetected(Severity::High), start);
+        tracker.acknowledge_warning();
+        assert_eq!(tracker.current(start + Duration::from_secs(1)), DisplayState::Recording);
+        // later warnings still show up
+        tracker.update(DisplayState::WarningDetected(Severity::Low), start + Duration::from_secs(2));
+        assert_eq!(tracker.current(start + Duration::from_secs(2)), DisplayState::WarningDetected(Severity::Low));
+    }
+
+    #[test]
+    fn test_virtual_framebuffer_png() {
+        let mut fb = Framebuffer::new_virtual();
+        fb.draw_ui(1, DisplayState::WarningDetected(Severity::High), false).unwrap();
+        let png = fb.to_png().unwrap();
+        let img = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap().to_rgb8();
+        assert_eq!(img.dimensions(), (128, 128));
+        assert_eq!(img.get_pixel(0, 0), &image::Rgb([255, 0, 0]));
+        assert_eq!(img.get_pixel(0, 2), &image::Rgb([0, 0, 0]));
+
+        assert!(matches!(Framebuffer::new("/dev/fb0").to_png(), Err(FramebufferError::NotVirtual)));
+    }
+
+    #[test]
+    fn test_no_data_screen() {
+        let mut fb = Framebuffer::new_virtual();
+        fb.draw_ui(2, DisplayState::NoQmdlData, false).unwrap();
+        let png = fb.to_png().unwrap();
+        let img = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap().to_rgb8();
+        let gray = image::Rgb([131, 129, 131]);
+        let num_gray = img.pixels().filter(|px| **px == gray).count();
+        let num_black = img.pixels().filter(|px| **px == image::Rgb([0, 0, 0])).count();
+        assert!(num_gray > 0);
+        assert_eq!(num_gray + num_black, 128 * 128);
+        // the text is centered, leaving the edges clear
+        assert_eq!(img.get_pixel(0, 0), &image::Rgb([0, 0, 0]));
+        assert_eq!(img.get_pixel(127, 127), &image::Rgb([0, 0, 0]));
+        // the N's top left corner
+        let text_width = (7 * 6 - 1) * GLYPH_SCALE;
+        let (left, top) = ((128 - text_width) / 2, (128 - 7 * GLYPH_SCALE) / 2);
+        assert_eq!(img.get_pixel(left, top), &gray);
+
+        // the status line is still just a line
+        fb.draw_ui(1, DisplayState::NoQmdlData, false).unwrap();
+    }
+
+    #[test]
+    fn test_draw_test_card() {
+        for c in GLYPH_CHARS.chars() {
+            assert_ne!(glyph(c), [0; GLYPH_HEIGHT as usize], "no glyph for {c}");
+        }
+
+        let mut fb = Framebuffer::new_virtual();
+        fb.draw_test_card(Color565::White).unwrap();
+        let png = fb.to_png().unwrap();
+        let img = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap().to_rgb8();
+        let white = image::Rgb([255, 255, 255]);
+        let black = image::Rgb([0, 0, 0]);
+        for (x, y) in [(0, 0), (127, 0), (0, 127), (127, 127), (64, 0), (0, 64)] {
+            assert_eq!(img.get_pixel(x, y), &white, "border missing at {x},{y}");
+        }
+        assert_eq!(img.get_pixel(1, 1), &black);
+        assert_eq!(img.get_pixel(126, 126), &black);
+        // the top of the first 1 in "128X128", drawn at twice the size
+        let text_width = (7 * 6 - 1) * 2;
+        let left = (128 - text_width) / 2;
+        assert_eq!(img.get_pixel(left + 2 * 2, 64), &white);
+        assert_eq!(img.get_pixel(left, 64), &black);
+    }
+
+    #[test]
+    fn test_draw_qr() {
+        let url = "http://192.168.1.1:8080";
+        let mut fb = Framebuffer::new_virtual();
+        fb.draw_qr(url).unwrap();
+        let png = fb.to_png().unwrap();
+        let img = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap().to_luma8();
+        // the quiet zone leaves the corners white
+        assert_eq!(img.get_pixel(0, 0), &image::Luma([255]));
+        assert_eq!(img.get_pixel(127, 127), &image::Luma([255]));
+
+        let mut prepared = rqrr::PreparedImage::prepare(img);
+        let grids = prepared.detect_grids();
+        assert_eq!(grids.len(), 1);
+        let (_, content) = grids[0].decode().unwrap();
+        assert_eq!(content, url);
+    }
+
+    #[test]
+    fn test_draw_invalid_gif() {
+        let mut fb = Framebuffer::new_virtual();
+        assert!(matches!(fb.draw_gif(&[]), Err(FramebufferError::ImageDecodeError(_))));
+        assert!(matches!(fb.draw_gif(b"not a gif at all"), Err(FramebufferError::ImageDecodeError(_))));
+    }
+
+    #[test]
+    fn test_scale_frame_delay() {
+        let delay = Delay::from_numer_denom_ms(100, 1);
+        assert_eq!(scale_frame_delay(delay, 1.0), Duration::from_millis(100));
+        assert_eq!(scale_frame_delay(delay, 2.0), Duration::from_millis(50));
+        assert_eq!(scale_frame_delay(delay, 0.5), Duration::from_millis(200));
+        // nonsense speeds are ignored
+        assert_eq!(scale_frame_delay(delay, 0.0), Duration::from_millis(100));
+        assert_eq!(scale_frame_delay(delay, -1.0), Duration::from_millis(100));
+        assert_eq!(scale_frame_delay(delay, f32::NAN), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_gif_loops() {
+        let gif = IMAGE_DIR.get_file("orca.gif").unwrap().contents();
+        let mut fb = Framebuffer::new_virtual().with_gif_playback(GifPlayback { loops: Some(1), speed: 1.0 });
+        fb.draw_gif(gif).unwrap();
+        assert_eq!(fb.gif_plays, 1);
+        let played = fb.to_png().unwrap();
+        // once it's done looping, the last frame stays up
+        fb.draw_gif(gif).unwrap();
+        assert_eq!(fb.gif_plays, 1);
+        assert_eq!(fb.to_png().unwrap(), played);
+
+        // an interrupted GIF isn't counted as played
+        let interrupt = CancellationToken::new();
+        interrupt.cancel();
+        let mut fb = Framebuffer::new_virtual().with_interrupt(interrupt);
+        fb.draw_gif(gif).unwrap();
+        assert_eq!(fb.gif_plays, 0);
+    }
+
+    #[test]
+    fn test_draw_gif_without_frames() {
+        let mut fb = Framebuffer::new_virtual();
+        // a well-formed 1x1 GIF with a two-color palette but no image blocks
+        let empty_gif = [
+            b'G', b'I', b'F', b'8', b'9', b'a',
+            0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0xff, 0xff, 0xff,
+            0x3b,
+        ];
+        assert!(fb.draw_gif(&empty_gif).is_err());
+    }
+}
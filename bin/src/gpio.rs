@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use rayhunter::analysis::analyzer::Severity;
+use tokio::fs;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::sleep;
+use tokio_util::task::TaskTracker;
+
+const GPIO_SYSFS_PATH: &str = "/sys/class/gpio";
+const BLINK_INTERVAL: Duration = Duration::from_millis(250);
+
+pub enum WarningLedMessage {
+    Warning(Severity),
+    Clear,
+}
+
+// Drives an LED wired to a GPIO pin via the sysfs GPIO interface. If no pin is
+// configured, or the pin can't be set up, this is a no-op so callers don't have
+// to care whether the hardware is actually present.
+pub struct WarningLed {
+    value_path: Option<PathBuf>,
+}
+
+impl WarningLed {
+    pub async fn new(maybe_pin: Option<u32>) -> Self {
+        let Some(pin) = maybe_pin else {
+            return WarningLed { value_path: None };
+        };
+        match export_output_pin(pin).await {
+            Ok(value_path) => {
+                info!("driving warning LED on GPIO pin {}", pin);
+                WarningLed { value_path: Some(value_path) }
+            },
+            Err(err) => {
+                warn!("couldn't set up GPIO pin {} for warning LED, disabling it: {}", pin, err);
+                WarningLed { value_path: None }
+            },
+        }
+    }
+
+    pub async fn set(&self, on: bool) {
+        let Some(value_path) = &self.value_path else {
+            return;
+        };
+        let value = if on { "1" } else { "0" };
+        if let Err(err) = fs::write(value_path, value).await {
+            error!("failed to write GPIO value {:?}: {}", value_path, err);
+        }
+    }
+
+    // Blinks the LED once per severity level (so a High warning blinks three
+    // times), then leaves it lit
+    pub async fn blink_for_severity(&self, severity: Severity) {
+        let blinks = match severity {
+            Severity::Low => 1,
+            Severity::Medium => 2,
+            Severity::High => 3,
+        };
+        for _ in 0..blinks {
+            self.set(false).await;
+            sleep(BLINK_INTERVAL).await;
+            self.set(true).await;
+            sleep(BLINK_INTERVAL).await;
+        }
+    }
+}
+
+async fn export_output_pin(pin: u32) -> Result<PathBuf, std::io::Error> {
+    let gpio_path = PathBuf::from(GPIO_SYSFS_PATH);
+    let pin_path = gpio_path.join(format!("gpio{}", pin));
+    if !fs::try_exists(&pin_path).await? {
+        fs::write(gpio_path.join("export"), pin.to_string()).await?;
+    }
+    fs::write(pin_path.join("direction"), "out").await?;
+    Ok(pin_path.join("value"))
+}
+
+pub fn run_warning_led_thread(
+    task_tracker: &TaskTracker,
+    maybe_pin: Option<u32>,
+    mut led_rx: Receiver<WarningLedMessage>,
+) {
+    task_tracker.spawn(async move {
+        let led = WarningLed::new(maybe_pin).await;
+        led.set(false).await;
+        // None means all the Senders have been dropped, so it's time to go
+        while let Some(msg) = led_rx.recv().await {
+            match msg {
+                WarningLedMessage::Warning(severity) => led.blink_for_severity(severity).await,
+                WarningLedMessage::Clear => led.set(false).await,
+            }
+        }
+        led.set(false).await;
+    });
+}
@@ -0,0 +1,161 @@
+use std::sync::{Arc, Mutex};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use log::debug;
+use rayhunter::analysis::analyzer::Severity;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::framebuffer::DisplayState;
+use crate::qmdl_store::ManifestEntry;
+use crate::server::ServerState;
+
+// How many updates a client can fall behind by before it starts missing them.
+// Entry updates come at most every STORE_UPDATE_INTERVAL, so this is plenty
+// for anything but a stalled connection.
+const LIVE_UPDATE_CAPACITY: usize = 64;
+
+// An update pushed to clients of /api/live-ws, each sent as a JSON text message
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveUpdate {
+    // what the device's screen is currently showing
+    DisplayState {
+        #[serde(flatten)]
+        state: DisplayState,
+    },
+    // a heuristic triggered on the current recording
+    Warning {
+        severity: Severity,
+        warning_count: usize,
+    },
+    // the current recording started, or its sizes or warning count changed
+    Entry {
+        entry: Box<ManifestEntry>,
+    },
+}
+
+// Fans live updates out to every connected client, and remembers the latest
+// display state so clients can be caught up whenever they (re)connect
+#[derive(Clone)]
+pub struct LiveUpdateSender {
+    sender: broadcast::Sender<LiveUpdate>,
+    display_state: Arc<Mutex<DisplayState>>,
+}
+
+impl LiveUpdateSender {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(LIVE_UPDATE_CAPACITY);
+        LiveUpdateSender {
+            sender,
+            display_state: Arc::new(Mutex::new(DisplayState::Recording)),
+        }
+    }
+
+    // Sends the update to every connected client, if there are any
+    pub fn send(&self, update: LiveUpdate) {
+        if let LiveUpdate::DisplayState { state } = update {
+            *self.display_state.lock().unwrap() = state;
+        }
+        // this only fails if nobody's listening, which is fine
+        let _ = self.sender.send(update);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<LiveUpdate> {
+        self.sender.subscribe()
+    }
+
+    fn display_state(&self) -> DisplayState {
+        *self.display_state.lock().unwrap()
+    }
+}
+
+impl Default for LiveUpdateSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn get_live_ws(State(state): State<Arc<ServerState>>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| serve_live_ws(socket, state))
+}
+
+// The updates which bring a client up to date: the current display state, and
+// the current recording if there is one
+async fn current_state(state: &ServerState) -> Vec<LiveUpdate> {
+    let mut updates = vec![LiveUpdate::DisplayState { state: state.live_update_sender.display_state() }];
+    if let Some((_, entry)) = state.qmdl_store_lock.read().await.get_current_entry() {
+        updates.push(LiveUpdate::Entry { entry: Box::new(entry.clone()) });
+    }
+    updates
+}
+
+async fn send_update(socket: &mut WebSocket, update: &LiveUpdate) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(update).map_err(axum::Error::new)?;
+    socket.send(Message::Text(json)).await
+}
+
+// Sends the client the current state, then every update after it until the
+// client goes away. If the client falls too far behind, it's sent the current
+// state again rather than a partial backlog.
+async fn serve_live_ws(mut socket: WebSocket, state: Arc<ServerState>) {
+    // subscribe before reading the current state, so no update can slip in
+    // between the two
+    let mut updates = state.live_update_sender.subscribe();
+    loop {
+        for update in current_state(&state).await {
+            if send_update(&mut socket, &update).await.is_err() {
+                return;
+            }
+        }
+        loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(update) => {
+                        if send_update(&mut socket, &update).await.is_err() {
+                            return;
+                        }
+                    },
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("live client fell {} updates behind, resending current state", skipped);
+                        break;
+                    },
+                    Err(RecvError::Closed) => return,
+                },
+                // we don't expect anything from the client, but need to
+                // notice when it disconnects
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => {},
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_live_update_json() {
+        let update = LiveUpdate::DisplayState { state: DisplayState::WarningDetected(Severity::High) };
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            serde_json::json!({"type": "display_state", "state": "warning_detected", "severity": "High"}),
+        );
+        let update = LiveUpdate::DisplayState { state: DisplayState::Paused };
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            serde_json::json!({"type": "display_state", "state": "paused"}),
+        );
+        let update = LiveUpdate::Warning { severity: Severity::Low, warning_count: 2 };
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            serde_json::json!({"type": "warning", "severity": "Low", "warning_count": 2}),
+        );
+    }
+}
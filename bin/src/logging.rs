@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Local};
+use log::{Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+
+use crate::server::ServerState;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // env_logger's usual human-readable lines
+    #[default]
+    Text,
+    // one JSON object per line, for shipping logs off to be collected
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LogLine {
+    timestamp: String,
+    level: String,
+    target: String,
+    message: String,
+}
+
+impl LogLine {
+    fn new(record: &Record, timestamp: DateTime<Local>) -> Self {
+        LogLine {
+            timestamp: timestamp.to_rfc3339(),
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        }
+    }
+
+    fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Text => format!("{} {:<5} {}: {}", self.timestamp, self.level, self.target, self.message),
+            // serializing a struct of strings can't fail
+            LogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+        }
+    }
+}
+
+fn format_json(record: &Record, timestamp: DateTime<Local>) -> String {
+    LogLine::new(record, timestamp).format(LogFormat::Json)
+}
+
+// Keeps the most recent log lines in memory, so they can be fetched over the
+// API without a shell on the device. Cloning it gives another handle to the
+// same lines.
+#[derive(Clone)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<LogLine>>>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        LogBuffer {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    fn push(&self, line: LogLine) {
+        if self.capacity == 0 {
+            return;
+        }
+        // a panic while logging can't leave the lines half-updated, so a
+        // poisoned lock is still fine to use
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    // The buffered lines, oldest first
+    pub fn lines(&self) -> Vec<LogLine> {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
+}
+
+// Logs everything through env_logger as usual, and also keeps whatever it
+// logged in a LogBuffer
+pub struct BufferedLogger {
+    inner: env_logger::Logger,
+    buffer: LogBuffer,
+}
+
+impl BufferedLogger {
+    pub fn new(inner: env_logger::Logger, buffer: LogBuffer) -> Self {
+        BufferedLogger { inner, buffer }
+    }
+}
+
+impl Log for BufferedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.matches(record) {
+            self.buffer.push(LogLine::new(record, Local::now()));
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Sets up logging in the given format. Either way, which messages get logged
+// is still controlled by RUST_LOG. If log_buffer_lines is set, that many of
+// the latest lines are also kept in the returned LogBuffer.
+pub fn init_logging(format: LogFormat, log_buffer_lines: Option<usize>) -> Option<LogBuffer> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", format_json(record, Local::now())));
+    }
+    let logger = builder.build();
+    let max_level = logger.filter();
+    let log_buffer = log_buffer_lines.map(LogBuffer::new);
+    let result = match &log_buffer {
+        Some(log_buffer) => log::set_boxed_logger(Box::new(BufferedLogger::new(logger, log_buffer.clone()))),
+        None => log::set_boxed_logger(Box::new(logger)),
+    };
+    result.expect("logging was already initialized");
+    log::set_max_level(max_level);
+    log_buffer
+}
+
+#[derive(Deserialize)]
+pub struct LogsParams {
+    #[serde(default)]
+    format: LogFormat,
+}
+
+// Returns the buffered log lines, oldest first, either as text or as one JSON
+// object per line
+pub async fn get_logs(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<LogsParams>,
+) -> Result<Response, (StatusCode, String)> {
+    let Some(log_buffer) = &state.log_buffer else {
+        return Err((StatusCode::NOT_FOUND, "log buffer is disabled, set log_buffer_lines to enable it".to_string()));
+    };
+    let mut body = String::new();
+    for line in log_buffer.lines() {
+        body.push_str(&line.format(params.format));
+        body.push('\n');
+    }
+    let content_type = match params.format {
+        LogFormat::Text => "text/plain; charset=utf-8",
+        LogFormat::Json => "application/x-ndjson",
+    };
+    Ok(([(CONTENT_TYPE, content_type)], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_json() {
+        let timestamp = DateTime::parse_from_rfc3339("2024-01-01T12:00:00+00:00").unwrap().with_timezone(&Local);
+        let line = format_json(
+            &Record::builder()
+                .args(format_args!("couldn't reach \"{}\"\n", "the modem"))
+                .level(log::Level::Warn)
+                .target("rayhunter_daemon::diag")
+                .build(),
+            timestamp,
+        );
+        // quotes and newlines are escaped, so each message stays on one line
+        assert_eq!(line.lines().count(), 1);
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["level"], "WARN");
+        assert_eq!(parsed["target"], "rayhunter_daemon::diag");
+        assert_eq!(parsed["message"], "couldn't reach \"the modem\"\n");
+        let parsed_timestamp = DateTime::parse_from_rfc3339(parsed["timestamp"].as_str().unwrap()).unwrap();
+        assert_eq!(parsed_timestamp, timestamp);
+    }
+
+    #[test]
+    fn test_log_buffer_keeps_latest_lines() {
+        let buffer = LogBuffer::new(2);
+        let logger = BufferedLogger::new(
+            env_logger::Builder::new().filter_level(log::LevelFilter::Info).build(),
+            buffer.clone(),
+        );
+        for message in ["first", "second", "third"] {
+            logger.log(&Record::builder().args(format_args!("{}", message)).level(log::Level::Info).build());
+        }
+        // filtered out lines aren't kept either
+        logger.log(&Record::builder().args(format_args!("hidden")).level(log::Level::Debug).build());
+        let messages: Vec<String> = buffer.lines().into_iter().map(|line| line.message).collect();
+        assert_eq!(messages, vec!["second", "third"]);
+
+        let disabled = LogBuffer::new(0);
+        disabled.push(LogLine::new(&Record::builder().args(format_args!("dropped")).build(), Local::now()));
+        assert!(disabled.lines().is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt::{Display, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+
+use crate::analysis::AnalysisStatus;
+use crate::qmdl_store::RecordingStore;
+use crate::server::ServerState;
+use crate::stats::DiagLoopStats;
+
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Builds up metrics in the Prometheus text exposition format
+#[derive(Default)]
+struct MetricsWriter {
+    output: String,
+}
+
+impl MetricsWriter {
+    fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.metric(name, "gauge", help, value);
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: impl Display) {
+        self.metric(name, "counter", help, value);
+    }
+
+    fn metric(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        // writing to a String can't fail
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.output, "{} {}", name, value);
+    }
+}
+
+fn render_metrics(
+    qmdl_store: &RecordingStore,
+    analysis_status: &AnalysisStatus,
+    diag_loop_stats: &DiagLoopStats,
+    uptime: Duration,
+) -> String {
+    let entries = &qmdl_store.manifest.entries;
+    let mut metrics = MetricsWriter::default();
+    metrics.gauge("rayhunter_uptime_seconds", "Seconds since the daemon started", uptime.as_secs());
+    metrics.gauge("rayhunter_recording_active", "Whether a recording is in progress", qmdl_store.current_entry.is_some() as u8);
+    metrics.gauge("rayhunter_recordings", "Number of recordings in the store", entries.len());
+    metrics.gauge(
+        "rayhunter_qmdl_size_bytes",
+        "Total size of every recording's QMDL file",
+        entries.iter().map(|entry| entry.qmdl_size_bytes as u64).sum::<u64>(),
+    );
+    metrics.gauge(
+        "rayhunter_analysis_size_bytes",
+        "Total size of every recording's analysis file",
+        entries.iter().map(|entry| entry.analysis_size_bytes as u64).sum::<u64>(),
+    );
+    metrics.gauge(
+        "rayhunter_current_qmdl_size_bytes",
+        "Size of the current recording's QMDL file, or 0 if not recording",
+        qmdl_store.get_current_entry().map_or(0, |(_, entry)| entry.qmdl_size_bytes),
+    );
+    metrics.counter(
+        "rayhunter_analysis_warnings_total",
+        "Number of warnings raised across every recording in the store",
+        entries.iter().map(|entry| entry.warning_count as u64).sum::<u64>(),
+    );
+    metrics.gauge("rayhunter_analysis_pending", "Number of recordings queued for or undergoing analysis", analysis_status.num_pending());
+    metrics.counter("rayhunter_diag_containers_read_total", "Diag containers read from the modem", diag_loop_stats.containers_read);
+    metrics.counter("rayhunter_diag_containers_processed_total", "Diag containers written and analyzed", diag_loop_stats.containers_processed);
+    metrics.counter("rayhunter_diag_containers_skipped_total", "Diag containers skipped for not being user space messages", diag_loop_stats.containers_skipped);
+    metrics.gauge("rayhunter_diag_max_processing_time_ms", "The longest any diag container has taken to process", diag_loop_stats.max_processing_time_ms);
+    metrics.output
+}
+
+pub async fn get_metrics(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let qmdl_store = state.qmdl_store_lock.read().await;
+    let analysis_status = state.analysis_status_lock.read().await;
+    let diag_loop_stats = state.diag_loop_stats_lock.read().await;
+    let metrics = render_metrics(&qmdl_store, &analysis_status, &diag_loop_stats, state.started_at.elapsed());
+    ([(CONTENT_TYPE, METRICS_CONTENT_TYPE)], metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render_metrics() {
+        let dir = tempfile::Builder::new().prefix("metrics_test").tempdir().unwrap();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let entry_index = store.current_entry.unwrap();
+        store.update_entry_qmdl_size(entry_index, 1234).await.unwrap();
+        store.update_entry_analysis(entry_index, 10, 3).await.unwrap();
+        let diag_loop_stats = DiagLoopStats { containers_read: 5, ..Default::default() };
+
+        let output = render_metrics(&store, &AnalysisStatus::default(), &diag_loop_stats, Duration::from_secs(42));
+        let mut values = Vec::new();
+        for line in output.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                assert!(comment.starts_with("HELP rayhunter_") || comment.starts_with("TYPE rayhunter_"), "{}", line);
+                continue;
+            }
+            let (name, value) = line.split_once(' ').unwrap();
+            assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "{}", line);
+            values.push((name, value.parse::<f64>().unwrap()));
+        }
+        let value = |name: &str| values.iter().find(|(n, _)| *n == name).map(|(_, v)| *v);
+        assert_eq!(value("rayhunter_uptime_seconds"), Some(42.0));
+        assert_eq!(value("rayhunter_recording_active"), Some(1.0));
+        assert_eq!(value("rayhunter_qmdl_size_bytes"), Some(1234.0));
+        assert_eq!(value("rayhunter_analysis_warnings_total"), Some(3.0));
+        assert_eq!(value("rayhunter_analysis_pending"), Some(0.0));
+        assert_eq!(value("rayhunter_diag_containers_read_total"), Some(5.0));
+    }
+}
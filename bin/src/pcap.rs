@@ -1,12 +1,12 @@
 use crate::ServerState;
 
-use rayhunter::diag::DataType;
+use rayhunter::diag::{DataType, Message};
 use rayhunter::gsmtap_parser;
 use rayhunter::pcap::GsmtapPcapWriter;
 use rayhunter::qmdl::QmdlReader;
 use axum::body::Body;
 use axum::http::header::CONTENT_TYPE;
-use axum::extract::{State, Path};
+use axum::extract::{State, Path, Query};
 use axum::http::StatusCode;
 use axum::response::{Response, IntoResponse};
 use tokio::io::duplex;
@@ -15,11 +15,34 @@ use std::{future, pin::pin};
 use std::sync::Arc;
 use log::error;
 use futures::TryStreamExt;
+use serde::Deserialize;
+
+// How messages are encapsulated in the pcap file
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PcapFormat {
+    // GSMTAP packets over UDP, which Wireshark dissects out of the box
+    #[default]
+    Gsmtap,
+    // the raw diag messages under a user-defined link type, for tools which
+    // understand diag themselves
+    Diag,
+}
+
+#[derive(Deserialize)]
+pub struct PcapParams {
+    #[serde(default)]
+    format: PcapFormat,
+}
 
 // Streams a pcap file chunk-by-chunk to the client by reading the QMDL data
 // written so far. This is done by spawning a thread which streams chunks of
 // pcap data to a channel that's piped to the client.
-pub async fn get_pcap(State(state): State<Arc<ServerState>>, Path(qmdl_name): Path<String>) -> Result<Response, (StatusCode, String)> {
+pub async fn get_pcap(
+    State(state): State<Arc<ServerState>>,
+    Path(qmdl_name): Path<String>,
+    Query(params): Query<PcapParams>,
+) -> Result<Response, (StatusCode, String)> {
     let qmdl_store = state.qmdl_store_lock.read().await;
     let (entry_index, entry) = qmdl_store.entry_for_name(&qmdl_name)
         .ok_or((StatusCode::NOT_FOUND, format!("couldn't find qmdl file with name {}", qmdl_name)))?;
@@ -36,7 +59,10 @@ pub async fn get_pcap(State(state): State<Arc<ServerState>>, Path(qmdl_name): Pa
     // (entry.size_bytes)
     let (reader, writer) = duplex(1024);
     let mut pcap_writer = GsmtapPcapWriter::new(writer).await.unwrap();
-    pcap_writer.write_iface_header().await.unwrap();
+    match params.format {
+        PcapFormat::Gsmtap => pcap_writer.write_iface_header().await.unwrap(),
+        PcapFormat::Diag => pcap_writer.write_diag_iface_header().await.unwrap(),
+    }
 
     tokio::spawn(async move {
         let mut reader = QmdlReader::new(qmdl_file, Some(qmdl_size_bytes));
@@ -44,9 +70,16 @@ pub async fn get_pcap(State(state): State<Arc<ServerState>>, Path(qmdl_name): Pa
             .try_filter(|container| future::ready(container.data_type == DataType::UserSpace)));
 
         while let Some(container) = messages_stream.try_next().await.expect("failed getting QMDL container") {
-            for maybe_msg in container.into_messages() {
+            for maybe_msg in container.into_messages_with_data() {
                 match maybe_msg {
-                    Ok(msg) => {
+                    Ok((msg, data)) if params.format == PcapFormat::Diag => {
+                        // only log messages carry a timestamp
+                        if let Message::Log { timestamp, .. } = msg {
+                            pcap_writer.write_diag_message(data, timestamp).await
+                                .expect("error writing pcap packet");
+                        }
+                    },
+                    Ok((msg, _)) => {
                         let maybe_gsmtap_msg = gsmtap_parser::parse(msg)
                             .expect("error parsing gsmtap message");
                         if let Some((timestamp, gsmtap_msg)) = maybe_gsmtap_msg {
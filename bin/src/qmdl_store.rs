@@ -1,23 +1,39 @@
+use rayhunter::analysis::observed_plmn::Plmn;
 use rayhunter::util::RuntimeMetadata;
+use async_compression::tokio::bufread::GzipDecoder;
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 use tokio::{
     fs::{self, try_exists, File, OpenOptions},
-    io::AsyncWriteExt,
+    io::{AsyncRead, AsyncSeekExt, AsyncWriteExt, BufReader},
 };
 
+const STORE_DIR_MODE: u32 = 0o700;
+
 #[derive(Debug, Error)]
 pub enum RecordingStoreError {
     #[error("Can't close an entry when there's no current entry")]
     NoCurrentEntry,
+    #[error("Can't delete the entry currently being recorded")]
+    DeleteCurrentEntryError,
+    #[error("No entry named {0}")]
+    NoSuchEntry(String),
     #[error("Couldn't create file: {0}")]
     CreateFileError(tokio::io::Error),
     #[error("Couldn't read file: {0}")]
     ReadFileError(tokio::io::Error),
+    #[error("Couldn't delete file: {0}")]
+    DeleteFileError(tokio::io::Error),
     #[error("Couldn't open directory at path: {0}")]
     OpenDirError(tokio::io::Error),
+    #[error("Couldn't create directory {0:?}: {1}")]
+    CreateDirError(PathBuf, tokio::io::Error),
+    #[error("Couldn't set permissions on directory {0:?}: {1}")]
+    SetPermissionsError(PathBuf, tokio::io::Error),
     #[error("Couldn't read manifest file: {0}")]
     ReadManifestError(tokio::io::Error),
     #[error("Couldn't write manifest file: {0}")]
@@ -44,9 +60,22 @@ pub struct ManifestEntry {
     pub last_message_time: Option<DateTime<Local>>,
     pub qmdl_size_bytes: usize,
     pub analysis_size_bytes: usize,
+    // older manifests don't have this, so assume they had no warnings
+    #[serde(default)]
+    pub warning_count: usize,
     pub rayhunter_version: Option<String>,
     pub system_os: Option<String>,
     pub arch: Option<String>,
+    // a user-chosen label for the recording, since its name is just a
+    // timestamp
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // the networks (MCC/MNC) broadcast by cells seen during the recording,
+    // which older manifests don't have
+    #[serde(default)]
+    pub observed_plmns: Vec<Plmn>,
 }
 
 impl ManifestEntry {
@@ -59,9 +88,13 @@ impl ManifestEntry {
             last_message_time: None,
             qmdl_size_bytes: 0,
             analysis_size_bytes: 0,
+            warning_count: 0,
             rayhunter_version: Some(metadata.rayhunter_version),
             system_os: Some(metadata.system_os),
             arch: Some(metadata.arch),
+            display_name: None,
+            tags: Vec::new(),
+            observed_plmns: Vec::new(),
         }
     }
 
@@ -71,6 +104,12 @@ impl ManifestEntry {
         filepath
     }
 
+    pub fn get_compressed_qmdl_filepath<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let mut filepath = path.as_ref().join(&self.name);
+        filepath.set_extension("qmdl.gz");
+        filepath
+    }
+
     pub fn get_analysis_filepath<P: AsRef<Path>>(&self, path: P) -> PathBuf {
         let mut filepath = path.as_ref().join(&self.name);
         filepath.set_extension("ndjson");
@@ -117,9 +156,15 @@ impl RecordingStore {
         P: AsRef<Path>,
     {
         let manifest_path = path.as_ref().join("manifest.toml");
+        // the store's parents may not exist yet on a freshly set up device.
+        // since recordings can contain identifying info, make sure only our
+        // user can read them
         fs::create_dir_all(&path)
             .await
-            .map_err(RecordingStoreError::OpenDirError)?;
+            .map_err(|e| RecordingStoreError::CreateDirError(path.as_ref().to_path_buf(), e))?;
+        fs::set_permissions(&path, Permissions::from_mode(STORE_DIR_MODE))
+            .await
+            .map_err(|e| RecordingStoreError::SetPermissionsError(path.as_ref().to_path_buf(), e))?;
         let mut manifest_file = File::create(&manifest_path)
             .await
             .map_err(RecordingStoreError::WriteManifestError)?;
@@ -154,7 +199,15 @@ impl RecordingStore {
         if self.current_entry.is_some() {
             self.close_current_entry().await?;
         }
-        let new_entry = ManifestEntry::new();
+        let mut new_entry = ManifestEntry::new();
+        // names come from the start time in seconds, so entries started in
+        // quick succession (e.g. when splitting a recording) need telling apart
+        let base_name = new_entry.name.clone();
+        let mut suffix = 1;
+        while self.entry_for_name(&new_entry.name).is_some() {
+            new_entry.name = format!("{}-{}", base_name, suffix);
+            suffix += 1;
+        }
         let qmdl_filepath = new_entry.get_qmdl_filepath(&self.path);
         let qmdl_file = File::options()
             .create(true)
@@ -175,15 +228,56 @@ impl RecordingStore {
         Ok((qmdl_file, analysis_file))
     }
 
-    // Returns the corresponding QMDL file for a given entry
+    // Returns a reader for the QMDL data of a given entry. If the entry's QMDL
+    // file has been gzipped (i.e. it ends in ".qmdl.gz"), it's transparently
+    // decompressed, so callers never need to care how it's stored.
     pub async fn open_entry_qmdl(
         &self,
         entry_index: usize,
-    ) -> Result<File, RecordingStoreError> {
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send>, RecordingStoreError> {
         let entry = &self.manifest.entries[entry_index];
-        File::open(entry.get_qmdl_filepath(&self.path))
+        let compressed_filepath = entry.get_compressed_qmdl_filepath(&self.path);
+        let is_compressed = try_exists(&compressed_filepath)
             .await
-            .map_err(RecordingStoreError::ReadFileError)
+            .map_err(RecordingStoreError::ReadFileError)?;
+        if is_compressed {
+            let file = File::open(compressed_filepath)
+                .await
+                .map_err(RecordingStoreError::ReadFileError)?;
+            let mut decoder = GzipDecoder::new(BufReader::new(file));
+            decoder.multiple_members(true);
+            return Ok(Box::new(decoder));
+        }
+        let file = File::open(entry.get_qmdl_filepath(&self.path))
+            .await
+            .map_err(RecordingStoreError::ReadFileError)?;
+        Ok(Box::new(file))
+    }
+
+    // Like open_entry_qmdl, but starting at offset into the QMDL data. Plain
+    // files are seeked straight there, but compressed ones can only be read
+    // through from the start, so this also returns how many bytes the caller
+    // still has to skip. That's best done after releasing the store's lock.
+    pub async fn open_entry_qmdl_at(
+        &self,
+        entry_index: usize,
+        offset: u64,
+    ) -> Result<(Box<dyn AsyncRead + Unpin + Send>, u64), RecordingStoreError> {
+        let entry = &self.manifest.entries[entry_index];
+        let compressed_filepath = entry.get_compressed_qmdl_filepath(&self.path);
+        let is_compressed = try_exists(&compressed_filepath)
+            .await
+            .map_err(RecordingStoreError::ReadFileError)?;
+        if is_compressed {
+            return Ok((self.open_entry_qmdl(entry_index).await?, offset));
+        }
+        let mut file = File::open(entry.get_qmdl_filepath(&self.path))
+            .await
+            .map_err(RecordingStoreError::ReadFileError)?;
+        file.seek(std::io::SeekFrom::Start(offset))
+            .await
+            .map_err(RecordingStoreError::ReadFileError)?;
+        Ok((Box::new(file), 0))
     }
 
     // Returns the corresponding QMDL file for a given entry
@@ -204,15 +298,96 @@ impl RecordingStore {
         let entry = &self.manifest.entries[entry_index];
         let file = OpenOptions::new()
             .write(true)
+            .create(true)
             .truncate(true)
             .open(entry.get_analysis_filepath(&self.path))
             .await
             .map_err(RecordingStoreError::ReadFileError)?;
-        self.update_entry_analysis_size(entry_index, 0)
+        self.update_entry_analysis(entry_index, 0, 0)
             .await?;
         Ok(file)
     }
 
+    // Deletes the given entry's analysis file, leaving its QMDL file alone. The
+    // analysis file is recreated the next time the entry is analyzed.
+    pub async fn delete_entry_analysis(
+        &mut self,
+        entry_index: usize,
+    ) -> Result<(), RecordingStoreError> {
+        let entry = &self.manifest.entries[entry_index];
+        remove_file_if_exists(entry.get_analysis_filepath(&self.path)).await?;
+        self.update_entry_analysis(entry_index, 0, 0)
+            .await
+    }
+
+    // Deletes the oldest entries, along with their files, until at most
+    // max_entries are left. The current entry is never deleted. Returns the
+    // names of the deleted entries.
+    pub async fn delete_oldest_entries(
+        &mut self,
+        max_entries: usize,
+    ) -> Result<Vec<String>, RecordingStoreError> {
+        self.delete_oldest_entries_while(|store| store.manifest.entries.len() > max_entries).await
+    }
+
+    // Like delete_oldest_entries, but deletes entries until the store's total
+    // size is at most max_bytes
+    pub async fn delete_oldest_entries_over_size(
+        &mut self,
+        max_bytes: u64,
+    ) -> Result<Vec<String>, RecordingStoreError> {
+        self.delete_oldest_entries_while(|store| store.total_size_bytes() > max_bytes).await
+    }
+
+    async fn delete_oldest_entries_while(
+        &mut self,
+        should_delete: impl Fn(&Self) -> bool,
+    ) -> Result<Vec<String>, RecordingStoreError> {
+        let mut deleted = Vec::new();
+        while should_delete(self) {
+            // entries are kept in the order they were created
+            let Some(entry_index) = (0..self.manifest.entries.len())
+                .find(|&index| Some(index) != self.current_entry) else {
+                break;
+            };
+            let entry = self.remove_entry(entry_index).await?;
+            deleted.push(entry.name);
+        }
+        Ok(deleted)
+    }
+
+    // Deletes the entry with the given name, along with its files. The current
+    // entry can't be deleted.
+    pub async fn delete_entry(&mut self, name: &str) -> Result<(), RecordingStoreError> {
+        let (entry_index, _) = self.entry_for_name(name)
+            .ok_or_else(|| RecordingStoreError::NoSuchEntry(name.to_string()))?;
+        if self.current_entry == Some(entry_index) {
+            return Err(RecordingStoreError::DeleteCurrentEntryError);
+        }
+        self.remove_entry(entry_index).await?;
+        Ok(())
+    }
+
+    // Removes an entry from the manifest, then deletes its files. Since the
+    // manifest is updated first, failing partway through can leave orphaned
+    // files behind, but never an entry whose files are missing.
+    async fn remove_entry(&mut self, entry_index: usize) -> Result<ManifestEntry, RecordingStoreError> {
+        let entry = self.manifest.entries.remove(entry_index);
+        if let Err(err) = self.write_manifest().await {
+            self.manifest.entries.insert(entry_index, entry);
+            return Err(err);
+        }
+        if let Some(current_entry) = self.current_entry.as_mut() {
+            if *current_entry > entry_index {
+                *current_entry -= 1;
+            }
+        }
+        remove_file_if_exists(entry.get_qmdl_filepath(&self.path)).await?;
+        remove_file_if_exists(entry.get_compressed_qmdl_filepath(&self.path)).await?;
+        remove_file_if_exists(entry.get_analysis_filepath(&self.path)).await?;
+        Ok(entry)
+    }
+
     // Unsets the current entry
     pub async fn close_current_entry(&mut self) -> Result<(), RecordingStoreError> {
         match self.current_entry {
@@ -235,20 +410,50 @@ impl RecordingStore {
         self.write_manifest().await
     }
 
-    // Sets the given entry's analysis file size
-    pub async fn update_entry_analysis_size(
+    // Sets the given entry's analysis file size and number of warnings raised
+    pub async fn update_entry_analysis(
         &mut self,
         entry_index: usize,
         size_bytes: usize,
+        warning_count: usize,
     ) -> Result<(), RecordingStoreError> {
         self.manifest.entries[entry_index].analysis_size_bytes = size_bytes;
+        self.manifest.entries[entry_index].warning_count = warning_count;
+        self.write_manifest().await
+    }
+
+    // Sets the PLMNs the given entry's analysis has seen so far
+    pub async fn update_entry_observed_plmns(
+        &mut self,
+        entry_index: usize,
+        observed_plmns: Vec<Plmn>,
+    ) -> Result<(), RecordingStoreError> {
+        self.manifest.entries[entry_index].observed_plmns = observed_plmns;
+        self.write_manifest().await
+    }
+
+    // Sets the given entry's display name and tags. Its name, and so its files
+    // on disk, stay the same.
+    pub async fn update_entry_labels(
+        &mut self,
+        entry_index: usize,
+        display_name: Option<String>,
+        tags: Vec<String>,
+    ) -> Result<(), RecordingStoreError> {
+        self.manifest.entries[entry_index].display_name = display_name;
+        self.manifest.entries[entry_index].tags = tags;
         self.write_manifest().await
     }
 
+    // Writes the manifest to a temporary file before moving it into place, so
+    // dying partway through never leaves a truncated manifest behind
     async fn write_manifest(&mut self) -> Result<(), RecordingStoreError> {
+        let tmp_path = self.path.join("manifest.toml.tmp");
         let mut manifest_file = File::options()
             .write(true)
-            .open(self.path.join("manifest.toml"))
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
             .await
             .map_err(RecordingStoreError::WriteManifestError)?;
         let manifest_contents =
@@ -257,6 +462,9 @@ impl RecordingStore {
             .write_all(manifest_contents.as_bytes())
             .await
             .map_err(RecordingStoreError::WriteManifestError)?;
+        fs::rename(&tmp_path, self.path.join("manifest.toml"))
+            .await
+            .map_err(RecordingStoreError::WriteManifestError)?;
         Ok(())
     }
 
@@ -269,12 +477,28 @@ impl RecordingStore {
         Some((entry_index, &self.manifest.entries[entry_index]))
     }
 
+    // The total size of every entry's QMDL and analysis files, going by the
+    // manifest
+    pub fn total_size_bytes(&self) -> u64 {
+        self.manifest.entries.iter()
+            .map(|entry| (entry.qmdl_size_bytes + entry.analysis_size_bytes) as u64)
+            .sum()
+    }
+
     pub fn get_current_entry(&self) -> Option<(usize, &ManifestEntry)> {
         let entry_index = self.current_entry?;
         Some((entry_index, &self.manifest.entries[entry_index]))
     }
 }
 
+async fn remove_file_if_exists<P: AsRef<Path>>(path: P) -> Result<(), RecordingStoreError> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(RecordingStoreError::DeleteFileError(err)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -294,6 +518,16 @@ mod tests {
         assert_eq!(loaded_store.manifest.entries.len(), 0);
     }
 
+    #[tokio::test]
+    async fn test_create_in_nested_dir() {
+        let dir = make_temp_dir();
+        let store_path = dir.path().join("data").join("rayhunter").join("qmdl");
+        let _created_store = RecordingStore::create(&store_path).await.unwrap();
+        assert!(RecordingStore::exists(&store_path).await.unwrap());
+        let mode = fs::metadata(&store_path).await.unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, STORE_DIR_MODE);
+    }
+
     #[tokio::test]
     async fn test_creating_updating_and_closing_entries() {
         let dir = make_temp_dir();
@@ -340,4 +574,147 @@ mod tests {
         assert_ne!(entry_index, new_entry_index);
         assert_eq!(store.manifest.entries.len(), 2);
     }
+
+    #[tokio::test]
+    async fn test_delete_oldest_entries() {
+        let dir = make_temp_dir();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let max_entries = 2;
+        // entry names are timestamps, so give them distinct names by hand
+        for name in ["1", "2", "3"] {
+            let _ = store.new_entry().await.unwrap();
+            let entry = store.manifest.entries.last_mut().unwrap();
+            entry.name = name.to_string();
+            std::fs::write(entry.get_qmdl_filepath(&store.path), name).unwrap();
+            std::fs::write(entry.get_analysis_filepath(&store.path), name).unwrap();
+        }
+        let oldest = store.manifest.entries[0].clone();
+
+        assert_eq!(store.delete_oldest_entries(max_entries).await.unwrap(), vec!["1"]);
+        let names: Vec<&str> = store.manifest.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["2", "3"]);
+        assert!(!oldest.get_qmdl_filepath(&store.path).exists());
+        assert!(!oldest.get_analysis_filepath(&store.path).exists());
+        assert!(store.manifest.entries[0].get_qmdl_filepath(&store.path).exists());
+        assert_eq!(store.get_current_entry().unwrap().1.name, "3");
+        assert_eq!(RecordingStore::read_manifest(dir.path()).await.unwrap(), store.manifest);
+
+        // the current entry is kept no matter what
+        assert_eq!(store.delete_oldest_entries(0).await.unwrap(), vec!["2"]);
+        assert_eq!(store.get_current_entry().unwrap().1.name, "3");
+    }
+
+    #[tokio::test]
+    async fn test_delete_oldest_entries_over_size() {
+        let dir = make_temp_dir();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        for name in ["1", "2", "3", "4"] {
+            let _ = store.new_entry().await.unwrap();
+            let entry_index = store.current_entry.unwrap();
+            store.manifest.entries[entry_index].name = name.to_string();
+            store.update_entry_qmdl_size(entry_index, 100).await.unwrap();
+            store.update_entry_analysis(entry_index, 10, 0).await.unwrap();
+            let entry = &store.manifest.entries[entry_index];
+            std::fs::write(entry.get_qmdl_filepath(&store.path), name).unwrap();
+            std::fs::write(entry.get_analysis_filepath(&store.path), name).unwrap();
+        }
+        let oldest = store.manifest.entries[0].clone();
+        assert_eq!(store.total_size_bytes(), 440);
+
+        // under the limit, nothing happens
+        assert!(store.delete_oldest_entries_over_size(440).await.unwrap().is_empty());
+
+        assert_eq!(store.delete_oldest_entries_over_size(300).await.unwrap(), vec!["1", "2"]);
+        let names: Vec<&str> = store.manifest.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["3", "4"]);
+        assert_eq!(store.total_size_bytes(), 220);
+        assert!(!oldest.get_qmdl_filepath(&store.path).exists());
+        assert!(!oldest.get_analysis_filepath(&store.path).exists());
+        assert_eq!(RecordingStore::read_manifest(dir.path()).await.unwrap(), store.manifest);
+
+        // the current entry is kept even if it's over the limit on its own
+        assert_eq!(store.delete_oldest_entries_over_size(0).await.unwrap(), vec!["3"]);
+        assert_eq!(store.get_current_entry().unwrap().1.name, "4");
+    }
+
+    #[tokio::test]
+    async fn test_observed_plmns() {
+        let dir = make_temp_dir();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let _ = store.new_entry().await.unwrap();
+        let entry_index = store.current_entry.unwrap();
+        let plmns = vec![Plmn::new("310", "410"), Plmn::new("310", "260")];
+        store.update_entry_observed_plmns(entry_index, plmns.clone()).await.unwrap();
+        let manifest = RecordingStore::read_manifest(dir.path()).await.unwrap();
+        assert_eq!(manifest.entries[entry_index].observed_plmns, plmns);
+
+        // entries from before PLMNs were recorded just don't have any
+        let old_manifest = r#"
+            [[entries]]
+            name = "1700000000"
+            start_time = "2023-11-14T22:13:20Z"
+            qmdl_size_bytes = 0
+            analysis_size_bytes = 0
+        "#;
+        let manifest: Manifest = toml::from_str(old_manifest).unwrap();
+        assert!(manifest.entries[0].observed_plmns.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_open_entry_qmdl_at() {
+        use async_compression::tokio::write::GzipEncoder;
+        use tokio::io::AsyncReadExt;
+
+        let dir = make_temp_dir();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let (mut qmdl_file, _) = store.new_entry().await.unwrap();
+        let entry_index = store.current_entry.unwrap();
+        qmdl_file.write_all(b"0123456789").await.unwrap();
+        store.close_current_entry().await.unwrap();
+
+        // plain files are already at the offset
+        let (mut reader, to_skip) = store.open_entry_qmdl_at(entry_index, 6).await.unwrap();
+        assert_eq!(to_skip, 0);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "6789");
+
+        // compressed ones are read from the start
+        let entry = &store.manifest.entries[entry_index];
+        let mut encoder = GzipEncoder::new(File::create(entry.get_compressed_qmdl_filepath(&store.path)).await.unwrap());
+        encoder.write_all(b"0123456789").await.unwrap();
+        encoder.shutdown().await.unwrap();
+        fs::remove_file(entry.get_qmdl_filepath(&store.path)).await.unwrap();
+        let (mut reader, to_skip) = store.open_entry_qmdl_at(entry_index, 6).await.unwrap();
+        assert_eq!(to_skip, 6);
+        let mut all = String::new();
+        reader.read_to_string(&mut all).await.unwrap();
+        assert_eq!(all, "0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_reading_compressed_qmdl() {
+        use async_compression::tokio::write::GzipEncoder;
+        use tokio::io::AsyncReadExt;
+
+        let dir = make_temp_dir();
+        let mut store = RecordingStore::create(dir.path()).await.unwrap();
+        let (mut qmdl_file, _) = store.new_entry().await.unwrap();
+        let entry_index = store.current_entry.unwrap();
+        let qmdl_bytes: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        qmdl_file.write_all(&qmdl_bytes).await.unwrap();
+        store.close_current_entry().await.unwrap();
+
+        let entry = &store.manifest.entries[entry_index];
+        let compressed_file = File::create(entry.get_compressed_qmdl_filepath(&store.path)).await.unwrap();
+        let mut encoder = GzipEncoder::new(compressed_file);
+        encoder.write_all(&qmdl_bytes).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        fs::remove_file(entry.get_qmdl_filepath(&store.path)).await.unwrap();
+
+        let mut read_bytes = Vec::new();
+        store.open_entry_qmdl(entry_index).await.unwrap()
+            .read_to_end(&mut read_bytes).await.unwrap();
+        assert_eq!(read_bytes, qmdl_bytes);
+    }
 }
@@ -0,0 +1,202 @@
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use futures::TryStreamExt;
+use log::info;
+use rayhunter::analysis::analyzer::Severity;
+use rayhunter::diag::{MessagesContainer, CRC_CCITT};
+use rayhunter::hdlc::hdlc_encapsulate;
+use rayhunter::qmdl::QmdlReader;
+use serde::Serialize;
+
+use crate::analysis::AnalysisWriter;
+use crate::config::AnalyzerConfig;
+use crate::framebuffer::{DisplayState, DisplayStateTracker};
+use crate::server::ServerState;
+
+// A diag log of an LTE RRC paging message for an S-TMSI (the same one the
+// lib's diag tests parse), fed through the pipeline in place of data from the
+// modem. The dummy analyzer warns about every one of these.
+pub const SYNTHETIC_MESSAGE: &[u8] = &[
+    16, 0, 38, 0, 38, 0, 192, 176, 26, 165, 245, 135, 118, 35, 2, 1, 20,
+    14, 48, 0, 160, 0, 2, 8, 0, 0, 217, 15, 5, 0, 0, 0, 0, 7, 0, 64, 1,
+    238, 173, 213, 77, 208
+];
+
+#[derive(Serialize, Debug)]
+pub struct SelfTestStage {
+    pub name: &'static str,
+    pub passed: bool,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub stages: Vec<SelfTestStage>,
+}
+
+impl SelfTestReport {
+    fn new() -> Self {
+        SelfTestReport {
+            passed: true,
+            stages: Vec::new(),
+        }
+    }
+
+    // Records the result of a stage, passing along its output if it passed
+    fn record<T>(&mut self, name: &'static str, result: Result<(T, String), String>) -> Option<T> {
+        let (output, passed, message) = match result {
+            Ok((output, message)) => (Some(output), true, message),
+            Err(message) => (None, false, message),
+        };
+        self.passed &= passed;
+        self.stages.push(SelfTestStage { name, passed, message });
+        output
+    }
+
+    // Once a stage fails, the ones depending on it can't run
+    fn skip(&mut self, names: &[&'static str]) {
+        for &name in names {
+            self.passed = false;
+            self.stages.push(SelfTestStage {
+                name,
+                passed: false,
+                message: "skipped, an earlier stage failed".to_string(),
+            });
+        }
+    }
+}
+
+// Pushes a synthetic QMDL fragment through the same QMDL parsing, analysis,
+// and UI state handling a real recording goes through, reporting how each stage
+// went. This never touches the diag device, but since it runs the dummy
+// analyzer, it's only available in debug mode.
+pub async fn run_self_test(State(state): State<Arc<ServerState>>) -> Result<Json<SelfTestReport>, (StatusCode, String)> {
+    if !state.config_lock.read().await.debug_mode {
+        return Err((StatusCode::FORBIDDEN, "the self test is only available in debug mode".to_string()));
+    }
+    let (analyzer_config, colorblind_mode) = {
+        let config = state.config_lock.read().await;
+        (config.analyzer_config.clone(), config.colorblind_mode)
+    };
+    let report = self_test(&analyzer_config, colorblind_mode).await;
+    info!("self test {}", if report.passed { "passed" } else { "failed" });
+    Ok(Json(report))
+}
+
+pub async fn self_test(analyzer_config: &AnalyzerConfig, colorblind_mode: bool) -> SelfTestReport {
+    let mut report = SelfTestReport::new();
+    let Some(container) = report.record("qmdl", read_synthetic_qmdl().await) else {
+        report.skip(&["analysis", "ndjson", "ui"]);
+        return report;
+    };
+    let analysis_file = match tempfile::NamedTempFile::new() {
+        Ok(file) => file,
+        Err(e) => {
+            report.record::<()>("analysis", Err(format!("couldn't create analysis file: {}", e)));
+            report.skip(&["ndjson", "ui"]);
+            return report;
+        }
+    };
+    let Some((size_bytes, severity)) = report.record("analysis", analyze(container, &analysis_file, analyzer_config).await) else {
+        report.skip(&["ndjson", "ui"]);
+        return report;
+    };
+    report.record("ndjson", check_ndjson(&analysis_file, size_bytes).await);
+    report.record("ui", check_ui_transition(severity, colorblind_mode));
+    report
+}
+
+async fn read_synthetic_qmdl() -> Result<(MessagesContainer, String), String> {
+    let fragment = hdlc_encapsulate(SYNTHETIC_MESSAGE, &CRC_CCITT);
+    let fragment_len = fragment.len();
+    let mut qmdl_reader = QmdlReader::new(Cursor::new(fragment), Some(fragment_len));
+    let mut containers: Vec<MessagesContainer> = qmdl_reader.as_stream().try_collect().await
+        .map_err(|e| format!("couldn't read QMDL fragment: {}", e))?;
+    if containers.len() != 1 {
+        return Err(format!("expected 1 container, got {}", containers.len()));
+    }
+    let container = containers.remove(0);
+    let messages = container.clone().into_messages();
+    if let Some(Err(e)) = messages.iter().find(|msg| msg.is_err()) {
+        return Err(format!("couldn't parse diag message: {:?}", e));
+    }
+    let message = format!("read {} bytes, parsed {} message(s)", fragment_len, messages.len());
+    Ok((container, message))
+}
+
+async fn analyze(
+    container: MessagesContainer,
+    analysis_file: &tempfile::NamedTempFile,
+    analyzer_config: &AnalyzerConfig,
+) -> Result<((usize, Severity), String), String> {
+    let file = analysis_file.reopen()
+        .map_err(|e| format!("couldn't open analysis file: {}", e))?;
+    // the dummy analyzer is what guarantees a warning
+    let analyzer_config = AnalyzerConfig {
+        enable_dummy_analyzer: true,
+        ..analyzer_config.clone()
+    };
+    let mut analysis_writer = AnalysisWriter::new(tokio::fs::File::from_std(file), &analyzer_config).await
+        .map_err(|e| format!("couldn't write analysis: {}", e))?;
+    let (size_bytes, max_warning_severity) = analysis_writer.analyze(container).await
+        .map_err(|e| format!("couldn't write analysis: {}", e))?;
+    let warning_count = analysis_writer.warning_count();
+    analysis_writer.close().await
+        .map_err(|e| format!("couldn't write analysis: {}", e))?;
+    let severity = max_warning_severity
+        .ok_or("no warnings were raised".to_string())?;
+    let message = format!("{} warning(s) raised, highest severity {:?}", warning_count, severity);
+    Ok(((size_bytes, severity), message))
+}
+
+async fn check_ndjson(analysis_file: &tempfile::NamedTempFile, size_bytes: usize) -> Result<((), String), String> {
+    let contents = tokio::fs::read_to_string(analysis_file.path()).await
+        .map_err(|e| format!("couldn't read analysis file: {}", e))?;
+    if contents.len() != size_bytes {
+        return Err(format!("expected {} bytes, found {}", size_bytes, contents.len()));
+    }
+    let rows = contents.lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("analysis file isn't valid ndjson: {}", e))?;
+    // the first row is the report's metadata
+    if rows.len() < 2 {
+        return Err("analysis file has no rows after its metadata".to_string());
+    }
+    Ok(((), format!("wrote {} rows ({} bytes)", rows.len(), size_bytes)))
+}
+
+fn check_ui_transition(severity: Severity, colorblind_mode: bool) -> Result<((), String), String> {
+    let recording = DisplayState::Recording;
+    let mut tracker = DisplayStateTracker::new(recording, None);
+    let now = Instant::now();
+    tracker.update(DisplayState::WarningDetected(severity), now);
+    let warning = match tracker.current(now) {
+        DisplayState::WarningDetected(shown) if shown == severity => DisplayState::WarningDetected(shown),
+        state => return Err(format!("expected the display to show a {:?} warning, but it shows {:?}", severity, state)),
+    };
+    let (recording_color, warning_color) = (recording.to_color(colorblind_mode), warning.to_color(colorblind_mode));
+    if recording_color == warning_color {
+        return Err(format!("the display stays {:?} when a warning is detected", warning_color));
+    }
+    Ok(((), format!("display went from {:?} ({:?}) to {:?} ({:?})", recording, recording_color, warning, warning_color)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_self_test() {
+        let report = self_test(&AnalyzerConfig::default(), false).await;
+        assert!(report.passed, "{:?}", report);
+        let names: Vec<&str> = report.stages.iter().map(|stage| stage.name).collect();
+        assert_eq!(names, vec!["qmdl", "analysis", "ndjson", "ui"]);
+    }
+}
@@ -1,29 +1,171 @@
 use axum::body::Body;
-use axum::http::header::{CONTENT_TYPE, self};
+use async_compression::tokio::bufread::GzipEncoder;
+use axum::http::header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY, WWW_AUTHENTICATE, self};
 use axum::extract::State;
-use axum::http::{StatusCode, HeaderValue};
+use axum::http::{HeaderMap, StatusCode, HeaderValue, Method, Uri};
+use axum::extract::Request;
+use axum::middleware::Next;
 use axum::response::{Response, IntoResponse};
-use axum::extract::Path;
+use axum::extract::{Path, Query};
+use axum::Json;
+use rayhunter::diag::{DataType, Message};
+use rayhunter::qmdl::QmdlReader;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::sync::RwLock;
-use tokio_util::io::ReaderStream;
+use futures::TryStreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use include_dir::{include_dir, Dir};
 
 use crate::{framebuffer, DiagDeviceCtrlMessage};
 use crate::analysis::{AnalysisCtrlMessage, AnalysisStatus};
-use crate::qmdl_store::RecordingStore;
+use crate::config::Config;
+use crate::live::LiveUpdateSender;
+use crate::logging::LogBuffer;
+use crate::stats::{DiagLoopStats, MessageStats};
+use crate::qmdl_store::{ManifestEntry, RecordingStore, RecordingStoreError};
 
 pub struct ServerState {
     pub qmdl_store_lock: Arc<RwLock<RecordingStore>>,
     pub diag_device_ctrl_sender: Sender<DiagDeviceCtrlMessage>,
-    pub ui_update_sender: Sender<framebuffer::DisplayState>,
+    pub ui_update_sender: Sender<framebuffer::UiUpdate>,
+    // the state currently shown on screen
+    pub display_state_lock: Arc<RwLock<framebuffer::DisplayState>>,
+    pub live_update_sender: LiveUpdateSender,
     pub analysis_status_lock: Arc<RwLock<AnalysisStatus>>,
     pub analysis_sender: Sender<AnalysisCtrlMessage>,
-    pub debug_mode: bool,
-    pub colorblind_mode: bool,
+    pub message_stats_lock: Arc<RwLock<MessageStats>>,
+    pub diag_loop_stats_lock: Arc<RwLock<DiagLoopStats>>,
+    // when the daemon started, for reporting its uptime
+    pub started_at: Instant,
+    // the daemon's config, which the settings that can be changed without
+    // restarting are reloaded into on SIGHUP
+    pub config_lock: Arc<RwLock<Config>>,
+    // the latest log lines, if they're being kept
+    pub log_buffer: Option<LogBuffer>,
+    // the TCP port actually being served on, which differs from the config's
+    // if we fell back to another one. None when serving on a unix socket.
+    pub bound_port: Option<u16>,
+    // for spawning tasks which should be waited on, and stop, when the daemon
+    // shuts down
+    pub task_tracker: TaskTracker,
+    pub shutdown_token: CancellationToken,
+    // cancels the current recording's duration_secs timer, if it has one
+    pub recording_timer_lock: Mutex<Option<CancellationToken>>,
+}
+
+// Renders what's currently on the device's screen to a PNG
+pub async fn get_screenshot(State(state): State<Arc<ServerState>>) -> Result<Response, (StatusCode, String)> {
+    let display_state = *state.display_state_lock.read().await;
+    let (ui_level, colorblind_mode) = {
+        let config = state.config_lock.read().await;
+        (config.ui_level, config.colorblind_mode)
+    };
+    let png = tokio::task::spawn_blocking(move || {
+        let mut fb = framebuffer::Framebuffer::new_virtual();
+        fb.draw_ui(ui_level, display_state, colorblind_mode)?;
+        fb.to_png()
+    }).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("screenshot task failed: {}", e)))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't render screenshot: {}", e)))?;
+    Ok(([(CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+// In read-only mode, rejects any request that could change the daemon's state,
+// i.e. anything other than a GET, while leaving the data endpoints viewable
+pub async fn read_only_guard(State(state): State<Arc<ServerState>>, request: Request, next: Next) -> Response {
+    if state.config_lock.read().await.read_only && !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return (StatusCode::FORBIDDEN, "server is in read-only mode").into_response();
+    }
+    next.run(request).await
+}
+
+// If an API token is configured, rejects requests which don't carry it as a
+// bearer token. The web UI's static files are exempt unless
+// api_token_protects_static is set, since browsers can't attach the header
+// when loading pages.
+pub async fn api_token_guard(State(state): State<Arc<ServerState>>, request: Request, next: Next) -> Response {
+    let authorized = {
+        let config = state.config_lock.read().await;
+        let path = request.uri().path();
+        let is_api = path.starts_with("/api/") || path == "/metrics";
+        match config.api_token.as_deref() {
+            None => true,
+            Some(_) if !is_api && !config.api_token_protects_static => true,
+            // API requests need an "Authorization: Bearer <api_token>" header
+            Some(api_token) => request.headers().get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|token| tokens_match(token, api_token)),
+        }
+    };
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")], "missing or invalid API token").into_response();
+    }
+    next.run(request).await
+}
+
+// Gzips responses which are worth it, if the client accepts gzip. The body's
+// compressed as it streams, so large analysis reports aren't buffered in
+// memory first.
+pub async fn compress_response(request: Request, next: Next) -> Response {
+    let compressible = is_compressible(request.uri());
+    let compress = compressible && accepts_gzip(request.headers());
+    let mut response = next.run(request).await;
+    if compressible {
+        response.headers_mut().append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    if !compress || !response.status().is_success() || response.headers().contains_key(CONTENT_ENCODING) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+    Response::from_parts(parts, Body::from_stream(ReaderStream::new(GzipEncoder::new(reader))))
+}
+
+// The analysis reports and manifest, which are large and compress well, and
+// the web UI's static files. QMDL and pcap downloads are binary and often
+// huge, bundles and exports are already compressed, and a followed analysis
+// report has to reach the client as each row is written, which gzip's
+// buffering would hold up.
+fn is_compressible(uri: &Uri) -> bool {
+    let path = uri.path();
+    if path.starts_with("/api/analysis-report/") {
+        let follow = uri.query().is_some_and(|query| query.split('&').any(|param| param == "follow=true"));
+        return !follow;
+    }
+    path == "/api/qmdl-manifest" || !(path.starts_with("/api/") || path == "/metrics")
+}
+
+// Whether the client's Accept-Encoding allows gzip, i.e. lists it without a
+// q=0
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers.get_all(ACCEPT_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let refused = params.any(|param| param.strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0));
+            name.eq_ignore_ascii_case("gzip") && !refused
+        })
+}
+
+// Compares tokens in time that only depends on their lengths, so the
+// comparison doesn't leak how much of a guess was right
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
 }
 
 pub async fn get_qmdl(State(state): State<Arc<ServerState>>, Path(qmdl_name): Path<String>) -> Result<Response, (StatusCode, String)> {
@@ -41,16 +183,229 @@ pub async fn get_qmdl(State(state): State<Arc<ServerState>>, Path(qmdl_name): Pa
     Ok((headers, body).into_response())
 }
 
+// Deletes a recording's QMDL and analysis files, and removes it from the
+// manifest. The current recording, and any queued for analysis, can't be
+// deleted.
+pub async fn delete_qmdl(State(state): State<Arc<ServerState>>, Path(qmdl_name): Path<String>) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let qmdl_name = qmdl_name.trim_end_matches(".qmdl");
+    let analysis_status = state.analysis_status_lock.read().await;
+    if analysis_status.is_queued_or_running(qmdl_name) {
+        return Err((StatusCode::CONFLICT, format!("{} is queued for analysis", qmdl_name)));
+    }
+    let mut qmdl_store = state.qmdl_store_lock.write().await;
+    qmdl_store.delete_entry(qmdl_name).await.map_err(|e| match e {
+        RecordingStoreError::NoSuchEntry(_) => (StatusCode::NOT_FOUND, format!("couldn't find qmdl file with name {}", qmdl_name)),
+        RecordingStoreError::DeleteCurrentEntryError => (StatusCode::CONFLICT, "can't delete the current recording".to_string()),
+        e => (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't delete recording: {}", e)),
+    })?;
+    Ok((StatusCode::OK, "ok".to_string()))
+}
+
+// Limits on recording labels, so they can't bloat the manifest
+const MAX_DISPLAY_NAME_LEN: usize = 128;
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LEN: usize = 32;
+
+#[derive(Deserialize)]
+pub struct RenameRequest {
+    display_name: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+// Sets a recording's display name and/or tags, leaving whichever isn't given
+// alone. An empty display name clears it. Returns the updated manifest entry.
+pub async fn rename_qmdl(
+    State(state): State<Arc<ServerState>>,
+    Path(qmdl_name): Path<String>,
+    Json(rename_request): Json<RenameRequest>,
+) -> Result<Json<ManifestEntry>, (StatusCode, String)> {
+    let mut qmdl_store = state.qmdl_store_lock.write().await;
+    let (entry_index, entry) = qmdl_store.entry_for_name(&qmdl_name)
+        .ok_or((StatusCode::NOT_FOUND, format!("couldn't find qmdl file with name {}", qmdl_name)))?;
+    let display_name = match rename_request.display_name {
+        Some(display_name) => {
+            let display_name = display_name.trim();
+            if display_name.chars().count() > MAX_DISPLAY_NAME_LEN {
+                return Err((StatusCode::BAD_REQUEST, format!("display_name can be at most {} characters", MAX_DISPLAY_NAME_LEN)));
+            }
+            Some(display_name.to_string()).filter(|name| !name.is_empty())
+        },
+        None => entry.display_name.clone(),
+    };
+    let tags = match rename_request.tags {
+        Some(tags) => {
+            let tags: Vec<String> = tags.iter()
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect();
+            if tags.len() > MAX_TAGS || tags.iter().any(|tag| tag.chars().count() > MAX_TAG_LEN) {
+                return Err((StatusCode::BAD_REQUEST, format!("there can be at most {} tags of at most {} characters each", MAX_TAGS, MAX_TAG_LEN)));
+            }
+            tags
+        },
+        None => entry.tags.clone(),
+    };
+    qmdl_store.update_entry_labels(entry_index, display_name, tags).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't update manifest: {}", e)))?;
+    Ok(Json(qmdl_store.manifest.entries[entry_index].clone()))
+}
+
+// Upper bound on how many bytes a single hexdump request can return, so a
+// careless query can't make us read (and format) an entire QMDL file
+const HEXDUMP_MAX_LEN: usize = 4096;
+const HEXDUMP_DEFAULT_LEN: usize = 256;
+
+#[derive(Deserialize)]
+pub struct HexdumpParams {
+    offset: Option<usize>,
+    len: Option<usize>,
+}
+
+// Returns a `hexdump -C` style dump of a byte range of a QMDL file, which is
+// handy when the parser itself is suspect
+pub async fn get_qmdl_hexdump(
+    State(state): State<Arc<ServerState>>,
+    Path(qmdl_name): Path<String>,
+    Query(params): Query<HexdumpParams>,
+) -> Result<Response, (StatusCode, String)> {
+    let qmdl_idx = qmdl_name.trim_end_matches(".qmdl");
+    let qmdl_store = state.qmdl_store_lock.read().await;
+    let (entry_index, entry) = qmdl_store.entry_for_name(qmdl_idx)
+        .ok_or((StatusCode::NOT_FOUND, format!("couldn't find qmdl file with name {}", qmdl_idx)))?;
+    let file_size = entry.qmdl_size_bytes;
+    // check the range before touching the file
+    let offset = params.offset.unwrap_or(0);
+    if offset >= file_size {
+        return Err((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            format!("offset {} is out of range for QMDL file of size {}", offset, file_size),
+        ));
+    }
+    let len = params.len.unwrap_or(HEXDUMP_DEFAULT_LEN.min(file_size - offset));
+    if len == 0 || len > HEXDUMP_MAX_LEN {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("len must be between 1 and {}", HEXDUMP_MAX_LEN),
+        ));
+    }
+    if offset + len > file_size {
+        return Err((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            format!("range {}..{} is out of range for QMDL file of size {}", offset, offset + len, file_size),
+        ));
+    }
+
+    let (mut qmdl_file, to_skip) = qmdl_store.open_entry_qmdl_at(entry_index, offset as u64).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("error opening QMDL file: {}", e)))?;
+    // don't hold up recording while reading, especially through a compressed
+    // file up to the offset
+    drop(qmdl_store);
+    tokio::io::copy(&mut (&mut qmdl_file).take(to_skip), &mut tokio::io::sink()).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("error reading QMDL file: {}", e)))?;
+    let mut buf = vec![0; len];
+    qmdl_file.read_exact(&mut buf).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("error reading QMDL file: {}", e)))?;
+
+    let headers = [(CONTENT_TYPE, "text/plain")];
+    Ok((headers, hexdump(&buf, offset)).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct MessageParams {
+    index: usize,
+}
+
+#[derive(Serialize)]
+pub struct DecodedMessage {
+    pub index: usize,
+    // which of the QMDL file's containers the message was in
+    pub container_index: usize,
+    pub message: Message,
+}
+
+// Returns a QMDL file's index'th diag message as the parser decodes it, for
+// debugging the parser without going through Wireshark. Messages are counted
+// across every user space container, including any which fail to parse, so
+// indexes stay put when the parser changes.
+pub async fn get_qmdl_message(
+    State(state): State<Arc<ServerState>>,
+    Path(qmdl_name): Path<String>,
+    Query(params): Query<MessageParams>,
+) -> Result<Json<DecodedMessage>, (StatusCode, String)> {
+    let qmdl_idx = qmdl_name.trim_end_matches(".qmdl");
+    let qmdl_store = state.qmdl_store_lock.read().await;
+    let (entry_index, entry) = qmdl_store.entry_for_name(qmdl_idx)
+        .ok_or((StatusCode::NOT_FOUND, format!("couldn't find qmdl file with name {}", qmdl_idx)))?;
+    let qmdl_size_bytes = entry.qmdl_size_bytes;
+    let qmdl_file = qmdl_store.open_entry_qmdl(entry_index).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("error opening QMDL file: {}", e)))?;
+    let mut reader = QmdlReader::new(qmdl_file, Some(qmdl_size_bytes));
+    let mut remaining = params.index;
+    let mut container_index = 0;
+    while let Some(container) = reader.get_next_messages_container().await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("error reading QMDL file: {}", e)))?
+    {
+        if container.data_type == DataType::UserSpace {
+            let mut messages = container.into_messages();
+            if remaining < messages.len() {
+                return match messages.swap_remove(remaining) {
+                    Ok(message) => Ok(Json(DecodedMessage { index: params.index, container_index, message })),
+                    Err(e) => Err((StatusCode::UNPROCESSABLE_ENTITY, format!("couldn't parse message {}: {}", params.index, e))),
+                };
+            }
+            remaining -= messages.len();
+        }
+        container_index += 1;
+    }
+    Err((StatusCode::NOT_FOUND, format!("QMDL file has only {} messages", params.index - remaining)))
+}
+
+// Formats bytes as lines of "offset  16 hex bytes  |ASCII|", labelling each
+// line with its offset relative to the start of the file
+fn hexdump(data: &[u8], base_offset: usize) -> String {
+    let mut result = String::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let _ = write!(result, "{:08x} ", base_offset + i * 16);
+        for j in 0..16 {
+            if j % 8 == 0 {
+                result.push(' ');
+            }
+            match chunk.get(j) {
+                Some(byte) => { let _ = write!(result, "{:02x} ", byte); },
+                None => result.push_str("   "),
+            }
+        }
+        result.push_str(" |");
+        for &byte in chunk {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                result.push(byte as char);
+            } else {
+                result.push('.');
+            }
+        }
+        result.push_str("|\n");
+    }
+    result
+}
+
 // Bundles the server's static files (html/css/js) into the binary for easy distribution
 static STATIC_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/static");
 
+// A hand-maintained description of the API. Keep this in sync with the routes
+// in run_server!
+static OPENAPI_SPEC: &str = include_str!("../openapi.json");
+
+pub async fn get_openapi_spec() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "application/json")], OPENAPI_SPEC)
+}
+
 pub async fn serve_static(State(state): State<Arc<ServerState>>, Path(path): Path<String>) -> impl IntoResponse {
     let path = path.trim_start_matches('/');
     let mime_type = mime_guess::from_path(path).first_or_text_plain();
 
     // if we're in debug mode, return the files from the build directory so we
     // don't have to rebuild every time the JS/HTML change
-    if state.debug_mode {
+    if state.config_lock.read().await.debug_mode {
         let mut build_path = std::path::PathBuf::new();
         build_path.push("bin");
         build_path.push("static");
@@ -92,3 +447,49 @@ pub async fn serve_static(State(state): State<Arc<ServerState>>, Path(path): Pat
             .unwrap(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hexdump() {
+        let data: Vec<u8> = (0x3c..0x50).collect();
+        assert_eq!(hexdump(&data, 0x20), concat!(
+            "00000020  3c 3d 3e 3f 40 41 42 43  44 45 46 47 48 49 4a 4b  |<=>?@ABCDEFGHIJK|\n",
+            "00000030  4c 4d 4e 4f                                       |LMNO|\n",
+        ));
+        assert_eq!(
+            hexdump(&[0x00, 0x7e, 0x20], 0),
+            "00000000  00 7e 20                                          |.~ |\n",
+        );
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        let accepts = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("gzip, deflate, br"));
+        assert!(accepts("br;q=1.0, GZIP;q=0.5"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("deflate, br"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        let compressible = |uri: &str| is_compressible(&uri.parse().unwrap());
+        assert!(compressible("/api/analysis-report/1234"));
+        assert!(compressible("/api/analysis-report/live?follow=false"));
+        assert!(!compressible("/api/analysis-report/live?follow=true"));
+        assert!(compressible("/api/qmdl-manifest?limit=10"));
+        assert!(compressible("/index.html"));
+        assert!(!compressible("/api/qmdl/1234.qmdl"));
+        assert!(!compressible("/api/pcap/1234.pcapng"));
+        assert!(!compressible("/metrics"));
+    }
+}
@@ -1,13 +1,16 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::qmdl_store::ManifestEntry;
 use crate::server::ServerState;
 
 use axum::Json;
-use axum::extract::State;
+use axum::extract::{Query, State};
 use axum::http::StatusCode;
 use log::error;
-use serde::Serialize;
+use rayhunter::diag::{DiagParsingError, Message};
+use serde::{Deserialize, Serialize};
 use tokio::process::Command;
 
 #[derive(Debug, Serialize)]
@@ -114,15 +117,203 @@ pub async fn get_system_stats(State(state): State<Arc<ServerState>>) -> Result<J
 #[derive(Serialize)]
 pub struct ManifestStats {
     pub entries: Vec<ManifestEntry>,
+    // how many entries there are in total, not counting the current one, so
+    // clients can tell how many pages there are
+    pub total: usize,
     pub current_entry: Option<ManifestEntry>,
 }
 
-pub async fn get_qmdl_manifest(State(state): State<Arc<ServerState>>) -> Result<Json<ManifestStats>, (StatusCode, String)> {
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ManifestSort {
+    #[default]
+    StartTime,
+    Size,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ManifestParams {
+    // how many entries to return. All of them are returned if unset.
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default)]
+    sort: ManifestSort,
+}
+
+// Sorts the entries newest (or largest) first, and returns the requested page
+// of them
+fn page_entries(mut entries: Vec<ManifestEntry>, params: &ManifestParams) -> Vec<ManifestEntry> {
+    match params.sort {
+        ManifestSort::StartTime => entries.sort_by_key(|entry| std::cmp::Reverse(entry.start_time)),
+        ManifestSort::Size => entries.sort_by_key(|entry| std::cmp::Reverse(entry.qmdl_size_bytes)),
+    }
+    entries.into_iter()
+        .skip(params.offset)
+        .take(params.limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
+pub async fn get_qmdl_manifest(
+    State(state): State<Arc<ServerState>>,
+    Query(params): Query<ManifestParams>,
+) -> Result<Json<ManifestStats>, (StatusCode, String)> {
     let qmdl_store = state.qmdl_store_lock.read().await;
     let mut entries = qmdl_store.manifest.entries.clone();
     let current_entry = qmdl_store.current_entry.map(|index| entries.remove(index));
     Ok(Json(ManifestStats {
-        entries,
+        total: entries.len(),
+        entries: page_entries(entries, &params),
         current_entry,
     }))
 }
+
+// Counts of each kind of diag message parsed during the current recording,
+// useful for checking that the log mask is getting us what we expect
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct MessageStats {
+    pub counts: HashMap<&'static str, u64>,
+}
+
+impl MessageStats {
+    pub fn record(&mut self, messages: &[Result<Message, DiagParsingError>]) {
+        for maybe_message in messages {
+            let name = match maybe_message {
+                Ok(Message::Log { body, .. }) => body.name(),
+                Ok(Message::Response { .. }) => "Response",
+                Err(_) => "ParsingError",
+            };
+            *self.counts.entry(name).or_insert(0) += 1;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.counts.clear();
+    }
+
+    pub fn merge(&mut self, other: &MessageStats) {
+        for (name, count) in &other.counts {
+            *self.counts.entry(name).or_insert(0) += count;
+        }
+    }
+}
+
+// Counters for the diag read loop, for telling whether we're keeping up with
+// the modem. Unlike MessageStats, these cover the daemon's whole lifetime.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DiagLoopStats {
+    pub containers_read: u64,
+    pub containers_processed: u64,
+    pub containers_skipped: u64,
+    pub led_updates_dropped: u64,
+    pub last_processing_time_ms: u64,
+    pub max_processing_time_ms: u64,
+}
+
+impl DiagLoopStats {
+    pub fn record_processing_time(&mut self, processing_time: Duration) {
+        let millis = processing_time.as_millis() as u64;
+        self.containers_processed += 1;
+        self.last_processing_time_ms = millis;
+        self.max_processing_time_ms = self.max_processing_time_ms.max(millis);
+    }
+
+    // Adds the counts from a batch of containers recorded separately
+    pub fn merge(&mut self, other: &DiagLoopStats) {
+        self.containers_read += other.containers_read;
+        self.containers_skipped += other.containers_skipped;
+        self.led_updates_dropped += other.led_updates_dropped;
+        if other.containers_processed > 0 {
+            self.containers_processed += other.containers_processed;
+            self.last_processing_time_ms = other.last_processing_time_ms;
+        }
+        self.max_processing_time_ms = self.max_processing_time_ms.max(other.max_processing_time_ms);
+    }
+}
+
+pub async fn get_diag_loop_stats(State(state): State<Arc<ServerState>>) -> Json<DiagLoopStats> {
+    Json(state.diag_loop_stats_lock.read().await.clone())
+}
+
+pub async fn get_message_stats(State(state): State<Arc<ServerState>>) -> Json<MessageStats> {
+    Json(state.message_stats_lock.read().await.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayhunter::diag::{LogBody, NrRrcOtaPacket, Timestamp};
+
+    fn make_log(body: LogBody) -> Result<Message, DiagParsingError> {
+        Ok(Message::Log {
+            pending_msgs: 0,
+            outer_length: 0,
+            inner_length: 0,
+            log_type: 0,
+            timestamp: Timestamp { ts: 0 },
+            body,
+        })
+    }
+
+    #[test]
+    fn test_message_stats() {
+        let mut stats = MessageStats::default();
+        stats.record(&[
+            make_log(LogBody::IpTraffic { msg: vec![] }),
+            make_log(LogBody::NrRrcOtaMessage { packet_version: 0, packet: NrRrcOtaPacket::Unknown { msg: vec![] } }),
+            make_log(LogBody::IpTraffic { msg: vec![] }),
+        ]);
+        assert_eq!(stats.counts.get("IpTraffic"), Some(&2));
+        assert_eq!(stats.counts.get("NrRrcOtaMessage"), Some(&1));
+        assert_eq!(stats.counts.get("LteRrcOtaMessage"), None);
+        stats.reset();
+        assert!(stats.counts.is_empty());
+    }
+
+    fn make_entry(name: &str, start_time: &str, qmdl_size_bytes: usize) -> ManifestEntry {
+        let start_time = chrono::DateTime::parse_from_rfc3339(start_time).unwrap().with_timezone(&chrono::Local);
+        ManifestEntry {
+            name: name.to_string(),
+            start_time,
+            last_message_time: None,
+            qmdl_size_bytes,
+            analysis_size_bytes: 0,
+            warning_count: 0,
+            rayhunter_version: None,
+            system_os: None,
+            arch: None,
+            display_name: None,
+            tags: Vec::new(),
+            observed_plmns: Vec::new(),
+        }
+    }
+
+    fn names(entries: &[ManifestEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_page_entries() {
+        let entries = vec![
+            make_entry("a", "2024-01-01T00:00:00Z", 300),
+            make_entry("b", "2024-01-03T00:00:00Z", 100),
+            make_entry("c", "2024-01-02T00:00:00Z", 200),
+        ];
+
+        // newest first by default
+        let page = page_entries(entries.clone(), &ManifestParams::default());
+        assert_eq!(names(&page), vec!["b", "c", "a"]);
+
+        let params = ManifestParams { sort: ManifestSort::Size, ..Default::default() };
+        assert_eq!(names(&page_entries(entries.clone(), &params)), vec!["a", "c", "b"]);
+
+        let params = ManifestParams { limit: Some(2), offset: 1, ..Default::default() };
+        assert_eq!(names(&page_entries(entries.clone(), &params)), vec!["c", "a"]);
+
+        // pages past the end are empty, rather than an error
+        let params = ManifestParams { limit: Some(2), offset: 3, ..Default::default() };
+        assert!(page_entries(entries.clone(), &params).is_empty());
+        let params = ManifestParams { limit: Some(0), ..Default::default() };
+        assert!(page_entries(entries, &params).is_empty());
+    }
+}
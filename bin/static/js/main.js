@@ -82,6 +82,22 @@ async function updateEntryAnalysisResult(entry) {
         entry.analysis_result = `!!! ${entry.analysis.warnings.length} warnings !!!`;
         for(warning of entry.analysis.warnings){
             msg = `${warning.timestamp}: ${warning.warning.events[1].message}`
+            const cell = [];
+            if (warning.warning.earfcn !== undefined) {
+                cell.push(`EARFCN ${warning.warning.earfcn}`)
+            }
+            if (warning.warning.band !== undefined) {
+                cell.push(warning.warning.band)
+            }
+            if (warning.warning.phy_cell_id !== undefined) {
+                cell.push(`PCI ${warning.warning.phy_cell_id}`)
+            }
+            if (warning.warning.tac !== undefined) {
+                cell.push(`TAC ${warning.warning.tac}`)
+            }
+            if (cell.length > 0) {
+                msg += ` (${cell.join(', ')})`
+            }
             console.log(msg)
             entry.analysis_result += `<br>${msg}`
         }
@@ -127,7 +143,10 @@ function createEntryRow(entry, isCurrent) {
     const row = document.createElement('tr');
     const name = document.createElement('th');
     name.scope = 'row';
-    name.innerText = entry.name;
+    name.innerText = entry.display_name ? `${entry.display_name} (${entry.name})` : entry.name;
+    if (entry.tags && entry.tags.length > 0) {
+        name.innerText += ` [${entry.tags.join(', ')}]`;
+    }
     row.appendChild(name);
 
     for (const key of ['start_time', 'last_message_time', 'qmdl_size_bytes']) {
@@ -183,8 +202,7 @@ async function getQmdlManifest() {
         entry.start_time = new Date(entry.start_time);
         entry.last_message_time = new Date(entry.last_message_time);
     }
-    // sort them in reverse chronological order
-    manifest.entries.reverse();
+    // the entries already come newest first
     return manifest;
 }
 
@@ -199,9 +217,21 @@ async function stopRecording() {
 }
 
 async function req(method, url) {
-    const response = await fetch(url, {
+    let response = await fetch(url, {
         method: method,
+        headers: authHeaders(),
     });
+    // the daemon's configured with an API token we don't have yet
+    if (response.status === 401) {
+        const token = prompt('This rayhunter requires an API token:');
+        if (token) {
+            localStorage.setItem('apiToken', token);
+            response = await fetch(url, {
+                method: method,
+                headers: authHeaders(),
+            });
+        }
+    }
     const body = await response.text();
     if (response.status >= 200 && response.status < 300) {
         return body;
@@ -209,3 +239,8 @@ async function req(method, url) {
         throw new Error(body);
     }
 }
+
+function authHeaders() {
+    const token = localStorage.getItem('apiToken');
+    return token ? { 'Authorization': `Bearer ${token}` } : {};
+}
@@ -1,16 +1,27 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use chrono::{DateTime, FixedOffset};
+use log::error;
 use serde::Serialize;
+use telcom_parser::lte_rrc::{BCCH_DL_SCH_MessageType, BCCH_DL_SCH_MessageType_c1};
+use thiserror::Error;
 
-use crate::{diag::MessagesContainer, gsmtap_parser};
+use crate::{diag::{earfcn_to_band, Band, DiagParsingError, LogBody, Message, MessagesContainer}, gsmtap_parser};
 use crate::util::RuntimeMetadata;
 
 use super::{
     imsi_requested::ImsiRequestedAnalyzer,
-    information_element::InformationElement,
+    information_element::{InformationElement, LteInformationElement},
     connection_redirect_downgrade::ConnectionRedirect2GDowngradeAnalyzer,
+    excessive_paging::ExcessivePagingAnalyzer,
     priority_2g_downgrade::LteSib6And7DowngradeAnalyzer,
     null_cipher::NullCipherAnalyzer,
+    nas_null_cipher::NasNullCipherAnalyzer,
+    observed_plmn::{sib1_plmns, ObservedPlmnAnalyzer, Plmn},
+    rat_downgrade::RatDowngradeAnalyzer,
+    rrc_reconfiguration_rate::RrcReconfigurationRateAnalyzer,
+    silent_sms::SilentSmsAnalyzer,
+    warning_rate::WarningRateAnalyzer,
 };
 
 /// Qualitative measure of how severe a Warning event type is.
@@ -18,7 +29,7 @@ use super::{
 ///   * Low: if combined with a large number of other Warnings, user should investigate
 ///   * Medium: if combined with a few other Warnings, user should investigate
 ///   * High: user should investigate
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Severity {
     Low,
     Medium,
@@ -43,6 +54,14 @@ pub struct Event {
     pub message: String,
 }
 
+/// An error from an [Analyzer] which leaves it unable to keep analyzing, e.g.
+/// because a message broke an assumption its state relies on.
+#[derive(Error, Debug)]
+pub enum AnalyzerError {
+    #[error("Unexpected message: {0}")]
+    UnexpectedMessage(String),
+}
+
 /// An [Analyzer] represents one type of heuristic for detecting an IMSI Catcher
 /// (IC). While maintaining some amount of state is useful, be mindful of how
 /// much memory your [Analyzer] uses at runtime, since rayhunter may run for
@@ -63,6 +82,23 @@ pub trait Analyzer {
     /// [Analyzer] updates per message, since it may be run over hundreds or
     /// thousands of them alongside many other [Analyzers](Analyzer).
     fn analyze_information_element(&mut self, ie: &InformationElement) -> Option<Event>;
+
+    /// Like [Analyzer::analyze_information_element], but also given the time
+    /// the message was logged, for heuristics which care about how often
+    /// something happens. This is what the [Harness] calls, and by default it
+    /// ignores the timestamp.
+    fn analyze_information_element_at(&mut self, ie: &InformationElement, _timestamp: DateTime<FixedOffset>) -> Option<Event> {
+        self.analyze_information_element(ie)
+    }
+
+    /// Like [Analyzer::analyze_information_element_at], but for heuristics
+    /// which can fail. This is what the [Harness] actually calls, and by
+    /// default it never fails. Returning an error disables the [Analyzer] for
+    /// the rest of the session, so heuristics should return one rather than
+    /// panic, which would take down the whole diag thread.
+    fn try_analyze_information_element_at(&mut self, ie: &InformationElement, timestamp: DateTime<FixedOffset>) -> Result<Option<Event>, AnalyzerError> {
+        Ok(self.analyze_information_element_at(ie, timestamp))
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -80,6 +116,20 @@ pub struct ReportMetadata {
 #[derive(Serialize, Debug, Clone)]
 pub struct PacketAnalysis {
     pub timestamp: DateTime<FixedOffset>,
+    /// The EARFCN (or NR-ARFCN) the packet was received on, and the band it
+    /// belongs to, for LTE and NR RRC packets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub earfcn: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub band: Option<Band>,
+    /// The physical cell ID of the cell which sent or received the packet, for
+    /// LTE and NR RRC packets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phy_cell_id: Option<u16>,
+    /// The tracking area code of that cell, if we've seen it broadcast in a
+    /// SIB1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tac: Option<u32>,
     pub events: Vec<Option<Event>>,
 }
 
@@ -107,38 +157,126 @@ impl AnalysisRow {
         }
         false
     }
+
+    /// Returns how many warnings were raised in this row.
+    pub fn warning_count(&self) -> usize {
+        self.analysis.iter()
+            .flat_map(|analysis| analysis.events.iter().flatten())
+            .filter(|event| matches!(event.event_type, EventType::QualitativeWarning { .. }))
+            .count()
+    }
+
+    /// Returns the highest [Severity] of any warning in this row, if any.
+    pub fn max_warning_severity(&self) -> Option<Severity> {
+        self.analysis.iter()
+            .flat_map(|analysis| analysis.events.iter().flatten())
+            .filter_map(|event| match event.event_type {
+                EventType::QualitativeWarning { severity } => Some(severity),
+                EventType::Informational => None,
+            })
+            .max()
+    }
+}
+
+/// Returns the tracking area code broadcast in a SIB1, if that's what the
+/// [InformationElement] is.
+fn sib1_tracking_area_code(ie: &InformationElement) -> Option<u32> {
+    let InformationElement::LTE(LteInformationElement::BcchDlSch(bcch_dl_sch_message)) = ie else {
+        return None;
+    };
+    let BCCH_DL_SCH_MessageType::C1(BCCH_DL_SCH_MessageType_c1::SystemInformationBlockType1(sib1)) = &bcch_dl_sch_message.message else {
+        return None;
+    };
+    let tac = sib1.cell_access_related_info.tracking_area_code.0.iter()
+        .fold(0, |tac, bit| (tac << 1) | *bit as u32);
+    Some(tac)
+}
+
+/// Every heuristic rayhunter ships with, along with the key used to enable or
+/// disable it in configs.
+pub fn all_analyzers() -> Vec<(&'static str, Box<dyn Analyzer + Send>)> {
+    vec![
+        ("imsi_requested", Box::new(ImsiRequestedAnalyzer::new())),
+        ("connection_redirect_2g_downgrade", Box::new(ConnectionRedirect2GDowngradeAnalyzer{})),
+        ("lte_sib6_and_7_downgrade", Box::new(LteSib6And7DowngradeAnalyzer{})),
+        ("null_cipher", Box::new(NullCipherAnalyzer{})),
+        ("nas_null_cipher", Box::new(NasNullCipherAnalyzer{})),
+        ("observed_plmn", Box::new(ObservedPlmnAnalyzer::new())),
+        ("excessive_paging", Box::new(ExcessivePagingAnalyzer::new())),
+        ("rat_downgrade", Box::new(RatDowngradeAnalyzer::new())),
+        ("rrc_reconfiguration_rate", Box::new(RrcReconfigurationRateAnalyzer::default())),
+        ("silent_sms", Box::new(SilentSmsAnalyzer{})),
+    ]
 }
 
 pub struct Harness {
     analyzers: Vec<Box<dyn Analyzer + Send>>,
+    // whether each analyzer has returned an error, in which case it's skipped for the
+    // rest of the session
+    disabled_analyzers: Vec<bool>,
+    warning_rate_analyzer: Option<WarningRateAnalyzer>,
+    // the tracking area codes broadcast by each cell we've seen a SIB1 from,
+    // keyed by EARFCN and physical cell ID
+    tracking_area_codes: HashMap<(u32, u16), u32>,
+    // every PLMN broadcast in a SIB1 so far, in the order they were first seen
+    observed_plmns: Vec<Plmn>,
 }
 
 impl Harness {
     pub fn new() -> Self {
-        Self { analyzers: Vec::new() }
+        Self {
+            analyzers: Vec::new(),
+            disabled_analyzers: Vec::new(),
+            warning_rate_analyzer: None,
+            tracking_area_codes: HashMap::new(),
+            observed_plmns: Vec::new(),
+        }
     }
 
     pub fn new_with_all_analyzers() -> Self {
-        let mut harness = Harness::new();
-        harness.add_analyzer(Box::new(ImsiRequestedAnalyzer::new()));
-        harness.add_analyzer(Box::new(ConnectionRedirect2GDowngradeAnalyzer{}));
-        harness.add_analyzer(Box::new(LteSib6And7DowngradeAnalyzer{}));
-        harness.add_analyzer(Box::new(NullCipherAnalyzer{}));
+        Harness::new_with_analyzers(|_| true)
+    }
 
+    /// Like [Harness::new_with_all_analyzers], but only adds the analyzers
+    /// whose key (see [all_analyzers]) `is_enabled` returns true for.
+    pub fn new_with_analyzers(is_enabled: impl Fn(&str) -> bool) -> Self {
+        let mut harness = Harness::new();
+        for (key, analyzer) in all_analyzers() {
+            if is_enabled(key) {
+                harness.add_analyzer(analyzer);
+            }
+        }
         harness
     }
 
+    /// Adds an [Analyzer] to run over every message, after those already
+    /// added. This is how heuristics which aren't in [all_analyzers] plug in,
+    /// e.g. ones specific to a single binary, or written by a contributor
+    /// before being added there.
     pub fn add_analyzer(&mut self, analyzer: Box<dyn Analyzer + Send>) {
         self.analyzers.push(analyzer);
+        self.disabled_analyzers.push(false);
+    }
+
+    /// Sets a [WarningRateAnalyzer] to run over the results of all the other
+    /// analyzers. Its events are appended after theirs.
+    pub fn set_warning_rate_analyzer(&mut self, analyzer: WarningRateAnalyzer) {
+        self.warning_rate_analyzer = Some(analyzer);
     }
 
     pub fn analyze_qmdl_messages(&mut self, container: MessagesContainer) -> AnalysisRow {
+        self.analyze_messages(container.into_messages())
+    }
+
+    /// Like [Harness::analyze_qmdl_messages], but for messages which have
+    /// already been parsed out of their container.
+    pub fn analyze_messages(&mut self, messages: Vec<Result<Message, DiagParsingError>>) -> AnalysisRow {
         let mut row = AnalysisRow {
             timestamp: chrono::Local::now().fixed_offset(),
             skipped_message_reasons: Vec::new(),
             analysis: Vec::new(),
         };
-        for maybe_qmdl_message in container.into_messages() {
+        for maybe_qmdl_message in messages {
             let qmdl_message = match maybe_qmdl_message {
                 Ok(msg) => msg,
                 Err(err) => {
@@ -147,30 +285,73 @@ impl Harness {
                 }
             };
 
-            let gsmtap_message = match gsmtap_parser::parse(qmdl_message) {
-                Ok(msg) => msg,
-                Err(err) => {
-                    row.skipped_message_reasons.push(format!("{:?}", err));
-                    continue;
-                }
+            let cell = match &qmdl_message {
+                Message::Log { body: LogBody::LteRrcOtaMessage { packet, .. }, .. } => Some((packet.get_earfcn(), packet.get_phy_cell_id())),
+                _ => None,
+            };
+            // NR cells are kept apart from LTE ones, since their ARFCNs can
+            // overlap and we don't decode their SIB1s for a TAC
+            let nr_cell = match &qmdl_message {
+                Message::Log { body: LogBody::NrRrcOtaMessage { packet, .. }, .. } => Some((packet.get_nr_arfcn(), packet.get_band(), packet.get_phy_cell_id())),
+                _ => None,
             };
 
-            let Some((timestamp, gsmtap_msg)) = gsmtap_message else {
-                continue;
+            let (timestamp, element) = match qmdl_message {
+                // we don't decode 2G/3G signalling yet, but knowing the modem
+                // is using those RATs at all is useful to heuristics
+                Message::Log { timestamp, body: LogBody::GsmRrSignallingMessage { .. }, .. } => (timestamp, InformationElement::GSM),
+                Message::Log { timestamp, body: LogBody::WcdmaSignallingMessage { .. }, .. } => (timestamp, InformationElement::UMTS),
+                Message::Log { timestamp, body: LogBody::NrRrcOtaMessage { .. }, .. } => (timestamp, InformationElement::FiveG),
+                qmdl_message => {
+                    let gsmtap_message = match gsmtap_parser::parse(qmdl_message) {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            row.skipped_message_reasons.push(format!("{:?}", err));
+                            continue;
+                        }
+                    };
+
+                    let Some((timestamp, gsmtap_msg)) = gsmtap_message else {
+                        continue;
+                    };
+
+                    match InformationElement::try_from(&gsmtap_msg) {
+                        Ok(element) => (timestamp, element),
+                        Err(err) => {
+                            row.skipped_message_reasons.push(format!("{:?}", err));
+                            continue;
+                        }
+                    }
+                },
             };
 
-            let element = match InformationElement::try_from(&gsmtap_msg) {
-                Ok(element) => element,
-                Err(err) => {
-                    row.skipped_message_reasons.push(format!("{:?}", err));
-                    continue;
+            if let (Some(cell), Some(tac)) = (cell, sib1_tracking_area_code(&element)) {
+                self.tracking_area_codes.insert(cell, tac);
+            }
+            for plmn in sib1_plmns(&element) {
+                if !self.observed_plmns.contains(&plmn) {
+                    self.observed_plmns.push(plmn);
                 }
-            };
+            }
 
-            let analysis_result = self.analyze_information_element(&element);
+            let timestamp = timestamp.to_datetime();
+            let mut analysis_result = self.analyze_information_element(&element, timestamp);
+            if let Some(warning_rate_analyzer) = self.warning_rate_analyzer.as_mut() {
+                let maybe_event = warning_rate_analyzer.analyze_events(timestamp, &analysis_result);
+                analysis_result.push(maybe_event);
+            }
             if analysis_result.iter().any(Option::is_some) {
+                let (earfcn, band, phy_cell_id) = match (cell, nr_cell) {
+                    (Some((earfcn, phy_cell_id)), _) => (Some(earfcn), earfcn_to_band(earfcn), Some(phy_cell_id)),
+                    (None, Some(nr_cell)) => nr_cell,
+                    (None, None) => (None, None, None),
+                };
                 row.analysis.push(PacketAnalysis {
-                    timestamp: timestamp.to_datetime(),
+                    timestamp,
+                    earfcn,
+                    band,
+                    phy_cell_id,
+                    tac: cell.and_then(|cell| self.tracking_area_codes.get(&cell).copied()),
                     events: analysis_result,
                 });
             }
@@ -178,21 +359,50 @@ impl Harness {
         row
     }
 
-    fn analyze_information_element(&mut self, ie: &InformationElement) -> Vec<Option<Event>> {
+    /// Runs each analyzer over the [InformationElement]. If an analyzer returns
+    /// an error, it's disabled for the rest of the session and an [Event]
+    /// saying so is returned in its place, so one broken heuristic can't stop
+    /// the others from running.
+    fn analyze_information_element(&mut self, ie: &InformationElement, timestamp: DateTime<FixedOffset>) -> Vec<Option<Event>> {
         self.analyzers.iter_mut()
-            .map(|analyzer| analyzer.analyze_information_element(ie))
+            .zip(self.disabled_analyzers.iter_mut())
+            .map(|(analyzer, disabled)| {
+                if *disabled {
+                    return None;
+                }
+                match analyzer.try_analyze_information_element_at(ie, timestamp) {
+                    Ok(maybe_event) => maybe_event,
+                    Err(err) => {
+                        *disabled = true;
+                        let name = analyzer.get_name();
+                        error!("heuristic \"{}\" failed analyzing {:?}, disabling it: {}", name, ie, err);
+                        Some(Event {
+                            event_type: EventType::Informational,
+                            message: format!("heuristic \"{}\" failed and has been disabled for the rest of this session", name),
+                        })
+                    },
+                }
+            })
             .collect()
     }
 
+    /// The distinct PLMNs broadcast in any SIB1 analyzed so far, whether or
+    /// not [ObservedPlmnAnalyzer] is enabled.
+    pub fn observed_plmns(&self) -> &[Plmn] {
+        &self.observed_plmns
+    }
+
     pub fn get_names(&self) -> Vec<Cow<'_, str>> {
         self.analyzers.iter()
             .map(|analyzer| analyzer.get_name())
+            .chain(self.warning_rate_analyzer.iter().map(|analyzer| analyzer.get_name()))
             .collect()
     }
 
     pub fn get_descriptions(&self) -> Vec<Cow<'_, str>> {
         self.analyzers.iter()
             .map(|analyzer| analyzer.get_description())
+            .chain(self.warning_rate_analyzer.iter().map(|analyzer| analyzer.get_description()))
             .collect()
     }
 
@@ -215,3 +425,262 @@ impl Harness {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingAnalyzer;
+
+    impl Analyzer for FailingAnalyzer {
+        fn get_name(&self) -> Cow<'_, str> {
+            Cow::from("Failing")
+        }
+
+        fn get_description(&self) -> Cow<'_, str> {
+            Cow::from("Always fails")
+        }
+
+        fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+            None
+        }
+
+        fn try_analyze_information_element_at(&mut self, _ie: &InformationElement, _timestamp: DateTime<FixedOffset>) -> Result<Option<Event>, AnalyzerError> {
+            Err(AnalyzerError::UnexpectedMessage("oh no".to_string()))
+        }
+    }
+
+    struct InfoAnalyzer;
+
+    impl Analyzer for InfoAnalyzer {
+        fn get_name(&self) -> Cow<'_, str> {
+            Cow::from("Info")
+        }
+
+        fn get_description(&self) -> Cow<'_, str> {
+            Cow::from("Always returns an informational event")
+        }
+
+        fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+            Some(Event {
+                event_type: EventType::Informational,
+                message: "info".to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_failing_analyzer_is_disabled() {
+        let mut harness = Harness::new();
+        harness.add_analyzer(Box::new(FailingAnalyzer));
+        harness.add_analyzer(Box::new(InfoAnalyzer));
+        let timestamp = chrono::Local::now().fixed_offset();
+
+        let events = harness.analyze_information_element(&InformationElement::GSM, timestamp);
+        assert_eq!(events.len(), 2);
+        let failure_event = events[0].as_ref().expect("expected an event for the failing analyzer");
+        assert!(matches!(failure_event.event_type, EventType::Informational));
+        assert!(failure_event.message.contains("Failing"));
+        assert_eq!(events[1].as_ref().unwrap().message, "info");
+
+        // the failing analyzer shouldn't run again, but the others should
+        let events = harness.analyze_information_element(&InformationElement::GSM, timestamp);
+        assert_eq!(events.len(), 2);
+        assert!(events[0].is_none());
+        assert_eq!(events[1].as_ref().unwrap().message, "info");
+    }
+
+    fn lte_rrc_message(phy_cell_id: u16, pdu_num: u8, payload: &[u8]) -> Message {
+        Message::Log {
+            pending_msgs: 0,
+            outer_length: 0,
+            inner_length: 0,
+            timestamp: crate::diag::Timestamp { ts: 0 },
+            log_type: 0xb0c0,
+            body: LogBody::LteRrcOtaMessage {
+                ext_header_version: 26,
+                packet: crate::diag::LteRrcOtaPacket::V25 {
+                    rrc_rel_maj: 15,
+                    rrc_rel_min: 64,
+                    nr_rrc_rel_maj: 15,
+                    nr_rrc_rel_min: 64,
+                    bearer_id: 1,
+                    phy_cell_id,
+                    earfcn: 1811,
+                    sfn_subfn: 0,
+                    pdu_num,
+                    sib_mask: 0,
+                    len: payload.len() as u16,
+                    packet: payload.to_vec(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_cell_identity() {
+        // a SIB1 for PLMN 310-410, with TAC 0x1234
+        let sib1 = [0x40, 0x4c, 0x42, 0x82, 0x11, 0x23, 0x41, 0xa2, 0xb3, 0xc4, 0x81, 0x81, 0x00, 0x00, 0x00];
+        // an empty paging message
+        let paging = [0x00];
+        let mut harness = Harness::new();
+        harness.add_analyzer(Box::new(InfoAnalyzer));
+
+        let row = harness.analyze_messages(vec![
+            Ok(lte_rrc_message(270, 3, &sib1)),
+            Ok(lte_rrc_message(270, 7, &paging)),
+            Ok(lte_rrc_message(271, 7, &paging)),
+        ]);
+        assert!(row.skipped_message_reasons.is_empty(), "{:?}", row.skipped_message_reasons);
+        let cells: Vec<_> = row.analysis.iter()
+            .map(|analysis| (analysis.earfcn, analysis.phy_cell_id, analysis.tac))
+            .collect();
+        assert_eq!(cells, vec![
+            (Some(1811), Some(270), Some(0x1234)),
+            (Some(1811), Some(270), Some(0x1234)),
+            // we haven't seen this cell's SIB1
+            (Some(1811), Some(271), None),
+        ]);
+
+        let json = serde_json::to_value(&row.analysis[0]).unwrap();
+        assert_eq!(json["earfcn"], 1811);
+        assert_eq!(json["band"], "B3");
+        assert_eq!(json["phy_cell_id"], 270);
+        assert_eq!(json["tac"], 0x1234);
+        let json = serde_json::to_value(&row.analysis[2]).unwrap();
+        assert!(json.get("tac").is_none());
+    }
+
+    fn nr_rrc_message(phy_cell_id: u16, nr_arfcn: u32) -> Message {
+        Message::Log {
+            pending_msgs: 0,
+            outer_length: 0,
+            inner_length: 0,
+            timestamp: crate::diag::Timestamp { ts: 0 },
+            log_type: 0xb821,
+            body: LogBody::NrRrcOtaMessage {
+                packet_version: 9,
+                packet: crate::diag::NrRrcOtaPacket::V9 {
+                    rrc_rel_maj: 15,
+                    rrc_rel_min: 3,
+                    bearer_id: 1,
+                    phy_cell_id,
+                    nr_arfcn,
+                    sfn_subfn: 0,
+                    pdu_num: 1,
+                    sib_mask: 0,
+                    len: 1,
+                    packet: vec![0x00],
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_nr_cell_identity() {
+        let mut harness = Harness::new();
+        harness.add_analyzer(Box::new(InfoAnalyzer));
+        let row = harness.analyze_messages(vec![Ok(nr_rrc_message(273, 630000))]);
+        assert!(row.skipped_message_reasons.is_empty(), "{:?}", row.skipped_message_reasons);
+        assert_eq!(row.analysis.len(), 1);
+        let analysis = &row.analysis[0];
+        assert_eq!(analysis.earfcn, Some(630000));
+        assert_eq!(analysis.band, Some(crate::diag::Band::Nr(78)));
+        assert_eq!(analysis.phy_cell_id, Some(273));
+        assert_eq!(analysis.tac, None);
+
+        let json = serde_json::to_value(analysis).unwrap();
+        assert_eq!(json["band"], "n78");
+    }
+
+    fn gsm_rr_message() -> Message {
+        Message::Log {
+            pending_msgs: 0,
+            outer_length: 0,
+            inner_length: 0,
+            timestamp: crate::diag::Timestamp { ts: 0 },
+            log_type: 0x512f,
+            body: LogBody::GsmRrSignallingMessage {
+                channel_type: 0,
+                message_type: 0,
+                length: 0,
+                msg: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_gsm_signalling_reaches_analyzers() {
+        let mut harness = Harness::new();
+        harness.add_analyzer(Box::new(RatDowngradeAnalyzer::new()));
+        let row = harness.analyze_messages(vec![
+            Ok(lte_rrc_message(270, 7, &[0x00])),
+            Ok(gsm_rr_message()),
+        ]);
+        assert!(row.skipped_message_reasons.is_empty(), "{:?}", row.skipped_message_reasons);
+        assert_eq!(row.max_warning_severity(), Some(Severity::High));
+        // GSM packets aren't tied to an LTE cell
+        assert_eq!(row.analysis.len(), 1);
+        assert_eq!(row.analysis[0].earfcn, None);
+    }
+
+    // counts the messages it's given, somewhere the test can still see them
+    // once the harness owns the analyzer
+    struct CountingAnalyzer {
+        count: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Analyzer for CountingAnalyzer {
+        fn get_name(&self) -> Cow<'_, str> {
+            Cow::from("Counting")
+        }
+
+        fn get_description(&self) -> Cow<'_, str> {
+            Cow::from("Counts the messages it sees")
+        }
+
+        fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+            self.count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            None
+        }
+    }
+
+    #[test]
+    fn test_custom_analyzer() {
+        let count = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut harness = Harness::new_with_all_analyzers();
+        harness.add_analyzer(Box::new(CountingAnalyzer { count: count.clone() }));
+        assert_eq!(harness.get_names().last().unwrap(), "Counting");
+        assert!(harness.get_metadata().analyzers.iter().any(|analyzer| analyzer.description == "Counts the messages it sees"));
+
+        harness.analyze_messages(vec![
+            Ok(lte_rrc_message(270, 7, &[0x00])),
+            Ok(lte_rrc_message(270, 7, &[0x00])),
+            Ok(gsm_rr_message()),
+        ]);
+        assert_eq!(count.load(std::sync::atomic::Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_disabled_analyzers() {
+        let all_keys: Vec<&str> = all_analyzers().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(Harness::new_with_all_analyzers().get_names().len(), all_keys.len());
+
+        let mut harness = Harness::new_with_analyzers(|key| key != "rat_downgrade");
+        assert_eq!(harness.get_names().len(), all_keys.len() - 1);
+        assert!(!harness.get_names().iter().any(|name| name == &RatDowngradeAnalyzer::new().get_name()));
+        let row = harness.analyze_messages(vec![
+            Ok(lte_rrc_message(270, 7, &[0x00])),
+            Ok(gsm_rr_message()),
+        ]);
+        assert_eq!(row.warning_count(), 0);
+
+        // the same messages do raise a warning with it enabled
+        let mut harness = Harness::new_with_analyzers(|key| key == "rat_downgrade");
+        let row = harness.analyze_messages(vec![
+            Ok(lte_rrc_message(270, 7, &[0x00])),
+            Ok(gsm_rr_message()),
+        ]);
+        assert_eq!(row.warning_count(), 1);
+    }
+}
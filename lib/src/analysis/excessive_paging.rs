@@ -0,0 +1,245 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use chrono::{DateTime, Duration, FixedOffset};
+use telcom_parser::lte_rrc::{PCCH_MessageType, PCCH_MessageType_c1, PagingUE_Identity, IMSI};
+
+use super::analyzer::{Analyzer, Event, EventType, Severity};
+use super::information_element::{InformationElement, LteInformationElement};
+
+const PAGING_THRESHOLD: usize = 10;
+const PAGING_WINDOW_SECS: i64 = 60;
+
+/// Watches the paging channel for any single identity being paged more than
+/// PAGING_THRESHOLD times within PAGING_WINDOW_SECS. An IMSI catcher trying to
+/// locate a target may page it over and over, whereas a legitimate network
+/// only pages a UE when it has something to deliver.
+pub struct ExcessivePagingAnalyzer {
+    threshold: usize,
+    window: Duration,
+    recent_pages: HashMap<String, VecDeque<DateTime<FixedOffset>>>,
+    // identities we've already warned about during their current burst
+    warned: HashSet<String>,
+}
+
+impl ExcessivePagingAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            threshold: PAGING_THRESHOLD,
+            window: Duration::seconds(PAGING_WINDOW_SECS),
+            recent_pages: HashMap::new(),
+            warned: HashSet::new(),
+        }
+    }
+
+    fn forget_old_pages(&mut self, now: DateTime<FixedOffset>) {
+        let window = self.window;
+        self.recent_pages.retain(|_, pages| {
+            while pages.front().is_some_and(|oldest| now - *oldest > window) {
+                pages.pop_front();
+            }
+            !pages.is_empty()
+        });
+        let recent_pages = &self.recent_pages;
+        let threshold = self.threshold;
+        self.warned.retain(|identity| {
+            recent_pages.get(identity).is_some_and(|pages| pages.len() > threshold)
+        });
+    }
+}
+
+impl Default for ExcessivePagingAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bits_to_u64(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.fold(0, |acc, bit| (acc << 1) | bit as u64)
+}
+
+/// Uniquely identifies a paged UE, for counting its pages. This is the full
+/// identity, so it mustn't end up in a report; see [redact_identity].
+fn identity_key(identity: &PagingUE_Identity) -> String {
+    match identity {
+        PagingUE_Identity::S_TMSI(s_tmsi) => format!(
+            "S-TMSI {:02x}-{:08x}",
+            bits_to_u64(s_tmsi.mmec.0.iter().by_vals()),
+            bits_to_u64(s_tmsi.m_tmsi.0.iter().by_vals()),
+        ),
+        PagingUE_Identity::Imsi(imsi) => format!("IMSI {}", imsi_digits(imsi)),
+        PagingUE_Identity::Ng_5G_S_TMSI_r15(ng_s_tmsi) => format!(
+            "5G-S-TMSI {:012x}",
+            bits_to_u64(ng_s_tmsi.0.iter().by_vals()),
+        ),
+        PagingUE_Identity::FullI_RNTI_r15(i_rnti) => format!(
+            "I-RNTI {:010x}",
+            bits_to_u64(i_rnti.0.iter().by_vals()),
+        ),
+    }
+}
+
+fn imsi_digits(imsi: &IMSI) -> String {
+    imsi.0.iter().map(|digit| digit.0.to_string()).collect()
+}
+
+/// Keeps the first `keep_start` and last `keep_end` characters of `s`,
+/// masking everything in between.
+fn mask(s: &str, keep_start: usize, keep_end: usize) -> String {
+    let len = s.chars().count();
+    s.chars().enumerate()
+        .map(|(i, c)| if i < keep_start || i + keep_end >= len { c } else { '*' })
+        .collect()
+}
+
+/// Describes a paged UE without revealing who it is, since analysis reports
+/// get shared. IMSIs keep their MCC and (2 digit) MNC, which only say which
+/// network the subscriber belongs to, and temporary identities keep the
+/// parts identifying the core network node that assigned them. Both keep
+/// their last 2 digits, so different UEs in the same report can still be
+/// told apart.
+fn redact_identity(identity: &PagingUE_Identity) -> String {
+    match identity {
+        PagingUE_Identity::S_TMSI(s_tmsi) => format!(
+            "S-TMSI {:02x}-{}",
+            bits_to_u64(s_tmsi.mmec.0.iter().by_vals()),
+            mask(&format!("{:08x}", bits_to_u64(s_tmsi.m_tmsi.0.iter().by_vals())), 0, 2),
+        ),
+        PagingUE_Identity::Imsi(imsi) => format!("IMSI {}", mask(&imsi_digits(imsi), 5, 2)),
+        // the AMF set and pointer take up the first 16 bits
+        PagingUE_Identity::Ng_5G_S_TMSI_r15(ng_s_tmsi) => format!(
+            "5G-S-TMSI {}",
+            mask(&format!("{:012x}", bits_to_u64(ng_s_tmsi.0.iter().by_vals())), 4, 2),
+        ),
+        PagingUE_Identity::FullI_RNTI_r15(i_rnti) => format!(
+            "I-RNTI {}",
+            mask(&format!("{:010x}", bits_to_u64(i_rnti.0.iter().by_vals())), 0, 2),
+        ),
+    }
+}
+
+impl Analyzer for ExcessivePagingAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("Excessive Paging")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from(format!(
+            "Tests whether any identity is paged more than {} times within {} seconds. Busy UEs (e.g. with many incoming calls or push notifications) can cause false positives.",
+            self.threshold,
+            self.window.num_seconds(),
+        ))
+    }
+
+    // paging rates only make sense with timestamps, see below
+    fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+        None
+    }
+
+    fn analyze_information_element_at(&mut self, ie: &InformationElement, timestamp: DateTime<FixedOffset>) -> Option<Event> {
+        let InformationElement::LTE(LteInformationElement::PCCH(pcch_message)) = ie else {
+            return None;
+        };
+        let PCCH_MessageType::C1(PCCH_MessageType_c1::Paging(paging)) = &pcch_message.message else {
+            return None;
+        };
+        let paging_records = paging.paging_record_list.as_ref()?;
+
+        self.forget_old_pages(timestamp);
+        let mut busiest: Option<(&PagingUE_Identity, usize)> = None;
+        for record in &paging_records.0 {
+            let key = identity_key(&record.ue_identity);
+            let pages = self.recent_pages.entry(key.clone()).or_default();
+            pages.push_back(timestamp);
+            let count = pages.len();
+            if count > self.threshold && !self.warned.contains(&key) {
+                self.warned.insert(key);
+                if busiest.as_ref().is_none_or(|(_, max)| count > *max) {
+                    busiest = Some((&record.ue_identity, count));
+                }
+            }
+        }
+
+        let (identity, count) = busiest?;
+        let window_secs = self.window.num_seconds();
+        Some(Event {
+            event_type: EventType::QualitativeWarning { severity: Severity::Medium },
+            message: format!(
+                "{} was paged {} times within {} seconds ({:.1} pages/minute)",
+                redact_identity(identity),
+                count,
+                window_secs,
+                count as f64 * 60.0 / window_secs as f64,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telcom_parser::lte_rrc::{IMSI_Digit, PCCH_Message, Paging, PagingRecord, PagingRecordCn_Domain, PagingRecordList};
+
+    fn paging(imsis: &[&str]) -> InformationElement {
+        let records = imsis.iter()
+            .map(|imsi| PagingRecord {
+                ue_identity: PagingUE_Identity::Imsi(IMSI(
+                    imsi.chars().map(|c| IMSI_Digit(c.to_digit(10).unwrap() as u8)).collect()
+                )),
+                cn_domain: PagingRecordCn_Domain(PagingRecordCn_Domain::PS),
+            })
+            .collect();
+        InformationElement::LTE(LteInformationElement::PCCH(PCCH_Message {
+            message: PCCH_MessageType::C1(PCCH_MessageType_c1::Paging(Paging {
+                paging_record_list: Some(PagingRecordList(records)),
+                system_info_modification: None,
+                etws_indication: None,
+                non_critical_extension: None,
+            })),
+        }))
+    }
+
+    #[test]
+    fn test_paging_burst() {
+        let mut analyzer = ExcessivePagingAnalyzer::new();
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        let target = "001010123456789";
+
+        // a burst of pages for the target, with some unrelated ones mixed in
+        for i in 0..PAGING_THRESHOLD as i64 {
+            let ie = paging(&[target, "001010000000001"]);
+            assert!(analyzer.analyze_information_element_at(&ie, start + Duration::seconds(i)).is_none());
+        }
+        let event = analyzer.analyze_information_element_at(&paging(&[target]), start + Duration::seconds(10))
+            .expect("expected a warning");
+        assert!(matches!(event.event_type, EventType::QualitativeWarning { severity: Severity::Medium }));
+        assert!(event.message.contains("IMSI 00101********89"));
+        assert!(!event.message.contains(target));
+        assert!(event.message.contains("11 times"));
+
+        // the same burst only warns once
+        assert!(analyzer.analyze_information_element_at(&paging(&[target]), start + Duration::seconds(11)).is_none());
+
+        // once the burst has aged out of the window, pages are counted afresh
+        let later = start + Duration::seconds(200);
+        assert!(analyzer.analyze_information_element_at(&paging(&[target]), later).is_none());
+    }
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(mask("001010123456789", 5, 2), "00101********89");
+        assert_eq!(mask("0123abcd", 0, 2), "******cd");
+        // nothing to mask if it's too short
+        assert_eq!(mask("123", 5, 2), "123");
+    }
+
+    #[test]
+    fn test_spread_out_paging() {
+        let mut analyzer = ExcessivePagingAnalyzer::new();
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        for i in 0..50 {
+            let ie = paging(&["001010123456789"]);
+            assert!(analyzer.analyze_information_element_at(&ie, start + Duration::seconds(i * 30)).is_none());
+        }
+    }
+}
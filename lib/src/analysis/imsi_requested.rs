@@ -26,7 +26,7 @@ impl Analyzer for ImsiRequestedAnalyzer {
 
     fn analyze_information_element(&mut self, ie: &InformationElement) -> Option<Event> {
         self.packet_num += 1;
-        let InformationElement::LTE(LteInformationElement::NAS(payload)) = ie else {
+        let InformationElement::LTE(LteInformationElement::NAS { payload, .. }) = ie else {
             return None;
         };
 
@@ -41,7 +41,10 @@ pub enum LteInformationElement {
     SbcchSlBchV2x(lte_rrc::SBCCH_SL_BCH_Message_V2X_r14),
 
     // FIXME: actually parse NAS messages
-    NAS(Vec<u8>),
+    NAS {
+        uplink: bool,
+        payload: Vec<u8>,
+    },
 
     // FIXME: unclear which message these "NB" types map to
     //DlCcchNb(),
@@ -83,7 +86,10 @@ impl TryFrom<&GsmtapMessage> for InformationElement {
                 Ok(InformationElement::LTE(lte))
             },
             GsmtapType::LteNas(LteNasSubtype::Plain) => {
-                Ok(InformationElement::LTE(LteInformationElement::NAS(gsmtap_msg.payload.clone())))
+                Ok(InformationElement::LTE(LteInformationElement::NAS {
+                    uplink: gsmtap_msg.header.uplink,
+                    payload: gsmtap_msg.payload.clone(),
+                }))
             },
             _ => Err(InformationElementError::UnsupportedGsmtapType(gsmtap_msg.header.gsmtap_type)),
         }
@@ -2,7 +2,14 @@ pub mod analyzer;
 pub mod information_element;
 pub mod priority_2g_downgrade;
 pub mod connection_redirect_downgrade;
+pub mod excessive_paging;
 pub mod imsi_provided;
 pub mod imsi_requested;
+pub mod nas_null_cipher;
 pub mod null_cipher;
+pub mod observed_plmn;
+pub mod rat_downgrade;
+pub mod rrc_reconfiguration_rate;
+pub mod silent_sms;
 pub mod util;
+pub mod warning_rate;
@@ -0,0 +1,105 @@
+use std::borrow::Cow;
+
+use super::analyzer::{Analyzer, Event, EventType, Severity};
+use super::information_element::{InformationElement, LteInformationElement};
+use super::util::plain_emm_message;
+
+const SECURITY_MODE_COMMAND: u8 = 0x5d;
+
+/// Looks for EMM Security Mode Commands from the network selecting the null
+/// ciphering (EEA0) or null integrity (EIA0) algorithm. This is the NAS
+/// counterpart to the [NullCipherAnalyzer](super::null_cipher::NullCipherAnalyzer),
+/// which checks the RRC layer.
+pub struct NasNullCipherAnalyzer {
+}
+
+/// Returns the (ciphering, integrity) algorithms selected by a NAS message, if
+/// it's an EMM Security Mode Command.
+fn selected_security_algorithms(payload: &[u8]) -> Option<(u8, u8)> {
+    let message = plain_emm_message(payload)?;
+    if *message.get(1)? != SECURITY_MODE_COMMAND {
+        return None;
+    }
+    // the selected NAS security algorithms IE: a spare bit, 3 bits of
+    // ciphering algorithm, a spare bit, then 3 bits of integrity algorithm
+    let algorithms = *message.get(2)?;
+    Some(((algorithms >> 4) & 0x07, algorithms & 0x07))
+}
+
+impl Analyzer for NasNullCipherAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("NAS Null Cipher")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from("Tests whether the network's NAS Security Mode Command selects null ciphering (EEA0) or null integrity protection (EIA0)")
+    }
+
+    fn analyze_information_element(&mut self, ie: &InformationElement) -> Option<Event> {
+        let InformationElement::LTE(LteInformationElement::NAS { uplink: false, payload }) = ie else {
+            return None;
+        };
+        let (ciphering, integrity) = selected_security_algorithms(payload)?;
+        let message = match (ciphering, integrity) {
+            (0, 0) => "NAS Security Mode Command selected null ciphering (EEA0) and null integrity protection (EIA0)",
+            (0, _) => "NAS Security Mode Command selected null ciphering (EEA0)",
+            (_, 0) => "NAS Security Mode Command selected null integrity protection (EIA0)",
+            _ => return None,
+        };
+        Some(Event {
+            event_type: EventType::QualitativeWarning { severity: Severity::High },
+            message: message.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A Security Mode Command selecting EEA0 and EIA2, still wrapped in its
+    // security protected header (integrity protected with a new EPS security
+    // context)
+    const NULL_CIPHER_SMC: &[u8] = &[0x37, 0x5b, 0x2e, 0x9a, 0x01, 0x00, 0x07, 0x5d, 0x02, 0x00, 0x02, 0xe0, 0xe0];
+
+    fn nas(uplink: bool, payload: &[u8]) -> InformationElement {
+        InformationElement::LTE(LteInformationElement::NAS { uplink, payload: payload.to_vec() })
+    }
+
+    #[test]
+    fn test_selected_security_algorithms() {
+        assert_eq!(selected_security_algorithms(NULL_CIPHER_SMC), Some((0, 2)));
+        // the same message, without the security protected header
+        assert_eq!(selected_security_algorithms(&NULL_CIPHER_SMC[6..]), Some((0, 2)));
+        // EEA1 and EIA1
+        assert_eq!(selected_security_algorithms(&[0x07, 0x5d, 0x11, 0x00]), Some((1, 1)));
+        // an identity request
+        assert_eq!(selected_security_algorithms(&[0x07, 0x55, 0x01]), None);
+        // an ESM message
+        assert_eq!(selected_security_algorithms(&[0x02, 0x5d, 0x00]), None);
+        // truncated
+        assert_eq!(selected_security_algorithms(&[0x37, 0x5b]), None);
+        assert_eq!(selected_security_algorithms(&[0x07, 0x5d]), None);
+        assert_eq!(selected_security_algorithms(&[]), None);
+    }
+
+    #[test]
+    fn test_null_cipher_smc() {
+        let mut analyzer = NasNullCipherAnalyzer {};
+        let event = analyzer.analyze_information_element(&nas(false, NULL_CIPHER_SMC))
+            .expect("expected a warning");
+        assert!(matches!(event.event_type, EventType::QualitativeWarning { severity: Severity::High }));
+        assert!(event.message.contains("EEA0"));
+        assert!(!event.message.contains("EIA0"));
+
+        let event = analyzer.analyze_information_element(&nas(false, &[0x07, 0x5d, 0x10, 0x00]))
+            .expect("expected a warning");
+        assert!(event.message.contains("EIA0"));
+        assert!(!event.message.contains("EEA0"));
+
+        // a secure SMC isn't a problem
+        assert!(analyzer.analyze_information_element(&nas(false, &[0x07, 0x5d, 0x22, 0x00])).is_none());
+        // only the network sends these, so ignore anything uplink
+        assert!(analyzer.analyze_information_element(&nas(true, NULL_CIPHER_SMC)).is_none());
+    }
+}
@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use telcom_parser::lte_rrc::{BCCH_DL_SCH_MessageType, BCCH_DL_SCH_MessageType_c1, PLMN_IdentityList};
+
+use super::analyzer::{Analyzer, Event, EventType};
+use super::information_element::{InformationElement, LteInformationElement};
+
+// A handful of well-known networks. This is only meant to make reports easier
+// to read, so it's fine for it to be incomplete.
+const KNOWN_OPERATORS: [(&str, &str, &str); 14] = [
+    ("001", "01", "Test Network"),
+    ("208", "01", "Orange France"),
+    ("234", "10", "O2 UK"),
+    ("234", "15", "Vodafone UK"),
+    ("234", "30", "EE"),
+    ("262", "01", "Telekom Deutschland"),
+    ("262", "02", "Vodafone Germany"),
+    ("302", "220", "Telus"),
+    ("302", "610", "Bell"),
+    ("302", "720", "Rogers"),
+    ("310", "120", "Sprint"),
+    ("310", "260", "T-Mobile US"),
+    ("310", "410", "AT&T"),
+    ("311", "480", "Verizon"),
+];
+
+/// A Public Land Mobile Network identity, i.e. an MCC/MNC pair. Digits are
+/// kept as strings since 2 and 3 digit MNCs (e.g. "01" and "001") are distinct.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Plmn {
+    pub mcc: String,
+    pub mnc: String,
+}
+
+impl Plmn {
+    pub fn new(mcc: &str, mnc: &str) -> Self {
+        Plmn { mcc: mcc.to_string(), mnc: mnc.to_string() }
+    }
+
+    pub fn operator_name(&self) -> Option<&'static str> {
+        KNOWN_OPERATORS.iter()
+            .find(|(mcc, mnc, _)| *mcc == self.mcc && *mnc == self.mnc)
+            .map(|(_, _, name)| *name)
+    }
+}
+
+impl fmt::Display for Plmn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.mcc, self.mnc)?;
+        if let Some(name) = self.operator_name() {
+            write!(f, " ({})", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts a SIB1 PLMN identity list into [Plmn]s. Per 36.331, an entry
+/// without an MCC uses the MCC of the entry before it.
+pub fn plmns_from_identity_list(list: &PLMN_IdentityList) -> Vec<Plmn> {
+    let mut plmns = Vec::new();
+    let mut last_mcc: Option<String> = None;
+    for info in &list.0 {
+        let identity = &info.plmn_identity;
+        if let Some(mcc) = &identity.mcc {
+            last_mcc = Some(mcc.0.iter().map(|digit| digit.0.to_string()).collect());
+        }
+        let Some(mcc) = last_mcc.as_ref() else {
+            continue;
+        };
+        let mnc: String = identity.mnc.0.iter().map(|digit| digit.0.to_string()).collect();
+        plmns.push(Plmn::new(mcc, &mnc));
+    }
+    plmns
+}
+
+/// The PLMNs broadcast in a SIB1, or none if `ie` isn't one.
+pub fn sib1_plmns(ie: &InformationElement) -> Vec<Plmn> {
+    let InformationElement::LTE(LteInformationElement::BcchDlSch(bcch_dl_sch_message)) = ie else {
+        return Vec::new();
+    };
+    let BCCH_DL_SCH_MessageType::C1(BCCH_DL_SCH_MessageType_c1::SystemInformationBlockType1(sib1)) = &bcch_dl_sch_message.message else {
+        return Vec::new();
+    };
+    plmns_from_identity_list(&sib1.cell_access_related_info.plmn_identity_list)
+}
+
+/// Emits an informational event the first time each PLMN is broadcast in a
+/// SIB1, so reports list which networks were seen during a recording.
+pub struct ObservedPlmnAnalyzer {
+    seen: Vec<Plmn>,
+}
+
+impl ObservedPlmnAnalyzer {
+    pub fn new() -> Self {
+        Self { seen: Vec::new() }
+    }
+}
+
+impl Default for ObservedPlmnAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Analyzer for ObservedPlmnAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("Observed PLMNs")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from("Lists the networks (MCC/MNC) broadcast by cells seen during the recording.")
+    }
+
+    fn analyze_information_element(&mut self, ie: &InformationElement) -> Option<Event> {
+        let new_plmns: Vec<Plmn> = sib1_plmns(ie)
+            .into_iter()
+            .filter(|plmn| !self.seen.contains(plmn))
+            .collect();
+        if new_plmns.is_empty() {
+            return None;
+        }
+        let message = format!(
+            "Observed new PLMN(s): {}",
+            new_plmns.iter().map(|plmn| plmn.to_string()).collect::<Vec<_>>().join(", ")
+        );
+        self.seen.extend(new_plmns);
+        Some(Event {
+            event_type: EventType::Informational,
+            message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telcom_parser::lte_rrc::{MCC_MNC_Digit, PLMN_Identity, PLMN_IdentityInfo, PLMN_IdentityInfoCellReservedForOperatorUse, MCC, MNC};
+
+    fn digits(s: &str) -> Vec<MCC_MNC_Digit> {
+        s.chars().map(|c| MCC_MNC_Digit(c.to_digit(10).unwrap() as u8)).collect()
+    }
+
+    fn identity_info(mcc: Option<&str>, mnc: &str) -> PLMN_IdentityInfo {
+        PLMN_IdentityInfo {
+            plmn_identity: PLMN_Identity {
+                mcc: mcc.map(|mcc| MCC(digits(mcc))),
+                mnc: MNC(digits(mnc)),
+            },
+            cell_reserved_for_operator_use: PLMN_IdentityInfoCellReservedForOperatorUse(
+                PLMN_IdentityInfoCellReservedForOperatorUse::NOT_RESERVED
+            ),
+        }
+    }
+
+    #[test]
+    fn test_operator_names() {
+        assert_eq!(Plmn::new("310", "410").operator_name(), Some("AT&T"));
+        assert_eq!(Plmn::new("234", "10").operator_name(), Some("O2 UK"));
+        assert_eq!(Plmn::new("999", "99").operator_name(), None);
+        // 2 and 3 digit MNCs aren't interchangeable
+        assert_eq!(Plmn::new("234", "010").operator_name(), None);
+
+        assert_eq!(Plmn::new("311", "480").to_string(), "311-480 (Verizon)");
+        assert_eq!(Plmn::new("999", "99").to_string(), "999-99");
+    }
+
+    #[test]
+    fn test_plmns_from_identity_list() {
+        let list = PLMN_IdentityList(vec![
+            identity_info(Some("310"), "410"),
+            identity_info(None, "260"),
+        ]);
+        assert_eq!(plmns_from_identity_list(&list), vec![
+            Plmn::new("310", "410"),
+            Plmn::new("310", "260"),
+        ]);
+    }
+}
@@ -0,0 +1,167 @@
+use std::borrow::Cow;
+
+use chrono::{DateTime, Duration, FixedOffset};
+use telcom_parser::lte_rrc::{DL_DCCH_MessageType, DL_DCCH_MessageType_c1};
+
+use super::analyzer::{Analyzer, Event, EventType, Severity};
+use super::information_element::{InformationElement, LteInformationElement};
+
+const RAT_DOWNGRADE_WINDOW_SECS: i64 = 60;
+
+/// Watches for the modem dropping from LTE to GSM or UMTS shortly after it was
+/// last seen camping on LTE. IMSI catchers commonly jam or otherwise push UEs
+/// off of LTE onto 2G, where there's no mutual authentication and ciphering can
+/// be disabled. A downgrade the network explicitly orders via a
+/// MobilityFromEUTRACommand (e.g. CS fallback for a voice call) isn't flagged.
+pub struct RatDowngradeAnalyzer {
+    window: Duration,
+    // when we last saw LTE signalling, cleared once we've warned about the
+    // downgrade which followed it, or the network ordered us off of LTE
+    last_lte_timestamp: Option<DateTime<FixedOffset>>,
+}
+
+impl RatDowngradeAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            window: Duration::seconds(RAT_DOWNGRADE_WINDOW_SECS),
+            last_lte_timestamp: None,
+        }
+    }
+}
+
+impl Default for RatDowngradeAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_mobility_from_eutra_command(lte_ie: &LteInformationElement) -> bool {
+    matches!(
+        lte_ie,
+        LteInformationElement::DlDcch(message)
+            if matches!(message.message, DL_DCCH_MessageType::C1(DL_DCCH_MessageType_c1::MobilityFromEUTRACommand(_)))
+    )
+}
+
+impl Analyzer for RatDowngradeAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("LTE to 2G/3G Downgrade")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from(format!(
+            "Tests whether GSM or UMTS signalling shows up within {} seconds of LTE signalling, without the LTE network having ordered the switch. Losing LTE coverage (e.g. in a basement or rural area) can cause false positives.",
+            self.window.num_seconds(),
+        ))
+    }
+
+    // downgrades only make sense with timestamps, see below
+    fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+        None
+    }
+
+    fn analyze_information_element_at(&mut self, ie: &InformationElement, timestamp: DateTime<FixedOffset>) -> Option<Event> {
+        let (rat, severity) = match ie {
+            InformationElement::LTE(lte_ie) => {
+                self.last_lte_timestamp = if is_mobility_from_eutra_command(lte_ie) {
+                    None
+                } else {
+                    Some(timestamp)
+                };
+                return None;
+            },
+            // unlike GSM, UMTS still mutually authenticates the network, so
+            // ending up there is less worrying
+            InformationElement::GSM => ("2G (GSM)", Severity::High),
+            InformationElement::UMTS => ("3G (UMTS)", Severity::Medium),
+            InformationElement::FiveG => return None,
+        };
+        let elapsed = timestamp - self.last_lte_timestamp.take()?;
+        if elapsed > self.window {
+            return None;
+        }
+        Some(Event {
+            event_type: EventType::QualitativeWarning { severity },
+            message: format!(
+                "Downgraded from LTE to {} {} seconds after the last LTE signalling, without being ordered to by the network",
+                rat,
+                elapsed.num_seconds(),
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telcom_parser::lte_rrc::{
+        DL_DCCH_Message, MobilityFromEUTRACommand, MobilityFromEUTRACommandCriticalExtensions,
+        MobilityFromEUTRACommandCriticalExtensions_criticalExtensionsFuture, PCCH_Message,
+        PCCH_MessageType, PCCH_MessageType_c1, Paging, RRC_TransactionIdentifier,
+    };
+
+    fn lte_paging() -> InformationElement {
+        InformationElement::LTE(LteInformationElement::PCCH(PCCH_Message {
+            message: PCCH_MessageType::C1(PCCH_MessageType_c1::Paging(Paging {
+                paging_record_list: None,
+                system_info_modification: None,
+                etws_indication: None,
+                non_critical_extension: None,
+            })),
+        }))
+    }
+
+    fn mobility_from_eutra_command() -> InformationElement {
+        InformationElement::LTE(LteInformationElement::DlDcch(DL_DCCH_Message {
+            message: DL_DCCH_MessageType::C1(DL_DCCH_MessageType_c1::MobilityFromEUTRACommand(MobilityFromEUTRACommand {
+                rrc_transaction_identifier: RRC_TransactionIdentifier(0),
+                critical_extensions: MobilityFromEUTRACommandCriticalExtensions::CriticalExtensionsFuture(
+                    MobilityFromEUTRACommandCriticalExtensions_criticalExtensionsFuture {}
+                ),
+            })),
+        }))
+    }
+
+    #[test]
+    fn test_lte_to_gsm_downgrade() {
+        let mut analyzer = RatDowngradeAnalyzer::new();
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+
+        // GSM without any prior LTE isn't a downgrade
+        assert!(analyzer.analyze_information_element_at(&InformationElement::GSM, start).is_none());
+
+        for i in 0..5 {
+            assert!(analyzer.analyze_information_element_at(&lte_paging(), start + Duration::seconds(i)).is_none());
+        }
+        let event = analyzer.analyze_information_element_at(&InformationElement::GSM, start + Duration::seconds(10))
+            .expect("expected a warning");
+        assert!(matches!(event.event_type, EventType::QualitativeWarning { severity: Severity::High }));
+        assert!(event.message.contains("2G (GSM) 6 seconds"));
+
+        // staying on GSM only warns once
+        assert!(analyzer.analyze_information_element_at(&InformationElement::GSM, start + Duration::seconds(11)).is_none());
+
+        // back to LTE, then down to UMTS
+        assert!(analyzer.analyze_information_element_at(&lte_paging(), start + Duration::seconds(20)).is_none());
+        let event = analyzer.analyze_information_element_at(&InformationElement::UMTS, start + Duration::seconds(21))
+            .expect("expected a warning");
+        assert!(matches!(event.event_type, EventType::QualitativeWarning { severity: Severity::Medium }));
+    }
+
+    #[test]
+    fn test_legitimate_rat_changes() {
+        let mut analyzer = RatDowngradeAnalyzer::new();
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+
+        // LTE went quiet long before we showed up on GSM
+        assert!(analyzer.analyze_information_element_at(&lte_paging(), start).is_none());
+        let later = start + Duration::seconds(RAT_DOWNGRADE_WINDOW_SECS + 1);
+        assert!(analyzer.analyze_information_element_at(&InformationElement::GSM, later).is_none());
+
+        // the network ordered the switch, e.g. for CS fallback
+        let start = later + Duration::seconds(10);
+        assert!(analyzer.analyze_information_element_at(&lte_paging(), start).is_none());
+        assert!(analyzer.analyze_information_element_at(&mobility_from_eutra_command(), start + Duration::seconds(1)).is_none());
+        assert!(analyzer.analyze_information_element_at(&InformationElement::GSM, start + Duration::seconds(2)).is_none());
+    }
+}
@@ -0,0 +1,178 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Duration, FixedOffset};
+use telcom_parser::lte_rrc::{DL_DCCH_MessageType, DL_DCCH_MessageType_c1, UL_CCCH_MessageType, UL_CCCH_MessageType_c1};
+
+use super::analyzer::{Analyzer, Event, EventType, Severity};
+use super::information_element::{InformationElement, LteInformationElement};
+
+pub const RECONFIGURATION_THRESHOLD: usize = 30;
+pub const RECONFIGURATION_WINDOW_SECS: i64 = 60;
+
+/// Watches for RRC connection reconfigurations and reestablishment requests
+/// arriving more than `threshold` times within `window`. An IMSI catcher may
+/// force a UE through rapid handovers or radio link failures to move it onto
+/// a cell of its choosing, whereas a legitimate network only reconfigures the
+/// connection now and then, e.g. on a handover or when a bearer is set up.
+pub struct RrcReconfigurationRateAnalyzer {
+    threshold: usize,
+    window: Duration,
+    recent_reconfigurations: VecDeque<DateTime<FixedOffset>>,
+    // whether we've already warned about the current burst
+    warned: bool,
+}
+
+impl RrcReconfigurationRateAnalyzer {
+    pub fn new(threshold: usize, window: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            recent_reconfigurations: VecDeque::new(),
+            warned: false,
+        }
+    }
+}
+
+impl Default for RrcReconfigurationRateAnalyzer {
+    fn default() -> Self {
+        Self::new(RECONFIGURATION_THRESHOLD, Duration::seconds(RECONFIGURATION_WINDOW_SECS))
+    }
+}
+
+fn is_reconfiguration(ie: &InformationElement) -> bool {
+    match ie {
+        InformationElement::LTE(LteInformationElement::DlDcch(dl_dcch)) => matches!(
+            dl_dcch.message,
+            DL_DCCH_MessageType::C1(DL_DCCH_MessageType_c1::RrcConnectionReconfiguration(_)),
+        ),
+        InformationElement::LTE(LteInformationElement::UlCcch(ul_ccch)) => matches!(
+            ul_ccch.message,
+            UL_CCCH_MessageType::C1(UL_CCCH_MessageType_c1::RrcConnectionReestablishmentRequest(_)),
+        ),
+        _ => false,
+    }
+}
+
+impl Analyzer for RrcReconfigurationRateAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("RRC Reconfiguration Rate")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from(format!(
+            "Tests whether the connection is reconfigured or reestablished more than {} times within {} seconds. Moving quickly between cells (e.g. on a highway or train) can cause false positives.",
+            self.threshold,
+            self.window.num_seconds(),
+        ))
+    }
+
+    // reconfiguration rates only make sense with timestamps, see below
+    fn analyze_information_element(&mut self, _ie: &InformationElement) -> Option<Event> {
+        None
+    }
+
+    fn analyze_information_element_at(&mut self, ie: &InformationElement, timestamp: DateTime<FixedOffset>) -> Option<Event> {
+        if !is_reconfiguration(ie) {
+            return None;
+        }
+        self.recent_reconfigurations.push_back(timestamp);
+        while self.recent_reconfigurations.front().is_some_and(|oldest| timestamp - *oldest > self.window) {
+            self.recent_reconfigurations.pop_front();
+        }
+
+        let count = self.recent_reconfigurations.len();
+        if count <= self.threshold {
+            self.warned = false;
+            return None;
+        }
+        if self.warned {
+            return None;
+        }
+        self.warned = true;
+        let window_secs = self.window.num_seconds();
+        Some(Event {
+            event_type: EventType::QualitativeWarning { severity: Severity::Medium },
+            message: format!(
+                "RRC connection was reconfigured or reestablished {} times within {} seconds ({:.1}/minute)",
+                count,
+                window_secs,
+                count as f64 * 60.0 / window_secs as f64,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use telcom_parser::lte_rrc::{
+        DL_DCCH_Message, RRCConnectionReconfiguration, RRCConnectionReconfigurationCriticalExtensions,
+        RRCConnectionReconfigurationCriticalExtensions_criticalExtensionsFuture, RRCConnectionReestablishmentRequest,
+        RRCConnectionReestablishmentRequestCriticalExtensions,
+        RRCConnectionReestablishmentRequestCriticalExtensions_criticalExtensionsFuture, RRC_TransactionIdentifier,
+        UL_CCCH_Message,
+    };
+
+    fn reconfiguration() -> InformationElement {
+        InformationElement::LTE(LteInformationElement::DlDcch(DL_DCCH_Message {
+            message: DL_DCCH_MessageType::C1(DL_DCCH_MessageType_c1::RrcConnectionReconfiguration(
+                RRCConnectionReconfiguration {
+                    rrc_transaction_identifier: RRC_TransactionIdentifier(0),
+                    critical_extensions: RRCConnectionReconfigurationCriticalExtensions::CriticalExtensionsFuture(
+                        RRCConnectionReconfigurationCriticalExtensions_criticalExtensionsFuture {},
+                    ),
+                },
+            )),
+        }))
+    }
+
+    fn reestablishment_request() -> InformationElement {
+        InformationElement::LTE(LteInformationElement::UlCcch(UL_CCCH_Message {
+            message: UL_CCCH_MessageType::C1(UL_CCCH_MessageType_c1::RrcConnectionReestablishmentRequest(
+                RRCConnectionReestablishmentRequest {
+                    critical_extensions: RRCConnectionReestablishmentRequestCriticalExtensions::CriticalExtensionsFuture(
+                        RRCConnectionReestablishmentRequestCriticalExtensions_criticalExtensionsFuture {},
+                    ),
+                },
+            )),
+        }))
+    }
+
+    #[test]
+    fn test_reconfiguration_burst() {
+        let mut analyzer = RrcReconfigurationRateAnalyzer::new(10, Duration::seconds(60));
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+
+        // reconfigurations and reestablishment requests both count
+        for i in 0..10 {
+            let ie = if i % 2 == 0 { reconfiguration() } else { reestablishment_request() };
+            assert!(analyzer.analyze_information_element_at(&ie, start + Duration::seconds(i)).is_none());
+        }
+        let event = analyzer.analyze_information_element_at(&reconfiguration(), start + Duration::seconds(10))
+            .expect("expected a warning");
+        assert!(matches!(event.event_type, EventType::QualitativeWarning { severity: Severity::Medium }));
+        assert!(event.message.contains("11 times within 60 seconds"));
+
+        // the same burst only warns once
+        assert!(analyzer.analyze_information_element_at(&reconfiguration(), start + Duration::seconds(11)).is_none());
+
+        // once the burst has aged out of the window, a new one warns again
+        let later = start + Duration::seconds(200);
+        assert!(analyzer.analyze_information_element_at(&reconfiguration(), later).is_none());
+        for i in 1..10 {
+            assert!(analyzer.analyze_information_element_at(&reconfiguration(), later + Duration::seconds(i)).is_none());
+        }
+        assert!(analyzer.analyze_information_element_at(&reconfiguration(), later + Duration::seconds(10)).is_some());
+    }
+
+    #[test]
+    fn test_normal_reconfiguration_rate() {
+        let mut analyzer = RrcReconfigurationRateAnalyzer::default();
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        // a handover or so every 10 seconds, for an hour
+        for i in 0..360 {
+            assert!(analyzer.analyze_information_element_at(&reconfiguration(), start + Duration::seconds(i * 10)).is_none());
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::borrow::Cow;
+
+use super::analyzer::{Analyzer, Event, EventType, Severity};
+use super::information_element::{InformationElement, LteInformationElement};
+use super::util::plain_emm_message;
+
+const DOWNLINK_NAS_TRANSPORT: u8 = 0x62;
+// See 3GPP TS 24.011 for the SMS control protocol (CP) and relay protocol (RP)
+const SMS_PROTOCOL_DISCRIMINATOR: u8 = 0x09;
+const CP_DATA: u8 = 0x01;
+const RP_DATA_NETWORK_TO_MS: u8 = 0x01;
+// See 3GPP TS 23.040 section 9.2 for the SMS transfer protocol (TP)
+const SMS_DELIVER: u8 = 0x00;
+const SHORT_MESSAGE_TYPE_0: u8 = 0x40;
+
+/// Looks for silent (type 0) SMS sent to the UE over LTE NAS. Phones
+/// acknowledge these without showing or storing them, so they can be used to
+/// make a phone talk to the network and reveal its location without its user
+/// noticing.
+pub struct SilentSmsAnalyzer {
+}
+
+/// Returns the value of the length-value IE starting at offset.
+fn length_value(buf: &[u8], offset: usize) -> Option<&[u8]> {
+    let len = *buf.get(offset)? as usize;
+    buf.get(offset + 1..offset + 1 + len)
+}
+
+/// Returns the TP-Protocol-Identifier of the SMS-DELIVER carried by a NAS
+/// message, if it's an EMM Downlink NAS Transport carrying one.
+fn sms_deliver_protocol_identifier(payload: &[u8]) -> Option<u8> {
+    let message = plain_emm_message(payload)?;
+    if *message.get(1)? != DOWNLINK_NAS_TRANSPORT {
+        return None;
+    }
+    // the NAS message container holds a CP-DATA message...
+    let cp = length_value(message, 2)?;
+    if cp.first()? & 0x0f != SMS_PROTOCOL_DISCRIMINATOR || *cp.get(1)? != CP_DATA {
+        return None;
+    }
+    // ...which holds an RP-DATA message...
+    let rp = length_value(cp, 2)?;
+    if rp.first()? & 0x07 != RP_DATA_NETWORK_TO_MS {
+        return None;
+    }
+    // ...whose user data follows the message reference and the originator
+    // and destination addresses
+    let mut offset = 2;
+    offset += 1 + *rp.get(offset)? as usize;
+    offset += 1 + *rp.get(offset)? as usize;
+    let tpdu = length_value(rp, offset)?;
+    if tpdu.first()? & 0x03 != SMS_DELIVER {
+        return None;
+    }
+    // the originating address is its length in digits, its type, then the
+    // digits packed two to an octet, and the protocol identifier follows it
+    let address_digits = *tpdu.get(1)? as usize;
+    tpdu.get(3 + address_digits.div_ceil(2)).copied()
+}
+
+impl Analyzer for SilentSmsAnalyzer {
+    fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("Silent SMS")
+    }
+
+    fn get_description(&self) -> Cow<'_, str> {
+        Cow::from("Tests whether the network delivers a silent (type 0) SMS, which the phone acknowledges without telling its user")
+    }
+
+    fn analyze_information_element(&mut self, ie: &InformationElement) -> Option<Event> {
+        // only incoming messages (0xb0e2 and 0xb0ec) can deliver an SMS to us
+        let InformationElement::LTE(LteInformationElement::NAS { uplink: false, payload }) = ie else {
+            return None;
+        };
+        if sms_deliver_protocol_identifier(payload)? != SHORT_MESSAGE_TYPE_0 {
+            return None;
+        }
+        Some(Event {
+            event_type: EventType::QualitativeWarning { severity: Severity::Medium },
+            message: "Received a silent (type 0) SMS in a downlink NAS message".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A Downlink NAS Transport delivering a type 0 SMS with no text, still
+    // wrapped in its security protected header
+    const SILENT_SMS: &[u8] = &[
+        0x27, 0x1a, 0x2b, 0x3c, 0x4d, 0x05,
+        0x07, 0x62, 0x22,
+        // CP-DATA
+        0x09, 0x01, 0x1f,
+        // RP-DATA, from an SMSC at +447785016005
+        0x01, 0x05, 0x07, 0x91, 0x44, 0x77, 0x58, 0x10, 0x06, 0x50, 0x00, 0x13,
+        // SMS-DELIVER, from +44791732546
+        0x04, 0x0b, 0x91, 0x44, 0x97, 0x71, 0x23, 0x45, 0xf6, 0x40, 0x00,
+        0x42, 0x10, 0x10, 0x21, 0x43, 0x65, 0x00, 0x00,
+    ];
+
+    // The same SMS as a normal one saying "hello", without the security
+    // protected header
+    const BENIGN_SMS: &[u8] = &[
+        0x07, 0x62, 0x27,
+        0x09, 0x01, 0x24,
+        0x01, 0x05, 0x07, 0x91, 0x44, 0x77, 0x58, 0x10, 0x06, 0x50, 0x00, 0x18,
+        0x04, 0x0b, 0x91, 0x44, 0x97, 0x71, 0x23, 0x45, 0xf6, 0x00, 0x00,
+        0x42, 0x10, 0x10, 0x21, 0x43, 0x65, 0x00, 0x05, 0xe8, 0x32, 0x9b, 0xfd, 0x06,
+    ];
+
+    fn nas(uplink: bool, payload: &[u8]) -> InformationElement {
+        InformationElement::LTE(LteInformationElement::NAS { uplink, payload: payload.to_vec() })
+    }
+
+    #[test]
+    fn test_sms_deliver_protocol_identifier() {
+        assert_eq!(sms_deliver_protocol_identifier(SILENT_SMS), Some(SHORT_MESSAGE_TYPE_0));
+        assert_eq!(sms_deliver_protocol_identifier(&SILENT_SMS[6..]), Some(SHORT_MESSAGE_TYPE_0));
+        assert_eq!(sms_deliver_protocol_identifier(BENIGN_SMS), Some(0x00));
+        // a Security Mode Command
+        assert_eq!(sms_deliver_protocol_identifier(&[0x07, 0x5d, 0x11, 0x00]), None);
+        // truncated anywhere along the way
+        for len in 0..SILENT_SMS.len() {
+            assert_eq!(sms_deliver_protocol_identifier(&SILENT_SMS[..len]), None, "parsed {len} bytes");
+        }
+    }
+
+    #[test]
+    fn test_silent_sms() {
+        let mut analyzer = SilentSmsAnalyzer {};
+        let event = analyzer.analyze_information_element(&nas(false, SILENT_SMS))
+            .expect("expected a warning");
+        assert!(matches!(event.event_type, EventType::QualitativeWarning { severity: Severity::Medium }));
+        assert!(event.message.contains("downlink"));
+
+        assert!(analyzer.analyze_information_element(&nas(false, BENIGN_SMS)).is_none());
+        // outgoing messages can't deliver an SMS to us
+        assert!(analyzer.analyze_information_element(&nas(true, SILENT_SMS)).is_none());
+    }
+}
@@ -30,3 +30,28 @@ macro_rules! unpack {
 
 // this is apparently how you make a macro publicly usable from this module
 pub(crate) use unpack;
+
+// See 3GPP TS 24.301 section 9 for the layout of EMM messages
+const EMM_PROTOCOL_DISCRIMINATOR: u8 = 0x07;
+// security header type, MAC and sequence number
+const SECURITY_PROTECTED_HEADER_LEN: usize = 6;
+
+// Returns the plain EMM message in a NAS payload, unwrapping it from its
+// security protected header if it has one. Returns None for anything that
+// isn't an EMM message, or whose contents are ciphered.
+pub(crate) fn plain_emm_message(payload: &[u8]) -> Option<&[u8]> {
+    let header = *payload.first()?;
+    if header & 0x0f != EMM_PROTOCOL_DISCRIMINATOR {
+        return None;
+    }
+    let message = match header >> 4 {
+        0 => payload,
+        // a plain message wrapped in a security protected one
+        1..=4 => payload.get(SECURITY_PROTECTED_HEADER_LEN..)?,
+        _ => return None,
+    };
+    if message.first()? & 0x0f != EMM_PROTOCOL_DISCRIMINATOR {
+        return None;
+    }
+    Some(message)
+}
@@ -0,0 +1,116 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Duration, FixedOffset};
+
+use super::analyzer::{Event, EventType, Severity};
+
+/// A meta-heuristic which, rather than inspecting messages itself, watches the
+/// warnings emitted by the other [Analyzers](super::analyzer::Analyzer) and
+/// raises its own warning when they arrive faster than some threshold. A sudden
+/// burst of warnings is suspicious even if each one is individually benign.
+pub struct WarningRateAnalyzer {
+    max_warnings: usize,
+    window: Duration,
+    recent_warnings: VecDeque<DateTime<FixedOffset>>,
+}
+
+impl WarningRateAnalyzer {
+    pub fn new(max_warnings: usize, window: Duration) -> Self {
+        Self {
+            max_warnings,
+            window,
+            recent_warnings: VecDeque::new(),
+        }
+    }
+
+    pub fn get_name(&self) -> Cow<'_, str> {
+        Cow::from("Warning Rate")
+    }
+
+    pub fn get_description(&self) -> Cow<'_, str> {
+        Cow::from(format!(
+            "Tests whether other heuristics raised more than {} warnings within {} seconds",
+            self.max_warnings,
+            self.window.num_seconds(),
+        ))
+    }
+
+    /// Records the warnings in a single packet's analysis results, returning an
+    /// [Event] if the rolling warning rate exceeds the threshold. Once it fires,
+    /// the window is reset so that a single burst only raises one alarm.
+    pub fn analyze_events(&mut self, timestamp: DateTime<FixedOffset>, events: &[Option<Event>]) -> Option<Event> {
+        let num_warnings = events.iter()
+            .flatten()
+            .filter(|event| matches!(event.event_type, EventType::QualitativeWarning { .. }))
+            .count();
+        for _ in 0..num_warnings {
+            self.recent_warnings.push_back(timestamp);
+        }
+        while let Some(oldest) = self.recent_warnings.front() {
+            if timestamp - *oldest > self.window {
+                self.recent_warnings.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.recent_warnings.len() > self.max_warnings {
+            let count = self.recent_warnings.len();
+            self.recent_warnings.clear();
+            return Some(Event {
+                event_type: EventType::QualitativeWarning { severity: Severity::High },
+                message: format!(
+                    "{} warnings were raised within {} seconds",
+                    count,
+                    self.window.num_seconds(),
+                ),
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warning() -> Option<Event> {
+        Some(Event {
+            event_type: EventType::QualitativeWarning { severity: Severity::Low },
+            message: "test warning".to_string(),
+        })
+    }
+
+    fn info() -> Option<Event> {
+        Some(Event {
+            event_type: EventType::Informational,
+            message: "test info".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_burst_triggers_alarm() {
+        let mut analyzer = WarningRateAnalyzer::new(10, Duration::seconds(60));
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        for i in 0..10 {
+            let timestamp = start + Duration::seconds(i);
+            assert!(analyzer.analyze_events(timestamp, &[warning(), None, info()]).is_none());
+        }
+        let event = analyzer.analyze_events(start + Duration::seconds(10), &[None, warning()])
+            .expect("expected the 11th warning to trigger the alarm");
+        assert!(matches!(event.event_type, EventType::QualitativeWarning { severity: Severity::High }));
+
+        // the window resets after firing
+        assert!(analyzer.analyze_events(start + Duration::seconds(11), &[warning()]).is_none());
+    }
+
+    #[test]
+    fn test_slow_warnings_dont_trigger_alarm() {
+        let mut analyzer = WarningRateAnalyzer::new(10, Duration::seconds(60));
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        for i in 0..100 {
+            let timestamp = start + Duration::seconds(i * 10);
+            assert!(analyzer.analyze_events(timestamp, &[warning()]).is_none());
+        }
+    }
+}
@@ -6,6 +6,7 @@ use deku::prelude::*;
 
 use crate::hdlc::{self, hdlc_decapsulate};
 use log::{warn, error};
+use serde::Serialize;
 use thiserror::Error;
 
 pub const MESSAGE_TERMINATOR: u8 = 0x7e;
@@ -86,6 +87,15 @@ pub struct MessagesContainer {
 
 impl MessagesContainer {
     pub fn into_messages(self) -> Vec<Result<Message, DiagParsingError>> {
+        self.into_messages_with_data().into_iter()
+            .map(|maybe_msg| maybe_msg.map(|(msg, _)| msg))
+            .collect()
+    }
+
+    /// Like [MessagesContainer::into_messages], but also returns each
+    /// message's HDLC-decapsulated bytes, for when the raw message is needed
+    /// too.
+    pub fn into_messages_with_data(self) -> Vec<Result<(Message, Vec<u8>), DiagParsingError>> {
         let mut result = Vec::new();
         for msg in self.messages {
             for sub_msg in msg.data.split_inclusive(|&b| b == MESSAGE_TERMINATOR) {
@@ -95,7 +105,7 @@ impl MessagesContainer {
                             if !leftover_bytes.is_empty() {
                                 warn!("warning: {} leftover bytes when parsing Message", leftover_bytes.len());
                             }
-                            result.push(Ok(res));
+                            result.push(Ok((res, data)));
                         },
                         Err(e) => result.push(Err(DiagParsingError::MessageParsingError(e, data))),
                     },
@@ -114,18 +124,22 @@ pub struct HdlcEncapsulatedMessage {
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite, Serialize)]
 #[deku(type = "u8")]
 pub enum Message {
     #[deku(id = "16")]
     Log {
         pending_msgs: u8,
         outer_length: u16,
+        // the log type and timestamp alone take up 12 bytes, so anything
+        // shorter is malformed
+        #[deku(assert = "*inner_length >= 12")]
         inner_length: u16,
         log_type: u16,
         timestamp: Timestamp,
-        // pass the log type and log length (inner_length - (sizeof(log_type) + sizeof(timestamp)))
-        #[deku(ctx = "*log_type, *inner_length - 12")]
+        // pass the log type and log length (inner_length - (sizeof(log_type) + sizeof(timestamp))),
+        // still saturating in case the assert above is ever dropped
+        #[deku(ctx = "*log_type, inner_length.saturating_sub(12)")]
         body: LogBody,
     },
 
@@ -143,7 +157,7 @@ pub enum Message {
     },
 }
 
-#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite, Serialize)]
 #[deku(ctx = "log_type: u16, hdr_len: u16", id = "log_type")]
 pub enum LogBody {
     #[deku(id = "0x412f")]
@@ -190,13 +204,30 @@ pub enum LogBody {
         rrc_version_minor: u8,
         rrc_version_major: u8,
         // message length = hdr_len - (sizeof(ext_header_version) + sizeof(rrc_rel) + sizeof(rrc_version_minor) + sizeof(rrc_version_major))
-        #[deku(count = "hdr_len - 4")]
+        #[deku(assert = "hdr_len >= 4", count = "hdr_len.saturating_sub(4)")]
+        msg: Vec<u8>,
+    },
+    // the four 5G NAS command opcodes refer to:
+    // * 0xb800: plain 5GSM NAS message (incoming)
+    // * 0xb801: plain 5GSM NAS message (outgoing)
+    // * 0xb80a: plain 5GMM NAS message (incoming)
+    // * 0xb80b: plain 5GMM NAS message (outgoing)
+    #[deku(id_pat = "0xb800 | 0xb801 | 0xb80a | 0xb80b")]
+    Nas5GMessage {
+        #[deku(ctx = "log_type")]
+        direction: Nas5GMessageDirection,
+        ext_header_version: u8,
+        rel_major: u8,
+        rel_minor: u8,
+        rel_patch: u8,
+        // message length = hdr_len - (sizeof(ext_header_version) + sizeof(rel_major) + sizeof(rel_minor) + sizeof(rel_patch))
+        #[deku(assert = "hdr_len >= 4", count = "hdr_len.saturating_sub(4)")]
         msg: Vec<u8>,
     },
     #[deku(id = "0x11eb")]
     IpTraffic {
         // is this right?? based on https://github.com/P1sec/QCSuper/blob/81dbaeee15ec7747e899daa8e3495e27cdcc1264/src/modules/pcap_dump.py#L378
-        #[deku(count = "hdr_len - 8")]
+        #[deku(assert = "hdr_len >= 8", count = "hdr_len.saturating_sub(8)")]
         msg: Vec<u8>,
     },
     #[deku(id = "0x713a")]
@@ -208,12 +239,33 @@ pub enum LogBody {
     },
     #[deku(id = "0xb821")]
     NrRrcOtaMessage {
-        #[deku(count = "hdr_len")]
-        msg: Vec<u8>,
+        #[deku(assert = "hdr_len >= 4")]
+        packet_version: u32,
+        // packet length = hdr_len - sizeof(packet_version)
+        #[deku(ctx = "*packet_version, hdr_len.saturating_sub(4)")]
+        packet: NrRrcOtaPacket,
     }
 }
 
-#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+impl LogBody {
+    /// Returns the name of this log body's variant, e.g. for tallying which
+    /// kinds of messages the modem is logging.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogBody::WcdmaSignallingMessage { .. } => "WcdmaSignallingMessage",
+            LogBody::GsmRrSignallingMessage { .. } => "GsmRrSignallingMessage",
+            LogBody::GprsMacSignallingMessage { .. } => "GprsMacSignallingMessage",
+            LogBody::LteRrcOtaMessage { .. } => "LteRrcOtaMessage",
+            LogBody::Nas4GMessage { .. } => "Nas4GMessage",
+            LogBody::Nas5GMessage { .. } => "Nas5GMessage",
+            LogBody::IpTraffic { .. } => "IpTraffic",
+            LogBody::UmtsNasOtaMessage { .. } => "UmtsNasOtaMessage",
+            LogBody::NrRrcOtaMessage { .. } => "NrRrcOtaMessage",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite, Serialize)]
 #[deku(ctx = "log_type: u16", id = "log_type")]
 pub enum Nas4GMessageDirection {
     // * 0xb0e2: plain ESM NAS message (incoming)
@@ -226,7 +278,16 @@ pub enum Nas4GMessageDirection {
     Uplink,
 }
 
-#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite, Serialize)]
+#[deku(ctx = "log_type: u16", id = "log_type")]
+pub enum Nas5GMessageDirection {
+    #[deku(id_pat = "0xb800 | 0xb80a")]
+    Downlink,
+    #[deku(id_pat = "0xb801 | 0xb80b")]
+    Uplink,
+}
+
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite, Serialize)]
 #[deku(ctx = "ext_header_version: u8", id = "ext_header_version")]
 pub enum LteRrcOtaPacket {
     #[deku(id_pat = "0..=4")]
@@ -288,6 +349,87 @@ pub enum LteRrcOtaPacket {
     },
 }
 
+// Based on the layouts SCAT parses in
+// https://github.com/fgsect/scat/blob/97442580e628de414c9f7c2a185f4e28d0ee7523/src/scat/parsers/qualcomm/diagnrlogparser.py
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite, Serialize)]
+#[deku(ctx = "packet_version: u32, packet_len: u16", id = "packet_version")]
+pub enum NrRrcOtaPacket {
+    #[deku(id_pat = "9 | 12 | 14")]
+    V9 {
+        rrc_rel_maj: u8,
+        rrc_rel_min: u8,
+        bearer_id: u8,
+        phy_cell_id: u16,
+        nr_arfcn: u32,
+        sfn_subfn: u32,
+        pdu_num: u8,
+        sib_mask: u32,
+        len: u16,
+        #[deku(count = "len")]
+        packet: Vec<u8>,
+    },
+    #[deku(id = "17")]
+    V17 {
+        rrc_rel_maj: u8,
+        rrc_rel_min: u8,
+        bearer_id: u8,
+        phy_cell_id: u16,
+        unknown: u64,
+        nr_arfcn: u32,
+        sfn_subfn: u32,
+        pdu_num: u8,
+        sib_mask: u32,
+        len: u16,
+        #[deku(count = "len")]
+        packet: Vec<u8>,
+    },
+    // versions we don't know the layout of are kept as raw bytes
+    #[deku(id_pat = "_")]
+    Unknown {
+        #[deku(count = "packet_len")]
+        msg: Vec<u8>,
+    },
+}
+
+impl NrRrcOtaPacket {
+    pub fn get_phy_cell_id(&self) -> Option<u16> {
+        match self {
+            NrRrcOtaPacket::V9 { phy_cell_id, .. } => Some(*phy_cell_id),
+            NrRrcOtaPacket::V17 { phy_cell_id, .. } => Some(*phy_cell_id),
+            NrRrcOtaPacket::Unknown { .. } => None,
+        }
+    }
+
+    pub fn get_nr_arfcn(&self) -> Option<u32> {
+        match self {
+            NrRrcOtaPacket::V9 { nr_arfcn, .. } => Some(*nr_arfcn),
+            NrRrcOtaPacket::V17 { nr_arfcn, .. } => Some(*nr_arfcn),
+            NrRrcOtaPacket::Unknown { .. } => None,
+        }
+    }
+
+    pub fn get_pdu_num(&self) -> Option<u8> {
+        match self {
+            NrRrcOtaPacket::V9 { pdu_num, .. } => Some(*pdu_num),
+            NrRrcOtaPacket::V17 { pdu_num, .. } => Some(*pdu_num),
+            NrRrcOtaPacket::Unknown { .. } => None,
+        }
+    }
+
+    pub fn get_band(&self) -> Option<Band> {
+        self.get_nr_arfcn().and_then(nr_arfcn_to_band)
+    }
+
+    // For unknown versions, this is everything after the packet version
+    pub fn take_payload(self) -> Vec<u8> {
+        match self {
+            NrRrcOtaPacket::V9 { packet, .. } => packet,
+            NrRrcOtaPacket::V17 { packet, .. } => packet,
+            NrRrcOtaPacket::Unknown { msg } => msg,
+        }
+    }
+}
+
 impl LteRrcOtaPacket {
     fn get_sfn_subfn(&self) -> u16 {
         match self {
@@ -314,6 +456,15 @@ impl LteRrcOtaPacket {
         }
     }
 
+    pub fn get_phy_cell_id(&self) -> u16 {
+        match self {
+            LteRrcOtaPacket::V0 { phy_cell_id, .. } => *phy_cell_id,
+            LteRrcOtaPacket::V5 { phy_cell_id, .. } => *phy_cell_id,
+            LteRrcOtaPacket::V8 { phy_cell_id, .. } => *phy_cell_id,
+            LteRrcOtaPacket::V25 { phy_cell_id, .. } => *phy_cell_id,
+        }
+    }
+
     pub fn get_earfcn(&self) -> u32 {
         match self {
             LteRrcOtaPacket::V0 { earfcn, .. } => *earfcn as u32,
@@ -331,9 +482,159 @@ impl LteRrcOtaPacket {
             LteRrcOtaPacket::V25 { packet, .. } => packet,
         }
     }
+
+    pub fn get_band(&self) -> Option<Band> {
+        earfcn_to_band(self.get_earfcn())
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+/// A 3GPP frequency band, displayed the way users usually refer to them, e.g.
+/// "B2" for LTE or "n78" for NR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Band {
+    Lte(u16),
+    Nr(u16),
+}
+
+impl std::fmt::Display for Band {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Band::Lte(band) => write!(f, "B{}", band),
+            Band::Nr(band) => write!(f, "n{}", band),
+        }
+    }
+}
+
+impl serde::Serialize for Band {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// (band, first EARFCN, last EARFCN) for each band's downlink and, where it has
+// a separate one, uplink, per 3GPP TS 36.101 table 5.7.3-1
+const LTE_EARFCN_RANGES: &[(u16, u32, u32)] = &[
+    (1, 0, 599), (1, 18000, 18599),
+    (2, 600, 1199), (2, 18600, 19199),
+    (3, 1200, 1949), (3, 19200, 19949),
+    (4, 1950, 2399), (4, 19950, 20399),
+    (5, 2400, 2649), (5, 20400, 20649),
+    (6, 2650, 2749), (6, 20650, 20749),
+    (7, 2750, 3449), (7, 20750, 21449),
+    (8, 3450, 3799), (8, 21450, 21799),
+    (9, 3800, 4149), (9, 21800, 22149),
+    (10, 4150, 4749), (10, 22150, 22749),
+    (11, 4750, 4949), (11, 22750, 22949),
+    (12, 5010, 5179), (12, 23010, 23179),
+    (13, 5180, 5279), (13, 23180, 23279),
+    (14, 5280, 5379), (14, 23280, 23379),
+    (17, 5730, 5849), (17, 23730, 23849),
+    (18, 5850, 5999), (18, 23850, 23999),
+    (19, 6000, 6149), (19, 24000, 24149),
+    (20, 6150, 6449), (20, 24150, 24449),
+    (21, 6450, 6599), (21, 24450, 24599),
+    (22, 6600, 7399), (22, 24600, 25399),
+    (23, 7500, 7699), (23, 25500, 25699),
+    (24, 7700, 8039), (24, 25700, 26039),
+    (25, 8040, 8689), (25, 26040, 26689),
+    (26, 8690, 9039), (26, 26690, 27039),
+    (27, 9040, 9209), (27, 27040, 27209),
+    (28, 9210, 9659), (28, 27210, 27659),
+    (29, 9660, 9769),
+    (30, 9770, 9869), (30, 27660, 27759),
+    (31, 9870, 9919), (31, 27760, 27809),
+    (32, 9920, 10359),
+    // TDD bands share EARFCNs between uplink and downlink
+    (33, 36000, 36199),
+    (34, 36200, 36349),
+    (35, 36350, 36949),
+    (36, 36950, 37549),
+    (37, 37550, 37749),
+    (38, 37750, 38249),
+    (39, 38250, 38649),
+    (40, 38650, 39649),
+    (41, 39650, 41589),
+    (42, 41590, 43589),
+    (43, 43590, 45589),
+    (44, 45590, 46589),
+    (45, 46590, 46789),
+    (46, 46790, 54539),
+    (47, 54540, 55239),
+    (48, 55240, 56739),
+    (49, 56740, 58239),
+    (50, 58240, 59089),
+    (51, 59090, 59139),
+    (52, 59140, 60139),
+    (53, 60140, 60254),
+    (65, 65536, 66435), (65, 131072, 131971),
+    (66, 66436, 67335), (66, 131972, 132671),
+    (67, 67336, 67535),
+    (68, 67536, 67835), (68, 132672, 132971),
+    (69, 67836, 68335),
+    (70, 68336, 68585), (70, 132972, 133121),
+    (71, 68586, 68935), (71, 133122, 133471),
+    (72, 68936, 68985), (72, 133472, 133521),
+    (73, 68986, 69035), (73, 133522, 133571),
+    (74, 69036, 69465), (74, 133572, 134001),
+    (75, 69466, 70315),
+    (76, 70316, 70365),
+    (85, 70366, 70545), (85, 134002, 134181),
+    (87, 70546, 70595), (87, 134182, 134231),
+    (88, 70596, 70645), (88, 134232, 134281),
+];
+
+// (band, first NR-ARFCN, last NR-ARFCN) for common NR bands, per 3GPP TS
+// 38.101-1 table 5.4.2.3-1 and 38.101-2 table 5.4.2.3-1
+const NR_ARFCN_RANGES: &[(u16, u32, u32)] = &[
+    (1, 422000, 434000),
+    (2, 386000, 398000),
+    (3, 361000, 376000),
+    (5, 173800, 178800),
+    (7, 524000, 538000),
+    (8, 185000, 192000),
+    (12, 145800, 149200),
+    (13, 149200, 151200),
+    (14, 151600, 153600),
+    (20, 158200, 164200),
+    (25, 386000, 399000),
+    (26, 171800, 178800),
+    (28, 151600, 160600),
+    (29, 143400, 145600),
+    (30, 470000, 472000),
+    (38, 514000, 524000),
+    (40, 460000, 480000),
+    (41, 499200, 537999),
+    (48, 636667, 646666),
+    (66, 422000, 440000),
+    (70, 399000, 404000),
+    (71, 123400, 130400),
+    (77, 620000, 680000),
+    (78, 620000, 653333),
+    (79, 693334, 733333),
+    (258, 2016667, 2070832),
+    (260, 2229166, 2279165),
+    (261, 2070833, 2084999),
+];
+
+/// Returns the LTE band an EARFCN (uplink or downlink) belongs to, if it's a
+/// known one.
+pub fn earfcn_to_band(earfcn: u32) -> Option<Band> {
+    LTE_EARFCN_RANGES.iter()
+        .find(|(_, first, last)| (*first..=*last).contains(&earfcn))
+        .map(|(band, _, _)| Band::Lte(*band))
+}
+
+/// Returns the NR band an NR-ARFCN belongs to, if it's a known one. NR bands
+/// overlap a lot (e.g. n78 lies entirely within n77), so this picks the
+/// narrowest band containing the NR-ARFCN.
+pub fn nr_arfcn_to_band(nr_arfcn: u32) -> Option<Band> {
+    NR_ARFCN_RANGES.iter()
+        .filter(|(_, first, last)| (*first..=*last).contains(&nr_arfcn))
+        .min_by_key(|(_, first, last)| last - first)
+        .map(|(band, _, _)| Band::Nr(*band))
+}
+
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite, Serialize)]
 #[deku(endian = "little")]
 pub struct Timestamp {
     pub ts: u64,
@@ -353,14 +654,14 @@ impl Timestamp {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite, Serialize)]
 #[deku(ctx = "opcode: u32, subopcode: u32", id = "opcode")]
 pub enum ResponsePayload {
     #[deku(id = "115")]
     LogConfig(#[deku(ctx = "subopcode")] LogConfigResponse),
 }
 
-#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite, Serialize)]
 #[deku(ctx = "subopcode: u32", id = "subopcode")]
 pub enum LogConfigResponse {
     #[deku(id = "1")]
@@ -397,6 +698,12 @@ pub fn build_log_mask_request(log_type: u32, log_mask_bitsize: u32, accepted_log
     })
 }
 
+/// Builds a SetMask request which turns off every log code of the given type,
+/// e.g. to stop the modem logging once we're no longer reading.
+pub fn build_clear_log_mask_request(log_type: u32, log_mask_bitsize: u32) -> Request {
+    build_log_mask_request(log_type, log_mask_bitsize, &[])
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -440,6 +747,24 @@ mod test {
         }));
     }
 
+    #[test]
+    fn test_build_clear_log_mask_request() {
+        let req = build_clear_log_mask_request(11, 513);
+        assert_eq!(req, Request::LogConfig(LogConfigRequest::SetMask {
+            log_type: 11,
+            log_mask_bitsize: 513,
+            log_mask: vec![0; 65],
+        }));
+        let bytes = req.to_bytes().unwrap();
+        assert_eq!(bytes[..16], [
+            115, 0, 0, 0,
+            3, 0, 0, 0,
+            11, 0, 0, 0,
+            1, 2, 0, 0,
+        ]);
+        assert_eq!(bytes[16..], [0; 65]);
+    }
+
     #[test]
     fn test_request_container() {
         let req = RequestContainer {
@@ -465,6 +790,106 @@ mod test {
         ]);
     }
 
+    #[test]
+    fn test_earfcn_to_band() {
+        assert_eq!(earfcn_to_band(0), Some(Band::Lte(1)));
+        assert_eq!(earfcn_to_band(599), Some(Band::Lte(1)));
+        assert_eq!(earfcn_to_band(600), Some(Band::Lte(2)));
+        assert_eq!(earfcn_to_band(2050), Some(Band::Lte(4)));
+        assert_eq!(earfcn_to_band(5110), Some(Band::Lte(12)));
+        assert_eq!(earfcn_to_band(19950), Some(Band::Lte(4)));
+        assert_eq!(earfcn_to_band(66435), Some(Band::Lte(65)));
+        assert_eq!(earfcn_to_band(66436), Some(Band::Lte(66)));
+        assert_eq!(earfcn_to_band(68586), Some(Band::Lte(71)));
+        // the gap between bands 11 and 12
+        assert_eq!(earfcn_to_band(5000), None);
+        assert_eq!(earfcn_to_band(u32::MAX), None);
+        assert_eq!(Band::Lte(66).to_string(), "B66");
+    }
+
+    #[test]
+    fn test_nr_arfcn_to_band() {
+        assert_eq!(nr_arfcn_to_band(630000), Some(Band::Nr(78)));
+        assert_eq!(nr_arfcn_to_band(653334), Some(Band::Nr(77)));
+        assert_eq!(nr_arfcn_to_band(640000), Some(Band::Nr(48)));
+        assert_eq!(nr_arfcn_to_band(125000), Some(Band::Nr(71)));
+        assert_eq!(nr_arfcn_to_band(100000), None);
+        assert_eq!(Band::Nr(78).to_string(), "n78");
+    }
+
+    #[test]
+    fn test_nr_rrc_ota_logs() {
+        let data = vec![
+            16, 0, 40, 0, 40, 0, 33, 184, 26, 165, 245, 135, 118, 35, 2, 1,
+            // packet version 9
+            9, 0, 0, 0,
+            // rrc release 15.3, bearer 1, phy_cell_id 273
+            15, 3, 1, 17, 1,
+            // nr_arfcn 630000
+            240, 156, 9, 0,
+            // sfn_subfn, pdu_num, sib_mask
+            4, 1, 0, 0, 1, 0, 0, 0, 0,
+            // 4 byte PDU
+            4, 0, 0x12, 0x34, 0x56, 0x78,
+        ];
+        let msg = Message::from_bytes((&data, 0)).unwrap().1;
+        let Message::Log { body: LogBody::NrRrcOtaMessage { packet_version, packet }, .. } = &msg else {
+            panic!("unexpected message {:?}", msg);
+        };
+        assert_eq!(*packet_version, 9);
+        assert_eq!(packet, &NrRrcOtaPacket::V9 {
+            rrc_rel_maj: 15,
+            rrc_rel_min: 3,
+            bearer_id: 1,
+            phy_cell_id: 273,
+            nr_arfcn: 630000,
+            sfn_subfn: 260,
+            pdu_num: 1,
+            sib_mask: 0,
+            len: 4,
+            packet: vec![0x12, 0x34, 0x56, 0x78],
+        });
+        assert_eq!(packet.get_band(), Some(Band::Nr(78)));
+        assert_eq!(msg.to_bytes().unwrap(), data);
+
+        // unknown versions keep their raw bytes
+        let mut data = data;
+        data[16] = 99;
+        let msg = Message::from_bytes((&data, 0)).unwrap().1;
+        let Message::Log { body: LogBody::NrRrcOtaMessage { packet, .. }, .. } = &msg else {
+            panic!("unexpected message {:?}", msg);
+        };
+        assert_eq!(packet, &NrRrcOtaPacket::Unknown { msg: data[20..].to_vec() });
+        assert_eq!(packet.get_nr_arfcn(), None);
+        assert_eq!(msg.to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn test_truncated_logs() {
+        // inner_length is too short to even cover the log header
+        let data = vec![16, 0, 4, 0, 4, 0, 0xc0, 0xb0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(Message::from_bytes((&data, 0)).is_err());
+        // hdr_len is too short to cover a Nas4GMessage's header
+        let data = vec![16, 0, 14, 0, 14, 0, 0xe2, 0xb0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2];
+        assert!(Message::from_bytes((&data, 0)).is_err());
+
+        // even with enough data following them, undersized lengths are
+        // rejected rather than read as empty payloads
+        let trailing = [0x55; 32];
+        let nas = [&[16, 0, 14, 0, 14, 0, 0xe2, 0xb0, 0, 0, 0, 0, 0, 0, 0, 0][..], &trailing].concat();
+        assert!(matches!(Message::from_bytes((&nas, 0)), Err(deku::DekuError::Assertion(_))));
+        let ip_traffic = [&[16, 0, 19, 0, 19, 0, 0xeb, 0x11, 0, 0, 0, 0, 0, 0, 0, 0][..], &trailing].concat();
+        assert!(matches!(Message::from_bytes((&ip_traffic, 0)), Err(deku::DekuError::Assertion(_))));
+        let mut lte_rrc = vec![
+            16, 0, 38, 0, 38, 0, 192, 176, 26, 165, 245, 135, 118, 35, 2, 1, 20,
+            14, 48, 0, 160, 0, 2, 8, 0, 0, 217, 15, 5, 0, 0, 0, 0, 7, 0, 64, 1,
+            238, 173, 213, 77, 208
+        ];
+        assert!(Message::from_bytes((&lte_rrc, 0)).is_ok());
+        lte_rrc[4] = 4;
+        assert!(matches!(Message::from_bytes((&lte_rrc, 0)), Err(deku::DekuError::Assertion(_))));
+    }
+
     #[test]
     fn test_logs() {
         let data = vec![
@@ -497,6 +922,33 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_5g_nas_logs() {
+        // a synthetic log of a plain 5GMM Identity Request asking for the
+        // SUCI, built by hand from the 0xb80a log format (with test_logs'
+        // timestamp) rather than captured from a device
+        let data = vec![
+            16, 0, 20, 0, 20, 0, 10, 184, 26, 165, 245, 135, 118, 35, 2, 1, 1,
+            16, 2, 0, 126, 0, 91, 1,
+        ];
+        let msg = Message::from_bytes((&data, 0)).unwrap().1;
+        assert_eq!(msg, Message::Log {
+            pending_msgs: 0,
+            outer_length: 20,
+            inner_length: 20,
+            log_type: 0xb80a,
+            timestamp: Timestamp { ts: 72659535985485082 },
+            body: LogBody::Nas5GMessage {
+                direction: Nas5GMessageDirection::Downlink,
+                ext_header_version: 1,
+                rel_major: 16,
+                rel_minor: 2,
+                rel_patch: 0,
+                msg: vec![0x7e, 0x00, 0x5b, 0x01],
+            },
+        });
+    }
+
     fn make_container(data_type: DataType, message: HdlcEncapsulatedMessage) -> MessagesContainer {
         MessagesContainer {
             data_type,
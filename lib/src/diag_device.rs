@@ -1,9 +1,10 @@
-use crate::hdlc::hdlc_encapsulate;
-use crate::diag::{build_log_mask_request, DataType, DiagParsingError, LogConfigRequest, LogConfigResponse, Message, MessagesContainer, Request, RequestContainer, ResponsePayload, CRC_CCITT};
+use crate::hdlc::{hdlc_decapsulate, hdlc_encapsulate};
+use crate::diag::{build_clear_log_mask_request, build_log_mask_request, DataType, DiagParsingError, LogConfigRequest, LogConfigResponse, Message, MessagesContainer, Request, RequestContainer, ResponsePayload, CRC_CCITT, MESSAGE_TERMINATOR};
 use crate::log_codes;
 
 use std::io::ErrorKind;
 use std::os::fd::AsRawFd;
+use std::time::Duration;
 use futures_core::TryStream;
 use thiserror::Error;
 use log::{info, warn, error};
@@ -25,6 +26,8 @@ pub enum DiagDeviceError {
     RequestFailed(u32, Request),
     #[error("Didn't receive response for request: {0:?}")]
     NoResponse(Request),
+    #[error("Didn't receive a response to the raw request within {0:?}")]
+    NoRawResponse(Duration),
     #[error("Failed to open QMDL file: {0}")]
     OpenQmdlFileError(std::io::Error),
     #[error("Failed to write to QMDL file: {0}")]
@@ -35,7 +38,7 @@ pub enum DiagDeviceError {
     ParseMessagesContainerError(deku::DekuError),
 }
 
-pub const LOG_CODES_FOR_RAW_PACKET_LOGGING: [u32; 11] = [
+pub const LOG_CODES_FOR_RAW_PACKET_LOGGING: [u32; 15] = [
     // Layer 2:
     log_codes::LOG_GPRS_MAC_SIGNALLING_MESSAGE_C, // 0x5226
 
@@ -51,12 +54,21 @@ pub const LOG_CODES_FOR_RAW_PACKET_LOGGING: [u32; 11] = [
     log_codes::LOG_LTE_NAS_ESM_OTA_OUT_MSG_LOG_C, // 0xb0e3
     log_codes::LOG_LTE_NAS_EMM_OTA_IN_MSG_LOG_C, // 0xb0ec
     log_codes::LOG_LTE_NAS_EMM_OTA_OUT_MSG_LOG_C, // 0xb0ed
+    log_codes::LOG_NR_NAS_5GSM_PLAIN_OTA_IN_MSG_LOG_C, // 0xb800
+    log_codes::LOG_NR_NAS_5GSM_PLAIN_OTA_OUT_MSG_LOG_C, // 0xb801
+    log_codes::LOG_NR_NAS_5GMM_PLAIN_OTA_IN_MSG_LOG_C, // 0xb80a
+    log_codes::LOG_NR_NAS_5GMM_PLAIN_OTA_OUT_MSG_LOG_C, // 0xb80b
 
     // User IP traffic:
     log_codes::LOG_DATA_PROTOCOL_LOGGING_C // 0x11eb
 ];
 
 const BUFFER_LEN: usize = 1024 * 1024 * 10;
+/// The command code of log messages, which keep arriving alongside responses
+/// while logging is enabled
+const DIAG_LOG_F: u8 = 0x10;
+/// How long [DiagDevice::send_raw_request] waits for a response
+const RAW_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
 const MEMORY_DEVICE_MODE: i32 = 2;
 
 #[cfg(target_arch = "arm")]
@@ -122,6 +134,10 @@ impl DiagDevice {
 
     async fn write_request(&mut self, req: &Request) -> DiagResult<()> {
         let req_bytes = &req.to_bytes().expect("Failed to serialize Request");
+        self.write_request_bytes(req_bytes).await
+    }
+
+    async fn write_request_bytes(&mut self, req_bytes: &[u8]) -> DiagResult<()> {
         let buf = RequestContainer {
             data_type: DataType::UserSpace,
             use_mdm: self.use_mdm > 0,
@@ -155,6 +171,38 @@ impl DiagDevice {
         }
     }
 
+    /// Sends an arbitrary serialized diag request, returning the raw (HDLC
+    /// decapsulated) bytes of each message in the response. This is a
+    /// reverse-engineering aid, so no attempt is made to parse the response.
+    pub async fn send_raw_request(&mut self, req_bytes: &[u8]) -> DiagResult<Vec<Vec<u8>>> {
+        self.write_request_bytes(req_bytes).await?;
+        tokio::time::timeout(RAW_RESPONSE_TIMEOUT, self.read_raw_response()).await
+            .map_err(|_| DiagDeviceError::NoRawResponse(RAW_RESPONSE_TIMEOUT))?
+    }
+
+    /// Reads containers until one holds something other than log messages,
+    /// returning the raw bytes of each message in it.
+    async fn read_raw_response(&mut self) -> DiagResult<Vec<Vec<u8>>> {
+        loop {
+            let container = self.get_next_messages_container().await?;
+            if container.data_type != DataType::UserSpace {
+                continue;
+            }
+            let mut result = Vec::new();
+            for msg in container.messages {
+                for sub_msg in msg.data.split_inclusive(|&b| b == MESSAGE_TERMINATOR) {
+                    match hdlc_decapsulate(sub_msg, &CRC_CCITT) {
+                        Ok(data) => result.push(data),
+                        Err(e) => error!("error decapsulating message: {:?}", e),
+                    }
+                }
+            }
+            if result.iter().any(|data| data.first() != Some(&DIAG_LOG_F)) {
+                return Ok(result);
+            }
+        }
+    }
+
     async fn retrieve_id_ranges(&mut self) -> DiagResult<[u32; 16]> {
         let req = Request::LogConfig(LogConfigRequest::RetrieveIdRanges);
         self.write_request(&req).await?;
@@ -178,8 +226,7 @@ impl DiagDevice {
         Err(DiagDeviceError::NoResponse(req))
     }
 
-    async fn set_log_mask(&mut self, log_type: u32, log_mask_bitsize: u32) -> DiagResult<()> {
-        let req = build_log_mask_request(log_type, log_mask_bitsize, &LOG_CODES_FOR_RAW_PACKET_LOGGING);
+    async fn set_log_mask(&mut self, req: Request) -> DiagResult<()> {
         self.write_request(&req).await?;
 
         for msg in self.read_response().await? {
@@ -206,13 +253,29 @@ impl DiagDevice {
 
         for (log_type, &log_mask_bitsize) in log_mask_sizes.iter().enumerate() {
             if log_mask_bitsize > 0 {
-                self.set_log_mask(log_type as u32, log_mask_bitsize).await?;
+                let req = build_log_mask_request(log_type as u32, log_mask_bitsize, &LOG_CODES_FOR_RAW_PACKET_LOGGING);
+                self.set_log_mask(req).await?;
                 info!("enabled logging for log type {}", log_type);
             }
         }
 
         Ok(())
     }
+
+    /// Clears every log mask set by [DiagDevice::config_logs], so the modem
+    /// stops logging once we're no longer reading from it.
+    pub async fn disable_logs(&mut self) -> DiagResult<()> {
+        let log_mask_sizes = self.retrieve_id_ranges().await?;
+
+        for (log_type, &log_mask_bitsize) in log_mask_sizes.iter().enumerate() {
+            if log_mask_bitsize > 0 {
+                self.set_log_mask(build_clear_log_mask_request(log_type as u32, log_mask_bitsize)).await?;
+                info!("disabled logging for log type {}", log_type);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 // Triggers the diag device's debug logging mode
@@ -116,9 +116,86 @@ fn log_to_gsmtap(value: LogBody) -> Result<Option<GsmtapMessage>, GsmtapParserEr
                 payload: msg,
             }))
         },
+        LogBody::GsmRrSignallingMessage { channel_type, msg, .. } => {
+            // the top bit is set for uplink messages, and the rest is one of
+            // the GSM channel types in log_codes
+            let subtype = match channel_type & 0x7f {
+                0x00 | 0x04 | 0x05 => UmSubtype::Sdcch, // DCCH, SACCH, SDCCH
+                0x01 => UmSubtype::Bcch,
+                0x02 | 0x08 => UmSubtype::Rach,
+                0x03 => UmSubtype::Ccch,
+                0x06 => UmSubtype::TchF,
+                0x07 => UmSubtype::TchH,
+                _ => UmSubtype::Unknown,
+            };
+            let mut header = GsmtapHeader::new(GsmtapType::Um(subtype));
+            header.uplink = channel_type & 0x80 != 0;
+            Ok(Some(GsmtapMessage {
+                header,
+                payload: msg,
+            }))
+        },
+        LogBody::WcdmaSignallingMessage { channel_type, msg, .. } => {
+            // these are the RRCLOG_SIG_* channel types in log_codes
+            let (subtype, uplink) = match channel_type {
+                0 => (UmtsRrcSubtype::UlCcch, true),
+                1 => (UmtsRrcSubtype::UlDcch, true),
+                2 => (UmtsRrcSubtype::DlCcch, false),
+                3 => (UmtsRrcSubtype::DlDcch, false),
+                4 => (UmtsRrcSubtype::BcchBch, false),
+                5 => (UmtsRrcSubtype::BcchFach, false),
+                6 => (UmtsRrcSubtype::Pcch, false),
+                7 => (UmtsRrcSubtype::Mcch, false),
+                8 => (UmtsRrcSubtype::Msch, false),
+                // SIB extensions and containers need unwrapping before
+                // they can be dissected, which we don't do yet
+                _ => return Ok(None),
+            };
+            let mut header = GsmtapHeader::new(GsmtapType::UmtsRrc(subtype));
+            header.uplink = uplink;
+            Ok(Some(GsmtapMessage {
+                header,
+                payload: msg,
+            }))
+        },
+        // GSMTAP has no type for 5G NAS, so these can't be written to a pcap
+        LogBody::Nas5GMessage { .. } => Ok(None),
         _ => {
             error!("gsmtap_sink: ignoring unhandled log type: {:?}", value);
             Ok(None)
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gsm_rr_and_wcdma() {
+        let body = LogBody::GsmRrSignallingMessage {
+            channel_type: 0x80, // uplink DCCH
+            message_type: 0x27,
+            length: 2,
+            msg: vec![0x06, 0x27],
+        };
+        let gsmtap_msg = log_to_gsmtap(body).unwrap().unwrap();
+        assert_eq!(gsmtap_msg.header.gsmtap_type, GsmtapType::Um(UmSubtype::Sdcch));
+        assert!(gsmtap_msg.header.uplink);
+        assert_eq!(gsmtap_msg.payload, vec![0x06, 0x27]);
+
+        let body = LogBody::WcdmaSignallingMessage {
+            channel_type: 6, // PCCH
+            radio_bearer: 0,
+            length: 1,
+            msg: vec![0x40],
+        };
+        let gsmtap_msg = log_to_gsmtap(body).unwrap().unwrap();
+        assert_eq!(gsmtap_msg.header.gsmtap_type, GsmtapType::UmtsRrc(UmtsRrcSubtype::Pcch));
+        assert!(!gsmtap_msg.header.uplink);
+
+        // SIB containers aren't supported
+        let body = LogBody::WcdmaSignallingMessage { channel_type: 10, radio_bearer: 0, length: 0, msg: vec![] };
+        assert!(log_to_gsmtap(body).unwrap().is_none());
+    }
+}
@@ -5,6 +5,7 @@ pub mod qmdl;
 pub mod log_codes;
 pub mod gsmtap;
 pub mod gsmtap_parser;
+pub mod nas_5g;
 pub mod pcap;
 pub mod analysis;
 pub mod util;
@@ -28,6 +28,10 @@ pub const PACKET_CHANNEL_REQUEST: u32 = 0x20;
 // These are 5G-related log types.
 
 pub const LOG_NR_RRC_OTA_MSG_LOG_C: u32 = 0xb821;
+pub const LOG_NR_NAS_5GSM_PLAIN_OTA_IN_MSG_LOG_C: u32 = 0xb800;
+pub const LOG_NR_NAS_5GSM_PLAIN_OTA_OUT_MSG_LOG_C: u32 = 0xb801;
+pub const LOG_NR_NAS_5GMM_PLAIN_OTA_IN_MSG_LOG_C: u32 = 0xb80a;
+pub const LOG_NR_NAS_5GMM_PLAIN_OTA_OUT_MSG_LOG_C: u32 = 0xb80b;
 
 // These are 4G-related log types.
 
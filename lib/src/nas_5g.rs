@@ -0,0 +1,147 @@
+//! Minimal parsing of 5G NAS messages, as described in 3GPP TS 24.501. We
+//! only go as far as identifying 5GMM message types, which is enough for
+//! heuristics to recognize things like Identity Requests.
+
+use thiserror::Error;
+
+// Extended protocol discriminators (TS 24.501 section 9.2)
+pub const EPD_5GMM: u8 = 0x7e;
+pub const EPD_5GSM: u8 = 0x2e;
+
+// Length of the security protected 5GS NAS message header (EPD, security
+// header type, MAC, and sequence number) which precedes the plain message
+const SECURITY_PROTECTED_HEADER_LEN: usize = 7;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Nas5GParsingError {
+    #[error("Message too short")]
+    MessageTooShort,
+    #[error("Not a 5GMM message (extended protocol discriminator {0:#04x})")]
+    Not5GmmMessage(u8),
+    #[error("Message is ciphered")]
+    Ciphered,
+    #[error("Unknown security header type {0}")]
+    UnknownSecurityHeaderType(u8),
+}
+
+// 5GMM message types (TS 24.501 section 9.7, table 9.7.1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FiveGmmMessageType {
+    RegistrationRequest,
+    RegistrationAccept,
+    RegistrationComplete,
+    RegistrationReject,
+    DeregistrationRequestUeOriginating,
+    DeregistrationAcceptUeOriginating,
+    DeregistrationRequestUeTerminated,
+    DeregistrationAcceptUeTerminated,
+    ServiceRequest,
+    ServiceReject,
+    ServiceAccept,
+    ConfigurationUpdateCommand,
+    ConfigurationUpdateComplete,
+    AuthenticationRequest,
+    AuthenticationResponse,
+    AuthenticationReject,
+    AuthenticationFailure,
+    AuthenticationResult,
+    IdentityRequest,
+    IdentityResponse,
+    SecurityModeCommand,
+    SecurityModeComplete,
+    SecurityModeReject,
+    FiveGmmStatus,
+    Notification,
+    NotificationResponse,
+    UlNasTransport,
+    DlNasTransport,
+    Unknown(u8),
+}
+
+impl From<u8> for FiveGmmMessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x41 => FiveGmmMessageType::RegistrationRequest,
+            0x42 => FiveGmmMessageType::RegistrationAccept,
+            0x43 => FiveGmmMessageType::RegistrationComplete,
+            0x44 => FiveGmmMessageType::RegistrationReject,
+            0x45 => FiveGmmMessageType::DeregistrationRequestUeOriginating,
+            0x46 => FiveGmmMessageType::DeregistrationAcceptUeOriginating,
+            0x47 => FiveGmmMessageType::DeregistrationRequestUeTerminated,
+            0x48 => FiveGmmMessageType::DeregistrationAcceptUeTerminated,
+            0x4c => FiveGmmMessageType::ServiceRequest,
+            0x4d => FiveGmmMessageType::ServiceReject,
+            0x4e => FiveGmmMessageType::ServiceAccept,
+            0x54 => FiveGmmMessageType::ConfigurationUpdateCommand,
+            0x55 => FiveGmmMessageType::ConfigurationUpdateComplete,
+            0x56 => FiveGmmMessageType::AuthenticationRequest,
+            0x57 => FiveGmmMessageType::AuthenticationResponse,
+            0x58 => FiveGmmMessageType::AuthenticationReject,
+            0x59 => FiveGmmMessageType::AuthenticationFailure,
+            0x5a => FiveGmmMessageType::AuthenticationResult,
+            0x5b => FiveGmmMessageType::IdentityRequest,
+            0x5c => FiveGmmMessageType::IdentityResponse,
+            0x5d => FiveGmmMessageType::SecurityModeCommand,
+            0x5e => FiveGmmMessageType::SecurityModeComplete,
+            0x5f => FiveGmmMessageType::SecurityModeReject,
+            0x64 => FiveGmmMessageType::FiveGmmStatus,
+            0x65 => FiveGmmMessageType::Notification,
+            0x66 => FiveGmmMessageType::NotificationResponse,
+            0x67 => FiveGmmMessageType::UlNasTransport,
+            0x68 => FiveGmmMessageType::DlNasTransport,
+            other => FiveGmmMessageType::Unknown(other),
+        }
+    }
+}
+
+/// Returns the 5GMM message type of a 5G NAS message. Integrity protected
+/// messages are unwrapped to get at the plain message inside, but ciphered
+/// messages can't be read.
+pub fn parse_5gmm_message_type(msg: &[u8]) -> Result<FiveGmmMessageType, Nas5GParsingError> {
+    if msg.len() < 3 {
+        return Err(Nas5GParsingError::MessageTooShort);
+    }
+    if msg[0] != EPD_5GMM {
+        return Err(Nas5GParsingError::Not5GmmMessage(msg[0]));
+    }
+    match msg[1] & 0x0f {
+        // plain 5GS NAS message
+        0 => Ok(FiveGmmMessageType::from(msg[2])),
+        // integrity protected, with or without a new 5G NAS security context
+        1 | 3 => match msg.get(SECURITY_PROTECTED_HEADER_LEN..) {
+            Some(inner) => parse_5gmm_message_type(inner),
+            None => Err(Nas5GParsingError::MessageTooShort),
+        },
+        // integrity protected and ciphered
+        2 | 4 => Err(Nas5GParsingError::Ciphered),
+        other => Err(Nas5GParsingError::UnknownSecurityHeaderType(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_5gmm_message() {
+        let identity_request = [0x7e, 0x00, 0x5b, 0x01];
+        assert_eq!(parse_5gmm_message_type(&identity_request), Ok(FiveGmmMessageType::IdentityRequest));
+    }
+
+    #[test]
+    fn test_integrity_protected_5gmm_message() {
+        let security_mode_complete = [
+            0x7e, 0x03, 0x12, 0x34, 0x56, 0x78, 0x00,
+            0x7e, 0x00, 0x5e,
+        ];
+        assert_eq!(parse_5gmm_message_type(&security_mode_complete), Ok(FiveGmmMessageType::SecurityModeComplete));
+    }
+
+    #[test]
+    fn test_unparseable_5gmm_messages() {
+        assert_eq!(parse_5gmm_message_type(&[0x7e, 0x00]), Err(Nas5GParsingError::MessageTooShort));
+        assert_eq!(parse_5gmm_message_type(&[0x2e, 0x01, 0x00, 0xc1]), Err(Nas5GParsingError::Not5GmmMessage(EPD_5GSM)));
+        assert_eq!(parse_5gmm_message_type(&[0x7e, 0x02, 0x12, 0x34, 0x56, 0x78, 0x00, 0xaa]), Err(Nas5GParsingError::Ciphered));
+        assert_eq!(parse_5gmm_message_type(&[0x7e, 0x03, 0x12]), Err(Nas5GParsingError::MessageTooShort));
+    }
+}
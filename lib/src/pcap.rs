@@ -1,5 +1,7 @@
 //! Parse QMDL files and create a pcap file. 
 //! Creates a plausible IP header and [GSMtap](https://osmocom.org/projects/baseband/wiki/GSMTAP) header and then puts the rest of the data under that for wireshark to parse. 
+//! Alternatively, the raw diag messages can be written as-is under a
+//! user-defined link type, for use with dissectors which understand them.
 use crate::gsmtap::GsmtapMessage;
 use crate::diag::Timestamp;
 
@@ -48,6 +50,11 @@ struct IpHeader {
     dst_addr: u32,
 }
 
+/// The link type raw diag messages are written with. This is the first of the
+/// link types reserved for private use, since there's no official one for
+/// Qualcomm's diag protocol.
+pub const DIAG_LINKTYPE: pcap_file_tokio::DataLink = pcap_file_tokio::DataLink::USER0;
+
 const UDP_HEADER_LEN: u16 = 8;
 const GSMTAP_PORT: u16 = 4729;
 #[derive(DekuWrite)]
@@ -78,9 +85,21 @@ impl<T> GsmtapPcapWriter<T> where T: AsyncWrite + Unpin + Send {
         Ok(GsmtapPcapWriter { writer, ip_id: 0 })
     }
 
+    /// Writes the interface header for GSMTAP packets, which must come before
+    /// any calls to [GsmtapPcapWriter::write_gsmtap_message].
     pub async fn write_iface_header(&mut self) -> Result<(), GsmtapPcapError> {
+        self.write_iface_header_with_linktype(pcap_file_tokio::DataLink::IPV4).await
+    }
+
+    /// Writes the interface header for raw diag messages, which must come
+    /// before any calls to [GsmtapPcapWriter::write_diag_message].
+    pub async fn write_diag_iface_header(&mut self) -> Result<(), GsmtapPcapError> {
+        self.write_iface_header_with_linktype(DIAG_LINKTYPE).await
+    }
+
+    async fn write_iface_header_with_linktype(&mut self, linktype: pcap_file_tokio::DataLink) -> Result<(), GsmtapPcapError> {
         let interface = InterfaceDescriptionBlock {
-            linktype: pcap_file_tokio::DataLink::IPV4,
+            linktype,
             snaplen: 0xffff,
             options: vec![],
         };
@@ -89,15 +108,6 @@ impl<T> GsmtapPcapWriter<T> where T: AsyncWrite + Unpin + Send {
     }
 
     pub async fn write_gsmtap_message(&mut self, msg: GsmtapMessage, timestamp: Timestamp) -> Result<(), GsmtapPcapError> {
-        let duration = timestamp.to_datetime()
-            .signed_duration_since(DateTime::UNIX_EPOCH)
-            .to_std()?;
-
-        // despite the timestamp above being correct, we have reduce it by
-        // orders of magnitude due to a bug in pcap_file:
-        // https://github.com/courvoif/pcap-file/pull/32
-        let duration = std::time::Duration::from_nanos(duration.as_micros() as u64);
-
         let msg_bytes = msg.to_bytes()?;
         let ip_header = IpHeader {
             version_and_ihl: 0x45,
@@ -122,6 +132,26 @@ impl<T> GsmtapPcapWriter<T> where T: AsyncWrite + Unpin + Send {
         data.extend(&ip_header.to_bytes()?);
         data.extend(&udp_header.to_bytes()?);
         data.extend(&msg_bytes);
+        self.write_packet(data, timestamp).await?;
+        self.ip_id = self.ip_id.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Writes an HDLC-decapsulated diag message without any other headers.
+    pub async fn write_diag_message(&mut self, data: Vec<u8>, timestamp: Timestamp) -> Result<(), GsmtapPcapError> {
+        self.write_packet(data, timestamp).await
+    }
+
+    async fn write_packet(&mut self, data: Vec<u8>, timestamp: Timestamp) -> Result<(), GsmtapPcapError> {
+        let duration = timestamp.to_datetime()
+            .signed_duration_since(DateTime::UNIX_EPOCH)
+            .to_std()?;
+
+        // despite the timestamp above being correct, we have reduce it by
+        // orders of magnitude due to a bug in pcap_file:
+        // https://github.com/courvoif/pcap-file/pull/32
+        let duration = std::time::Duration::from_nanos(duration.as_micros() as u64);
+
         let packet = EnhancedPacketBlock {
             interface_id: 0,
             timestamp: duration,
@@ -130,7 +160,80 @@ impl<T> GsmtapPcapWriter<T> where T: AsyncWrite + Unpin + Send {
             options: vec![],
         };
         self.writer.write_pcapng_block(packet).await?;
-        self.ip_id = self.ip_id.wrapping_add(1);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diag::Message;
+    use crate::gsmtap_parser;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Splits pcapng data into (block type, block body) pairs
+    fn read_blocks(mut bytes: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        while !bytes.is_empty() {
+            let block_type = read_u32(bytes, 0);
+            let block_len = read_u32(bytes, 4) as usize;
+            assert_eq!(read_u32(bytes, block_len - 4) as usize, block_len);
+            blocks.push((block_type, &bytes[8..block_len - 4]));
+            bytes = &bytes[block_len..];
+        }
+        blocks
+    }
+
+    #[tokio::test]
+    async fn test_write_lte_rrc_message() {
+        // an LTE RRC OTA message carrying a PCCH PDU
+        let msg_bytes = &[
+            0x10, 0x00, 0x23, 0x00, 0x23, 0x00, 0xc0, 0xb0,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x1a, 0x0f, 0x40, 0x0f, 0x40, 0x01, 0x0e, 0x01,
+            0x13, 0x07, 0x00, 0x00, 0x00, 0x00, 0x0b, 0x00,
+            0x00, 0x00, 0x00, 0x02, 0x00, 0x10, 0x15,
+        ];
+        let (_, msg) = Message::from_bytes((msg_bytes, 0)).unwrap();
+        let (timestamp, gsmtap_msg) = gsmtap_parser::parse(msg).unwrap().unwrap();
+
+        let mut output = Vec::new();
+        let mut writer = GsmtapPcapWriter::new(&mut output).await.unwrap();
+        writer.write_iface_header().await.unwrap();
+        writer.write_gsmtap_message(gsmtap_msg, timestamp).await.unwrap();
+        drop(writer);
+
+        let blocks = read_blocks(&output);
+        assert_eq!(blocks.len(), 3);
+
+        // section header: byte order magic, then version 1.0
+        let (block_type, section) = blocks[0];
+        assert_eq!(block_type, 0x0a0d0d0a);
+        assert_eq!(read_u32(section, 0), 0x1a2b3c4d);
+        assert_eq!(&section[4..8], &[0, 1, 0, 0]);
+
+        // interface description: the IPv4 link type
+        let (block_type, interface) = blocks[1];
+        assert_eq!(block_type, 1);
+        assert_eq!(u16::from_be_bytes([interface[0], interface[1]]), 228);
+
+        // enhanced packet: IP and UDP headers, then the GSMTAP header and the
+        // RRC PDU
+        let (block_type, packet) = blocks[2];
+        assert_eq!(block_type, 6);
+        let captured_len = read_u32(packet, 12) as usize;
+        let data = &packet[20..20 + captured_len];
+        assert_eq!(captured_len, 20 + 8 + 16 + 2);
+        assert_eq!(data[0], 0x45);
+        assert_eq!(data[9], 0x11);
+        assert_eq!(u16::from_be_bytes([data[22], data[23]]), GSMTAP_PORT);
+        let gsmtap = &data[28..];
+        assert_eq!(gsmtap[0], 2); // version
+        assert_eq!(gsmtap[2], 0x0d); // LTE RRC
+        assert_eq!(gsmtap[12], 3); // PCCH
+        assert_eq!(&gsmtap[16..], &[0x10, 0x15]);
+    }
+}